@@ -7,26 +7,19 @@
 
 use core::cell::RefCell;
 
-use alloc::vec::Vec;
 use perun::{
     abiencode::types::U256,
     channel::{
         fixed_size_payment::{Allocation, Balances, ParticipantBalances},
-        Asset, ProposalBuildError, SignError,
+        Asset, ProposalAcceptError, ProposalBuildError, SignError,
     },
-    messages::{
-        ConversionError, FunderReplyMessage, LedgerChannelProposal, ParticipantMessage,
-        WatcherReplyMessage,
+    messages::{LedgerChannelProposal, ParticipantMessage, ServiceReplyMessage},
+    wire::{
+        decode_participant_message, decode_service_message, AuthError, BusError, DecodeError,
+        FrameDecoder, FrameError, Identity, ParticipantEnvelope, PeerAuth, ProtoBufEncodingLayer,
     },
-    perunwire::{
-        self,
-        envelope::{self, Msg},
-        Envelope,
-    },
-    wire::ProtoBufEncodingLayer,
-    Address, Hash, InvalidProposal, PerunClient,
+    Address, InvalidProposal, PerunClient,
 };
-use prost::{DecodeError, Message};
 use rand::{rngs::StdRng, Rng};
 use rand_core::RngCore;
 use smoltcp::{
@@ -41,9 +34,9 @@ use crate::{
     channel::{self, Channel},
 };
 
-/// We are currently copying from the rx-buffer to a slice for decoding
-/// protobuf, because that needs a single consecutive area of memory (see
-/// comments in [`try_recv`] for details).
+/// Largest message we can receive. Incoming frames are buffered in a
+/// [`FrameDecoder`] until they are complete, which needs a single consecutive
+/// area of memory for decoding protobuf.
 pub const MAX_MESSAGE_SIZE: usize = 510;
 
 /// Configuration for the demo: Peers and where to find the
@@ -53,7 +46,6 @@ pub struct Config {
     pub other_participant: (IpAddress, u16),
     pub service_server: (IpAddress, u16),
     pub listen_port: u16,
-    pub participants: [&'static str; 2],
 }
 
 /// State machine for the demo logic: Fetch information about the blockchain
@@ -68,8 +60,17 @@ where
     service_handle: SocketHandle,
     config: Config,
     rng: StdRng,
-    client: &'cl PerunClient<ProtoBufEncodingLayer<Bus<'cl, DeviceT>>>,
+    client: &'cl PerunClient<ProtoBufEncodingLayer<&'cl Bus<'cl, DeviceT>>>,
+    bus: &'cl Bus<'cl, DeviceT>,
     addr: Address,
+    participant_frames: FrameDecoder<{ MAX_MESSAGE_SIZE + 2 }>,
+    service_frames: FrameDecoder<{ MAX_MESSAGE_SIZE + 2 }>,
+    /// Wire address of the go-side participant, received from the config
+    /// dealer.
+    peer: Identity,
+    /// Authentication of the current participant connection, all participant
+    /// messages go through it.
+    auth: PeerAuth,
 }
 
 /// Enum to represent the states the Application can be in.
@@ -125,7 +126,7 @@ where
     Active {
         eth_holder: Address,
         withdraw_receiver: Address,
-        channel: Channel<'cl, ProtoBufEncodingLayer<Bus<'cl, DeviceT>>>,
+        channel: Channel<&'cl PerunClient<ProtoBufEncodingLayer<&'cl Bus<'cl, DeviceT>>>>,
     },
 }
 
@@ -133,13 +134,14 @@ where
 pub enum Error {
     Network(smoltcp::Error),
     InvalidProposal(InvalidProposal),
-    ProstDecode(DecodeError),
-    EnvelopeHasNoMsg,
+    Decode(DecodeError),
     UnexpectedMsg,
-    ConversionError(ConversionError),
     ChannelError(channel::Error),
     InvalidState,
-    MessageLargerThanRxBuffer(usize),
+    Frame(FrameError),
+    Bus(BusError),
+    Auth(AuthError),
+    ProposalAcceptError(ProposalAcceptError),
     ProposalBuildError(ProposalBuildError),
     SignError(SignError),
 }
@@ -154,14 +156,24 @@ impl From<InvalidProposal> for Error {
         Self::InvalidProposal(e)
     }
 }
-impl From<prost::DecodeError> for Error {
-    fn from(e: prost::DecodeError) -> Self {
-        Self::ProstDecode(e)
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Self {
+        Self::Frame(e)
+    }
+}
+impl From<BusError> for Error {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
     }
 }
-impl From<ConversionError> for Error {
-    fn from(e: ConversionError) -> Self {
-        Self::ConversionError(e)
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Self {
+        Self::Auth(e)
+    }
+}
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Self::Decode(e)
     }
 }
 impl From<channel::Error> for Error {
@@ -169,6 +181,11 @@ impl From<channel::Error> for Error {
         Self::ChannelError(e)
     }
 }
+impl From<ProposalAcceptError> for Error {
+    fn from(e: ProposalAcceptError) -> Self {
+        Self::ProposalAcceptError(e)
+    }
+}
 impl From<ProposalBuildError> for Error {
     fn from(e: ProposalBuildError) -> Self {
         Self::ProposalBuildError(e)
@@ -180,11 +197,6 @@ impl From<SignError> for Error {
     }
 }
 
-enum ServiceReplyMessage {
-    Watcher(WatcherReplyMessage),
-    Funder(FunderReplyMessage),
-}
-
 impl<'cl, DeviceT> Application<'cl, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
@@ -195,7 +207,8 @@ where
         config: Config,
         rng: StdRng,
         addr: Address,
-        client: &'cl PerunClient<ProtoBufEncodingLayer<Bus<'cl, DeviceT>>>,
+        client: &'cl PerunClient<ProtoBufEncodingLayer<&'cl Bus<'cl, DeviceT>>>,
+        bus: &'cl Bus<'cl, DeviceT>,
         iface: &'cl RefCell<Interface<'cl, DeviceT>>,
     ) -> Self {
         Self {
@@ -205,8 +218,13 @@ where
             config,
             rng,
             client,
+            bus,
             addr,
             iface,
+            participant_frames: FrameDecoder::new(),
+            service_frames: FrameDecoder::new(),
+            peer: Identity::new(),
+            auth: PeerAuth::new(client.identity(), None),
         }
     }
 
@@ -232,19 +250,23 @@ where
             // enough bytes are available (we only received partial data for
             // some reason).
             //
-            // Note that this will fail if we are at a ringbuffer boundry, see
-            // `try_recv` for details. In this demo this is not a problem
-            // because the rx_buffer is always empty when this function is
-            // called and can thus always fit 40 bytes in a consecutive slice.
-            if let Some((eth_holder, withdraw_receiver)) = socket.recv(|x| {
-                if x.len() >= 40 {
+            // Note that this would fail if we are at a ringbuffer boundry. In
+            // this demo this is not a problem because the rx_buffer is always
+            // empty when this function is called and can thus always fit 60
+            // bytes in a consecutive slice.
+            if let Some((eth_holder, withdraw_receiver, peer)) = socket.recv(|x| {
+                if x.len() >= 60 {
                     let eth_holder = Address(x[..20].try_into().unwrap());
                     let withdraw_receiver = Address(x[20..40].try_into().unwrap());
-                    (40, Some((eth_holder, withdraw_receiver)))
+                    let peer = x[40..60].to_vec();
+                    (60, Some((eth_holder, withdraw_receiver, peer)))
                 } else {
                     (0, None)
                 }
             })? {
+                // The wire address of the go-side participant, which has to
+                // prove that it owns the corresponding key.
+                self.peer = peer;
                 self.state = ApplicationState::ClosingSockets {
                     eth_holder,
                     withdraw_receiver,
@@ -278,6 +300,11 @@ where
         let psocket = iface.get_socket::<TcpSocket>(self.participant_handle);
         if !ssocket_active && !psocket.is_active() {
             psocket.listen(self.config.listen_port)?;
+            // Don't mix up leftovers from previous connections with new ones.
+            self.participant_frames.reset();
+            self.service_frames.reset();
+            self.bus.queue.clear();
+            self.auth = PeerAuth::new(self.client.identity(), Some(self.peer.clone()));
             self.state = ApplicationState::Listening {
                 eth_holder,
                 withdraw_receiver,
@@ -301,25 +328,15 @@ where
             }
         }
 
-        let env: Envelope = match self.try_recv(self.participant_handle)? {
-            Some(env) => env,
-            None => return Ok(()),
-        };
-
-        match env.msg {
-            Some(envelope::Msg::AuthResponseMsg(_)) => {}
-            Some(_) => return Err(Error::UnexpectedMsg),
-            None => return Err(Error::InvalidState),
-        }
-
-        let my_wire_address = self.config.participants[0].into();
-
-        if env.recipient[..] != self.config.participants[0].as_bytes()[..] {
-            return Err(Error::UnexpectedMsg);
+        // The first message has to be the handshake of the peer, anything
+        // else is rejected while receiving.
+        self.try_recv_participant_msg()?;
+        if !self.auth.is_authenticated() {
+            return Ok(());
         }
 
         self.client
-            .send_handshake_msg(&my_wire_address, &env.sender);
+            .send_handshake_msg(self.auth.local(), &self.peer, None)?;
 
         let mut iface = self.iface.borrow_mut();
         let (ssocket, cx) = iface.get_socket_and_context::<TcpSocket>(self.service_handle);
@@ -353,9 +370,9 @@ where
         match self.try_recv_participant_msg()? {
             Some(ParticipantMessage::ChannelProposal(prop)) => {
                 let mut channel = self.client.handle_proposal(prop, withdraw_receiver)?;
-                // Accept the proposal (this can not fail because we only just
-                // got the channel and so we cannot have accepted it already).
-                channel.accept(self.rng.gen(), self.addr).unwrap();
+                // Accept the proposal (we only just got the channel, so this
+                // can only fail if the acceptance could not be sent).
+                channel.accept(self.rng.gen(), self.addr)?;
                 // Immediately process into an agreed upon channel (because we
                 // have just accepted it and this state machine only supports
                 // 2-participant channels).
@@ -425,12 +442,8 @@ where
             drop(iface);
 
             // Handshake
-            let peers: Vec<Vec<u8>> = self
-                .config
-                .participants
-                .map(|p| p.as_bytes().to_vec())
-                .into();
-            self.client.send_handshake_msg(&peers[0], &peers[1]);
+            self.client
+                .send_handshake_msg(self.auth.local(), &self.peer, None)?;
 
             self.state = ApplicationState::WaitForHandshake {
                 eth_holder,
@@ -440,125 +453,30 @@ where
         Ok(())
     }
 
-    fn try_recv<T: Message + Default>(&mut self, handle: SocketHandle) -> Result<Option<T>, Error> {
-        // Yes, this function is long when including comments. When not
-        // including them it is still complex, but I have not found a way to do
-        // this without reading everything into a heap-allocated buffer or
-        // storing some information between calls to try_recv using the API
-        // smoltcp currently provides.
-        let mut iface = self.iface.borrow_mut();
+    fn try_recv<T>(
+        iface: &RefCell<Interface<'cl, DeviceT>>,
+        handle: SocketHandle,
+        frames: &mut FrameDecoder<{ MAX_MESSAGE_SIZE + 2 }>,
+        decode: fn(&[u8]) -> Result<T, DecodeError>,
+    ) -> Result<Option<T>, Error> {
+        let mut iface = iface.borrow_mut();
         let socket = iface.get_socket::<TcpSocket>(handle);
-
-        let recv_queue = socket.recv_queue();
-        if recv_queue < 2 {
-            return Ok(None); // We don't have 2 bytes of length
-        }
-
-        // Peek at the message length (keeping length and message in the
-        // rx-buffer if it is not completely received)
-        let mut buf_msg_length = [0u8; 2];
-        let bytes_peeked = socket.peek_slice(&mut buf_msg_length)?;
-        if bytes_peeked < 2 {
-            // smoltcp currently does not provide the capability to peek
-            // over the edge of the (internal) rx ringbuffer. the current
-            // peek cannot have this ability without copying data
-            // internally, peek_slice does however, at least based on its
-            // API design and comment. Unfortunately (likely due to a bug in
-            // smoltcp) it does not do so, which makes it impossible to read
-            // the length if we are at the end of the ringbuffer. This can
-            // be solved in one of the following ways:
-            // - Change peek_slice to do what the comment says: Do the same
-            //   as recv_slice, which does look over the ringbuffer boundry.
-            // - Add a `peek_offset(&mut self, size: usize, offset: usize)`
-            //   to smoltcp which allows us to do option 1 ourselves
-            // - Read and dequeue the message length, then store it
-            //   somewhere in the application.
-            // - Read and dequeue the message length, then immediately
-            //   follow with the message and panic if it is not complete,
-            //   yet. This would likely happen more often than panicing if
-            //   we are exactly at the ringbuffer border.
-            //
-            // Technical debt: Because this is likely a bug in smoltcp and
-            // option 3 would require a lot of changes we're panicking in
-            // this case for now (at least until we have this fixed in a
-            // separate branch on smoltcp or a fork).
-            //
-            // The probability that this happens is `1/rx_buffer.len()`,
-            // which is currently < 1/512.
-            panic!("Bug/Limitation in smoltcp");
-        }
-        let length: usize = u16::from_be_bytes(buf_msg_length).into();
-
-        // Make sure it is even possible to receive the message.
-        if (2 + length) > socket.recv_capacity() {
-            // To handle messages larger than the rx_buffer size requires one of
-            // the following:
-            // - Partial protobuf decoding and storing the partial data
-            //   somewhere. Difficult if not impossible with the Protobuf
-            //   library (although it should in theory be possible)
-            // - Copying the data into a separate buffer that can hold it over
-            //   multiple poll calls. Difficult to do, especially since that
-            //   would require a heap large enough to store the data which could
-            //   be up to 64KiB of space, which would be near impossible on a
-            //   device with just low ram.
-            // - Keep a counter of the remaining message size and discard a
-            //   message over multiple calls to `try_recv` (with calls to
-            //   `iface.poll` in between). This would allow keeping the
-            //   connection open even if someone sends a too big message. The
-            //   problem with this approach is that it may break some
-            //   assumptions on the other side.
-            // - Panic or return an error, thus effectively dropping the
-            //   connection as there is no way to handle such big messages. This
-            //   is the option implemented below.
-            //
-            // Note that such messages won't happen under normal protocol
-            // completion as long as the rx_buffer is large enough to hold the
-            // largest possible message type (512 is sufficient for channels
-            // with 2 participants and 1 asset).
-            return Err(Error::MessageLargerThanRxBuffer(2 + length));
-        }
-
-        // Only continue if the message is complete.
-        if socket.recv_queue() < 2 + length {
-            return Ok(None); // We don't have all the data
-        }
-
-        // Read the entire message and decode it.
-        //
-        // Technical debt: We're currently creating a copy of the bytes in
-        // memory for decoding. It should be possible to do this without
-        // creating a copy (in a local variable) by implementing a custom buffer
-        // to decode from. This would also eliminate the need for the
-        // MAX_MESSAGE_SIZE local array.
-        //
-        // unsized local variables are currently unstable rust, see
-        // https://doc.rust-lang.org/unstable-book/language-features/unsized-locals.html.
-        // Therefore we need to specify a size. We cannot take it from socket or
-        // self.config because neither is constant => MAX_MESSAGE_SIZE
-        //
-        // Discard 2 bytes of length information.
-        let read = socket.recv(|x| {
-            let len = x.len().min(2);
-            (len, len)
-        })?;
-        if read != 2 {
-            // At the moment this cannot happen because we're panicking earlier
-            // if we are at the bingbuffer boundry (the only situation where
-            // this could happen). I've nevertheless added the logic to handle
-            // this case as a defensive mechanism (i.e. we won't panic here) in
-            // case someone fixes the panic above but doesn't change this part.
-            socket.recv(|_| (2 - read, ()))?;
-        }
-        let mut buf = [0u8; MAX_MESSAGE_SIZE];
-        let bytes_read = socket.recv_slice(&mut buf[..length])?;
-        if bytes_read != length {
-            // This can only happen if the rx_buffer runs out, which can't
-            // happen because we have queued bytes. Note that this only holds
-            // true as long as smoltcp does not queue out-of-order packets.
-            unreachable!("We previously checked for queue size, did smoltcp add storage for out-of-order packets?")
+        loop {
+            if let Some(frame) = frames.next_frame()? {
+                return Ok(Some(decode(frame)?));
+            }
+            if socket.recv_queue() == 0 {
+                return Ok(None); // We don't have all the data
+            }
+            // `recv` only gives us the bytes up to the ringbuffer boundry, the
+            // rest is read in the next iteration. Whatever the decoder can't
+            // take (if its buffer is full) stays in the rx-buffer until the
+            // next frame has been taken out.
+            socket.recv(|data| {
+                let n = frames.push(data);
+                (n, ())
+            })?;
         }
-        let env = T::decode(&buf[..length])?;
-        Ok(Some(env))
     }
 
     fn wait_handshake_and_propose_channel(
@@ -566,14 +484,13 @@ where
         eth_holder: Address,
         withdraw_receiver: Address,
     ) -> Result<(), Error> {
-        // Only continue if we have a complete package and there was no decoding
-        // error. Note that we currently do not check the addresses in the
-        // envelope.
+        // Only continue once the peer has proven its identity with its
+        // handshake and there was no decoding error.
         match self.try_recv_participant_msg()? {
-            Some(ParticipantMessage::Auth) => {
+            Some(_) => Err(Error::UnexpectedMsg),
+            None if self.auth.is_authenticated() => {
                 self.send_channel_proposal(eth_holder, withdraw_receiver)
             }
-            Some(_) => Err(Error::UnexpectedMsg),
             None => Ok(()),
         }
     }
@@ -585,11 +502,7 @@ where
     ) -> Result<(), Error> {
         // Channel Proposal
         let init_balance = Balances([ParticipantBalances([100_000.into(), 100_000.into()])]);
-        let peers = self
-            .config
-            .participants
-            .map(|p| p.as_bytes().to_vec())
-            .into();
+        let peers = [self.auth.local().clone(), self.peer.clone()].into();
         let prop = LedgerChannelProposal {
             proposal_id: self.rng.gen(),
             challenge_duration: 25,
@@ -616,83 +529,31 @@ where
         Ok(())
     }
 
+    fn try_recv_participant_env(&mut self) -> Result<Option<ParticipantEnvelope>, Error> {
+        Self::try_recv(
+            self.iface,
+            self.participant_handle,
+            &mut self.participant_frames,
+            decode_participant_message,
+        )
+    }
+
+    /// Receive the next message from the other participant, if it is
+    /// authenticated. Its handshake is consumed here and returns `None`.
     fn try_recv_participant_msg(&mut self) -> Result<Option<ParticipantMessage>, Error> {
-        let env: Envelope = match self.try_recv(self.participant_handle)? {
-            Some(env) => env,
-            None => return Ok(None),
-        };
-        let msg = match env.msg {
-            Some(m) => m,
-            None => return Err(Error::EnvelopeHasNoMsg),
-        };
-        let msg = match msg {
-            Msg::PingMsg(_) => unimplemented!(),
-            Msg::PongMsg(_) => unimplemented!(),
-            Msg::ShutdownMsg(_) => unimplemented!(),
-            Msg::AuthResponseMsg(_) => ParticipantMessage::Auth,
-            Msg::LedgerChannelProposalMsg(m) => ParticipantMessage::ChannelProposal(m.try_into()?), // Possible in the library but this Application does not support incoming requests.
-            Msg::LedgerChannelProposalAccMsg(m) => {
-                ParticipantMessage::ProposalAccepted(m.try_into()?)
-            }
-            Msg::SubChannelProposalMsg(_) => unimplemented!(),
-            Msg::SubChannelProposalAccMsg(_) => unimplemented!(),
-            Msg::VirtualChannelProposalMsg(_) => unimplemented!(),
-            Msg::VirtualChannelProposalAccMsg(_) => unimplemented!(),
-            Msg::ChannelProposalRejMsg(m) => ParticipantMessage::ProposalRejected {
-                id: Hash(m.proposal_id.try_into().unwrap()),
-                reason: m.reason,
-            },
-            Msg::ChannelUpdateMsg(m) => ParticipantMessage::ChannelUpdate(m.try_into()?),
-            Msg::VirtualChannelFundingProposalMsg(_) => unimplemented!(),
-            Msg::VirtualChannelSettlementProposalMsg(_) => unimplemented!(),
-            Msg::ChannelUpdateAccMsg(m) => ParticipantMessage::ChannelUpdateAccepted(m.try_into()?),
-            Msg::ChannelUpdateRejMsg(m) => ParticipantMessage::ChannelUpdateRejected {
-                id: Hash(m.channel_id.try_into().unwrap()),
-                version: m.version,
-                reason: m.reason,
-            },
-            Msg::ChannelSyncMsg(_) => unimplemented!(),
-        };
-        Ok(Some(msg))
+        match self.try_recv_participant_env()? {
+            Some(env) => Ok(self.auth.receive(self.client, env)?),
+            None => Ok(None),
+        }
     }
 
     fn try_recv_service_msg(&mut self) -> Result<Option<ServiceReplyMessage>, Error> {
-        let env: perunwire::Message = match self.try_recv(self.service_handle)? {
-            Some(env) => env,
-            None => return Ok(None),
-        };
-        let msg = match env.msg {
-            Some(m) => m,
-            None => return Err(Error::EnvelopeHasNoMsg),
-        };
-        let msg = match msg {
-            perunwire::message::Msg::FundingRequest(_) => unimplemented!(),
-            perunwire::message::Msg::FundingResponse(m) => {
-                ServiceReplyMessage::Funder(FunderReplyMessage::Funded {
-                    id: Hash(m.channel_id.try_into().unwrap()),
-                })
-            }
-            perunwire::message::Msg::WatchRequest(_) => unimplemented!(),
-            perunwire::message::Msg::WatchResponse(m) => {
-                ServiceReplyMessage::Watcher(WatcherReplyMessage::Ack {
-                    id: Hash(m.channel_id.try_into().unwrap()),
-                    version: m.version,
-                })
-            }
-            perunwire::message::Msg::ForceCloseRequest(_) => unimplemented!(),
-            perunwire::message::Msg::ForceCloseResponse(m) => {
-                ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeAck {
-                    id: Hash(m.channel_id.try_into().unwrap()),
-                })
-            }
-            perunwire::message::Msg::DisputeNotification(m) => {
-                ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeNotification {
-                    id: Hash(m.channel_id.try_into().unwrap()),
-                })
-            }
-        };
-
-        Ok(Some(msg))
+        Self::try_recv(
+            self.iface,
+            self.service_handle,
+            &mut self.service_frames,
+            decode_service_message,
+        )
     }
 
     /// Helper function to not duplicate code. We have to process a message
@@ -701,7 +562,10 @@ where
     fn forward_messages<T, F1, F2>(&mut self, recv_fn: F1, process_fn: F2) -> Result<bool, Error>
     where
         F1: Fn(&mut Self) -> Result<Option<T>, Error>,
-        F2: Fn(&mut Channel<ProtoBufEncodingLayer<Bus<DeviceT>>>, T) -> Result<(), Error>,
+        F2: Fn(
+            &mut Channel<&PerunClient<ProtoBufEncodingLayer<&Bus<DeviceT>>>>,
+            T,
+        ) -> Result<(), Error>,
     {
         let msg: Option<T> = recv_fn(self)?;
 
@@ -768,6 +632,8 @@ where
     /// Main polling function transitioning between states. Call this regularly,
     /// for example always after polling the network interface.
     pub fn poll(&mut self) -> Result<(), Error> {
+        // Send queued messages the sockets had no space for so far.
+        self.bus.flush();
         match self.state {
             ApplicationState::InitialState => self.connect_config_dealer(),
             ApplicationState::ConnectingToConfigDealer => self.wait_connected_and_read_config(),
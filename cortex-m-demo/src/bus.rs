@@ -1,12 +1,19 @@
 use core::cell::RefCell;
 
-use perun::wire::BytesBus;
+use perun::wire::{BusError, BytesBus, QueuedBus};
 use smoltcp::{
     iface::{Interface, SocketHandle},
     phy::Device,
     socket::TcpSocket,
 };
 
+/// Bytes of outgoing messages we can hold for the other participant.
+pub const PARTICIPANT_QUEUE_SIZE: usize = 512;
+/// Bytes of outgoing messages we can hold for the funder/watcher. This
+/// currently needs to have space for FundingRequestMsg (388 bytes) and
+/// WatchRequestMsg (544 bytes) simultaneously.
+pub const SERVICE_QUEUE_SIZE: usize = 1024;
+
 pub struct Bus<'iface, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
@@ -14,47 +21,65 @@ where
     pub iface: &'iface RefCell<Interface<'iface, DeviceT>>,
     pub participant_handle: SocketHandle,
     pub service_handle: SocketHandle,
+    pub queue: QueuedBus<PARTICIPANT_QUEUE_SIZE, SERVICE_QUEUE_SIZE>,
 }
 
 impl<'iface, DeviceT> Bus<'iface, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
 {
-    fn send(&self, handle: SocketHandle, msg: &[u8]) {
-        let mut iface = self.iface.borrow_mut();
-        let socket = iface.get_socket::<TcpSocket>(handle);
-        // Note: In this implementation the entire message has to fit into the
-        // tx buffer. To loosen that requirement you'd need some way to queue
-        // half the data and resume later, which is not easily doable without
-        // async afaict.
-        let count_written = socket.send_slice(msg).unwrap();
-        if count_written != msg.len() {
-            panic!(
-                "Could not send message, wrote {count_written}/{} bytes",
-                msg.len()
-            );
+    /// Move as much of the queued messages into the tx buffers of the sockets
+    /// as fits. Call this regularly, for example always after polling the
+    /// network interface, which frees up space in the tx buffers.
+    ///
+    /// Does nothing if the interface is currently borrowed, the messages are
+    /// then sent on the next call.
+    pub fn flush(&self) {
+        let mut iface = match self.iface.try_borrow_mut() {
+            Ok(iface) => iface,
+            Err(_) => return,
+        };
+        let socket = iface.get_socket::<TcpSocket>(self.participant_handle);
+        if socket.may_send() {
+            self.queue
+                .drain_participant(|data| socket.send_slice(data).unwrap_or(0));
+        }
+        let socket = iface.get_socket::<TcpSocket>(self.service_handle);
+        if socket.may_send() {
+            self.queue
+                .drain_service(|data| socket.send_slice(data).unwrap_or(0));
         }
     }
 }
 
+// Messages are only queued here, the tx buffers of the sockets don't have to
+// be large enough to hold an entire message. If the queue is full we report
+// back-pressure with `BusError::Busy`, the channel stays unchanged and the
+// user can try again later.
 impl<'iface, DeviceT> BytesBus for Bus<'iface, DeviceT>
 where
     DeviceT: for<'d> Device<'d>,
 {
-    fn send_to_watcher(&self, msg: &[u8]) {
-        self.send(self.service_handle, msg)
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.queue.send_to_watcher(msg)?;
+        self.flush();
+        Ok(())
     }
 
-    fn send_to_funder(&self, msg: &[u8]) {
-        self.send(self.service_handle, msg)
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.queue.send_to_funder(msg)?;
+        self.flush();
+        Ok(())
     }
 
     fn send_to_participant(
         &self,
-        _sender: &perun::wire::Identity,
-        _recipient: &perun::wire::Identity,
+        sender: &perun::wire::Identity,
+        recipient: &perun::wire::Identity,
         msg: &[u8],
-    ) {
-        self.send(self.participant_handle, msg)
+    ) -> Result<(), BusError> {
+        self.queue.send_to_participant(sender, recipient, msg)?;
+        self.flush();
+        Ok(())
     }
 }
@@ -5,26 +5,25 @@
 //! - Easier handling because it is just a single struct instead of having
 //!   things in the type system for compile time errors.
 
-use alloc::string::String;
 use perun::{
     abiencode::types::U256,
-    channel::{self, AgreedUponChannel, ProposedChannel},
-    messages::{FunderReplyMessage, ParticipantMessage, WatcherReplyMessage},
-    wire::MessageBus,
+    channel::{
+        self, fixed_size_payment::TransferError, AgreedUponChannel, FundingError, ProposedChannel,
+        SyncError, SyncOutcome,
+    },
+    messages::{FunderReplyMessage, ParticipantMessage, RejectReason, WatcherReplyMessage},
+    ClientRef,
 };
 
-pub struct Channel<'cl, B: MessageBus> {
-    inner: ChannelInner<'cl, B>,
+pub struct Channel<C: ClientRef> {
+    inner: ChannelInner<C>,
 }
 
-enum ChannelInner<'cl, B: MessageBus> {
-    Proposed(channel::ProposedChannel<'cl, B>),
-    AgreedUpon(channel::AgreedUponChannel<'cl, B>),
-    Signed(channel::SignedChannel<'cl, B>, bool, bool),
-    Active(
-        channel::ActiveChannel<'cl, B>,
-        Option<channel::ChannelUpdate>,
-    ),
+enum ChannelInner<C: ClientRef> {
+    Proposed(channel::ProposedChannel<C>),
+    AgreedUpon(channel::AgreedUponChannel<C>),
+    Signed(channel::SignedChannel<C>, bool),
+    Active(channel::ActiveChannel<C>, Option<channel::ChannelUpdate>),
     /// We store owned values in this enum and need to move the channel out of
     /// the previous enum to be able to transition to the next state. While we
     /// could lift that restriction (it is added just to forbid duplicating a
@@ -44,10 +43,13 @@ enum ChannelInner<'cl, B: MessageBus> {
 
     /// We have agreed on an update with is_final=true, sent it to the watcher
     /// and are now waiting for confirmation.
-    Closing(channel::ActiveChannel<'cl, B>),
+    Closing(channel::ActiveChannel<C>),
     /// We have sent a dispute request (force close request) to the watcher and
     /// are now waiting for confirmation.
     ForceClosing,
+    /// The watcher noticed a dispute started by someone else. We wait for the
+    /// channel to be concluded and our funds to be withdrawn.
+    Disputed(channel::DisputedChannel<C>),
     /// We got the confirmation and the channel is now closed.
     Closed,
 }
@@ -56,7 +58,7 @@ enum ChannelInner<'cl, B: MessageBus> {
 pub enum Error {
     InvalidState,
     HandleAccept(channel::HandleAcceptError),
-    Rejected { reason: String },
+    Rejected { reason: RejectReason },
     ProposalBuild(channel::ProposalBuildError),
     Signing(channel::SignError),
     AddSignature(channel::AddSignatureError),
@@ -66,8 +68,29 @@ pub enum Error {
     Accept(channel::AcceptError),
     ApplyUpdate(channel::ApplyError),
     NotEnoughFunds,
+    Transfer(TransferError),
+    Funding(FundingError),
+    Sync(SyncError),
     Closed,
 }
+impl From<SyncError> for Error {
+    fn from(e: SyncError) -> Self {
+        Self::Sync(e)
+    }
+}
+impl From<FundingError> for Error {
+    fn from(e: FundingError) -> Self {
+        Self::Funding(e)
+    }
+}
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        match e {
+            TransferError::InsufficientBalance => Self::NotEnoughFunds,
+            e => Self::Transfer(e),
+        }
+    }
+}
 impl From<channel::HandleAcceptError> for Error {
     fn from(e: channel::HandleAcceptError) -> Self {
         Self::HandleAccept(e)
@@ -114,13 +137,13 @@ impl From<channel::ApplyError> for Error {
     }
 }
 
-impl<'cl, B: MessageBus> Channel<'cl, B> {
-    pub fn new(channel: ProposedChannel<'cl, B>) -> Self {
+impl<C: ClientRef> Channel<C> {
+    pub fn new(channel: ProposedChannel<C>) -> Self {
         Self {
             inner: ChannelInner::Proposed(channel),
         }
     }
-    pub fn new_agreed_upon(channel: AgreedUponChannel<'cl, B>) -> Self {
+    pub fn new_agreed_upon(channel: AgreedUponChannel<C>) -> Self {
         Self {
             inner: ChannelInner::AgreedUpon(channel),
         }
@@ -142,9 +165,7 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
     /// crash immediately due to the panic in `f`.
     fn progress<F>(&mut self, f: F) -> Result<(), Error>
     where
-        F: FnOnce(
-            ChannelInner<'cl, B>,
-        ) -> Result<ChannelInner<'cl, B>, (ChannelInner<'cl, B>, Error)>,
+        F: FnOnce(ChannelInner<C>) -> Result<ChannelInner<C>, (ChannelInner<C>, Error)>,
     {
         // Move ChannelInner out of self so we get ownership of the variant.
         let mut inner = ChannelInner::TemporaryInvalidState;
@@ -177,13 +198,8 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
         // self.progress by now.
         match self.inner {
             ChannelInner::Active(ref mut ch, ref mut update) => {
-                let mut new_state = ch.state().make_next_state();
-                if new_state.outcome.balances.0[0].0[1] < amount {
-                    return Err(Error::NotEnoughFunds);
-                }
-                let part_idx = ch.part_idx();
-                new_state.outcome.balances.0[0].0[1 - part_idx] += amount;
-                new_state.outcome.balances.0[0].0[part_idx] -= amount;
+                let asset = ch.state().outcome.assets[0];
+                let mut new_state = ch.payment(asset, amount)?;
                 new_state.is_final = is_final;
                 match ch.update(new_state) {
                     Ok(u) => {
@@ -202,7 +218,7 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
         self.progress(|inner| match inner {
             ChannelInner::Active(ch, update) => match ch.force_close() {
                 Ok(_) => Ok(ChannelInner::ForceClosing),
-                Err((ch, e)) => Err((ChannelInner::Active(ch, update), e.into())),
+                Err((ch, e)) => Err((ChannelInner::Active(*ch, update), e.into())),
             },
             ChannelInner::TemporaryInvalidState => unreachable!(),
             inner => return Err((inner, Error::InvalidState)),
@@ -211,11 +227,11 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
 
     pub fn process_watcher_reply(&mut self, msg: WatcherReplyMessage) -> Result<(), Error> {
         self.progress(|inner| match (inner, msg) {
-            (ChannelInner::Signed(ch, _, funded), WatcherReplyMessage::Ack { .. }) => {
-                if funded {
-                    Ok(ChannelInner::Active(ch.mark_funded(), None))
-                } else {
-                    Ok(ChannelInner::Signed(ch, true, funded))
+            (ChannelInner::Signed(ch, _), WatcherReplyMessage::Ack { .. }) => {
+                match ch.mark_funded() {
+                    Ok(ch) => Ok(ChannelInner::Active(ch, None)),
+                    // Still waiting for the funder.
+                    Err((ch, _)) => Ok(ChannelInner::Signed(*ch, true)),
                 }
             }
             // We're currently not processing acknowledge messages, as there is
@@ -225,11 +241,39 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
             // already handled by TCP), so there is currently no need to do
             // anything with the acknowledgement.
             (inner @ ChannelInner::Active(_, _), WatcherReplyMessage::Ack { .. }) => Ok(inner),
-            (ChannelInner::Active(_, _), WatcherReplyMessage::DisputeNotification { .. }) => {
-                Ok(ChannelInner::Closed)
-            }
+            (
+                ChannelInner::Active(ch, update),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => match ch.handle_dispute(version) {
+                Ok(ch) => Ok(ChannelInner::Disputed(ch)),
+                Err((ch, e)) => Err((ChannelInner::Active(*ch, update), e.into())),
+            },
             (ChannelInner::Closing(_), WatcherReplyMessage::Ack { .. }) => Ok(ChannelInner::Closed),
-            (ChannelInner::Closing(_), WatcherReplyMessage::DisputeNotification { .. }) => {
+            (
+                ChannelInner::Closing(ch),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => match ch.handle_dispute(version) {
+                Ok(ch) => Ok(ChannelInner::Disputed(ch)),
+                Err((ch, e)) => Err((ChannelInner::Closing(*ch), e.into())),
+            },
+            (
+                ChannelInner::Disputed(mut ch),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => match ch.handle_registered(version) {
+                Ok(()) => Ok(ChannelInner::Disputed(ch)),
+                Err(e) => Err((ChannelInner::Disputed(ch), e.into())),
+            },
+            // Acknowledges our refutation, the new registration is notified
+            // separately.
+            (inner @ ChannelInner::Disputed(_), WatcherReplyMessage::DisputeAck { .. }) => {
+                Ok(inner)
+            }
+            (ChannelInner::Disputed(mut ch), WatcherReplyMessage::Concluded { version, .. }) => {
+                ch.handle_concluded(version);
+                Ok(ChannelInner::Disputed(ch))
+            }
+            (ChannelInner::Disputed(ch), WatcherReplyMessage::Withdrawn { amounts, .. }) => {
+                ch.handle_withdrawn(amounts);
                 Ok(ChannelInner::Closed)
             }
             (ChannelInner::ForceClosing, WatcherReplyMessage::DisputeAck { .. }) => {
@@ -242,6 +286,10 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
             (inner @ ChannelInner::Closed, WatcherReplyMessage::DisputeNotification { .. }) => {
                 Ok(inner) // Ignore it, just in case the Watcher is slow and we receive a DisputeAck first.
             }
+            (
+                inner @ ChannelInner::Closed,
+                WatcherReplyMessage::Concluded { .. } | WatcherReplyMessage::Withdrawn { .. },
+            ) => Ok(inner), // We forgot the channel right after force-closing it.
             (inner @ ChannelInner::ForceClosing, _) => Err((inner, Error::Closed)),
             (ChannelInner::TemporaryInvalidState, _) => unreachable!(),
             (inner, _) => Err((inner, Error::InvalidState)),
@@ -250,14 +298,21 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
 
     pub fn process_funder_reply(&mut self, msg: FunderReplyMessage) -> Result<(), Error> {
         self.progress(|inner| match (inner, msg) {
-            (ChannelInner::Signed(ch, watching, _), FunderReplyMessage::Funded { .. }) => {
-                if watching {
-                    Ok(ChannelInner::Active(ch.mark_funded(), None))
-                } else {
-                    Ok(ChannelInner::Signed(ch, watching, true))
-                }
-            }
+            (ChannelInner::Signed(mut ch, watching), msg) => match ch.handle_funder_reply(msg) {
+                Ok(()) if watching => match ch.mark_funded() {
+                    Ok(ch) => Ok(ChannelInner::Active(ch, None)),
+                    Err((ch, e)) => Err((ChannelInner::Signed(*ch, watching), e.into())),
+                },
+                Ok(()) => Ok(ChannelInner::Signed(ch, watching)),
+                // Get our deposit back.
+                Err(FundingError::Failed) => match ch.refund() {
+                    Ok(ch) => Ok(ChannelInner::Disputed(ch)),
+                    Err((ch, e)) => Err((ChannelInner::Signed(*ch, watching), e.into())),
+                },
+                Err(e) => Err((ChannelInner::Signed(ch, watching), e.into())),
+            },
             (inner @ ChannelInner::Closing(_), _) => Err((inner, Error::Closed)),
+            (inner @ ChannelInner::Disputed(_), _) => Err((inner, Error::Closed)),
             (inner @ ChannelInner::ForceClosing, _) => Err((inner, Error::Closed)),
             (inner @ ChannelInner::Closed, _) => Err((inner, Error::Closed)),
             (ChannelInner::TemporaryInvalidState, _) => unreachable!(),
@@ -280,17 +335,24 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
                 }
                 let mut ch = match ch.build() {
                     Ok(ch) => ch,
-                    Err((ch, e)) => return Err((ChannelInner::Proposed(ch), e.into())),
+                    Err((ch, e)) => return Err((ChannelInner::Proposed(*ch), e.into())),
                 };
                 match ch.sign() {
                     Ok(_) => Ok(ChannelInner::AgreedUpon(ch)),
                     Err(e) => unreachable!("We could reach this only by failing to abiencode the state, and there currently is no way to recover from this except for panicing. Error: {e:?}"),
                 }
             }
-            (
-                inner @ ChannelInner::Proposed(_),
-                ParticipantMessage::ProposalRejected { reason, .. },
-            ) => Err((inner, Error::Rejected { reason })),
+            (ChannelInner::Proposed(ch), ParticipantMessage::ProposalRejected { id, reason }) => {
+                match ch.handle_rejection(id, &reason) {
+                    Ok(rejected) => Err((
+                        ChannelInner::Closed,
+                        Error::Rejected {
+                            reason: rejected.reason().clone(),
+                        },
+                    )),
+                    Err((ch, _)) => Err((ChannelInner::Proposed(*ch), Error::InvalidState)),
+                }
+            }
 
             // AgreedUponChannel
             (ChannelInner::AgreedUpon(mut ch), ParticipantMessage::ChannelUpdateAccepted(msg)) => {
@@ -299,14 +361,19 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
                     Err(e) => return Err((ChannelInner::AgreedUpon(ch), e.into())),
                 }
                 match ch.build() {
-                    Ok(ch) => Ok(ChannelInner::Signed(ch, false, false)),
-                    Err((ch, e)) => Err((ChannelInner::AgreedUpon(ch), e.into())),
+                    Ok(ch) => Ok(ChannelInner::Signed(ch, false)),
+                    Err((ch, e)) => Err((ChannelInner::AgreedUpon(*ch), e.into())),
                 }
             }
             (
                 inner @ ChannelInner::AgreedUpon(_),
                 ParticipantMessage::ChannelUpdateRejected { reason, .. },
-            ) => Err((inner, Error::Rejected { reason })),
+            ) => Err((
+                inner,
+                Error::Rejected {
+                    reason: reason.as_str().into(),
+                },
+            )),
 
             // SignedChannel: (nothing is valid until we receive a response from
             // Watcher/Funder, though production code probably will have to
@@ -385,10 +452,32 @@ impl<'cl, B: MessageBus> Channel<'cl, B> {
                 }
             }
             (
-                ChannelInner::Active(ch, Some(_)),
-                ParticipantMessage::ChannelUpdateRejected { .. },
-            ) => Ok(ChannelInner::Active(ch, None)),
+                ChannelInner::Active(mut ch, Some(update)),
+                ParticipantMessage::ChannelUpdateRejected {
+                    version, reason, ..
+                },
+            ) => match update.handle_rejection(&mut ch, version, &reason) {
+                Ok(_) => Ok(ChannelInner::Active(ch, None)),
+                Err((update, _)) => Err((ChannelInner::Active(ch, Some(*update)), Error::InvalidState)),
+            },
+            (ChannelInner::Active(mut ch, update), ParticipantMessage::ChannelSync(msg)) => {
+                match ch.handle_sync(msg) {
+                    Ok(SyncOutcome::Adopted) => {
+                        // Our update either made it into the adopted state or
+                        // is outdated now.
+                        let update = update.filter(|u| u.state().version() > ch.version());
+                        if ch.state().is_final {
+                            Ok(ChannelInner::Closing(ch))
+                        } else {
+                            Ok(ChannelInner::Active(ch, update))
+                        }
+                    }
+                    Ok(_) => Ok(ChannelInner::Active(ch, update)),
+                    Err(e) => Err((ChannelInner::Active(ch, update), e.into())),
+                }
+            }
             (inner @ ChannelInner::Closing(_), _) => Err((inner, Error::Closed)),
+            (inner @ ChannelInner::Disputed(_), _) => Err((inner, Error::Closed)),
             (inner @ ChannelInner::ForceClosing, _) => Err((inner, Error::Closed)),
             (inner @ ChannelInner::Closed, _) => Err((inner, Error::Closed)),
             (ChannelInner::TemporaryInvalidState, _) => unreachable!(),
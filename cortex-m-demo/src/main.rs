@@ -14,7 +14,11 @@ use bus::Bus;
 use button::DebouncedButton;
 use cortex_m::{interrupt::Mutex, peripheral::SYST};
 use cortex_m_rt::{entry, exception};
-use perun::{sig::Signer, wire::ProtoBufEncodingLayer, PerunClient};
+use perun::{
+    sig::Signer,
+    wire::{ProtoBufEncodingLayer, QueuedBus},
+    PerunClient,
+};
 use rand::{rngs::StdRng, SeedableRng};
 use rand_core::RngCore;
 use smoltcp::{
@@ -68,6 +72,7 @@ const DEVICE_LISTEN_PORT: u16 = 1234;
 const CIDR_PREFIX_LEN: u8 = 24;
 const MAC_ADDRESS: EthernetAddress = EthernetAddress([0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF]);
 const DEBOUNCE_THRESHHOLD: u64 = 100; // Milliseconds
+const TX_BUFFER_SIZE: usize = 128; // Bytes, see `bus::Bus` for the message queues
 
 static TIME: Mutex<RefCell<u64>> = Mutex::new(RefCell::new(0));
 
@@ -170,8 +175,10 @@ fn main() {
 
     // Configure TCP socket (and allocate buffers)
     // Config and Participant communication
+    // Outgoing messages are queued in the bus (see `bus::Bus`), so the tx
+    // buffers don't have to fit an entire message.
     let mut participant_rx_buffer = [0; MAX_MESSAGE_SIZE + 2];
-    let mut participant_tx_buffer = [0; MAX_MESSAGE_SIZE + 2];
+    let mut participant_tx_buffer = [0; TX_BUFFER_SIZE];
     let participant_socket = TcpSocket::new(
         TcpSocketBuffer::new(&mut participant_rx_buffer[..]),
         TcpSocketBuffer::new(&mut participant_tx_buffer[..]),
@@ -179,9 +186,7 @@ fn main() {
     let participant_handle = iface.add_socket(participant_socket);
     // Funder/Watcher communication
     let mut service_rx_buffer = [0; MAX_MESSAGE_SIZE + 2];
-    // service_tx_buffer currently needs to have space for FundingRequestMsg
-    // (388 bytes) and WatchRequestMsg (544 bytes) simultaneosly.
-    let mut service_tx_buffer = [0; 1024];
+    let mut service_tx_buffer = [0; TX_BUFFER_SIZE];
     let service_socket = TcpSocket::new(
         TcpSocketBuffer::new(&mut service_rx_buffer[..]),
         TcpSocketBuffer::new(&mut service_tx_buffer[..]),
@@ -190,18 +195,18 @@ fn main() {
 
     blue_led.set_high(); // Setup finished
 
-    // If we reset the device it will open a new TCP connection to the go-perun
-    // participant and propose a channel. If our wire address is smaller
-    // (alphabetically) than that of the go-side, the go-side will drop the
-    // connection under some circumstances and will not reply to our channel
-    // proposal. To prevent this from happening in this demo we use the larger
-    // wire address. See https://github.com/hyperledger-labs/go-perun/issues/386
+    // Our wire address is the address of our (random) signing key, the one of
+    // the go-side is sent by the config dealer. If we reset the device it will
+    // open a new TCP connection to the go-perun participant and propose a
+    // channel. If our wire address is smaller than that of the go-side, the
+    // go-side will drop the connection under some circumstances and will not
+    // reply to our channel proposal. Resetting again gives us a new wire
+    // address. See https://github.com/hyperledger-labs/go-perun/issues/386
     let config = Config {
         config_server: (IpAddress::from(SERVER_IP_ADDRESS), SERVER_CONFIG_PORT),
         other_participant: (IpAddress::from(SERVER_IP_ADDRESS), SERVER_PARTICIPANT_PORT),
         service_server: (IpAddress::from(SERVER_IP_ADDRESS), SERVER_SERVICE_PORT),
         listen_port: DEVICE_LISTEN_PORT,
-        participants: ["Bob", "Alice"],
     };
 
     // Move the interface into a RefCell because we need a mutable reference in
@@ -215,6 +220,7 @@ fn main() {
         iface,
         participant_handle,
         service_handle,
+        queue: QueuedBus::new(),
     };
     // We need/want randomness for signing and for generating the ephemeral
     // port numbers. Creating a new RNG from the one we got is the easiest
@@ -226,7 +232,7 @@ fn main() {
     let mut rng2 = StdRng::seed_from_u64(hw_rng.next_u64());
     let signer = Signer::new(&mut rng2);
     let addr = signer.address();
-    let client = PerunClient::new(ProtoBufEncodingLayer { bus }, signer);
+    let client = PerunClient::new(ProtoBufEncodingLayer { bus: &bus }, signer);
     let mut app = Application::new(
         participant_handle,
         service_handle,
@@ -234,6 +240,7 @@ fn main() {
         rng2,
         addr,
         &client,
+        &bus,
         iface,
     );
 
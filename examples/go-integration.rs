@@ -10,12 +10,11 @@ use perun::{
         fixed_size_payment::{Allocation, Balances, ParticipantBalances},
         Asset, ChannelUpdate, LedgerChannelProposal,
     },
-    perunwire::{self, envelope},
+    messages::{ParticipantMessage, ServiceReplyMessage},
     sig::Signer,
-    wire::{BytesBus, Identity, MessageBus, ProtoBufEncodingLayer},
-    Address, PerunClient,
+    wire::{BusError, BytesBus, Identity, ParticipantEnvelope, PeerAuth, ProtoBufEncodingLayer},
+    Address, ClientRef, PerunClient,
 };
-use prost::Message;
 use rand::{CryptoRng, Rng};
 
 #[cfg(not(any(feature = "std", feature = "nostd-example")))]
@@ -66,7 +65,7 @@ fn entry() -> ! {
     loop {}
 }
 
-const PARTICIPANTS: [&'static str; 2] = ["Bob", "Alice"];
+const PARTICIPANTS: [&str; 2] = ["Bob", "Alice"];
 const NORMAL_CLOSE: bool = false;
 const SEND_DISPUTE: bool = true;
 
@@ -74,6 +73,8 @@ const SEND_DISPUTE: bool = true;
 pub struct Config {
     pub eth_holder: Address,
     pub withdraw_receiver: Address,
+    /// Wire address of the go-side participant, used to authenticate it.
+    pub peer: Address,
 }
 
 #[cfg(not(feature = "std"))]
@@ -88,7 +89,7 @@ macro_rules! print_bold {
     ($($arg:tt)*) => {
         print!("\x1b[1m");
         print!($($arg)*);
-        print!("\x1b[0m\n");
+        println!("\x1b[0m");
     };
 }
 
@@ -97,13 +98,14 @@ macro_rules! print_user_interaction {
     ($($arg:tt)*) => {
         print!("\x1b[1;34m");
         print!($($arg)*);
-        print!("\x1b[0m\n");
+        println!("\x1b[0m");
     };
 }
 
 #[cfg(all(feature = "std", not(feature = "no-go-comm")))]
 mod net {
     use super::*;
+    use perun::wire::{decode_participant_message, decode_service_message};
     use std::{
         io::{Read, Write},
         net::TcpStream,
@@ -121,9 +123,13 @@ mod net {
         config_stream.read_exact(&mut buf).unwrap();
         let withdraw_receiver = Address(buf);
 
+        config_stream.read_exact(&mut buf).unwrap();
+        let peer = Address(buf);
+
         Config {
             eth_holder,
             withdraw_receiver,
+            peer,
         }
     }
 
@@ -145,25 +151,27 @@ mod net {
             }
         }
 
-        pub fn recv_envelope(&self) -> perunwire::Envelope {
-            Self::recv_to(&self.stream)
-        }
-
-        pub fn recv_message(&self) -> perunwire::Message {
-            Self::recv_to(&self.remote_stream)
+        pub fn recv_envelope(&self) -> ParticipantEnvelope {
+            let buf = Self::recv(&self.stream);
+            let env = decode_participant_message(&buf).unwrap();
+            println!("Received: {:#?}", env);
+            env
         }
 
-        fn recv_to<T: Message + Default>(stream: &RefCell<TcpStream>) -> T {
-            let buf = Self::recv(stream);
-
-            // Decode data (the Encoding Layer currently does not decode, so to
-            // print stuff or call methods we have to do it at the moment).
-            let msg = T::decode(buf.as_slice()).unwrap();
+        pub fn recv_message(&self) -> ServiceReplyMessage {
+            let buf = Self::recv(&self.remote_stream);
+            let msg = decode_service_message(&buf).unwrap();
             println!("Received: {:#?}", msg);
-
             msg
         }
 
+        fn send(stream: &RefCell<TcpStream>, msg: &[u8]) -> Result<(), BusError> {
+            stream
+                .borrow_mut()
+                .write_all(msg)
+                .or(Err(BusError::NotConnected))
+        }
+
         fn recv(stream: &RefCell<TcpStream>) -> Vec<u8> {
             let mut stream = stream.borrow_mut();
             // big endian u16 for length in bytes
@@ -178,24 +186,29 @@ mod net {
     }
 
     impl BytesBus for &Bus {
-        fn send_to_watcher(&self, msg: &[u8]) {
-            print!("{}->Watcher: {:?}\n", PARTICIPANTS[self.participant], msg);
-            self.remote_stream.borrow_mut().write(msg).unwrap();
+        fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+            println!("{}->Watcher: {:?}", PARTICIPANTS[self.participant], msg);
+            Bus::send(&self.remote_stream, msg)
         }
 
-        fn send_to_funder(&self, msg: &[u8]) {
-            print!("{}->Funder: {:?}\n", PARTICIPANTS[self.participant], msg);
-            self.remote_stream.borrow_mut().write(msg).unwrap();
+        fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+            println!("{}->Funder: {:?}", PARTICIPANTS[self.participant], msg);
+            Bus::send(&self.remote_stream, msg)
         }
 
-        fn send_to_participant(&self, _: &Identity, _: &Identity, msg: &[u8]) {
-            print!(
-                "{}->{}: {:?}\n",
+        fn send_to_participant(
+            &self,
+            _: &Identity,
+            _: &Identity,
+            msg: &[u8],
+        ) -> Result<(), BusError> {
+            println!(
+                "{}->{}: {:?}",
                 PARTICIPANTS[self.participant],
                 PARTICIPANTS[1 - self.participant],
                 msg,
             );
-            self.stream.borrow_mut().write(msg).unwrap();
+            Bus::send(&self.stream, msg)
         }
     }
 }
@@ -205,9 +218,11 @@ mod net {
     use perun::{
         abiencode::{self, types::Bytes32},
         channel::fixed_size_payment::{Params, State},
-        messages::{LedgerChannelProposalAcc, LedgerChannelUpdate, LedgerChannelUpdateAccepted},
-        perunwire::{message, AuthResponseMsg, Envelope},
-        sig::k256::Signer,
+        messages::{
+            FunderReplyMessage, LedgerChannelProposalAcc, LedgerChannelUpdateAccepted,
+            WatcherReplyMessage,
+        },
+        wire::{decode_participant_message, sign_identity},
     };
     use sha3::{Digest, Sha3_256};
 
@@ -216,8 +231,20 @@ mod net {
     use rand::{rngs::StdRng, SeedableRng};
 
     pub fn read_config() -> Config {
-        print!("read_config\n");
-        Config::default()
+        println!("read_config");
+        Config {
+            peer: mock_participant().1.address(),
+            ..Config::default()
+        }
+    }
+
+    /// Rng and signer of the scripted go-side participant.
+    fn mock_participant() -> (StdRng, Signer) {
+        // Don't do that in production! For this example/demonstration this was the
+        // easiest way to get a working (though deterministic) Rng.
+        let mut rng = StdRng::seed_from_u64(666);
+        let signer = Signer::new(&mut rng);
+        (rng, signer)
     }
 
     #[derive(Debug)]
@@ -227,6 +254,8 @@ mod net {
         signer: Signer,
         proposal: Option<LedgerChannelProposal>,
         state: Option<State<1, 2>>,
+        /// Identity of the Rust participant, learned from its handshake.
+        remote: Identity,
     }
 
     #[derive(Debug)]
@@ -237,12 +266,9 @@ mod net {
 
     impl Bus {
         pub fn new() -> Self {
-            print!("Bus::new\n");
+            println!("Bus::new");
 
-            // Don't do that in production! For this example/demonstration this was the
-            // easiest way to get a working (though deterministic) Rng.
-            let mut rng = StdRng::seed_from_u64(666);
-            let signer = Signer::new(&mut rng);
+            let (rng, signer) = mock_participant();
 
             let inner = InnerMutableData {
                 send_counter: 0,
@@ -250,6 +276,7 @@ mod net {
                 signer,
                 proposal: None,
                 state: None,
+                remote: Identity::new(),
             };
 
             Self {
@@ -258,13 +285,15 @@ mod net {
             }
         }
 
-        pub fn recv_envelope(&self) -> perunwire::Envelope {
-            print!("Bus::recv_envelope (replying with scripted value)\n");
-            let peers = get_peers();
+        pub fn recv_envelope(&self) -> ParticipantEnvelope {
+            println!("Bus::recv_envelope (replying with scripted value)");
             let mut inner = self.inner.borrow_mut();
+            let identity = inner.signer.address().0.to_vec();
 
-            let wiremsg = match inner.send_counter {
-                0 => envelope::Msg::AuthResponseMsg(AuthResponseMsg {}),
+            let msg = match inner.send_counter {
+                0 => ParticipantMessage::Auth {
+                    signature: sign_identity(&inner.signer, &identity, None),
+                },
                 1 => {
                     let nonce_share: Bytes32 = inner.rng.gen();
                     let proposal = inner
@@ -281,7 +310,7 @@ mod net {
 
                     let params = Params {
                         challenge_duration: proposal.challenge_duration,
-                        nonce: nonce,
+                        nonce,
                         participants: [proposal.participant, inner.signer.address()],
                         app: Address::default(),
                         ledger_channel: true,
@@ -289,108 +318,94 @@ mod net {
                     };
                     inner.state = Some(State::new(params, proposal.init_bals).unwrap());
 
-                    envelope::Msg::LedgerChannelProposalAccMsg(
-                        LedgerChannelProposalAcc {
-                            nonce_share,
-                            participant: inner.signer.address(),
-                            proposal_id,
-                        }
-                        .into(),
-                    )
+                    ParticipantMessage::ProposalAccepted(LedgerChannelProposalAcc {
+                        nonce_share,
+                        participant: inner.signer.address(),
+                        proposal_id,
+                    })
                 }
                 2 => {
                     let hash = abiencode::to_hash(&inner.state.unwrap()).unwrap();
                     let sig = inner.signer.sign_eth(hash);
-                    envelope::Msg::ChannelUpdateAccMsg(
-                        LedgerChannelUpdateAccepted {
-                            channel: inner
-                                .state
-                                .expect("Example should have proposed a channel by now.")
-                                .channel_id(),
-                            version: 0,
-                            sig,
-                        }
-                        .into(),
-                    )
+                    ParticipantMessage::ChannelUpdateAccepted(LedgerChannelUpdateAccepted {
+                        channel: inner
+                            .state
+                            .expect("Example should have proposed a channel by now.")
+                            .channel_id(),
+                        version: 0,
+                        sig,
+                    })
                 }
                 3 | 4 => panic!("Expected to send message message"),
                 5 => {
                     let hash = abiencode::to_hash(&inner.state.unwrap()).unwrap();
                     let sig = inner.signer.sign_eth(hash);
-                    envelope::Msg::ChannelUpdateAccMsg(
-                        LedgerChannelUpdateAccepted {
-                            channel: inner.state.unwrap().channel_id(),
-                            version: 1,
-                            sig,
-                        }
-                        .into(),
-                    )
+                    ParticipantMessage::ChannelUpdateAccepted(LedgerChannelUpdateAccepted {
+                        channel: inner.state.unwrap().channel_id(),
+                        version: 1,
+                        sig,
+                    })
                 }
                 x if x < 7 => panic!("Expected to send envelope message"),
                 _ => unimplemented!("End of scripted responses"),
             };
-            let response = Envelope {
-                sender: peers[1].clone(),
-                recipient: peers[0].clone(),
-                msg: Some(wiremsg),
+            let response = ParticipantEnvelope {
+                sender: identity,
+                recipient: inner.remote.clone(),
+                msg,
             };
             inner.send_counter += 1;
             response
         }
 
-        pub fn recv_message(&self) -> perunwire::Message {
-            print!("Bus::recv_message\n");
+        pub fn recv_message(&self) -> ServiceReplyMessage {
+            println!("Bus::recv_message");
             let mut inner = self.inner.borrow_mut();
-            let wiremsg = match inner.send_counter {
-                3 => message::Msg::WatchResponse(perunwire::WatchResponseMsg {
-                    channel_id: inner.state.unwrap().channel_id().0.to_vec(),
-                    version: 0,
-                    success: true,
-                }),
-                4 => message::Msg::FundingResponse(perunwire::FundingResponseMsg {
-                    channel_id: inner.state.unwrap().channel_id().0.to_vec(),
-                    success: true,
-                }),
-                6 => message::Msg::ForceCloseResponse(perunwire::ForceCloseResponseMsg {
-                    channel_id: inner.state.unwrap().channel_id().0.to_vec(),
-                    success: true,
-                }),
+            let id = inner.state.unwrap().channel_id();
+            let msg = match inner.send_counter {
+                3 => ServiceReplyMessage::Watcher(WatcherReplyMessage::Ack { id, version: 0 }),
+                4 => ServiceReplyMessage::Funder(FunderReplyMessage::Funded { id }),
+                6 => ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeAck { id }),
                 x if x < 7 => panic!("Expected to send envelope message"),
                 _ => unimplemented!("End of scripted responses"),
             };
             inner.send_counter += 1;
-            perunwire::Message { msg: Some(wiremsg) }
+            msg
         }
     }
 
     impl BytesBus for &Bus {
-        fn send_to_watcher(&self, msg: &[u8]) {
-            print!("{}->Watcher: {:?}\n", PARTICIPANTS[self.participant], msg);
+        fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+            println!("{}->Watcher: {:?}", PARTICIPANTS[self.participant], msg);
+            Ok(())
         }
 
-        fn send_to_funder(&self, msg: &[u8]) {
-            print!("{}->Funder: {:?}\n", PARTICIPANTS[self.participant], msg);
+        fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+            println!("{}->Funder: {:?}", PARTICIPANTS[self.participant], msg);
+            Ok(())
         }
 
-        fn send_to_participant(&self, _: &Identity, _: &Identity, msg: &[u8]) {
-            print!(
-                "{}->{}: {:?}\n",
+        fn send_to_participant(
+            &self,
+            _: &Identity,
+            _: &Identity,
+            msg: &[u8],
+        ) -> Result<(), BusError> {
+            println!(
+                "{}->{}: {:?}",
                 PARTICIPANTS[self.participant],
                 PARTICIPANTS[1 - self.participant],
                 msg,
             );
             let mut inner = self.inner.borrow_mut();
-            let envelope: perunwire::Envelope = perunwire::Envelope::decode(&msg[2..]).unwrap();
-            match envelope.msg.unwrap() {
-                perunwire::envelope::Msg::LedgerChannelProposalMsg(msg) => {
-                    inner.proposal = Some(msg.try_into().unwrap())
-                }
-                perunwire::envelope::Msg::ChannelUpdateMsg(msg) => {
-                    let update: LedgerChannelUpdate = msg.try_into().unwrap();
-                    inner.state = Some(update.state);
-                }
+            let env = decode_participant_message(&msg[2..]).unwrap();
+            inner.remote = env.sender;
+            match env.msg {
+                ParticipantMessage::ChannelProposal(proposal) => inner.proposal = Some(proposal),
+                ParticipantMessage::ChannelUpdate(update) => inner.state = Some(update.state),
                 _ => {}
             }
+            Ok(())
         }
     }
 }
@@ -410,8 +425,9 @@ fn get_rng() -> impl Rng + CryptoRng {
     rand::rngs::StdRng::seed_from_u64(0)
 }
 
-fn get_peers() -> Vec<Vec<u8>> {
-    PARTICIPANTS.map(|p| p.as_bytes().to_vec()).into()
+/// Wire identities of both participants, which are their addresses.
+fn get_peers<C: ClientRef>(client: C, config: &Config) -> Vec<Vec<u8>> {
+    [client.client().identity(), config.peer.0.to_vec()].into()
 }
 
 fn main() {
@@ -423,14 +439,26 @@ fn main() {
 
     // Networking
     let bus = Bus::new();
-    let peers = get_peers();
 
     // Signer, Addresses and Client
     let signer = Signer::new(&mut rng);
     let addr = signer.address();
     let client = PerunClient::new(ProtoBufEncodingLayer { bus: &bus }, signer);
-    client.send_handshake_msg(&peers[0], &peers[1]);
-    bus.recv_envelope();
+    let peers = get_peers(&client, &config);
+
+    // Authenticate both sides of the connection, all further messages have to
+    // come from the authenticated peer.
+    let mut auth = PeerAuth::new(peers[0].clone(), Some(peers[1].clone()));
+    client
+        .send_handshake_msg(&peers[0], &peers[1], None)
+        .unwrap();
+    auth.receive(&client, bus.recv_envelope()).unwrap();
+    assert!(auth.is_authenticated());
+    let mut recv = || {
+        auth.receive(&client, bus.recv_envelope())
+            .unwrap()
+            .expect("Unexpected handshake")
+    };
 
     // Create channel proposal (user configuration)
     print_user_interaction!("Proposing channel");
@@ -454,16 +482,13 @@ fn main() {
     let mut channel = client
         .propose_channel(prop, config.withdraw_receiver)
         .unwrap();
-    match bus.recv_envelope().msg {
-        Some(envelope::Msg::LedgerChannelProposalAccMsg(msg)) => channel
-            .participant_accepted(1, msg.try_into().unwrap())
-            .unwrap(),
-        Some(envelope::Msg::ChannelProposalRejMsg(_)) => {
+    match recv() {
+        ParticipantMessage::ProposalAccepted(msg) => channel.participant_accepted(1, msg).unwrap(),
+        ParticipantMessage::ProposalRejected { .. } => {
             print_bold!("Bob done: Received ProposalRejected");
             return;
         }
-        Some(_) => panic!("Unexpected message"),
-        None => panic!("Envelope did not contain a msg"),
+        _ => panic!("Unexpected message"),
     }
     print_bold!(
         "Both agreed on proposal and nonces, Both sign the initial state and exchange signatures"
@@ -472,34 +497,32 @@ fn main() {
     // Go to Phase 2: Signing the initial state
     let mut channel = channel.build().unwrap();
     channel.sign().unwrap();
-    match bus.recv_envelope().msg {
-        Some(envelope::Msg::ChannelUpdateAccMsg(msg)) => {
-            channel.add_signature(msg.try_into().unwrap()).unwrap()
-        }
-        Some(envelope::Msg::ChannelUpdateRejMsg(_)) => {
+    match recv() {
+        ParticipantMessage::ChannelUpdateAccepted(msg) => channel.add_signature(msg).unwrap(),
+        ParticipantMessage::ChannelUpdateRejected { .. } => {
             print_bold!("Bob done: Did not receive Signature from Bob");
             return;
         }
-        Some(_) => panic!("Unexpected message"),
-        None => panic!("Envelope did not contain a msg"),
+        _ => panic!("Unexpected message"),
     }
 
     print_bold!("Bob: Received all signatures, send to watcher/funder");
 
-    let channel = channel.build().unwrap();
-    // Receive acknowledgements (currently not checked but we have to read them
-    // anyways).
-    bus.recv_message();
+    let mut channel = channel.build().unwrap();
+    // Receive acknowledgements (the watcher's is currently not checked but we
+    // have to read it anyways).
     bus.recv_message();
+    match bus.recv_message() {
+        ServiceReplyMessage::Funder(msg) => channel.handle_funder_reply(msg).unwrap(),
+        _ => panic!("Expected funding response"),
+    }
 
-    let mut channel = channel.mark_funded();
+    let mut channel = channel.mark_funded().unwrap();
 
     print_user_interaction!("Bob: Propose Update");
-    let mut new_state = channel.state().make_next_state();
-    new_state.outcome.balances.0[0].0[0] += 10.into();
-    new_state.outcome.balances.0[0].0[1] -= 10.into();
-    let update = channel.update(new_state).unwrap();
-    handle_update_response(&bus, &mut channel, update);
+    let asset = channel.state().outcome.assets[0];
+    let update = channel.pay(asset, 10.into()).unwrap();
+    handle_update_response(&mut recv, &mut channel, update);
 
     if NORMAL_CLOSE {
         print_user_interaction!("Bob: Propose Normal close");
@@ -507,7 +530,7 @@ fn main() {
         // Propose a normal closure
         new_state.is_final = true;
         let update = channel.update(new_state).unwrap();
-        handle_update_response(&bus, &mut channel, update);
+        handle_update_response(&mut recv, &mut channel, update);
     }
 
     if SEND_DISPUTE {
@@ -520,22 +543,21 @@ fn main() {
 }
 
 fn handle_update_response(
-    bus: &Bus,
-    channel: &mut ActiveChannel<impl MessageBus>,
+    mut recv: impl FnMut() -> ParticipantMessage,
+    channel: &mut ActiveChannel<impl ClientRef>,
     mut update: ChannelUpdate,
 ) {
-    match bus.recv_envelope().msg {
-        Some(envelope::Msg::ChannelUpdateAccMsg(msg)) => {
-            update
-                .participant_accepted(channel, 1, msg.try_into().unwrap())
-                .unwrap();
+    match recv() {
+        ParticipantMessage::ChannelUpdateAccepted(msg) => {
+            update.participant_accepted(channel, 1, msg).unwrap();
             update.apply(channel).unwrap();
         }
-        Some(envelope::Msg::ChannelUpdateRejMsg(_)) => {
-            print_bold!("Aborting update");
-            drop(update);
+        ParticipantMessage::ChannelUpdateRejected {
+            version, reason, ..
+        } => {
+            let rejected = update.handle_rejection(channel, version, &reason).unwrap();
+            print_bold!("Aborting update: {}", rejected.reason());
         }
-        Some(_) => panic!("Unexpected message"),
-        None => panic!("Envelope did not contain a msg"),
+        _ => panic!("Unexpected message"),
     }
 }
@@ -10,13 +10,12 @@ use perun::{
         WatcherReplyMessage, WatcherRequestMessage,
     },
     sig::Signer,
-    wire::{Identity, MessageBus},
+    wire::{BusError, Identity, MessageBus},
     Address, Hash, PerunClient,
 };
 use std::{fmt::Debug, sync::mpsc};
-use tokio;
 
-const PARTICIPANTS: [&'static str; 2] = ["Alice", "Bob"];
+const PARTICIPANTS: [&str; 2] = ["Alice", "Bob"];
 const ACCEPT_PROPOSAL: bool = true;
 // Note that, due to the example MessageBus implementation, both threads hang if
 // neither signs it.
@@ -39,6 +38,7 @@ enum ServiceMsg {
     /// communication)
     Dispute {
         id: Hash,
+        version: u64,
     },
     /// Notification to the service to stop (only for this example)
     Stop,
@@ -56,24 +56,33 @@ struct Bus {
 }
 
 impl MessageBus for &Bus {
-    fn send_to_watcher(&self, msg: WatcherRequestMessage) {
+    fn send_to_watcher(&self, msg: WatcherRequestMessage) -> Result<(), BusError> {
         println!("{}->Watcher: {:#?}", PARTICIPANTS[self.participant], msg);
-        self.service_tx.send(ServiceMsg::WatcherReq(msg)).unwrap();
+        self.service_tx
+            .send(ServiceMsg::WatcherReq(msg))
+            .or(Err(BusError::NotConnected))
     }
 
-    fn send_to_funder(&self, msg: FunderRequestMessage) {
+    fn send_to_funder(&self, msg: FunderRequestMessage) -> Result<(), BusError> {
         println!("{}->Funder: {:#?}", PARTICIPANTS[self.participant], msg);
-        self.service_tx.send(ServiceMsg::FunderReq(msg)).unwrap();
+        self.service_tx
+            .send(ServiceMsg::FunderReq(msg))
+            .or(Err(BusError::NotConnected))
     }
 
-    fn send_to_participant(&self, _: &Identity, _: &Identity, msg: ParticipantMessage) {
+    fn send_to_participant(
+        &self,
+        _: &Identity,
+        _: &Identity,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError> {
         println!(
             "{}->{}: {:#?}",
             PARTICIPANTS[self.participant],
             PARTICIPANTS[1 - self.participant],
             msg,
         );
-        self.tx.send(msg).unwrap();
+        self.tx.send(msg).or(Err(BusError::NotConnected))
     }
 }
 
@@ -129,8 +138,12 @@ async fn alice(bus: Bus) {
         ParticipantMessage::ProposalAccepted(msg) => {
             channel.participant_accepted(1, msg).unwrap();
         }
-        ParticipantMessage::ProposalRejected { reason, .. } => {
-            print_bold!("Alice done: Received ProposalRejected: {}", reason);
+        ParticipantMessage::ProposalRejected { id, reason } => {
+            let rejected = channel.handle_rejection(id, &reason).unwrap();
+            print_bold!(
+                "Alice done: Received ProposalRejected: {}",
+                rejected.reason()
+            );
             return;
         }
         _ => panic!("Unexpected message"),
@@ -169,14 +182,17 @@ async fn alice(bus: Bus) {
     }
 
     print_bold!("Alice: Received all signatures, send to watcher/funder");
-    let channel = channel.build().unwrap();
-    // Wait for Funded and WatchRequestAck messages (content not checked in this
-    // example)
-    bus.service_rx.recv().unwrap();
-    bus.service_rx.recv().unwrap();
+    let mut channel = channel.build().unwrap();
+    // Wait for Funded and WatchRequestAck messages (the acknowledgement is not
+    // checked in this example)
+    for _ in 0..2 {
+        if let ServiceMsg::FunderRepl(msg) = bus.service_rx.recv().unwrap() {
+            channel.handle_funder_reply(msg).unwrap();
+        }
+    }
 
     print_bold!("Alice: Received Funded + WatchAck Message => Channel can be used");
-    let mut channel = channel.mark_funded();
+    let mut channel = channel.mark_funded().unwrap();
 
     // Wait until we receive an update proposal from bob (or whatever the
     // application wants to do in the meantime, Alice could also send update
@@ -196,7 +212,9 @@ async fn alice(bus: Bus) {
                 // service.
                 bus.service_rx.recv().unwrap();
             } else {
-                update.reject(&mut channel, "Alice configured to reject update");
+                update
+                    .reject(&mut channel, "Alice configured to reject update")
+                    .unwrap();
             }
         }
         _ => panic!("Unexpected Message or channel closure"),
@@ -209,7 +227,7 @@ async fn alice(bus: Bus) {
         let mut update = channel.close_normal().unwrap();
         match bus.rx.recv() {
             Ok(ParticipantMessage::ChannelUpdateAccepted(msg)) => {
-                update.participant_accepted(&mut channel, 1, msg).unwrap();
+                update.participant_accepted(&channel, 1, msg).unwrap();
                 update.apply(&mut channel).unwrap();
                 bus.service_rx.recv().unwrap(); // Receive Ack from Watcher
                 print_bold!("Alice done: Channel closed normally and the Watcher has the data");
@@ -256,7 +274,9 @@ async fn bob(bus: Bus) {
         channel.accept(rand::random(), addr).unwrap();
     } else {
         print_bold!("Bob done: rejects proposed channel");
-        channel.reject("Bob is configured to not accept the channel");
+        channel
+            .reject("Bob is configured to not accept the channel")
+            .unwrap();
         return;
     }
 
@@ -287,33 +307,43 @@ async fn bob(bus: Bus) {
     }
 
     print_bold!("Bob: Received all signatures, send to watcher/funder");
-    let channel = channel.build().unwrap();
-    // Wait for Funded and WatchRequestAck messages (content not checked in this
-    // example)
-    bus.service_rx.recv().unwrap();
-    bus.service_rx.recv().unwrap();
+    let mut channel = channel.build().unwrap();
+    // Wait for Funded and WatchRequestAck messages (the acknowledgement is not
+    // checked in this example)
+    for _ in 0..2 {
+        if let ServiceMsg::FunderRepl(msg) = bus.service_rx.recv().unwrap() {
+            channel.handle_funder_reply(msg).unwrap();
+        }
+    }
 
     print_bold!("Bob: Received Funded + WatchAck Message => Channel can be used");
-    let mut channel = channel.mark_funded();
+    let mut channel = channel.mark_funded().unwrap();
 
     print_user_interaction!("Bob: Propose Update");
-    let mut new_state = channel.state().make_next_state();
-    // Transfer 10 wei (assuming that's the channels currency) from Alice
-    // (channel proposer) to Bob.
-    //
-    // There will be helper functions to do such simple changes and we'll most
-    // likely remove the `.0`.
-    new_state.outcome.balances.0[0].0[0] += 10.into();
-    new_state.outcome.balances.0[0].0[1] -= 10.into();
-    let mut update = channel.update(new_state).unwrap();
+    // Transfer 10 wei (assuming that's the channels currency) from Bob to
+    // Alice (channel proposer). Use `channel.update()` for arbitrary changes
+    // to the state.
+    let asset = channel.state().outcome.assets[0];
+    let mut update = channel.pay(asset, 10.into()).unwrap();
     let accepted = match bus.rx.recv() {
         Ok(ParticipantMessage::ChannelUpdateAccepted(msg)) => {
-            update.participant_accepted(&mut channel, 0, msg).unwrap();
+            update.participant_accepted(&channel, 0, msg).unwrap();
             update.apply(&mut channel).unwrap();
             true
         }
-        Ok(ParticipantMessage::ChannelUpdateRejected { .. }) => {
-            print_bold!("Bob: Aborting update, alice rejected");
+        Ok(ParticipantMessage::ChannelUpdateRejected {
+            id,
+            version,
+            reason,
+        }) => {
+            let rejected = update
+                .handle_rejection(&mut channel, version, &reason)
+                .unwrap();
+            debug_assert_eq!(rejected.channel_id(), id);
+            print_bold!(
+                "Bob: Aborting update, alice rejected: {}",
+                rejected.reason()
+            );
             false
         }
         Ok(_) => panic!("Unexpected message"),
@@ -366,7 +396,9 @@ async fn bob(bus: Bus) {
                     print_bold!("Bob done: Channel closed normally and the Watcher has the data");
                     return;
                 } else {
-                    update.reject(&mut channel, "Bob configured to reject normal close");
+                    update
+                        .reject(&mut channel, "Bob configured to reject normal close")
+                        .unwrap();
                 }
             }
             Ok(_) => panic!("Unexpected message"),
@@ -375,12 +407,18 @@ async fn bob(bus: Bus) {
     }
 
     if ALICE_FORCE_CLOSE && !(ALICE_PROPOSE_NORMAL_CLOSE && BOB_ACCEPTS_NORMAL_CLOSE) {
-        bus.service_rx.recv().unwrap(); // Receive Dispute Notification
-        channel.handle_dispute();
-        // In reality, Bob will have to ensure the Watcher has received the
-        // latest known state before calling handle_dispute (which drops the
-        // channel), this is excluded for brevity here.
-        print_bold!("Bob done: Received dispute notification, so we can forget the channel now");
+        let version = match bus.service_rx.recv().unwrap() {
+            ServiceMsg::WatcherRepl(WatcherReplyMessage::DisputeNotification {
+                version, ..
+            }) => version,
+            _ => panic!("Unexpected message"),
+        };
+        // Alice registered the latest state, so there is nothing to refute.
+        // In reality, Bob would now wait for the Watcher to conclude the
+        // channel and withdraw his funds, this is excluded for brevity here.
+        let channel = channel.handle_dispute(version).unwrap();
+        assert!(!channel.is_refuting());
+        print_bold!("Bob done: Received dispute notification, the Watcher takes it from here");
         return;
     }
 
@@ -403,7 +441,9 @@ async fn service(
                 println!("Watcher->{}: {:#?}", PARTICIPANTS[participant], res);
                 snd.send(ServiceMsg::WatcherRepl(res)).unwrap();
             }
-            Ok(ServiceMsg::WatcherReq(WatcherRequestMessage::StartDispute(msg))) => {
+            Ok(ServiceMsg::WatcherReq(
+                WatcherRequestMessage::StartDispute(msg) | WatcherRequestMessage::Refute(msg),
+            )) => {
                 let res = WatcherReplyMessage::DisputeAck {
                     id: msg.state.channel_id(),
                 };
@@ -413,14 +453,35 @@ async fn service(
                 blockchain_snd
                     .send(ServiceMsg::Dispute {
                         id: msg.state.channel_id(),
+                        version: msg.state.version(),
                     })
                     .unwrap();
             }
+            Ok(ServiceMsg::WatcherReq(WatcherRequestMessage::Conclude(msg))) => {
+                let id = msg.state.channel_id();
+                let res = WatcherReplyMessage::Concluded {
+                    id,
+                    version: msg.state.version(),
+                };
+                println!("Watcher->{}: {:#?}", PARTICIPANTS[participant], res);
+                snd.send(ServiceMsg::WatcherRepl(res)).unwrap();
+                let res = WatcherReplyMessage::Withdrawn {
+                    id,
+                    amounts: msg
+                        .state
+                        .outcome
+                        .balances
+                        .0
+                        .map(|bals| bals.0[msg.part_idx]),
+                };
+                println!("Watcher->{}: {:#?}", PARTICIPANTS[participant], res);
+                snd.send(ServiceMsg::WatcherRepl(res)).unwrap();
+            }
             Ok(ServiceMsg::WatcherRepl(_)) => panic!("Invalid Message"),
-            Ok(ServiceMsg::Dispute { id }) => {
+            Ok(ServiceMsg::Dispute { id, version }) => {
                 // Message received from the mock blockchain, forward the info
                 // to the participant this service is responsible for.
-                let res = WatcherReplyMessage::DisputeNotification { id };
+                let res = WatcherReplyMessage::DisputeNotification { id, version };
                 println!("Watcher->{}: {:#?}", PARTICIPANTS[participant], res);
                 snd.send(ServiceMsg::WatcherRepl(res)).unwrap();
             }
@@ -369,14 +369,14 @@ where
     // sequences) that has to be written in Pass::Head (and thus has an effect
     // on the total size of the encoded value), but does not count towards the
     // offset.
-    fn serialize_tuple_element<T: ?Sized>(
+    fn serialize_tuple_element<T>(
         &mut self,
         name: Option<&'static str>,
         value: &T,
         offset_reduction: usize,
     ) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         match self.pass {
             Pass::HeadSize {
@@ -687,9 +687,9 @@ where
         Err(Error::TypeNotRepresentable("none"))
     }
 
-    fn serialize_some<T: ?Sized>(self, _: &T) -> Result<()>
+    fn serialize_some<T>(self, _: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("serialize_some", &self.pass);
         Err(Error::TypeNotRepresentable("some"))
@@ -726,15 +726,15 @@ where
         Err(Error::TypeNotRepresentable("unit variant (enum)"))
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("serialize_newtype_struct", &self.pass);
         self.serialize_tuple_element(Some(name), value, 0)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _: &'static str,
         _: u32,
@@ -742,7 +742,7 @@ where
         _: &T,
     ) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("serialize_newtype_variant", &self.pass);
         Err(Error::TypeNotRepresentable("newtype variant (enum)"))
@@ -816,9 +816,9 @@ where
     }
 
     #[cfg(not(feature = "std"))]
-    fn collect_str<T: ?Sized>(self, _value: &T) -> Result<()>
+    fn collect_str<T>(self, _value: &T) -> Result<()>
     where
-        T: core::fmt::Display,
+        T: ?Sized + core::fmt::Display,
     {
         trace("collect_str", &self.pass);
         unimplemented!()
@@ -833,9 +833,9 @@ where
 
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("Seq: serialize_element", &self.pass);
         // The sequence length (written in Pass::Head) is not part of the offset
@@ -858,9 +858,9 @@ where
 
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("Tuple: serialize_element", &self.pass);
         self.serialize_tuple_element(None, value, 0)
@@ -880,9 +880,9 @@ where
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("TupleStruct: serialize_field", &self.pass);
         self.serialize_tuple_element(None, value, 0)
@@ -902,9 +902,9 @@ where
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unreachable!("Because serialize_tuple_variant never returns Ok")
     }
@@ -922,16 +922,16 @@ where
 
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, _key: &T) -> Result<()>
+    fn serialize_key<T>(&mut self, _key: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unreachable!("Because serialize_map never returns Ok")
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, _value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unreachable!("Because serialize_map never returns Ok")
     }
@@ -949,9 +949,9 @@ where
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, name: &'static str, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, name: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         trace("Struct: serialize_field", &self.pass);
         self.serialize_tuple_element(Some(name), value, 0)
@@ -971,9 +971,9 @@ where
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, _key: &'static str, _value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, _key: &'static str, _value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         unreachable!("Because serialize_struct_variant never returns Ok")
     }
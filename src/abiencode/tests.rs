@@ -34,7 +34,9 @@ s = "..."
 print(*(s[i:i+64] for i in range(0, len(s), 64)), sep="\n")
 ```
 */
+// Debugging helpers for when a test fails.
 #[cfg(feature = "std")]
+#[allow(dead_code)]
 fn print_hex_slice(value: &[u8]) {
     print!("0x");
     for b in value {
@@ -44,6 +46,7 @@ fn print_hex_slice(value: &[u8]) {
 }
 
 #[cfg(feature = "std")]
+#[allow(dead_code)]
 struct PrintWriter;
 
 #[cfg(feature = "std")]
@@ -179,7 +182,7 @@ trait Bytes {
 struct BytesViaTupleAttr(#[serde(with = "as_bytes")] [u8; 4]);
 impl Bytes for BytesViaTupleAttr {
     fn gen(base: u8) -> Self {
-        return Self([0x01 | base, 0x02 | base, 0x03 | base, 0x04 | base]);
+        Self([0x01 | base, 0x02 | base, 0x03 | base, 0x04 | base])
     }
 }
 
@@ -8,7 +8,7 @@ pub trait BytesContainer {
 pub struct BytesContainerViaTupleAttr(#[serde(with = "as_bytes")] [u8; 4]);
 impl BytesContainer for BytesContainerViaTupleAttr {
     fn gen(base: u8) -> Self {
-        return Self([0x01 | base, 0x02 | base, 0x03 | base, 0x04 | base]);
+        Self([0x01 | base, 0x02 | base, 0x03 | base, 0x04 | base])
     }
 }
 
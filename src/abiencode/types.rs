@@ -1,3 +1,6 @@
+// Triggered by the code construct_uint! generates for U256.
+#![allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+
 use core::fmt::Debug;
 
 use rand::{distributions::Standard, prelude::Distribution};
@@ -25,7 +28,7 @@ macro_rules! impl_hex_debug {
 
 macro_rules! bytesN {
     ( $T:ident, $N:literal ) => {
-        #[derive(PartialEq, Eq, Copy, Clone)]
+        #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
         pub struct $T(pub [u8; $N]);
 
         impl Serialize for $T {
@@ -0,0 +1,555 @@
+//! Async API on top of the [ChannelRegistry].
+//!
+//! The channel objects and the registry are sans-IO: They send messages
+//! through the [MessageBus][crate::wire::MessageBus] and have to be fed the
+//! incoming messages by the application. This module adds futures that
+//! complete once the other participants, the funder and the watcher have
+//! answered, for example [AsyncClient::propose] resolves to a funded channel.
+//!
+//! Nothing here depends on a specific executor or on `std`, it works with
+//! anything that can run `!Send` futures (e.g. tokio's `LocalSet` or embassy).
+//! The application still has to receive messages and pass them to
+//! [AsyncClient::handle_participant_message] (and its watcher/funder
+//! equivalents), usually in a separate task. This wakes up the futures waiting
+//! for them. Events that require a decision by the application
+//! ([Event::ProposalReceived], [Event::UpdateReceived]) are returned from
+//! there, all other events can be ignored by the receiving task.
+
+use crate::{
+    abiencode::types::{Address, Hash, U256},
+    channel::{
+        fixed_size_payment::{self, TransferError},
+        NonceShare, SettledChannel,
+    },
+    messages::{
+        FunderReplyMessage, LedgerChannelProposal, ParticipantMessage, RejectReason,
+        WatcherReplyMessage,
+    },
+    registry::{ChannelRegistry, Event, RegistryError},
+    time::{Clock, Instant, NoClock, Phase},
+    ClientRef,
+};
+use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod tests;
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+#[derive(Debug)]
+pub enum AsyncError {
+    Registry(RegistryError),
+    ProposalRejected(RejectReason),
+    UpdateRejected(RejectReason),
+    /// The other participant proposed a different update at the same time and
+    /// theirs has priority, see [Event::UpdateConflict]. Ours was dropped.
+    UpdateConflict,
+    /// The channel moved to a newer state before our update went through,
+    /// see [Event::UpdateAutoAccepted] and [Event::ChannelSynced]. Ours was
+    /// dropped.
+    UpdateSuperseded,
+    /// The channel was disputed on-chain before the operation finished.
+    Disputed,
+    /// The funder could not fund the channel, see [Event::FundingFailed].
+    FundingFailed,
+    /// Someone did not answer in time, see [Event::TimedOut].
+    TimedOut(Phase),
+    InsufficientBalance,
+    BalanceOverflow,
+}
+impl From<RegistryError> for AsyncError {
+    fn from(e: RegistryError) -> Self {
+        Self::Registry(e)
+    }
+}
+
+/// What a future is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Proposal(Hash),
+    Channel(Hash),
+}
+
+/// The part of an [Event] a waiting future is interested in.
+#[derive(Debug)]
+enum Outcome {
+    /// The proposal was accepted, contains the channel id.
+    Accepted(Hash),
+    Active,
+    Updated(u64),
+    /// Our update was dropped in favor of the other participant's.
+    Conflict,
+    /// Our update was dropped because the channel moved to a newer state.
+    Superseded,
+    /// The application answered an update of the other participant.
+    Idle,
+    Rejected(RejectReason),
+    Closed,
+    Settled(SettledChannel),
+    Disputed,
+    FundingFailed,
+    TimedOut(Phase),
+}
+
+#[derive(Debug, Default)]
+struct Waiter {
+    waker: Option<Waker>,
+    /// Outcomes that were not yet seen by the future, in the order they
+    /// happened.
+    outcomes: Vec<Outcome>,
+}
+
+#[derive(Debug)]
+struct Inner<C: ClientRef, K: Clock> {
+    registry: ChannelRegistry<C, K>,
+    waiters: BTreeMap<Key, Waiter>,
+}
+
+impl<C: ClientRef, K: Clock> Inner<C, K> {
+    /// Record the outcome for whoever waits for it. Returns the waker to call
+    /// once the [RefCell] is no longer borrowed.
+    fn dispatch(&mut self, event: &Event) -> Option<Waker> {
+        let (key, outcome) = match event {
+            Event::ProposalAccepted {
+                proposal_id,
+                channel_id,
+            } => {
+                // The channel may become active before the future is polled
+                // again, make sure this is not lost.
+                if self.waiters.contains_key(&Key::Proposal(*proposal_id)) {
+                    self.waiters.entry(Key::Channel(*channel_id)).or_default();
+                }
+                (Key::Proposal(*proposal_id), Outcome::Accepted(*channel_id))
+            }
+            Event::ProposalRejected {
+                proposal_id,
+                reason,
+            } => (
+                Key::Proposal(*proposal_id),
+                Outcome::Rejected(reason.clone()),
+            ),
+            Event::ChannelActive { channel_id } => (Key::Channel(*channel_id), Outcome::Active),
+            Event::FundingFailed { channel_id } => {
+                (Key::Channel(*channel_id), Outcome::FundingFailed)
+            }
+            Event::UpdateAccepted {
+                channel_id,
+                version,
+            } => (Key::Channel(*channel_id), Outcome::Updated(*version)),
+            // Our own update (if any) was dropped in favor of theirs.
+            Event::UpdateConflict { channel_id, .. } => {
+                (Key::Channel(*channel_id), Outcome::Conflict)
+            }
+            Event::UpdateAutoAccepted { channel_id, .. }
+            | Event::ChannelSynced { channel_id, .. } => {
+                (Key::Channel(*channel_id), Outcome::Superseded)
+            }
+            Event::UpdateRefused {
+                channel_id, reason, ..
+            } => (
+                Key::Channel(*channel_id),
+                Outcome::Rejected((*reason).into()),
+            ),
+            Event::UpdateRejected {
+                channel_id, reason, ..
+            } => (Key::Channel(*channel_id), Outcome::Rejected(reason.clone())),
+            Event::ChannelClosed { channel_id } => (Key::Channel(*channel_id), Outcome::Closed),
+            Event::ChannelSettled(settled) => (
+                Key::Channel(settled.channel_id()),
+                Outcome::Settled(*settled),
+            ),
+            Event::ChannelDisputed { channel_id, .. } => {
+                (Key::Channel(*channel_id), Outcome::Disputed)
+            }
+            Event::TimedOut {
+                id,
+                phase: Phase::Proposal,
+            } => (Key::Proposal(*id), Outcome::TimedOut(Phase::Proposal)),
+            Event::TimedOut { id, phase } => (Key::Channel(*id), Outcome::TimedOut(*phase)),
+            Event::ProposalReceived(_)
+            | Event::ProposalRefused { .. }
+            | Event::ChannelSigned { .. }
+            | Event::ChannelConcluded { .. }
+            | Event::StateAcknowledged { .. }
+            | Event::UpdateReceived { .. } => return None,
+        };
+        self.notify(key, outcome)
+    }
+
+    fn notify(&mut self, key: Key, outcome: Outcome) -> Option<Waker> {
+        let waiter = self.waiters.get_mut(&key)?;
+        waiter.outcomes.push(outcome);
+        waiter.waker.take()
+    }
+}
+
+/// Removes the [Waiter] when the future is done or dropped.
+struct Interest<'a, C: ClientRef, K: Clock> {
+    inner: &'a RefCell<Inner<C, K>>,
+    key: Key,
+}
+
+impl<C: ClientRef, K: Clock> Drop for Interest<'_, C, K> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(waiter) = inner.waiters.remove(&self.key) {
+            // Nobody is going to pick up the channel of an accepted proposal.
+            for outcome in waiter.outcomes {
+                if let Outcome::Accepted(id) = outcome {
+                    inner.waiters.remove(&Key::Channel(id));
+                }
+            }
+        }
+    }
+}
+
+/// Async wrapper around a [ChannelRegistry], see the
+/// [module documentation][self].
+///
+/// Cloning it is cheap, all clones refer to the same registry.
+#[derive(Debug)]
+pub struct AsyncClient<C: ClientRef, K: Clock = NoClock> {
+    inner: Rc<RefCell<Inner<C, K>>>,
+}
+
+impl<C: ClientRef, K: Clock> Clone for AsyncClient<C, K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C: ClientRef> AsyncClient<C> {
+    pub fn new(client: C) -> Self {
+        Self::from_registry(ChannelRegistry::new(client))
+    }
+}
+
+impl<C: ClientRef, K: Clock> AsyncClient<C, K> {
+    /// Use an existing registry, for example one created with
+    /// [ChannelRegistry::with_timeouts].
+    pub fn from_registry(registry: ChannelRegistry<C, K>) -> Self {
+        AsyncClient {
+            inner: Rc::new(RefCell::new(Inner {
+                registry,
+                waiters: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn client(&self) -> C {
+        self.inner.borrow().registry.client().clone()
+    }
+
+    /// Propose a new channel and wait until it is funded and watched.
+    pub async fn propose(
+        &self,
+        prop: LedgerChannelProposal,
+        withdraw_receiver: Address,
+    ) -> Result<AsyncChannel<C, K>, AsyncError> {
+        let proposal_id = self
+            .inner
+            .borrow_mut()
+            .registry
+            .propose_channel(prop, withdraw_receiver)?;
+        let channel_id = self
+            .wait(Key::Proposal(proposal_id), |outcome| match outcome {
+                Outcome::Accepted(id) => Some(Ok(id)),
+                Outcome::Rejected(reason) => Some(Err(AsyncError::ProposalRejected(reason))),
+                Outcome::TimedOut(phase) => Some(Err(AsyncError::TimedOut(phase))),
+                _ => None,
+            })
+            .await?;
+        self.wait_active(channel_id).await
+    }
+
+    /// Accept a proposal received with [Event::ProposalReceived] and wait
+    /// until the channel is funded and watched.
+    pub async fn accept_proposal(
+        &self,
+        proposal_id: Hash,
+        nonce_share: NonceShare,
+        withdraw_receiver: Address,
+    ) -> Result<AsyncChannel<C, K>, AsyncError> {
+        let channel_id = self.inner.borrow_mut().registry.accept_proposal(
+            proposal_id,
+            nonce_share,
+            withdraw_receiver,
+        )?;
+        self.wait_active(channel_id).await
+    }
+
+    /// See [ChannelRegistry::reject_proposal].
+    pub fn reject_proposal(&self, proposal_id: Hash, reason: &str) -> Result<(), RegistryError> {
+        self.inner
+            .borrow_mut()
+            .registry
+            .reject_proposal(proposal_id, reason)
+    }
+
+    /// See [ChannelRegistry::accept_update].
+    pub fn accept_update(&self, channel_id: Hash) -> Result<(), RegistryError> {
+        self.answer_update(channel_id, |registry| registry.accept_update(channel_id))
+    }
+
+    /// See [ChannelRegistry::reject_update].
+    pub fn reject_update(&self, channel_id: Hash, reason: &str) -> Result<(), RegistryError> {
+        self.answer_update(channel_id, |registry| {
+            registry.reject_update(channel_id, reason)
+        })
+    }
+
+    /// Wakes up a [AsyncChannel::pay] waiting to retry after a conflict.
+    fn answer_update(
+        &self,
+        channel_id: Hash,
+        f: impl FnOnce(&mut ChannelRegistry<C, K>) -> Result<(), RegistryError>,
+    ) -> Result<(), RegistryError> {
+        let mut inner = self.inner.borrow_mut();
+        f(&mut inner.registry)?;
+        let waker = inner.notify(Key::Channel(channel_id), Outcome::Idle);
+        drop(inner);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Pass a message from another participant to the registry and wake up
+    /// the futures waiting for it.
+    pub fn handle_participant_message(
+        &self,
+        msg: ParticipantMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        self.handle(|registry| registry.handle_participant_message(msg))
+    }
+
+    /// Pass a message from the watcher to the registry and wake up the futures
+    /// waiting for it.
+    pub fn handle_watcher_message(
+        &self,
+        msg: WatcherReplyMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        self.handle(|registry| registry.handle_watcher_message(msg))
+    }
+
+    /// Pass a message from the funder to the registry and wake up the futures
+    /// waiting for it.
+    pub fn handle_funder_message(
+        &self,
+        msg: FunderReplyMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        self.handle(|registry| registry.handle_funder_message(msg))
+    }
+
+    /// See [ChannelRegistry::handle_timeouts], wakes up the futures waiting
+    /// for the proposal or channel that timed out.
+    pub fn handle_timeouts(&self) -> Result<Option<Event>, RegistryError> {
+        self.handle(|registry| registry.handle_timeouts())
+    }
+
+    /// See [ChannelRegistry::next_deadline].
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.inner.borrow().registry.next_deadline()
+    }
+
+    fn handle(
+        &self,
+        f: impl FnOnce(&mut ChannelRegistry<C, K>) -> Result<Option<Event>, RegistryError>,
+    ) -> Result<Option<Event>, RegistryError> {
+        let mut inner = self.inner.borrow_mut();
+        let event = f(&mut inner.registry)?;
+        let waker = event.as_ref().and_then(|e| inner.dispatch(e));
+        drop(inner);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(event)
+    }
+
+    async fn wait_active(&self, channel_id: Hash) -> Result<AsyncChannel<C, K>, AsyncError> {
+        self.wait(Key::Channel(channel_id), |outcome| match outcome {
+            Outcome::Active => Some(Ok(())),
+            // The other participant did not sign the initial state.
+            Outcome::Rejected(reason) => Some(Err(AsyncError::ProposalRejected(reason))),
+            Outcome::Disputed => Some(Err(AsyncError::Disputed)),
+            Outcome::FundingFailed => Some(Err(AsyncError::FundingFailed)),
+            Outcome::TimedOut(phase) => Some(Err(AsyncError::TimedOut(phase))),
+            _ => None,
+        })
+        .await?;
+        Ok(AsyncChannel {
+            client: self.clone(),
+            id: channel_id,
+        })
+    }
+
+    /// Wait until `f` returns `Some` for an outcome recorded for `key`.
+    ///
+    /// The waiter is registered before the first `.await`, so any call to the
+    /// registry made before calling this in the same async fn cannot miss its
+    /// outcome.
+    async fn wait<T>(
+        &self,
+        key: Key,
+        mut f: impl FnMut(Outcome) -> Option<Result<T, AsyncError>>,
+    ) -> Result<T, AsyncError> {
+        self.inner.borrow_mut().waiters.entry(key).or_default();
+        let _interest = Interest {
+            inner: &self.inner,
+            key,
+        };
+        poll_fn(|cx| {
+            let mut inner = self.inner.borrow_mut();
+            let waiter = inner.waiters.entry(key).or_default();
+            while !waiter.outcomes.is_empty() {
+                if let Some(res) = f(waiter.outcomes.remove(0)) {
+                    return Poll::Ready(res);
+                }
+            }
+            waiter.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Handle to a funded channel in an [AsyncClient].
+///
+/// Updates take `&mut self`, there can only be one update in progress per
+/// channel.
+#[derive(Debug)]
+pub struct AsyncChannel<C: ClientRef, K: Clock = NoClock> {
+    client: AsyncClient<C, K>,
+    id: Hash,
+}
+
+impl<C: ClientRef, K: Clock> AsyncChannel<C, K> {
+    pub fn channel_id(&self) -> Hash {
+        self.id
+    }
+
+    /// Current state, `None` if the channel no longer exists (e.g. after a
+    /// dispute).
+    pub fn state(&self) -> Option<State> {
+        let inner = self.client.inner.borrow();
+        inner.registry.channel(self.id).map(|c| c.state())
+    }
+
+    /// Propose `new_state` and wait until the other participant accepted it.
+    pub async fn update(&mut self, new_state: State) -> Result<(), AsyncError> {
+        let version = new_state.version();
+        self.client
+            .inner
+            .borrow_mut()
+            .registry
+            .update(self.id, new_state)?;
+        self.client
+            .wait(Key::Channel(self.id), |outcome| match outcome {
+                Outcome::Updated(v) if v == version => Some(Ok(())),
+                Outcome::Conflict => Some(Err(AsyncError::UpdateConflict)),
+                Outcome::Superseded => Some(Err(AsyncError::UpdateSuperseded)),
+                Outcome::Rejected(reason) => Some(Err(AsyncError::UpdateRejected(reason))),
+                Outcome::Disputed => Some(Err(AsyncError::Disputed)),
+                Outcome::TimedOut(phase) => Some(Err(AsyncError::TimedOut(phase))),
+                _ => None,
+            })
+            .await
+    }
+
+    /// Send `amount` of the (only) asset to the other participant.
+    ///
+    /// If the other participant proposes an update at the same time and
+    /// theirs has priority, the payment is proposed again once the application
+    /// has answered theirs. If the channel moved to a newer state in the
+    /// meantime, the payment is proposed again on top of it.
+    pub async fn pay(&mut self, amount: U256) -> Result<(), AsyncError> {
+        loop {
+            let new_state = self.payment(amount)?;
+            match self.update(new_state).await {
+                Err(AsyncError::UpdateConflict) => self.wait_idle().await?,
+                Err(AsyncError::UpdateSuperseded) => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// The next state after paying `amount` to the other participant.
+    fn payment(&self, amount: U256) -> Result<State, AsyncError> {
+        let inner = self.client.inner.borrow();
+        let channel = inner
+            .registry
+            .channel(self.id)
+            .ok_or(RegistryError::UnknownChannel(self.id))?;
+        let asset = channel.state().outcome.assets[0];
+        channel.payment(asset, amount).map_err(|e| match e {
+            TransferError::BalanceOverflow => AsyncError::BalanceOverflow,
+            TransferError::InsufficientBalance | TransferError::UnknownAsset => {
+                AsyncError::InsufficientBalance
+            }
+        })
+    }
+
+    /// Wait until the application answered the update of the other
+    /// participant.
+    async fn wait_idle(&self) -> Result<(), AsyncError> {
+        if !self.client.inner.borrow().registry.update_pending(self.id) {
+            return Ok(());
+        }
+        self.client
+            .wait(Key::Channel(self.id), |outcome| match outcome {
+                Outcome::Idle => Some(Ok(())),
+                Outcome::Disputed => Some(Err(AsyncError::Disputed)),
+                _ => None,
+            })
+            .await
+    }
+
+    /// Close the channel with the current balances and wait until it was
+    /// concluded on-chain and our funds have been withdrawn.
+    ///
+    /// If the update or the watcher times out, or someone disputes the channel
+    /// in the meantime, this completes once it is settled via the dispute. If
+    /// the other participant proposed an update at the same time and theirs
+    /// has priority, this fails with [AsyncError::UpdateConflict], see
+    /// [Event::UpdateConflict]. If the channel moved to a newer state first,
+    /// it fails with [AsyncError::UpdateSuperseded].
+    pub async fn settle(&mut self) -> Result<SettledChannel, AsyncError> {
+        self.client.inner.borrow_mut().registry.close(self.id)?;
+        self.client
+            .wait(Key::Channel(self.id), |outcome| match outcome {
+                Outcome::Settled(settled) => Some(Ok(settled)),
+                Outcome::Conflict => Some(Err(AsyncError::UpdateConflict)),
+                Outcome::Superseded => Some(Err(AsyncError::UpdateSuperseded)),
+                Outcome::Rejected(reason) => Some(Err(AsyncError::UpdateRejected(reason))),
+                _ => None,
+            })
+            .await
+    }
+
+    /// Close the channel via a dispute on-chain and wait until the watcher
+    /// acknowledged it.
+    pub async fn force_close(self) -> Result<(), AsyncError> {
+        self.client
+            .inner
+            .borrow_mut()
+            .registry
+            .force_close(self.id)?;
+        self.client
+            .wait(Key::Channel(self.id), |outcome| match outcome {
+                Outcome::Closed => Some(Ok(())),
+                Outcome::Disputed => Some(Err(AsyncError::Disputed)),
+                _ => None,
+            })
+            .await
+    }
+}
@@ -0,0 +1,378 @@
+use super::{AsyncChannel, AsyncClient, AsyncError};
+use crate::{
+    abiencode::types::{Address, U256},
+    channel::{
+        fixed_size_payment::{Allocation, Balances, ParticipantBalances},
+        Asset, LedgerChannelProposal,
+    },
+    policy::{AutoAcceptBelow, NoLoss},
+    registry::{
+        tests::{Delivery, Network, TestBus, ALICE, BOB, NAMES},
+        Event,
+    },
+    sig::Signer,
+    PerunClient,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+type Client = AsyncClient<Rc<PerunClient<TestBus>>>;
+
+/// Remembers if it was woken since the last check.
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A future polled by hand, to check when it gets woken.
+struct Task<F: Future> {
+    future: Pin<Box<F>>,
+    flag: Arc<Flag>,
+}
+
+impl<F: Future> Task<F> {
+    fn new(future: F) -> Self {
+        Task {
+            future: Box::pin(future),
+            flag: Arc::default(),
+        }
+    }
+
+    fn woken(&self) -> bool {
+        self.flag.0.swap(false, Ordering::SeqCst)
+    }
+
+    fn poll(&mut self) -> Poll<F::Output> {
+        let waker = Waker::from(self.flag.clone());
+        self.future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+
+    fn pending(&mut self) {
+        assert!(self.poll().is_pending());
+    }
+
+    /// The future has to be woken and complete now.
+    fn ready(mut self) -> F::Output {
+        assert!(self.woken());
+        match self.poll() {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+}
+
+struct Setup {
+    net: Rc<Network>,
+    clients: [Client; 2],
+    rng: StdRng,
+}
+
+impl Setup {
+    fn new() -> Self {
+        Self::build(|_, _| {})
+    }
+
+    /// Like [Self::new], `f` can configure each client before it is used.
+    fn build(f: impl Fn(usize, &mut PerunClient<TestBus>)) -> Self {
+        let mut rng = StdRng::seed_from_u64(0);
+        let net = Rc::new(Network::default());
+        let clients = [ALICE, BOB].map(|idx| {
+            let bus = TestBus {
+                net: net.clone(),
+                idx,
+            };
+            let mut client = PerunClient::new(bus, Signer::new(&mut rng));
+            f(idx, &mut client);
+            AsyncClient::new(Rc::new(client))
+        });
+        Setup { net, clients, rng }
+    }
+
+    fn address(&self, idx: usize) -> Address {
+        self.clients[idx].client().signer.address()
+    }
+
+    /// Deliver all messages, returning the events of each participant.
+    fn deliver(&self) -> [Vec<Event>; 2] {
+        let mut events = [vec![], vec![]];
+        loop {
+            let next = self.net.queue.borrow_mut().pop_front();
+            let (idx, delivery) = match next {
+                Some(v) => v,
+                None => return events,
+            };
+            let client = &self.clients[idx];
+            let event = match delivery {
+                Delivery::Participant(msg) => client.handle_participant_message(*msg),
+                Delivery::Watcher(msg) => client.handle_watcher_message(msg),
+                Delivery::Funder(msg) => client.handle_funder_message(msg),
+            }
+            .unwrap();
+            events[idx].extend(event);
+        }
+    }
+
+    fn proposal(&mut self) -> LedgerChannelProposal {
+        LedgerChannelProposal {
+            proposal_id: self.rng.gen(),
+            challenge_duration: 100,
+            nonce_share: self.rng.gen(),
+            init_bals: Allocation::new(
+                [Asset {
+                    chain_id: U256::from(1337),
+                    holder: Address([1; 20]),
+                }],
+                Balances([ParticipantBalances([100.into(), 100.into()])]),
+            ),
+            funding_agreement: Balances([ParticipantBalances([100.into(), 100.into()])]),
+            participant: self.address(ALICE),
+            peers: NAMES.iter().map(|n| n.to_vec()).collect(),
+        }
+    }
+
+    /// Open a channel proposed by Alice.
+    fn open(&mut self) -> [AsyncChannel<Rc<PerunClient<TestBus>>>; 2] {
+        let prop = self.proposal();
+        let mut alice = Task::new(self.clients[ALICE].propose(prop, self.address(ALICE)));
+        alice.pending();
+
+        let [_, events] = self.deliver();
+        let proposal_id = match &events[..] {
+            [Event::ProposalReceived(p)] => p.proposal_id,
+            _ => panic!("unexpected events: {:?}", events),
+        };
+        let mut bob = Task::new(self.clients[BOB].accept_proposal(
+            proposal_id,
+            self.rng.gen(),
+            self.address(BOB),
+        ));
+        bob.pending();
+        assert!(!alice.woken());
+
+        self.deliver();
+        [alice.ready().unwrap(), bob.ready().unwrap()]
+    }
+}
+
+fn balances(channel: &AsyncChannel<Rc<PerunClient<TestBus>>>) -> [U256; 2] {
+    channel.state().unwrap().outcome.balances.0[0].0
+}
+
+#[test]
+fn propose_pay_settle() {
+    let mut s = Setup::new();
+    let [mut alice, mut bob] = s.open();
+    assert_eq!(alice.channel_id(), bob.channel_id());
+    let id = alice.channel_id();
+
+    let mut pay = Task::new(alice.pay(10.into()));
+    pay.pending();
+    let [_, events] = s.deliver();
+    assert!(matches!(&events[..], [Event::UpdateReceived { .. }]));
+    assert!(!pay.woken());
+    s.clients[BOB].accept_update(id).unwrap();
+    s.deliver();
+    pay.ready().unwrap();
+    assert_eq!(balances(&alice), [90.into(), 110.into()]);
+    assert_eq!(balances(&bob), [90.into(), 110.into()]);
+
+    let mut settle = Task::new(bob.settle());
+    settle.pending();
+    s.deliver();
+    s.clients[ALICE].accept_update(id).unwrap();
+    s.deliver();
+    let settled = settle.ready().unwrap();
+    assert_eq!(settled.version(), 2);
+    assert_eq!(settled.balances(), Some([110.into()]));
+    assert!(settled.is_complete());
+    assert!(alice.state().is_none());
+}
+
+#[test]
+fn insufficient_balance() {
+    let mut s = Setup::new();
+    let [mut alice, _] = s.open();
+    let mut pay = Task::new(alice.pay(101.into()));
+    assert!(matches!(
+        pay.poll(),
+        Poll::Ready(Err(AsyncError::InsufficientBalance))
+    ));
+    drop(pay);
+    assert_eq!(balances(&alice), [100.into(), 100.into()]);
+}
+
+#[test]
+fn rejected() {
+    let mut s = Setup::new();
+    let prop = s.proposal();
+    let proposal_id = prop.proposal_id;
+    let mut propose = Task::new(s.clients[ALICE].propose(prop, s.address(ALICE)));
+    propose.pending();
+    s.deliver();
+    s.clients[BOB].reject_proposal(proposal_id, "no").unwrap();
+    s.deliver();
+    assert!(matches!(propose.ready(), Err(AsyncError::ProposalRejected(r)) if r.as_str() == "no"));
+
+    let [mut alice, _] = s.open();
+    let id = alice.channel_id();
+    let mut pay = Task::new(alice.pay(10.into()));
+    pay.pending();
+    s.deliver();
+    s.clients[BOB].reject_update(id, "no").unwrap();
+    s.deliver();
+    assert!(matches!(pay.ready(), Err(AsyncError::UpdateRejected(r)) if r.as_str() == "no"));
+    assert_eq!(balances(&alice), [100.into(), 100.into()]);
+}
+
+#[test]
+fn concurrent_payments() {
+    let mut s = Setup::new();
+    let [mut alice, mut bob] = s.open();
+    let id = alice.channel_id();
+
+    let mut alice_pay = Task::new(alice.pay(10.into()));
+    let mut bob_pay = Task::new(bob.pay(5.into()));
+    alice_pay.pending();
+    bob_pay.pending();
+
+    // Alice's update has priority, Bob pays again after accepting it.
+    let [_, events] = s.deliver();
+    assert!(matches!(&events[..], [Event::UpdateConflict { .. }]));
+    s.clients[BOB].accept_update(id).unwrap();
+    assert!(bob_pay.woken());
+    bob_pay.pending();
+    let [events, _] = s.deliver();
+    assert!(matches!(
+        &events[..],
+        [Event::UpdateAccepted { .. }, Event::UpdateReceived { .. }]
+    ));
+    alice_pay.ready().unwrap();
+
+    s.clients[ALICE].accept_update(id).unwrap();
+    s.deliver();
+    bob_pay.ready().unwrap();
+    assert_eq!(balances(&alice), [95.into(), 105.into()]);
+    assert_eq!(balances(&bob), [95.into(), 105.into()]);
+}
+
+#[test]
+fn concurrent_payment_auto_accepted() {
+    let mut s = Setup::build(|idx, client| {
+        if idx == BOB {
+            client.set_policy(AutoAcceptBelow(1.into()));
+        }
+    });
+    let [mut alice, mut bob] = s.open();
+    let id = alice.channel_id();
+
+    let mut alice_pay = Task::new(alice.pay(10.into()));
+    let mut bob_pay = Task::new(bob.pay(5.into()));
+    alice_pay.pending();
+    bob_pay.pending();
+
+    // Bob's policy signs Alice's update, his payment is proposed again on top
+    // of it right away.
+    let [alice_events, bob_events] = s.deliver();
+    assert!(matches!(
+        &bob_events[..],
+        [Event::UpdateAutoAccepted { .. }]
+    ));
+    assert!(matches!(&alice_events[..], [Event::UpdateAccepted { .. }]));
+    alice_pay.ready().unwrap();
+    assert!(bob_pay.woken());
+    bob_pay.pending();
+    let [events, _] = s.deliver();
+    assert!(matches!(&events[..], [Event::UpdateReceived { .. }]));
+
+    s.clients[ALICE].accept_update(id).unwrap();
+    s.deliver();
+    bob_pay.ready().unwrap();
+    assert_eq!(balances(&alice), [95.into(), 105.into()]);
+    assert_eq!(balances(&bob), [95.into(), 105.into()]);
+}
+
+#[test]
+fn concurrent_payment_refused() {
+    let mut s = Setup::build(|idx, client| {
+        if idx == BOB {
+            client.set_policy(NoLoss);
+        }
+    });
+    let [mut alice, mut bob] = s.open();
+
+    let mut state = alice.state().unwrap().make_next_state();
+    state.outcome.balances.0[0].0 = [110.into(), 90.into()];
+    let mut alice_update = Task::new(alice.update(state));
+    let mut bob_pay = Task::new(bob.pay(5.into()));
+    alice_update.pending();
+    bob_pay.pending();
+
+    // Bob's policy rejects Alice's update, which also drops his own.
+    let [_, events] = s.deliver();
+    assert!(matches!(&events[..], [Event::UpdateRefused { .. }]));
+    assert!(matches!(
+        bob_pay.ready(),
+        Err(AsyncError::UpdateRejected(r)) if r.as_str() == "update decreases our balance"
+    ));
+    s.deliver();
+    assert!(matches!(
+        alice_update.ready(),
+        Err(AsyncError::UpdateRejected(_))
+    ));
+    assert_eq!(balances(&alice), [100.into(), 100.into()]);
+    assert_eq!(balances(&bob), [100.into(), 100.into()]);
+}
+
+#[test]
+fn settle_conflicts_with_payment() {
+    let mut s = Setup::new();
+    let [mut alice, mut bob] = s.open();
+    let id = alice.channel_id();
+
+    // Alice has the lower index, her payment goes through first.
+    let mut alice_pay = Task::new(alice.pay(10.into()));
+    let mut bob_settle = Task::new(bob.settle());
+    alice_pay.pending();
+    bob_settle.pending();
+    s.deliver();
+    assert!(matches!(
+        bob_settle.ready(),
+        Err(AsyncError::UpdateConflict)
+    ));
+    s.clients[BOB].accept_update(id).unwrap();
+    s.deliver();
+    alice_pay.ready().unwrap();
+    assert_eq!(balances(&bob), [90.into(), 110.into()]);
+
+    let mut settle = Task::new(bob.settle());
+    settle.pending();
+    s.deliver();
+    s.clients[ALICE].accept_update(id).unwrap();
+    s.deliver();
+    let settled = settle.ready().unwrap();
+    assert_eq!(settled.balances(), Some([110.into()]));
+}
+
+#[test]
+fn dropped_future_stops_waiting() {
+    let mut s = Setup::new();
+    let prop = s.proposal();
+    let mut propose = Task::new(s.clients[ALICE].propose(prop, s.address(ALICE)));
+    propose.pending();
+    assert_eq!(s.clients[ALICE].inner.borrow().waiters.len(), 1);
+    drop(propose);
+    assert!(s.clients[ALICE].inner.borrow().waiters.is_empty());
+}
@@ -1,8 +1,10 @@
 mod active;
 mod agreed_upon;
 mod channel_update;
+mod disputed;
 pub mod fixed_size_payment;
 mod proposal;
+mod settling;
 mod signed;
 mod withdrawal_auth;
 
@@ -13,8 +15,9 @@ use serde::Serialize;
 pub use active::*;
 pub use agreed_upon::*;
 pub use channel_update::*;
-pub use channel_update::*;
+pub use disputed::*;
 pub use proposal::*;
+pub use settling::*;
 pub use signed::*;
 
 // Re-exported because it is part of the low-level channel API
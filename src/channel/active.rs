@@ -1,18 +1,26 @@
 use super::{
-    channel_update::ChannelUpdate,
-    fixed_size_payment::{self},
-    withdrawal_auth, PartIdx, Peers, SignError,
+    channel_update::{ChannelUpdate, InvalidChannel, RejectError},
+    disputed::DisputedChannel,
+    fixed_size_payment::{self, TransferError},
+    settling::{SettleError, SettlingChannel},
+    withdrawal_auth, Asset, PartIdx, Peers, SignError,
 };
 use crate::{
     abiencode::{
         self,
-        types::{Address, Hash, Signature},
+        types::{Address, Hash, Signature, U256},
     },
-    messages::{LedgerChannelUpdate, ParticipantMessage, WatchInfo, WatcherRequestMessage},
+    messages::{
+        LedgerChannelSync, LedgerChannelUpdate, ParticipantMessage, WatchInfo,
+        WatcherRequestMessage,
+    },
+    policy::{Decision, UpdateInfo},
     sig,
-    wire::{BroadcastMessageBus, MessageBus},
-    PerunClient,
+    storage::{ChannelSnapshot, ChannelStorage, SignedVersion, StorageError},
+    wire::{BroadcastMessageBus, BusError, MessageBus},
+    ClientRef, PerunClient,
 };
+use alloc::boxed::Box;
 
 const ASSETS: usize = 1;
 const PARTICIPANTS: usize = 2;
@@ -23,17 +31,43 @@ type Params = fixed_size_payment::Params<PARTICIPANTS>;
 pub enum ProposeUpdateError {
     AbiEncodeError(abiencode::Error),
     InvalidUpdate(InvalidUpdate),
+    /// We have already signed a different state with this version, which has
+    /// not been rejected.
+    ConflictingSignature,
+    StorageError(StorageError),
+    /// The payment could not be applied to the current balances.
+    Transfer(TransferError),
+    /// The update decreases our balance, but the watcher has not acknowledged
+    /// the current state yet, see [ActiveChannel::set_require_watcher_ack].
+    NotAcked,
+    /// The proposal could not be sent, nothing has been signed.
+    Bus(BusError),
 }
 impl From<abiencode::Error> for ProposeUpdateError {
     fn from(e: abiencode::Error) -> Self {
         Self::AbiEncodeError(e)
     }
 }
+impl From<StorageError> for ProposeUpdateError {
+    fn from(e: StorageError) -> Self {
+        Self::StorageError(e)
+    }
+}
+impl From<TransferError> for ProposeUpdateError {
+    fn from(e: TransferError) -> Self {
+        Self::Transfer(e)
+    }
+}
 impl From<InvalidUpdate> for ProposeUpdateError {
     fn from(e: InvalidUpdate) -> Self {
         Self::InvalidUpdate(e)
     }
 }
+impl From<BusError> for ProposeUpdateError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
 
 #[derive(Debug)]
 pub enum HandleUpdateError {
@@ -41,6 +75,18 @@ pub enum HandleUpdateError {
     RecoveryFailed(sig::Error),
     InvalidSignature(Address),
     InvalidUpdate(InvalidUpdate),
+    /// The actor index does not refer to the other participant.
+    InvalidPartIdx(PartIdx),
+    /// We have already signed a different state with this version and it
+    /// takes precedence, see [ActiveChannel::handle_update]. Ignore the
+    /// message, the other participant drops its update by the same rule.
+    ConflictingUpdate,
+    /// The client's [Policy][crate::policy::Policy] rejected the update, the
+    /// rejection has been sent to the other participant.
+    Rejected(&'static str),
+    /// The client's [Policy][crate::policy::Policy] rejected the update, but
+    /// the rejection could not be sent.
+    Bus(BusError),
 }
 impl From<abiencode::Error> for HandleUpdateError {
     fn from(e: abiencode::Error) -> Self {
@@ -57,6 +103,76 @@ impl From<InvalidUpdate> for HandleUpdateError {
         Self::InvalidUpdate(e)
     }
 }
+impl From<InvalidChannel> for HandleUpdateError {
+    fn from(e: InvalidChannel) -> Self {
+        match e {
+            InvalidChannel::WrongVersion => {
+                Self::InvalidUpdate(InvalidUpdate::InvalidVersionNumber)
+            }
+            InvalidChannel::WrongChannelId => Self::InvalidUpdate(InvalidUpdate::InvalidChannelID),
+        }
+    }
+}
+impl From<RejectError> for HandleUpdateError {
+    fn from(e: RejectError) -> Self {
+        match e {
+            RejectError::WrongVersion => Self::InvalidUpdate(InvalidUpdate::InvalidVersionNumber),
+            RejectError::WrongChannelId => Self::InvalidUpdate(InvalidUpdate::InvalidChannelID),
+            RejectError::Bus(e) => Self::Bus(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    AbiEncodeError(abiencode::Error),
+    RecoveryFailed(sig::Error),
+    InvalidSignature(Address),
+    InvalidChannelID,
+    /// The other participant has a different fully signed state with the
+    /// same version. This can only happen if someone signed two states with
+    /// the same version, use [ActiveChannel::force_close] to let the
+    /// blockchain decide.
+    ConflictingState,
+    Sign(SignError),
+    Bus(BusError),
+}
+impl From<abiencode::Error> for SyncError {
+    fn from(e: abiencode::Error) -> Self {
+        Self::AbiEncodeError(e)
+    }
+}
+impl From<sig::Error> for SyncError {
+    fn from(e: sig::Error) -> Self {
+        Self::RecoveryFailed(e)
+    }
+}
+impl From<SignError> for SyncError {
+    fn from(e: SignError) -> Self {
+        Self::Sign(e)
+    }
+}
+impl From<BusError> for SyncError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
+
+/// Result of [ActiveChannel::handle_sync].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// Both participants have the same latest state.
+    InSync,
+    /// The other participant has an older state, ours has been sent to it.
+    PeerBehind,
+    /// The other participant had a newer fully signed state, which is now our
+    /// current state.
+    Adopted,
+    /// The other participant has a newer state that is not fully signed, for
+    /// example because an update was in progress when the connection dropped.
+    /// It can't be used, our state stays the current one.
+    PeerPending,
+}
 
 #[derive(Debug)]
 pub enum InvalidUpdate {
@@ -67,20 +183,39 @@ pub enum InvalidUpdate {
     TotalAllocationAmountMismatch,
 }
 
+/// Our signature on a state that is not fully signed (yet).
+///
+/// A state is binding once all participants have signed it. To never end up
+/// with two binding states with the same version, we sign at most one state
+/// per version. The only exception is our own proposal after it was rejected
+/// or lost against a concurrent proposal (see [ActiveChannel::handle_update]):
+/// The other participant won't sign it, so we may sign a different one. This is only
+/// known while the [ChannelUpdate] is around, a restored signature can never
+/// be released.
+#[derive(Debug, Clone, Copy)]
+struct PendingSignature {
+    signed: SignedVersion,
+    proposed_by_us: bool,
+}
+
 #[derive(Debug)]
-pub struct ActiveChannel<'cl, B: MessageBus> {
+pub struct ActiveChannel<C: ClientRef> {
     part_idx: PartIdx,
     withdraw_receiver: Address,
-    client: &'cl PerunClient<B>,
+    client: C,
     state: State,
     params: Params,
     signatures: [Signature; PARTICIPANTS],
     peers: Peers,
+    pending_signature: Option<PendingSignature>,
+    require_watcher_ack: bool,
+    /// Highest version the watcher has acknowledged.
+    acked_version: Option<u64>,
 }
 
-impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
+impl<C: ClientRef> ActiveChannel<C> {
     pub(super) fn new(
-        client: &'cl PerunClient<B>,
+        client: C,
         part_idx: PartIdx,
         withdraw_receiver: Address,
         init_state: State,
@@ -98,6 +233,9 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
             signatures,
             withdraw_receiver,
             peers,
+            pending_signature: None,
+            require_watcher_ack: false,
+            acked_version: None,
         }
     }
 
@@ -116,8 +254,8 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
         self.part_idx
     }
 
-    pub fn client(&self) -> &PerunClient<B> {
-        self.client
+    pub fn client(&self) -> &PerunClient<C::Bus> {
+        self.client.client()
     }
 
     pub fn peers(&self) -> &Peers {
@@ -128,6 +266,147 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
         self.params
     }
 
+    pub fn withdraw_receiver(&self) -> Address {
+        self.withdraw_receiver
+    }
+
+    /// Only sign new states that decrease our balance once the watcher has
+    /// acknowledged the current one. Otherwise, losing connectivity right
+    /// after an update could leave the watcher with an older state in a
+    /// dispute. Off by default.
+    pub fn set_require_watcher_ack(&mut self, require: bool) {
+        self.require_watcher_ack = require;
+    }
+
+    /// Record a [WatcherReplyMessage::Ack][crate::messages::WatcherReplyMessage::Ack]
+    /// for this channel.
+    pub fn handle_watcher_ack(&mut self, version: u64) {
+        // We never sent versions above the current one.
+        if version <= self.version() && !matches!(self.acked_version, Some(v) if v >= version) {
+            self.acked_version = Some(version);
+        }
+    }
+
+    /// Highest version the watcher has acknowledged, if any.
+    pub fn acked_version(&self) -> Option<u64> {
+        self.acked_version
+    }
+
+    /// Whether the watcher has acknowledged the current state.
+    pub fn is_acked(&self) -> bool {
+        self.acked_version == Some(self.version())
+    }
+
+    /// Whether we may sign `new_state`, see [Self::set_require_watcher_ack].
+    pub(super) fn may_release(&self, new_state: &State) -> bool {
+        if !self.require_watcher_ack || self.is_acked() {
+            return true;
+        }
+        let current = self.state.outcome.balances.0.iter();
+        current
+            .zip(new_state.outcome.balances.0.iter())
+            .all(|(bals, new_bals)| new_bals.0[self.part_idx] >= bals.0[self.part_idx])
+    }
+
+    // Hard-coded: Only 2-party channels are supported.
+    fn peer_idx(&self) -> PartIdx {
+        1 - self.part_idx
+    }
+
+    /// Our balance of `asset` in the current state, `None` if the channel
+    /// does not hold this asset.
+    pub fn my_balance(&self, asset: Asset) -> Option<U256> {
+        self.state.outcome.balance(asset, self.part_idx)
+    }
+
+    /// The balance of the other participant, see [Self::my_balance].
+    pub fn peer_balance(&self, asset: Asset) -> Option<U256> {
+        self.state.outcome.balance(asset, self.peer_idx())
+    }
+
+    /// The next state after paying `amount` of `asset` to the other
+    /// participant. Use this instead of [Self::pay] to change more than the
+    /// balances, for example to make the state final.
+    pub fn payment(&self, asset: Asset, amount: U256) -> Result<State, TransferError> {
+        let mut new_state = self.state.make_next_state();
+        new_state
+            .outcome
+            .transfer(asset, self.part_idx, self.peer_idx(), amount)?;
+        Ok(new_state)
+    }
+
+    /// Propose an update paying `amount` of `asset` to the other participant.
+    pub fn pay(&mut self, asset: Asset, amount: U256) -> Result<ChannelUpdate, ProposeUpdateError> {
+        let new_state = self.payment(asset, amount)?;
+        self.update(new_state)
+    }
+
+    /// Propose an update in which the other participant pays `amount` of
+    /// `asset` to us. Like any other update, it only takes effect if they
+    /// accept it.
+    pub fn request(
+        &mut self,
+        asset: Asset,
+        amount: U256,
+    ) -> Result<ChannelUpdate, ProposeUpdateError> {
+        let mut new_state = self.state.make_next_state();
+        new_state
+            .outcome
+            .transfer(asset, self.peer_idx(), self.part_idx, amount)?;
+        self.update(new_state)
+    }
+
+    /// Restore a channel from a [ChannelSnapshot], for example after a reboot.
+    ///
+    /// The watcher may not know about the latest state if we lost power
+    /// between storing and sending it, so call
+    /// [Self::send_current_state_to_watcher] after restoring.
+    pub fn restore(client: C, snapshot: ChannelSnapshot) -> Self {
+        let mut channel = Self::new(
+            client,
+            snapshot.part_idx,
+            snapshot.withdraw_receiver,
+            snapshot.state,
+            snapshot.params,
+            snapshot.signatures,
+            snapshot.peers,
+        );
+        channel.pending_signature = snapshot.signed.map(|signed| PendingSignature {
+            signed,
+            proposed_by_us: false,
+        });
+        channel
+    }
+
+    pub fn snapshot(&self) -> ChannelSnapshot {
+        self.snapshot_with(self.state, self.signatures)
+    }
+
+    /// Snapshot of this channel with a different (newer) state.
+    pub(super) fn snapshot_with(
+        &self,
+        state: State,
+        signatures: [Signature; PARTICIPANTS],
+    ) -> ChannelSnapshot {
+        ChannelSnapshot {
+            part_idx: self.part_idx,
+            withdraw_receiver: self.withdraw_receiver,
+            params: self.params,
+            state,
+            signatures,
+            peers: self.peers.clone(),
+            signed: self
+                .pending_signature
+                .map(|s| s.signed)
+                .filter(|s| s.version > state.version()),
+        }
+    }
+
+    /// Store the current state in `storage`.
+    pub fn persist(&self, storage: &mut impl ChannelStorage) -> Result<(), StorageError> {
+        storage.store(&self.snapshot())
+    }
+
     fn check_valid_transition(&self, new_state: State) -> Result<(), InvalidUpdate> {
         debug_assert_eq!(new_state.outcome.locked.len(), 0, "At the moment we don't support subchannels and thus don't represent locked balances. This assert exists for when we do add it, thus warning us if this 'we don't have locked values' assumption changes. If it does: Go-Perun asserts that the `SubAlloc` (locked values) are equivalent and did not change, see `validTwoPartyUpdate`.");
         new_state.outcome.debug_assert_valid();
@@ -147,13 +426,46 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
         }
     }
 
-    pub fn update(&self, new_state: State) -> Result<ChannelUpdate, ProposeUpdateError> {
+    pub fn update(&mut self, new_state: State) -> Result<ChannelUpdate, ProposeUpdateError> {
+        self.update_impl(new_state, None)
+    }
+
+    /// Like [Self::update], but stores that we signed `new_state` in `storage`
+    /// before sending the proposal, so we don't sign a different state with
+    /// the same version after a reboot. Nothing is sent if storing fails.
+    pub fn update_persisted(
+        &mut self,
+        new_state: State,
+        storage: &mut impl ChannelStorage,
+    ) -> Result<ChannelUpdate, ProposeUpdateError> {
+        self.update_impl(new_state, Some(storage))
+    }
+
+    fn update_impl(
+        &mut self,
+        new_state: State,
+        storage: Option<&mut dyn ChannelStorage>,
+    ) -> Result<ChannelUpdate, ProposeUpdateError> {
         self.check_valid_transition(new_state)?;
+        if !self.may_release(&new_state) {
+            return Err(ProposeUpdateError::NotAcked);
+        }
 
         // Sign immediately, we need the signature to send the proposal.
         let hash = abiencode::to_hash(&new_state)?;
-        let sig = self.client.signer.sign_eth(hash);
-        self.client.bus.broadcast_to_participants(
+        if !self.can_sign(new_state.version(), hash) {
+            return Err(ProposeUpdateError::ConflictingSignature);
+        }
+        if let Some(storage) = storage {
+            let mut snapshot = self.snapshot();
+            snapshot.signed = Some(SignedVersion {
+                version: new_state.version(),
+                hash,
+            });
+            storage.store(&snapshot)?;
+        }
+        let sig = self.client.client().signer.sign_eth(hash);
+        self.client.client().bus.broadcast_to_participants(
             self.part_idx,
             &self.peers,
             ParticipantMessage::ChannelUpdate(LedgerChannelUpdate {
@@ -161,25 +473,162 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
                 actor_idx: self.part_idx,
                 sig,
             }),
-        );
+        )?;
+        self.mark_signed(new_state.version(), hash, true);
 
-        Ok(ChannelUpdate::new(self, new_state, self.part_idx, sig))
+        Ok(ChannelUpdate::new(
+            self,
+            new_state,
+            hash,
+            self.part_idx,
+            sig,
+        ))
     }
 
+    /// Verify an update proposed by another participant.
+    ///
+    /// If both participants propose an update for the same version at the
+    /// same time, the proposal of the participant with the lower index wins
+    /// (like in go-perun): If that's the other participant, our own update is
+    /// dropped and the returned one can be accepted instead. Otherwise this
+    /// returns [HandleUpdateError::ConflictingUpdate] and the other
+    /// participant drops its update.
+    ///
+    /// Note that the other participant keeps our signature on the dropped
+    /// update. If it doesn't follow the rule, it can sign that one, too, and
+    /// pick whichever of the two states suits it better. A signature on
+    /// anything but our own proposal is never dropped.
+    ///
+    /// The client's [Policy][crate::policy::Policy] (see
+    /// [PerunClient::set_policy]) then decides whether the update is
+    /// acceptable. If it rejects the update, the rejection is sent to the
+    /// other participant and [HandleUpdateError::Rejected] is returned.
+    /// Otherwise [ChannelUpdate::decision] tells whether to accept it right
+    /// away or to ask the application. Updates we may not sign yet, see
+    /// [Self::set_require_watcher_ack], are never accepted right away.
     pub fn handle_update(
-        &self,
+        &mut self,
         msg: LedgerChannelUpdate,
     ) -> Result<ChannelUpdate, HandleUpdateError> {
         self.check_valid_transition(msg.state)?;
+        if msg.actor_idx >= PARTICIPANTS || msg.actor_idx == self.part_idx {
+            return Err(HandleUpdateError::InvalidPartIdx(msg.actor_idx));
+        }
 
         let hash = abiencode::to_hash(&msg.state)?;
-        let signer = self.client.signer.recover_signer(hash, msg.sig)?;
+        let signer = self.client.client().signer.recover_signer(hash, msg.sig)?;
 
         if self.params.participants[msg.actor_idx] != signer {
             return Err(HandleUpdateError::InvalidSignature(signer));
         }
 
-        Ok(ChannelUpdate::new(self, msg.state, msg.actor_idx, msg.sig))
+        if !self.can_sign(msg.state.version(), hash) {
+            match self.pending_signature {
+                Some(s) if s.proposed_by_us && msg.actor_idx < self.part_idx => {
+                    self.pending_signature = None;
+                }
+                _ => return Err(HandleUpdateError::ConflictingUpdate),
+            }
+        }
+
+        let mut update = ChannelUpdate::new(self, msg.state, hash, msg.actor_idx, msg.sig);
+        let decision = self.client.client().policy.check_update(&UpdateInfo {
+            part_idx: self.part_idx,
+            actor_idx: msg.actor_idx,
+            current: &self.state,
+            proposed: &msg.state,
+        });
+        match decision {
+            Decision::Reject(reason) => {
+                update.reject(self, reason).map_err(|(_, e)| e)?;
+                return Err(HandleUpdateError::Rejected(reason));
+            }
+            Decision::Accept if !self.may_release(&msg.state) => {}
+            decision => update.set_decision(decision),
+        }
+        Ok(update)
+    }
+
+    /// Send our latest fully signed state to the other participants, for
+    /// example after reconnecting. They answer with theirs if ours is
+    /// outdated, see [Self::handle_sync].
+    pub fn sync(&self) -> Result<(), BusError> {
+        self.client.client().bus.broadcast_to_participants(
+            self.part_idx,
+            &self.peers,
+            ParticipantMessage::ChannelSync(LedgerChannelSync::new(self.state, self.signatures)),
+        )
+    }
+
+    /// Reconcile with the latest state of another participant, see
+    /// [Self::sync].
+    ///
+    /// If theirs is older, we answer with ours. If theirs is newer and fully
+    /// signed, it becomes our current state and is sent to the watcher. A
+    /// newer state with missing signatures is ignored
+    /// ([SyncOutcome::PeerPending]). An
+    /// update that was half-signed when the connection dropped either made it
+    /// into that state or is outdated now, so the [ChannelUpdate] should be
+    /// dropped in both cases. Store the new state with [Self::persist].
+    pub fn handle_sync(&mut self, msg: LedgerChannelSync) -> Result<SyncOutcome, SyncError> {
+        if msg.state.channel_id() != self.channel_id() {
+            return Err(SyncError::InvalidChannelID);
+        }
+        let version = msg.state.version();
+        if version < self.version() {
+            self.sync()?;
+            return Ok(SyncOutcome::PeerBehind);
+        }
+        let hash = abiencode::to_hash(&msg.state)?;
+        if version == self.version() {
+            return if hash == abiencode::to_hash(&self.state)? {
+                Ok(SyncOutcome::InSync)
+            } else {
+                Err(SyncError::ConflictingState)
+            };
+        }
+
+        let mut signatures = [Signature::default(); PARTICIPANTS];
+        for (part_idx, sig) in msg.signatures.iter().enumerate() {
+            let sig = match sig {
+                Some(sig) => *sig,
+                None => return Ok(SyncOutcome::PeerPending),
+            };
+            let signer = self.client.client().signer.recover_signer(hash, sig)?;
+            if signer != self.params.participants[part_idx] {
+                return Err(SyncError::InvalidSignature(signer));
+            }
+            signatures[part_idx] = sig;
+        }
+        self.force_update(msg.state, signatures)?;
+        if matches!(self.pending_signature, Some(s) if s.signed.version <= version) {
+            self.pending_signature = None;
+        }
+        Ok(SyncOutcome::Adopted)
+    }
+
+    /// Whether we may sign the state with the given version and hash, see
+    /// [PendingSignature].
+    pub(super) fn can_sign(&self, version: u64, hash: Hash) -> bool {
+        match self.pending_signature {
+            Some(s) if s.signed.version >= version => s.signed == SignedVersion { version, hash },
+            _ => true,
+        }
+    }
+
+    pub(super) fn mark_signed(&mut self, version: u64, hash: Hash, proposed_by_us: bool) {
+        self.pending_signature = Some(PendingSignature {
+            signed: SignedVersion { version, hash },
+            proposed_by_us,
+        });
+    }
+
+    /// Forget our signature on our own proposal after it was rejected.
+    pub(super) fn release_signature(&mut self, version: u64, hash: Hash) {
+        if matches!(self.pending_signature, Some(s) if s.proposed_by_us && s.signed == SignedVersion { version, hash })
+        {
+            self.pending_signature = None;
+        }
     }
 
     pub(super) fn force_update(
@@ -189,9 +638,8 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
     ) -> Result<(), SignError> {
         // To prevent modifying self (the channel state+signatures) in case
         // send_current_state_to_watcher returns an Error we roll-back the
-        // changes made here. At the moment this could only happen if we can't
-        // abiencode the WithdrawalAuth message, but in the future this might
-        // include Errors from the MessageBus.
+        // changes made here. This happens if we can't abiencode the
+        // WithdrawalAuth message or the MessageBus can't send it.
         //
         // Alternatives considered:
         // - Make `make_watch_update` independent of self (associated funciton
@@ -223,7 +671,7 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
 
     fn make_watch_info(&self) -> Result<WatchInfo, SignError> {
         let withdrawal_auths = withdrawal_auth::make_signed_withdrawal_auths(
-            &self.client.signer,
+            &self.client.client().signer,
             self.channel_id(),
             self.params,
             self.state,
@@ -242,34 +690,81 @@ impl<'cl, B: MessageBus> ActiveChannel<'cl, B> {
 
     pub fn send_current_state_to_watcher(&self) -> Result<(), SignError> {
         self.client
+            .client()
             .bus
-            .send_to_watcher(WatcherRequestMessage::WatchRequest(self.make_watch_info()?));
+            .send_to_watcher(WatcherRequestMessage::WatchRequest(self.make_watch_info()?))?;
         Ok(())
     }
 
     // Use `update()` if the state has to change, too
-    pub fn close_normal(&self) -> Result<ChannelUpdate, ProposeUpdateError> {
+    pub fn close_normal(&mut self) -> Result<ChannelUpdate, ProposeUpdateError> {
         let mut new_state = self.state.make_next_state();
         new_state.is_final = true;
         self.update(new_state)
     }
 
-    // At the moment this just drops the channel after sending the message. In
-    // the future it might make sense to have a struct representing a closing
-    // channel, for example to allow resending the last message.
-    pub fn force_close(self) -> Result<(), (Self, SignError)> {
-        let watch_info = match self.make_watch_info() {
-            Ok(v) => v,
-            Err(e) => return Err((self, e)),
-        };
+    /// Hand the final state to the watcher, which concludes the channel
+    /// on-chain and withdraws our funds. Call this once the final state is
+    /// fully signed and acknowledged by the watcher.
+    pub fn settle(self) -> Result<SettlingChannel<C>, (Box<Self>, SettleError)> {
+        if !self.state.is_final {
+            return Err((Box::new(self), SettleError::NotFinal));
+        }
+        match self.conclude() {
+            Ok(()) => Ok(SettlingChannel::new(self)),
+            Err(e) => Err((Box::new(self), e.into())),
+        }
+    }
+
+    pub(super) fn conclude(&self) -> Result<(), SignError> {
+        self.client
+            .client()
+            .bus
+            .send_to_watcher(WatcherRequestMessage::Conclude(self.make_watch_info()?))?;
+        Ok(())
+    }
+
+    /// Ask the watcher to register our latest state on-chain. Like for a
+    /// dispute started by someone else, the watcher concludes the channel
+    /// after the challenge period and withdraws our funds.
+    pub fn force_close(self) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        match self.start_dispute() {
+            Ok(()) => {
+                let version = self.version();
+                Ok(DisputedChannel::new(self, version))
+            }
+            Err(e) => Err((Box::new(self), e)),
+        }
+    }
+
+    pub(super) fn start_dispute(&self) -> Result<(), SignError> {
         self.client
+            .client()
             .bus
-            .send_to_watcher(WatcherRequestMessage::StartDispute(watch_info));
+            .send_to_watcher(WatcherRequestMessage::StartDispute(self.make_watch_info()?))?;
         Ok(())
     }
 
-    // At the moment this just drops the channel. In the future it might make
-    // sense to have a struct representing a closing channel, for example to
-    // allow resending the last message.
-    pub fn handle_dispute(self) {}
+    /// Freeze the channel after the watcher noticed a dispute on-chain in
+    /// which the state with `registered_version` was registered. If our latest
+    /// state is newer, the watcher is asked to refute with it.
+    pub fn handle_dispute(
+        self,
+        registered_version: u64,
+    ) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        if registered_version < self.version() {
+            if let Err(e) = self.refute() {
+                return Err((Box::new(self), e));
+            }
+        }
+        Ok(DisputedChannel::new(self, registered_version))
+    }
+
+    pub(super) fn refute(&self) -> Result<(), SignError> {
+        self.client
+            .client()
+            .bus
+            .send_to_watcher(WatcherRequestMessage::Refute(self.make_watch_info()?))?;
+        Ok(())
+    }
 }
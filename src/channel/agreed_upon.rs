@@ -7,16 +7,17 @@ use super::{
 use crate::{
     abiencode::{
         self,
-        types::{Address, Signature},
+        types::{Address, Hash, Signature},
     },
     messages::{
         FunderRequestMessage, LedgerChannelFundingRequest, LedgerChannelUpdateAccepted,
         ParticipantMessage, WatchInfo, WatcherRequestMessage,
     },
     sig,
-    wire::{BroadcastMessageBus, MessageBus},
-    PerunClient,
+    wire::{BroadcastMessageBus, BusError, MessageBus},
+    ClientRef,
 };
+use alloc::{boxed::Box, string::ToString};
 
 const ASSETS: usize = 1;
 const PARTICIPANTS: usize = 2;
@@ -28,12 +29,18 @@ type Balances = fixed_size_payment::Balances<ASSETS, PARTICIPANTS>;
 pub enum SignError {
     AbiEncodeError(abiencode::Error),
     AlreadySigned,
+    Bus(BusError),
 }
 impl From<abiencode::Error> for SignError {
     fn from(e: abiencode::Error) -> Self {
         Self::AbiEncodeError(e)
     }
 }
+impl From<BusError> for SignError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
 
 #[derive(Debug)]
 pub enum AddSignatureError {
@@ -41,6 +48,8 @@ pub enum AddSignatureError {
     RecoveryFailed(sig::Error),
     AlreadySigned,
     InvalidSignature(Address),
+    /// The participant index does not refer to another participant.
+    InvalidPartIdx(PartIdx),
     // Used to indicate that the incomming message does not match the update.
     InvalidChannelID,
     InvalidVersionNumber,
@@ -68,22 +77,54 @@ impl From<InvalidChannel> for AddSignatureError {
     }
 }
 
+/// Error returned when resending the last message of a phase.
+#[derive(Debug)]
+pub enum ResendError {
+    /// We have not sent anything that could be repeated.
+    NothingToResend,
+    WrongVersion,
+    WrongChannelId,
+    Bus(BusError),
+}
+impl From<InvalidChannel> for ResendError {
+    fn from(e: InvalidChannel) -> Self {
+        match e {
+            InvalidChannel::WrongVersion => Self::WrongVersion,
+            InvalidChannel::WrongChannelId => Self::WrongChannelId,
+        }
+    }
+}
+impl From<BusError> for ResendError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
+
 #[derive(Debug)]
 pub enum BuildError {
     MissingSignatureResponse(PartIdx),
     AbiEncodeError(abiencode::Error),
+    /// The watch or funding request could not be sent. Building again sends
+    /// both requests again, which the watcher and funder have to tolerate
+    /// anyways.
+    Bus(BusError),
 }
 impl From<abiencode::Error> for BuildError {
     fn from(e: abiencode::Error) -> Self {
         Self::AbiEncodeError(e)
     }
 }
+impl From<BusError> for BuildError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
 
 #[derive(Debug)]
-pub struct AgreedUponChannel<'cl, B: MessageBus> {
+pub struct AgreedUponChannel<C: ClientRef> {
     part_idx: PartIdx,
     withdraw_receiver: Address,
-    client: &'cl PerunClient<B>,
+    client: C,
     funding_agreement: Balances,
     init_state: State,
     params: Params,
@@ -91,9 +132,9 @@ pub struct AgreedUponChannel<'cl, B: MessageBus> {
     peers: Peers,
 }
 
-impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
+impl<C: ClientRef> AgreedUponChannel<C> {
     pub(super) fn new(
-        client: &'cl PerunClient<B>,
+        client: C,
         funding_agreement: Balances,
         part_idx: PartIdx,
         withdraw_receiver: Address,
@@ -113,17 +154,19 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
         }
     }
 
+    pub fn channel_id(&self) -> Hash {
+        self.init_state.channel_id()
+    }
+
     pub fn sign(&mut self) -> Result<(), SignError> {
         match self.signatures[self.part_idx] {
             Some(_) => Err(SignError::AlreadySigned),
             None => {
                 // Sign the initial state
                 let hash = abiencode::to_hash(&self.init_state)?;
-                let sig = self.client.signer.sign_eth(hash);
-                // Add signature to the proposed channel
-                self.signatures[self.part_idx] = Some(sig);
+                let sig = self.client.client().signer.sign_eth(hash);
                 // Send to other participants
-                self.client.bus.broadcast_to_participants(
+                self.client.client().bus.broadcast_to_participants(
                     self.part_idx,
                     &self.peers,
                     ParticipantMessage::ChannelUpdateAccepted(LedgerChannelUpdateAccepted {
@@ -131,12 +174,50 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
                         version: self.init_state.version(),
                         sig,
                     }),
-                );
+                )?;
+                // Add signature to the proposed channel only once it was sent,
+                // so `sign` can be called again if sending failed.
+                self.signatures[self.part_idx] = Some(sig);
                 Ok(())
             }
         }
     }
 
+    /// Send our signature to the other participants again, for example
+    /// because they did not answer in time.
+    pub fn resend_signature(&self) -> Result<(), ResendError> {
+        let sig = self.signatures[self.part_idx].ok_or(ResendError::NothingToResend)?;
+        self.client.client().bus.broadcast_to_participants(
+            self.part_idx,
+            &self.peers,
+            ParticipantMessage::ChannelUpdateAccepted(LedgerChannelUpdateAccepted {
+                channel: self.init_state.channel_id(),
+                version: self.init_state.version(),
+                sig,
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Abort opening the channel and tell the other participants, for example
+    /// because they did not send their signature in time.
+    ///
+    /// This is safe as long as the channel has not been built: Nobody can have
+    /// asked the funder to deposit anything without all signatures. The
+    /// channel is only given back if the rejection could not be sent.
+    pub fn reject(self, reason: &str) -> Result<(), (Box<Self>, BusError)> {
+        let res = self.client.client().bus.broadcast_to_participants(
+            self.part_idx,
+            &self.peers,
+            ParticipantMessage::ChannelUpdateRejected {
+                id: self.init_state.channel_id(),
+                version: self.init_state.version(),
+                reason: reason.to_string(),
+            },
+        );
+        res.map_err(|e| (Box::new(self), e))
+    }
+
     // This function allows adding our own signature if we really want. There is
     // currently no easy way to get one, but it is possible.
     pub fn add_signature(
@@ -151,7 +232,7 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
         }
 
         let hash = abiencode::to_hash(&self.init_state)?;
-        let signer = self.client.signer.recover_signer(hash, msg.sig)?;
+        let signer = self.client.client().signer.recover_signer(hash, msg.sig)?;
 
         // Verify signature is comming from a valid participant.
         //
@@ -182,7 +263,7 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
         }
     }
 
-    pub fn build(self) -> Result<SignedChannel<'cl, B>, (Self, BuildError)> {
+    pub fn build(self) -> Result<SignedChannel<C>, (Box<Self>, BuildError)> {
         // Make sure we have the signature from all participants. They have
         // already been verified in `add_signature()` or we created it ourselves
         // with `sign()`. At the same time, this loop collects the signatures
@@ -191,40 +272,18 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
         for (part_idx, s) in self.signatures.iter().enumerate() {
             signatures[part_idx] = match s {
                 Some(v) => *v,
-                None => return Err((self, BuildError::MissingSignatureResponse(part_idx))),
+                None => {
+                    return Err((
+                        Box::new(self),
+                        BuildError::MissingSignatureResponse(part_idx),
+                    ))
+                }
             };
         }
 
-        self.client
-            .bus
-            .send_to_watcher(WatcherRequestMessage::WatchRequest(WatchInfo {
-                part_idx: self.part_idx,
-                params: self.params,
-                state: self.init_state,
-                signatures,
-                withdrawal_auths: match make_signed_withdrawal_auths(
-                    &self.client.signer,
-                    self.init_state.channel_id(),
-                    self.params,
-                    self.init_state,
-                    self.withdraw_receiver,
-                    self.part_idx,
-                ) {
-                    Ok(v) => v,
-                    Err(e) => return Err((self, e.into())),
-                },
-            }));
-
-        self.client
-            .bus
-            .send_to_funder(FunderRequestMessage::FundingRequest(
-                LedgerChannelFundingRequest {
-                    part_idx: self.part_idx,
-                    funding_agreement: self.funding_agreement,
-                    params: self.params,
-                    state: self.init_state,
-                },
-            ));
+        if let Err(e) = self.send_requests(signatures) {
+            return Err((Box::new(self), e));
+        }
 
         Ok(SignedChannel::new(
             self.client,
@@ -236,12 +295,42 @@ impl<'cl, B: MessageBus> AgreedUponChannel<'cl, B> {
             self.peers,
         ))
     }
+
+    /// Ask the watcher to watch the channel and the funder to deposit our
+    /// part.
+    fn send_requests(&self, signatures: [Signature; PARTICIPANTS]) -> Result<(), BuildError> {
+        let withdrawal_auths = make_signed_withdrawal_auths(
+            &self.client.client().signer,
+            self.init_state.channel_id(),
+            self.params,
+            self.init_state,
+            self.withdraw_receiver,
+            self.part_idx,
+        )?;
+        let bus = &self.client.client().bus;
+        bus.send_to_watcher(WatcherRequestMessage::WatchRequest(WatchInfo {
+            part_idx: self.part_idx,
+            params: self.params,
+            state: self.init_state,
+            signatures,
+            withdrawal_auths,
+        }))?;
+        bus.send_to_funder(FunderRequestMessage::FundingRequest(
+            LedgerChannelFundingRequest {
+                part_idx: self.part_idx,
+                funding_agreement: self.funding_agreement,
+                params: self.params,
+                state: self.init_state,
+            },
+        ))?;
+        Ok(())
+    }
 }
 
-impl<'cl, B: MessageBus> TryFrom<AgreedUponChannel<'cl, B>> for SignedChannel<'cl, B> {
-    type Error = (AgreedUponChannel<'cl, B>, BuildError);
+impl<C: ClientRef> TryFrom<AgreedUponChannel<C>> for SignedChannel<C> {
+    type Error = (Box<AgreedUponChannel<C>>, BuildError);
 
-    fn try_from(value: AgreedUponChannel<'cl, B>) -> Result<Self, Self::Error> {
+    fn try_from(value: AgreedUponChannel<C>) -> Result<Self, Self::Error> {
         value.build()
     }
 }
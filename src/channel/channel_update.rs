@@ -1,13 +1,19 @@
 use super::{
-    active::ActiveChannel, agreed_upon::AddSignatureError, fixed_size_payment, PartIdx, SignError,
+    active::ActiveChannel,
+    agreed_upon::{AddSignatureError, ResendError},
+    fixed_size_payment, PartIdx, SignError,
 };
 use crate::{
     abiencode::{self, types::Signature},
-    messages::{LedgerChannelUpdateAccepted, ParticipantMessage},
-    wire::{BroadcastMessageBus, MessageBus},
-    Hash,
+    messages::{
+        LedgerChannelUpdate, LedgerChannelUpdateAccepted, ParticipantMessage, RejectReason,
+    },
+    policy::Decision,
+    storage::{ChannelStorage, StorageError},
+    wire::{BroadcastMessageBus, BusError},
+    ClientRef, Hash,
 };
-use alloc::string::ToString;
+use alloc::{boxed::Box, string::ToString};
 
 const ASSETS: usize = 1;
 const PARTICIPANTS: usize = 2;
@@ -20,12 +26,28 @@ pub enum AcceptError {
     AlreadyAccepted,
     WrongVersion,
     WrongChannelId,
+    /// We have already signed a different state with this version.
+    ConflictingSignature,
+    /// The update decreases our balance, but the watcher has not acknowledged
+    /// the current state yet, see [ActiveChannel::set_require_watcher_ack].
+    NotAcked,
+    /// Only returned by `accept_persisted`: The state is not fully signed
+    /// after adding our signature, so there is nothing to persist.
+    MissingSignature(PartIdx),
+    StorageError(StorageError),
+    /// Our signature could not be sent, the update can be accepted again.
+    Bus(BusError),
 }
 impl From<abiencode::Error> for AcceptError {
     fn from(e: abiencode::Error) -> Self {
         Self::AbiEncodeError(e)
     }
 }
+impl From<StorageError> for AcceptError {
+    fn from(e: StorageError) -> Self {
+        Self::StorageError(e)
+    }
+}
 impl From<InvalidChannel> for AcceptError {
     fn from(e: InvalidChannel) -> Self {
         match e {
@@ -34,6 +56,11 @@ impl From<InvalidChannel> for AcceptError {
         }
     }
 }
+impl From<BusError> for AcceptError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
 
 #[derive(Debug)]
 pub enum ApplyError {
@@ -56,6 +83,42 @@ impl From<InvalidChannel> for ApplyError {
     }
 }
 
+/// Error returned when rejecting an update failed.
+#[derive(Debug)]
+pub enum RejectError {
+    WrongVersion,
+    WrongChannelId,
+    Bus(BusError),
+}
+impl From<InvalidChannel> for RejectError {
+    fn from(e: InvalidChannel) -> Self {
+        match e {
+            InvalidChannel::WrongVersion => Self::WrongVersion,
+            InvalidChannel::WrongChannelId => Self::WrongChannelId,
+        }
+    }
+}
+impl From<BusError> for RejectError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
+
+/// Error returned when a rejection is not for this update.
+#[derive(Debug)]
+pub enum RejectionError {
+    WrongVersion,
+    WrongChannelId,
+}
+impl From<InvalidChannel> for RejectionError {
+    fn from(e: InvalidChannel) -> Self {
+        match e {
+            InvalidChannel::WrongVersion => Self::WrongVersion,
+            InvalidChannel::WrongChannelId => Self::WrongChannelId,
+        }
+    }
+}
+
 pub enum InvalidChannel {
     WrongVersion,
     WrongChannelId,
@@ -85,13 +148,17 @@ pub struct ChannelUpdate {
     // could accidentaly get wrong.
     channel_id: Hash,
     new_state: State,
+    /// Hash of `new_state`, which is what gets signed.
+    hash: Hash,
     signatures: [Option<Signature>; PARTICIPANTS],
+    decision: Decision,
 }
 
 impl ChannelUpdate {
     pub(crate) fn new(
-        channel: &ActiveChannel<impl MessageBus>,
+        channel: &ActiveChannel<impl ClientRef>,
         new_state: State,
+        hash: Hash,
         sig_part_idx: PartIdx,
         sig: Signature,
     ) -> Self {
@@ -100,50 +167,114 @@ impl ChannelUpdate {
         ChannelUpdate {
             channel_id: channel.channel_id(),
             new_state,
+            hash,
             signatures,
+            decision: Decision::Ask,
         }
     }
 
+    pub(super) fn set_decision(&mut self, decision: Decision) {
+        self.decision = decision;
+    }
+
     pub fn state(&self) -> &State {
         &self.new_state
     }
 
+    /// What the client's [Policy][crate::policy::Policy] decided about an
+    /// update proposed by another participant: [Decision::Accept] if it can
+    /// be accepted right away, [Decision::Ask] if the application has to
+    /// decide. Rejected updates are never returned, see
+    /// [ActiveChannel::handle_update].
+    pub fn decision(&self) -> Decision {
+        self.decision
+    }
+
+    /// Sign the new state and send our signature to the other participants.
+    ///
+    /// This is not crash-safe: Nothing is stored, so if we lose power after
+    /// sending the signature, the other participant can use the new state in
+    /// a dispute while we only know the old one. Use [Self::accept_persisted]
+    /// unless the channel is kept in memory only.
     pub fn accept(
         &mut self,
-        channel: &mut ActiveChannel<impl MessageBus>,
+        channel: &mut ActiveChannel<impl ClientRef>,
+    ) -> Result<(), AcceptError> {
+        self.accept_impl(channel, None)
+    }
+
+    /// Like [Self::accept], but stores the fully signed new state in `storage`
+    /// before sending our signature to the other participants.
+    ///
+    /// Once they have our signature they can use the new state in a dispute,
+    /// so we have to be able to do the same even if we lose power right after
+    /// sending it. Nothing is sent if storing fails.
+    pub fn accept_persisted(
+        &mut self,
+        channel: &mut ActiveChannel<impl ClientRef>,
+        storage: &mut impl ChannelStorage,
+    ) -> Result<(), AcceptError> {
+        self.accept_impl(channel, Some(storage))
+    }
+
+    fn accept_impl(
+        &mut self,
+        channel: &mut ActiveChannel<impl ClientRef>,
+        storage: Option<&mut dyn ChannelStorage>,
     ) -> Result<(), AcceptError> {
         self.ensure_valid_channel(channel)?;
 
         match self.signatures[channel.part_idx()] {
             Some(_) => Err(AcceptError::AlreadyAccepted),
             None => {
-                let hash = abiencode::to_hash(&self.new_state)?;
-                let sig = channel.client().signer.sign_eth(hash);
+                if !channel.can_sign(self.new_state.version(), self.hash) {
+                    return Err(AcceptError::ConflictingSignature);
+                }
+                if !channel.may_release(&self.new_state) {
+                    return Err(AcceptError::NotAcked);
+                }
+                let sig = channel.client().signer.sign_eth(self.hash);
 
-                let acc: _ = LedgerChannelUpdateAccepted {
+                if let Some(storage) = storage {
+                    let mut signatures = self.signatures;
+                    signatures[channel.part_idx()] = Some(sig);
+                    let mut full_signatures = [Signature::default(); PARTICIPANTS];
+                    for (part_idx, s) in signatures.iter().enumerate() {
+                        full_signatures[part_idx] =
+                            s.ok_or(AcceptError::MissingSignature(part_idx))?;
+                    }
+                    storage.store(&channel.snapshot_with(self.new_state, full_signatures))?;
+                }
+
+                let acc = LedgerChannelUpdateAccepted {
                     channel: self.channel_id,
                     version: self.new_state.version(),
                     sig,
                 };
-                self.signatures[channel.part_idx()] = Some(sig);
                 channel.client().bus.broadcast_to_participants(
                     channel.part_idx(),
                     channel.peers(),
                     ParticipantMessage::ChannelUpdateAccepted(acc),
-                );
+                )?;
+                self.signatures[channel.part_idx()] = Some(sig);
+                channel.mark_signed(self.new_state.version(), self.hash, false);
                 Ok(())
             }
         }
     }
 
+    /// Reject the update and tell the other participants. The update is only
+    /// given back if this failed.
     pub fn reject(
         self,
-        channel: &mut ActiveChannel<impl MessageBus>,
+        channel: &mut ActiveChannel<impl ClientRef>,
         reason: &str,
-    ) -> Result<(), InvalidChannel> {
-        self.ensure_valid_channel(channel)?;
+    ) -> Result<(), (Box<Self>, RejectError)> {
+        if let Err(e) = self.ensure_valid_channel(channel) {
+            return Err((Box::new(self), e.into()));
+        }
 
-        channel.client().bus.broadcast_to_participants(
+        let res = channel.client().bus.broadcast_to_participants(
             channel.part_idx(),
             channel.peers(),
             ParticipantMessage::ChannelUpdateRejected {
@@ -152,17 +283,68 @@ impl ChannelUpdate {
                 reason: reason.to_string(),
             },
         );
+        res.map_err(|e| (Box::new(self), e.into()))
+    }
+
+    /// Handle a [ParticipantMessage::ChannelUpdateRejected] for our update.
+    /// Afterwards we may propose (and sign) a different state with the same
+    /// version.
+    ///
+    /// Like with concurrent updates (see [ActiveChannel::handle_update]), this
+    /// relies on the other participant: It still has our signature on the
+    /// rejected state and could sign it after all.
+    pub fn handle_rejection(
+        self,
+        channel: &mut ActiveChannel<impl ClientRef>,
+        version: u64,
+        reason: &str,
+    ) -> Result<RejectedUpdate, (Box<Self>, RejectionError)> {
+        if let Err(e) = self.ensure_valid_channel(channel) {
+            return Err((Box::new(self), e.into()));
+        }
+        if version != self.new_state.version() {
+            return Err((Box::new(self), RejectionError::WrongVersion));
+        }
+        channel.release_signature(version, self.hash);
+        Ok(RejectedUpdate {
+            channel_id: self.channel_id,
+            version,
+            reason: reason.into(),
+        })
+    }
+
+    /// Send the update to the other participants again, for example because
+    /// they did not answer in time.
+    ///
+    /// Only possible if we have signed the update, which is always the case if
+    /// we proposed it.
+    pub fn resend(&self, channel: &ActiveChannel<impl ClientRef>) -> Result<(), ResendError> {
+        self.ensure_valid_channel(channel)?;
+
+        let sig = self.signatures[channel.part_idx()].ok_or(ResendError::NothingToResend)?;
+        channel.client().bus.broadcast_to_participants(
+            channel.part_idx(),
+            channel.peers(),
+            ParticipantMessage::ChannelUpdate(LedgerChannelUpdate {
+                state: self.new_state,
+                actor_idx: channel.part_idx(),
+                sig,
+            }),
+        )?;
         Ok(())
     }
 
     pub fn participant_accepted(
         &mut self,
-        channel: &ActiveChannel<impl MessageBus>,
+        channel: &ActiveChannel<impl ClientRef>,
         part_idx: PartIdx,
         msg: LedgerChannelUpdateAccepted,
     ) -> Result<(), AddSignatureError> {
         self.ensure_valid_channel(channel)?;
 
+        if part_idx >= PARTICIPANTS || part_idx == channel.part_idx() {
+            return Err(AddSignatureError::InvalidPartIdx(part_idx));
+        }
         if msg.channel != self.channel_id {
             return Err(AddSignatureError::InvalidChannelID);
         }
@@ -170,8 +352,7 @@ impl ChannelUpdate {
             return Err(AddSignatureError::InvalidVersionNumber);
         }
 
-        let hash = abiencode::to_hash(&self.new_state)?;
-        let signer = channel.client().signer.recover_signer(hash, msg.sig)?;
+        let signer = channel.client().signer.recover_signer(self.hash, msg.sig)?;
 
         if channel.params().participants[part_idx] != signer {
             return Err(AddSignatureError::InvalidSignature(signer));
@@ -197,7 +378,7 @@ impl ChannelUpdate {
 
     fn ensure_valid_channel(
         &self,
-        channel: &ActiveChannel<impl MessageBus>,
+        channel: &ActiveChannel<impl ClientRef>,
     ) -> Result<(), InvalidChannel> {
         if self.new_state.version() != channel.version() + 1 {
             Err(InvalidChannel::WrongVersion)
@@ -208,13 +389,33 @@ impl ChannelUpdate {
         }
     }
 
-    pub fn apply(
-        &mut self,
-        channel: &mut ActiveChannel<impl MessageBus>,
-    ) -> Result<(), ApplyError> {
+    pub fn apply(&mut self, channel: &mut ActiveChannel<impl ClientRef>) -> Result<(), ApplyError> {
         self.ensure_valid_channel(channel)?;
 
         channel.force_update(self.new_state, self.signatures()?)?;
         Ok(())
     }
 }
+
+/// An update another participant has rejected, see
+/// [ChannelUpdate::handle_rejection]. The channel stays at its current state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedUpdate {
+    channel_id: Hash,
+    version: u64,
+    reason: RejectReason,
+}
+
+impl RejectedUpdate {
+    pub fn channel_id(&self) -> Hash {
+        self.channel_id
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn reason(&self) -> &RejectReason {
+        &self.reason
+    }
+}
@@ -0,0 +1,86 @@
+use super::{active::ActiveChannel, fixed_size_payment, settling::SettledChannel, SignError};
+use crate::{
+    abiencode::types::{Hash, U256},
+    ClientRef,
+};
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+/// A channel with a dispute on-chain, see [ActiveChannel::handle_dispute] and
+/// [ActiveChannel::force_close].
+///
+/// It can no longer be updated. The watcher refutes outdated states for us,
+/// concludes the channel after the challenge period and withdraws our funds,
+/// reporting each step with a [WatcherReplyMessage][crate::messages::WatcherReplyMessage].
+#[derive(Debug)]
+pub struct DisputedChannel<C: ClientRef> {
+    channel: ActiveChannel<C>,
+    registered_version: u64,
+    concluded: bool,
+}
+
+impl<C: ClientRef> DisputedChannel<C> {
+    pub(super) fn new(channel: ActiveChannel<C>, registered_version: u64) -> Self {
+        DisputedChannel {
+            channel,
+            registered_version,
+            concluded: false,
+        }
+    }
+
+    pub fn channel_id(&self) -> Hash {
+        self.channel.channel_id()
+    }
+
+    /// Our latest fully signed state, which is not necessarily the one
+    /// registered on-chain.
+    pub fn state(&self) -> State {
+        self.channel.state()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.channel.version()
+    }
+
+    /// Version of the state currently registered on-chain.
+    pub fn registered_version(&self) -> u64 {
+        self.registered_version
+    }
+
+    /// Whether the registered state is older than ours, in which case the
+    /// watcher has been asked to refute it.
+    pub fn is_refuting(&self) -> bool {
+        !self.concluded && self.registered_version < self.channel.version()
+    }
+
+    /// Whether the challenge period is over and the channel was concluded
+    /// on-chain.
+    pub fn is_concluded(&self) -> bool {
+        self.concluded
+    }
+
+    /// The watcher noticed another registration during the challenge period,
+    /// for example our refutation. If it is still older than our latest
+    /// state, the watcher is asked to refute again.
+    pub fn handle_registered(&mut self, version: u64) -> Result<(), SignError> {
+        self.registered_version = version;
+        if self.is_refuting() {
+            self.channel.refute()?;
+        }
+        Ok(())
+    }
+
+    /// The challenge period is over and the channel was concluded on-chain
+    /// with the state of the given version. Nothing can be refuted anymore.
+    pub fn handle_concluded(&mut self, version: u64) {
+        self.registered_version = version;
+        self.concluded = true;
+    }
+
+    /// Our funds have arrived at our withdraw receiver.
+    pub fn handle_withdrawn(self, amounts: [U256; ASSETS]) -> SettledChannel {
+        SettledChannel::new(&self.channel, self.registered_version, amounts)
+    }
+}
@@ -39,6 +39,9 @@ impl<const P: usize> TryFrom<perunwire::Params> for Params<P> {
     type Error = ConversionError;
 
     fn try_from(value: perunwire::Params) -> Result<Self, Self::Error> {
+        if value.parts.len() != P {
+            return Err(ConversionError::ParticipantSizeMissmatch);
+        }
         let mut participants = [Address::default(); P];
         for (a, b) in participants.iter_mut().zip(value.parts) {
             *a = Address(b.try_into().or(Err(ConversionError::ByteLengthMissmatch))?);
@@ -46,7 +49,7 @@ impl<const P: usize> TryFrom<perunwire::Params> for Params<P> {
 
         Ok(Self {
             challenge_duration: value.challenge_duration,
-            nonce: U256::from_big_endian(&value.nonce),
+            nonce: u256_from_wire(&value.nonce)?,
             participants,
             app: Address(
                 value
@@ -295,6 +298,9 @@ impl<const A: usize, const P: usize> TryFrom<perunwire::Allocation> for Allocati
     type Error = ConversionError;
 
     fn try_from(value: perunwire::Allocation) -> Result<Self, Self::Error> {
+        if value.assets.len() != A {
+            return Err(ConversionError::AssetSizeMissmatch);
+        }
         let mut assets = [Asset::default(); A];
         for (a, b) in assets.iter_mut().zip(value.assets) {
             if b.len() < 4 {
@@ -314,8 +320,7 @@ impl<const A: usize, const P: usize> TryFrom<perunwire::Allocation> for Allocati
             }
             let chain_id = if chain_id_length > 0 {
                 let mut buffer = [0u8; 32];
-                buffer[(32 - chain_id_length as usize)..]
-                    .copy_from_slice(&b[2..2 + chain_id_length]);
+                buffer[(32 - chain_id_length)..].copy_from_slice(&b[2..2 + chain_id_length]);
                 U256::from_big_endian(&buffer)
             } else {
                 0.into()
@@ -326,7 +331,10 @@ impl<const A: usize, const P: usize> TryFrom<perunwire::Allocation> for Allocati
                     .try_into()
                     .unwrap(),
             );
-            if holder_length > 20 || b.len() != 2 + chain_id_length + 2 + (holder_length as usize) {
+            // An address is either empty or exactly 20 bytes long.
+            if !(holder_length == 0 || holder_length == 20)
+                || b.len() != 2 + chain_id_length + 2 + (holder_length as usize)
+            {
                 return Err(ConversionError::ByteLengthMissmatch);
             }
             let mut holder = Address::default();
@@ -387,6 +395,58 @@ impl<const A: usize, const P: usize> Allocation<A, P> {
             locked: [],
         }
     }
+
+    /// Balance of participant `part_idx` in `asset`, `None` if the asset is
+    /// not part of this allocation.
+    pub fn balance(&self, asset: Asset, part_idx: usize) -> Option<U256> {
+        let asset_idx = self.assets.iter().position(|a| *a == asset)?;
+        Some(self.balances.0[asset_idx].0[part_idx])
+    }
+
+    /// Move `amount` of `asset` from participant `from` to participant `to`.
+    /// Nothing is changed if this fails.
+    pub fn transfer(
+        &mut self,
+        asset: Asset,
+        from: usize,
+        to: usize,
+        amount: U256,
+    ) -> Result<(), TransferError> {
+        let asset_idx = self
+            .assets
+            .iter()
+            .position(|a| *a == asset)
+            .ok_or(TransferError::UnknownAsset)?;
+        let balances = &mut self.balances.0[asset_idx].0;
+        let new_from = balances[from]
+            .checked_sub(amount)
+            .ok_or(TransferError::InsufficientBalance)?;
+        let new_to = balances[to]
+            .checked_add(amount)
+            .ok_or(TransferError::BalanceOverflow)?;
+        balances[from] = new_from;
+        balances[to] = new_to;
+        Ok(())
+    }
+}
+
+/// Error returned by [Allocation::transfer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The asset is not part of the allocation.
+    UnknownAsset,
+    /// The sender does not have enough funds.
+    InsufficientBalance,
+    /// The receiver's balance would not fit into an [U256] anymore.
+    BalanceOverflow,
+}
+
+/// Big-endian number from the wire, which must fit into 32 bytes.
+fn u256_from_wire(b: &[u8]) -> Result<U256, ConversionError> {
+    if b.len() > 32 {
+        return Err(ConversionError::ByteLengthMissmatch);
+    }
+    Ok(U256::from_big_endian(b))
 }
 
 /// Currently needed as a work-around for marking nested arrays as dynamic.
@@ -414,7 +474,7 @@ impl<const P: usize> TryFrom<perunwire::Balance> for ParticipantBalances<P> {
         } else {
             let mut balances = Self::default();
             for (a, b) in balances.0.iter_mut().zip(value.balance) {
-                *a = U256::from_big_endian(&b)
+                *a = u256_from_wire(&b)?;
             }
             Ok(balances)
         }
@@ -589,4 +649,62 @@ mod tests {
                 "0xe274ea53fa64de7338bffbf264dc1f58a81e3660e426d328a2838944cbcc040205353a79da2bf1c67650c14e32e944ae6644c1a7f8f06146f7b6d152c87bdfb11c"[2..]
             );
     }
+
+    #[test]
+    fn transfer() {
+        let mut outcome = build_test_state().outcome;
+        let asset = outcome.assets[0];
+        outcome.transfer(asset, 0, 1, 0x5555.into()).unwrap();
+        assert_eq!(outcome.balance(asset, 0), Some(0.into()));
+        assert_eq!(outcome.balance(asset, 1), Some(0xbbbb.into()));
+
+        assert_eq!(
+            outcome.transfer(asset, 0, 1, 1.into()),
+            Err(TransferError::InsufficientBalance)
+        );
+        outcome.balances.0[0].0[0] = U256::MAX;
+        assert_eq!(
+            outcome.transfer(asset, 1, 0, 1.into()),
+            Err(TransferError::BalanceOverflow)
+        );
+        assert_eq!(outcome.balance(asset, 1), Some(0xbbbb.into()));
+
+        let other = Asset {
+            chain_id: 1.into(),
+            holder: asset.holder,
+        };
+        assert_eq!(outcome.balance(other, 0), None);
+        assert_eq!(
+            outcome.transfer(other, 0, 1, 1.into()),
+            Err(TransferError::UnknownAsset)
+        );
+    }
+
+    #[test]
+    fn malformed_allocation() {
+        let valid: perunwire::Allocation = build_test_state().outcome.into();
+        Allocation::<1, 2>::try_from(valid.clone()).unwrap();
+
+        let mut too_many_assets = valid.clone();
+        too_many_assets.assets.push(valid.assets[0].clone());
+        assert!(matches!(
+            Allocation::<1, 2>::try_from(too_many_assets),
+            Err(ConversionError::AssetSizeMissmatch)
+        ));
+
+        // Chain id length 0, holder length 5.
+        let mut short_holder = valid.clone();
+        short_holder.assets[0] = vec![0, 0, 5, 0, 1, 2, 3, 4, 5];
+        assert!(matches!(
+            Allocation::<1, 2>::try_from(short_holder),
+            Err(ConversionError::ByteLengthMissmatch)
+        ));
+
+        let mut large_balance = valid;
+        large_balance.balances.as_mut().unwrap().balances[0].balance[0] = vec![1; 33];
+        assert!(matches!(
+            Allocation::<1, 2>::try_from(large_balance),
+            Err(ConversionError::ByteLengthMissmatch)
+        ));
+    }
 }
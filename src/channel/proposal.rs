@@ -6,20 +6,20 @@
 //! data in rust (e.g. using `Vec<T>` vs no-heap `Vec<T>` vs `fixed-size<A,P>`).
 
 use super::{
-    agreed_upon::AgreedUponChannel,
+    agreed_upon::{AgreedUponChannel, ResendError},
     fixed_size_payment::{self},
     NonceShare, PartIdx,
 };
 use crate::{
     abiencode::{
         self,
-        types::{Address, U256},
+        types::{Address, Hash, U256},
     },
-    messages::{LedgerChannelProposal, LedgerChannelProposalAcc, ParticipantMessage},
-    wire::{BroadcastMessageBus, MessageBus},
-    PerunClient,
+    messages::{LedgerChannelProposal, LedgerChannelProposalAcc, ParticipantMessage, RejectReason},
+    wire::{BroadcastMessageBus, BusError},
+    ClientRef,
 };
-use alloc::string::ToString;
+use alloc::{boxed::Box, string::ToString};
 use sha3::{Digest, Sha3_256};
 
 const ASSETS: usize = 1;
@@ -27,16 +27,31 @@ const PARTICIPANTS: usize = 2;
 type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
 type Params = fixed_size_payment::Params<PARTICIPANTS>;
 
-/// Error returned when the proposal was already accepted by a participant.
+/// Error returned when accepting a proposal failed.
 #[derive(Debug)]
-pub struct AlreadyAcceptedError;
+pub enum ProposalAcceptError {
+    /// The proposal was already accepted by us, or we proposed it.
+    AlreadyAccepted,
+    Bus(BusError),
+}
+impl From<BusError> for ProposalAcceptError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
 
 #[derive(Debug)]
 pub enum HandleAcceptError {
     InvalidProposalID,
     AlreadyAccepted,
+    /// The participant index does not refer to another participant.
+    InvalidPartIdx(PartIdx),
 }
 
+/// Error returned when a rejection is for a different proposal.
+#[derive(Debug)]
+pub struct InvalidProposalIDError(pub Hash);
+
 /// Error returned when the transition from ProposedChannel -> AgreedUponChannel failed.
 #[derive(Debug)]
 pub enum ProposalBuildError {
@@ -55,13 +70,13 @@ impl From<abiencode::Error> for ProposalBuildError {
 /// Use `build()` or `try_into()` to get an [AgreedUponChannel], to sign the
 /// initial state and exchange those signatures.
 #[derive(Debug)]
-pub struct ProposedChannel<'cl, B: MessageBus> {
+pub struct ProposedChannel<C: ClientRef> {
     /// Who are we in this channel (0 is the channel proposer).
     part_idx: PartIdx,
     /// Who should receive funds when withdrawing
     withdraw_receiver: Address,
     /// Reference to the PerunClient, used for communication.
-    client: &'cl PerunClient<B>,
+    client: C,
     /// Needed for creating the initial state, Params and for the application to
     /// decide if those are valid Parameters.
     proposal: LedgerChannelProposal,
@@ -73,13 +88,13 @@ pub struct ProposedChannel<'cl, B: MessageBus> {
     responses: [Option<LedgerChannelProposalAcc>; 1],
 }
 
-impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
+impl<C: ClientRef> ProposedChannel<C> {
     /// Create a new ProposedChannel.
     ///
     /// The caller ([PerunClient]) is responsible for sending the proposal
     /// message to all participants.
     pub(crate) fn new(
-        client: &'cl PerunClient<B>,
+        client: C,
         part_idx: PartIdx,
         withdraw_receiver: Address,
         proposal: LedgerChannelProposal,
@@ -101,26 +116,26 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
         &mut self,
         nonce_share: NonceShare,
         address: Address,
-    ) -> Result<(), AlreadyAcceptedError> {
+    ) -> Result<(), ProposalAcceptError> {
         // In go-perun this "can we sign it" is checked in `completeCPP` by
         // trying to unlock the corresponding wallet.
-        assert_eq!(address, self.client.signer.address(), "We have to be able to sign things with this address and the current implementation is only able to have a single singer address. It is still part of the accept function signature because this will probably change in the future and this change would be backwards incompatible.");
+        assert_eq!(address, self.client.client().signer.address(), "We have to be able to sign things with this address and the current implementation is only able to have a single singer address. It is still part of the accept function signature because this will probably change in the future and this change would be backwards incompatible.");
 
         if self.part_idx == 0 || self.responses[self.part_idx - 1].is_some() {
-            return Err(AlreadyAcceptedError);
+            return Err(ProposalAcceptError::AlreadyAccepted);
         }
 
-        let acc: _ = LedgerChannelProposalAcc {
+        let acc = LedgerChannelProposalAcc {
             proposal_id: self.proposal.proposal_id,
             nonce_share,
             participant: address,
         };
-        self.responses[self.part_idx - 1] = Some(acc);
-        self.client.bus.broadcast_to_participants(
+        self.client.client().bus.broadcast_to_participants(
             self.part_idx,
             &self.proposal.peers,
             ParticipantMessage::ProposalAccepted(acc),
-        );
+        )?;
+        self.responses[self.part_idx - 1] = Some(acc);
 
         Ok(())
     }
@@ -128,9 +143,10 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
     /// Reject a proposed channel and send the reply to the participants.
     ///
     /// Drops the ProposedChannel object because using it no longer makes sense,
-    /// as we have rejected the proposal.
-    pub fn reject(self, reason: &str) {
-        self.client.bus.broadcast_to_participants(
+    /// as we have rejected the proposal. It is only given back if the
+    /// rejection could not be sent.
+    pub fn reject(self, reason: &str) -> Result<(), (Box<Self>, BusError)> {
+        let res = self.client.client().bus.broadcast_to_participants(
             self.part_idx,
             &self.proposal.peers,
             ParticipantMessage::ProposalRejected {
@@ -138,6 +154,40 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
                 reason: reason.to_string(),
             },
         );
+        res.map_err(|e| (Box::new(self), e))
+    }
+
+    /// Handle a [ParticipantMessage::ProposalRejected] from another
+    /// participant. The proposal can no longer be used, we stop waiting for it.
+    pub fn handle_rejection(
+        self,
+        id: Hash,
+        reason: &str,
+    ) -> Result<RejectedProposal, (Box<Self>, InvalidProposalIDError)> {
+        if id != self.proposal.proposal_id {
+            return Err((Box::new(self), InvalidProposalIDError(id)));
+        }
+        Ok(RejectedProposal {
+            proposal_id: id,
+            reason: reason.into(),
+        })
+    }
+
+    /// Send the proposal (or our accept message) to the other participants
+    /// again, for example because they did not answer in time.
+    pub fn resend(&self) -> Result<(), ResendError> {
+        let msg = if self.part_idx == 0 {
+            ParticipantMessage::ChannelProposal(self.proposal.clone())
+        } else {
+            let acc = self.responses[self.part_idx - 1].ok_or(ResendError::NothingToResend)?;
+            ParticipantMessage::ProposalAccepted(acc)
+        };
+        self.client.client().bus.broadcast_to_participants(
+            self.part_idx,
+            &self.proposal.peers,
+            msg,
+        )?;
+        Ok(())
     }
 
     /// Call this when receiving an Accept response form a participant.
@@ -155,6 +205,10 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
         if msg.proposal_id != self.proposal.proposal_id {
             return Err(HandleAcceptError::InvalidProposalID);
         }
+        // The proposer (0) does not send an accept message.
+        if part_idx == 0 || part_idx >= PARTICIPANTS || part_idx == self.part_idx {
+            return Err(HandleAcceptError::InvalidPartIdx(part_idx));
+        }
 
         let index = part_idx - 1;
         match self.responses[index] {
@@ -181,7 +235,7 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
     /// from it, so we have to give self back. If we wouldn't do that the caller
     /// would be forced to (implicitly) throw away the entire channel, so we
     /// could just as well have paniced in case of an error.
-    pub fn build(self) -> Result<AgreedUponChannel<'cl, B>, (Self, ProposalBuildError)> {
+    pub fn build(self) -> Result<AgreedUponChannel<C>, (Box<Self>, ProposalBuildError)> {
         let mut participants = [Address::default(); PARTICIPANTS];
         participants[0] = self.proposal.participant;
 
@@ -206,7 +260,12 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
             // Unwrap all responses, returning an error if one is missing
             let res = match res {
                 Some(v) => v,
-                None => return Err((self, ProposalBuildError::MissingAccResponse(index + 1))),
+                None => {
+                    return Err((
+                        Box::new(self),
+                        ProposalBuildError::MissingAccResponse(index + 1),
+                    ))
+                }
             };
 
             // Store in new participants list that doesn't use options and
@@ -229,7 +288,7 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
         };
         let init_state = match State::new(params, self.proposal.init_bals) {
             Ok(v) => v,
-            Err(e) => return Err((self, e.into())),
+            Err(e) => return Err((Box::new(self), e.into())),
         };
 
         Ok(AgreedUponChannel::new(
@@ -244,10 +303,28 @@ impl<'cl, B: MessageBus> ProposedChannel<'cl, B> {
     }
 }
 
-impl<'cl, B: MessageBus> TryFrom<ProposedChannel<'cl, B>> for AgreedUponChannel<'cl, B> {
-    type Error = (ProposedChannel<'cl, B>, ProposalBuildError);
+impl<C: ClientRef> TryFrom<ProposedChannel<C>> for AgreedUponChannel<C> {
+    type Error = (Box<ProposedChannel<C>>, ProposalBuildError);
 
-    fn try_from(value: ProposedChannel<'cl, B>) -> Result<Self, Self::Error> {
+    fn try_from(value: ProposedChannel<C>) -> Result<Self, Self::Error> {
         value.build()
     }
 }
+
+/// A proposal another participant has rejected, see
+/// [ProposedChannel::handle_rejection]. This is the end of its life.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedProposal {
+    proposal_id: Hash,
+    reason: RejectReason,
+}
+
+impl RejectedProposal {
+    pub fn proposal_id(&self) -> Hash {
+        self.proposal_id
+    }
+
+    pub fn reason(&self) -> &RejectReason {
+        &self.reason
+    }
+}
@@ -0,0 +1,160 @@
+use super::{active::ActiveChannel, disputed::DisputedChannel, fixed_size_payment, SignError};
+use crate::{
+    abiencode::types::{Address, Hash, U256},
+    ClientRef,
+};
+use alloc::boxed::Box;
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+#[derive(Debug)]
+pub enum SettleError {
+    /// Only a final state can be concluded cooperatively, use
+    /// [ActiveChannel::force_close] otherwise.
+    NotFinal,
+    Sign(SignError),
+}
+impl From<SignError> for SettleError {
+    fn from(e: SignError) -> Self {
+        Self::Sign(e)
+    }
+}
+
+/// A channel with a fully signed final state that the watcher concludes
+/// on-chain, see [ActiveChannel::settle].
+#[derive(Debug)]
+pub struct SettlingChannel<C: ClientRef> {
+    channel: ActiveChannel<C>,
+    concluded_version: Option<u64>,
+}
+
+impl<C: ClientRef> SettlingChannel<C> {
+    pub(super) fn new(channel: ActiveChannel<C>) -> Self {
+        SettlingChannel {
+            channel,
+            concluded_version: None,
+        }
+    }
+
+    pub fn channel_id(&self) -> Hash {
+        self.channel.channel_id()
+    }
+
+    /// The final state.
+    pub fn state(&self) -> State {
+        self.channel.state()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.channel.version()
+    }
+
+    pub fn is_concluded(&self) -> bool {
+        self.concluded_version.is_some()
+    }
+
+    /// Send the final state to the watcher again, for example because it did
+    /// not answer in time.
+    pub fn resend(&self) -> Result<(), SignError> {
+        self.channel.conclude()
+    }
+
+    /// The channel was concluded on-chain with the state of the given
+    /// version, which should be our final one.
+    pub fn handle_concluded(&mut self, version: u64) {
+        self.concluded_version = Some(version);
+    }
+
+    /// Our funds have arrived at our withdraw receiver.
+    pub fn handle_withdrawn(self, amounts: [U256; ASSETS]) -> SettledChannel {
+        let version = self.concluded_version.unwrap_or(self.channel.version());
+        SettledChannel::new(&self.channel, version, amounts)
+    }
+
+    /// Someone registered a state on-chain before the channel was concluded,
+    /// see [ActiveChannel::handle_dispute]. The channel is given back if the
+    /// refutation could not be sent.
+    pub fn handle_dispute(
+        self,
+        registered_version: u64,
+    ) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        let concluded_version = self.concluded_version;
+        self.channel
+            .handle_dispute(registered_version)
+            .map_err(|(channel, e)| {
+                let channel = SettlingChannel {
+                    channel: *channel,
+                    concluded_version,
+                };
+                (Box::new(channel), e)
+            })
+    }
+}
+
+/// A channel that was concluded on-chain and from which our funds have been
+/// withdrawn. This is the end of the channel's life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SettledChannel {
+    channel_id: Hash,
+    version: u64,
+    withdraw_receiver: Address,
+    balances: Option<[U256; ASSETS]>,
+    withdrawn: [U256; ASSETS],
+}
+
+impl SettledChannel {
+    /// `version` is the version of the concluded state, the balances are only
+    /// known if it is our latest state.
+    pub(super) fn new<C: ClientRef>(
+        channel: &ActiveChannel<C>,
+        version: u64,
+        withdrawn: [U256; ASSETS],
+    ) -> Self {
+        let balances = (version == channel.version()).then(|| {
+            let state = channel.state();
+            state
+                .outcome
+                .balances
+                .0
+                .map(|bals| bals.0[channel.part_idx()])
+        });
+        SettledChannel {
+            channel_id: channel.channel_id(),
+            version,
+            withdraw_receiver: channel.withdraw_receiver(),
+            balances,
+            withdrawn,
+        }
+    }
+
+    pub fn channel_id(&self) -> Hash {
+        self.channel_id
+    }
+
+    /// Version of the state the channel was concluded with.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn withdraw_receiver(&self) -> Address {
+        self.withdraw_receiver
+    }
+
+    /// Our balance of each asset in the concluded state, `None` if it was
+    /// concluded with a state we don't know.
+    pub fn balances(&self) -> Option<[U256; ASSETS]> {
+        self.balances
+    }
+
+    /// What arrived at the withdraw receiver for each asset.
+    pub fn withdrawn(&self) -> [U256; ASSETS] {
+        self.withdrawn
+    }
+
+    /// Whether we received exactly our balances of the concluded state.
+    pub fn is_complete(&self) -> bool {
+        self.balances == Some(self.withdrawn)
+    }
+}
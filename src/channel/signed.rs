@@ -1,9 +1,13 @@
-use super::{active::ActiveChannel, fixed_size_payment, PartIdx, Peers};
+use super::{
+    active::ActiveChannel, disputed::DisputedChannel, fixed_size_payment, PartIdx, Peers, SignError,
+};
 use crate::{
     abiencode::types::{Hash, Signature},
-    wire::MessageBus,
-    Address, PerunClient,
+    messages::FunderReplyMessage,
+    storage::{ChannelStorage, StorageError},
+    Address, ClientRef,
 };
+use alloc::boxed::Box;
 
 const ASSETS: usize = 1;
 const PARTICIPANTS: usize = 2;
@@ -11,11 +15,32 @@ type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
 type Params = fixed_size_payment::Params<PARTICIPANTS>;
 
 #[derive(Debug)]
-pub struct SignedChannel<'cl, B: MessageBus>(ActiveChannel<'cl, B>);
+pub enum FundingError {
+    /// The funder's reply is for a different channel.
+    WrongChannel(Hash),
+    /// The funder has not confirmed the funding (yet).
+    NotFunded,
+    /// The funder could not fund the channel, use [SignedChannel::refund] to
+    /// get our deposit back.
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FundingStatus {
+    Pending,
+    Funded,
+    Failed,
+}
 
-impl<'cl, B: MessageBus> SignedChannel<'cl, B> {
+#[derive(Debug)]
+pub struct SignedChannel<C: ClientRef> {
+    channel: ActiveChannel<C>,
+    funding: FundingStatus,
+}
+
+impl<C: ClientRef> SignedChannel<C> {
     pub(super) fn new(
-        client: &'cl PerunClient<B>,
+        client: C,
         part_idx: PartIdx,
         withdraw_receiver: Address,
         init_state: State,
@@ -23,22 +48,106 @@ impl<'cl, B: MessageBus> SignedChannel<'cl, B> {
         signatures: [Signature; PARTICIPANTS],
         peers: Peers,
     ) -> Self {
-        SignedChannel(ActiveChannel::new(
-            client,
-            part_idx,
-            withdraw_receiver,
-            init_state,
-            params,
-            signatures,
-            peers,
-        ))
+        SignedChannel {
+            channel: ActiveChannel::new(
+                client,
+                part_idx,
+                withdraw_receiver,
+                init_state,
+                params,
+                signatures,
+                peers,
+            ),
+            funding: FundingStatus::Pending,
+        }
+    }
+
+    /// Whether the funder confirmed that the channel is fully funded.
+    pub fn is_funded(&self) -> bool {
+        self.funding == FundingStatus::Funded
+    }
+
+    /// Handle the reply to our funding request.
+    pub fn handle_funder_reply(&mut self, msg: FunderReplyMessage) -> Result<(), FundingError> {
+        let (id, status) = match msg {
+            FunderReplyMessage::Funded { id } => (id, FundingStatus::Funded),
+            FunderReplyMessage::FundingFailed { id } => (id, FundingStatus::Failed),
+        };
+        if id != self.channel_id() {
+            return Err(FundingError::WrongChannel(id));
+        }
+        self.funding = status;
+        match status {
+            FundingStatus::Failed => Err(FundingError::Failed),
+            _ => Ok(()),
+        }
     }
 
-    pub fn mark_funded(self) -> ActiveChannel<'cl, B> {
-        self.0
+    /// Start using the channel, only possible once the funder confirmed the
+    /// funding with [Self::handle_funder_reply]. The channel is given back
+    /// (boxed, it is rather large) otherwise.
+    pub fn mark_funded(self) -> Result<ActiveChannel<C>, (Box<Self>, FundingError)> {
+        match self.funding {
+            FundingStatus::Funded => Ok(self.channel),
+            FundingStatus::Pending => Err((Box::new(self), FundingError::NotFunded)),
+            FundingStatus::Failed => Err((Box::new(self), FundingError::Failed)),
+        }
+    }
+
+    /// Register the initial state on-chain to get our deposit back, for
+    /// example because the funding failed. The watcher concludes the channel
+    /// after the challenge period and withdraws our funds.
+    pub fn refund(self) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        match self.channel.start_dispute() {
+            Ok(()) => {
+                let version = self.channel.version();
+                Ok(DisputedChannel::new(self.channel, version))
+            }
+            Err(e) => Err((Box::new(self), e)),
+        }
     }
 
     pub fn channel_id(&self) -> Hash {
-        self.0.channel_id()
+        self.channel.channel_id()
+    }
+
+    /// Send the initial state to the watcher again, for example because it did
+    /// not acknowledge it in time.
+    pub fn send_current_state_to_watcher(&self) -> Result<(), SignError> {
+        self.channel.send_current_state_to_watcher()
+    }
+
+    /// Ask the watcher to register the initial state on-chain, to get our
+    /// deposit back if the channel never gets fully funded.
+    pub fn force_close(self) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        let funding = self.funding;
+        self.channel.force_close().map_err(|(channel, e)| {
+            let channel = *channel;
+            (Box::new(SignedChannel { channel, funding }), e)
+        })
+    }
+
+    /// Freeze the channel after the watcher noticed a dispute on-chain, see
+    /// [ActiveChannel::handle_dispute].
+    pub fn handle_dispute(
+        self,
+        registered_version: u64,
+    ) -> Result<DisputedChannel<C>, (Box<Self>, SignError)> {
+        let funding = self.funding;
+        self.channel
+            .handle_dispute(registered_version)
+            .map_err(|(channel, e)| {
+                let channel = SignedChannel {
+                    channel: *channel,
+                    funding,
+                };
+                (Box::new(channel), e)
+            })
+    }
+
+    /// Store the initial state. Do this right after building the channel, our
+    /// funds may be locked in it as soon as the funding request is processed.
+    pub fn persist(&self, storage: &mut impl ChannelStorage) -> Result<(), StorageError> {
+        self.channel.persist(storage)
     }
 }
@@ -1,14 +1,28 @@
 use crate::channel::ProposedChannel;
 use crate::messages::{LedgerChannelProposal, ParticipantMessage};
+use crate::policy::{AskAlways, Policy};
 use crate::sig::Signer;
-use crate::wire::{BroadcastMessageBus, Identity, MessageBus};
-use crate::Address;
+use crate::wire::{sign_identity, BroadcastMessageBus, BusError, Identity, MessageBus};
+use crate::{Address, Hash};
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync::Arc;
+use alloc::{boxed::Box, rc::Rc};
 use core::fmt::Debug;
 
 #[derive(Debug)]
 pub enum InvalidProposal {
     NoChallengeDurationSet,
     PeerParticipantCountMismatch,
+    /// The client's [Policy] rejected the proposal, the rejection has been
+    /// sent to the other participants.
+    Rejected(&'static str),
+    /// The proposal is valid, but could not be sent.
+    Bus(BusError),
+}
+impl From<BusError> for InvalidProposal {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
 }
 
 /// The main Perun object used to create new channels and configure
@@ -24,20 +38,89 @@ pub enum InvalidProposal {
 pub struct PerunClient<B: MessageBus> {
     pub(crate) bus: B,
     pub(crate) signer: Signer,
+    pub(crate) policy: Box<dyn Policy + Send + Sync>,
+}
+
+/// Handle to a [PerunClient], held by the channel objects.
+///
+/// With a plain reference the channels borrow the client, which is the
+/// cheapest option if they don't have to outlive it. Use `Rc<PerunClient<B>>`
+/// or `Arc<PerunClient<B>>` to get channels without a lifetime, which can be
+/// stored next to the client (for example in a map) or moved into a task.
+pub trait ClientRef: Clone {
+    type Bus: MessageBus;
+
+    fn client(&self) -> &PerunClient<Self::Bus>;
+}
+
+impl<B: MessageBus> ClientRef for &PerunClient<B> {
+    type Bus = B;
+
+    fn client(&self) -> &PerunClient<B> {
+        self
+    }
+}
+
+impl<B: MessageBus> ClientRef for Rc<PerunClient<B>> {
+    type Bus = B;
+
+    fn client(&self) -> &PerunClient<B> {
+        self
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<B: MessageBus> ClientRef for Arc<PerunClient<B>> {
+    type Bus = B;
+
+    fn client(&self) -> &PerunClient<B> {
+        self
+    }
 }
 
 impl<B: MessageBus> PerunClient<B> {
     /// Creates a new [PerunClient] with the given [MessageBus].
     pub fn new(bus: B, signer: Signer) -> Self {
-        PerunClient { bus, signer }
+        PerunClient {
+            bus,
+            signer,
+            policy: Box::new(AskAlways),
+        }
+    }
+
+    /// Decide about proposals and updates of other participants with `policy`
+    /// instead of leaving everything to the application, see
+    /// [Self::handle_proposal] and
+    /// [ActiveChannel::handle_update][crate::channel::ActiveChannel::handle_update].
+    /// By default, nothing is rejected.
+    pub fn set_policy(&mut self, policy: impl Policy + Send + Sync + 'static) {
+        self.policy = Box::new(policy);
     }
 
-    pub fn send_handshake_msg(&self, sender: &Identity, recipient: &Identity) {
+    /// Authenticate ourselves to `recipient` by sending an `Auth` message with
+    /// a signature of `sender`, which has to be our address. See
+    /// [PeerAuth][crate::wire::PeerAuth] for the other side, `binding` has to
+    /// be the one it is bound to. go-perun expects `None`.
+    pub fn send_handshake_msg(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        binding: Option<&Hash>,
+    ) -> Result<(), BusError> {
+        let signature = sign_identity(&self.signer, sender, binding);
         self.bus
-            .send_to_participant(sender, recipient, ParticipantMessage::Auth);
+            .send_to_participant(sender, recipient, ParticipantMessage::Auth { signature })
+    }
+
+    /// Our identity on the wire, which is needed for authenticating
+    /// connections with [PeerAuth][crate::wire::PeerAuth].
+    pub fn identity(&self) -> Identity {
+        self.signer.address().0.to_vec()
     }
 
-    fn check_valid_proposal(prop: &LedgerChannelProposal) -> Result<(), InvalidProposal> {
+    pub(crate) fn check_valid_proposal(
+        prop: &LedgerChannelProposal,
+    ) -> Result<(), InvalidProposal> {
         prop.init_bals.debug_assert_valid();
         debug_assert_eq!(
             prop.init_bals.locked.len(),
@@ -60,7 +143,18 @@ impl<B: MessageBus> PerunClient<B> {
         &self,
         prop: LedgerChannelProposal,
         withdraw_receiver: Address,
-    ) -> Result<ProposedChannel<B>, InvalidProposal> {
+    ) -> Result<ProposedChannel<&Self>, InvalidProposal> {
+        Self::propose_channel_with(self, prop, withdraw_receiver)
+    }
+
+    /// Like [Self::propose_channel], but the returned channel uses the given
+    /// [ClientRef] (for example an `Rc<PerunClient<B>>`) instead of borrowing
+    /// the client.
+    pub fn propose_channel_with<C: ClientRef<Bus = B>>(
+        client: C,
+        prop: LedgerChannelProposal,
+        withdraw_receiver: Address,
+    ) -> Result<ProposedChannel<C>, InvalidProposal> {
         // For sub-channels and virtual-channels, go-perun checks if the parent
         // exists (is known) and locks the parent's context for the duration of
         // the handshake (including funding) or returns an Error if it does not.
@@ -90,17 +184,33 @@ impl<B: MessageBus> PerunClient<B> {
         // reference (which also requires a second clone), or read the proposal
         // back from the ProposedChannel.
         let msg = ParticipantMessage::ChannelProposal(prop.clone());
-        self.bus.broadcast_to_participants(0, &prop.peers, msg);
-        Ok(ProposedChannel::new(self, 0, withdraw_receiver, prop))
+        client
+            .client()
+            .bus
+            .broadcast_to_participants(0, &prop.peers, msg)?;
+        Ok(ProposedChannel::new(client, 0, withdraw_receiver, prop))
     }
 
     /// Call this when receiving a proposal message, then call `accept()` or
     /// `reject()` to send the response.
+    ///
+    /// If the client's [Policy] rejects the proposal, the rejection is sent
+    /// right away and [InvalidProposal::Rejected] is returned.
     pub fn handle_proposal(
         &self,
         prop: LedgerChannelProposal,
         withdraw_receiver: Address,
-    ) -> Result<ProposedChannel<B>, InvalidProposal> {
+    ) -> Result<ProposedChannel<&Self>, InvalidProposal> {
+        Self::handle_proposal_with(self, prop, withdraw_receiver)
+    }
+
+    /// Like [Self::handle_proposal], but the returned channel uses the given
+    /// [ClientRef] instead of borrowing the client.
+    pub fn handle_proposal_with<C: ClientRef<Bus = B>>(
+        client: C,
+        prop: LedgerChannelProposal,
+        withdraw_receiver: Address,
+    ) -> Result<ProposedChannel<C>, InvalidProposal> {
         // For sub-channels and virtual-channels, go-perun additionaly checks if
         // the parent channel exists and locks its context until the channel is
         // funded. See propose_channel for details.
@@ -111,6 +221,15 @@ impl<B: MessageBus> PerunClient<B> {
         // are possible (which is also the case in go-perun and more channels
         // currently require changing some constants in go-perun, so this isn't
         // a big deal for now).
-        Ok(ProposedChannel::new(self, 1, withdraw_receiver, prop))
+        let part_idx = 1;
+        let policy = client.client().policy.check_proposal(part_idx, &prop);
+        let channel = ProposedChannel::new(client, part_idx, withdraw_receiver, prop);
+        match policy {
+            Ok(()) => Ok(channel),
+            Err(reason) => {
+                channel.reject(reason).map_err(|(_, e)| e)?;
+                Err(InvalidProposal::Rejected(reason))
+            }
+        }
     }
 }
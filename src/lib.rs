@@ -22,16 +22,28 @@ pub mod abiencode {
 pub mod messages;
 pub mod sig;
 
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub mod asynch;
 pub mod channel;
 mod client;
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+pub mod loopback;
+pub mod policy;
+pub mod registry;
+pub mod storage;
+pub mod time;
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub mod transport;
 pub mod wire;
 
 pub use abiencode::types::{Address, Hash};
-pub use client::{InvalidProposal, PerunClient};
+pub use client::{ClientRef, InvalidProposal, PerunClient};
 
-// TODO: This probably shouldn't be public, but the example currently needs it,
-// since the encoding layer doesn't do decoding, yet.
-pub mod perunwire {
+#[allow(clippy::enum_variant_names)]
+pub(crate) mod perunwire {
     // The message types are currently defined in two separate .proto files with
     // different package names. This makes sense (as of now), since they are
     // defined in different repositories. Using the same package names in the
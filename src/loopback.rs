@@ -0,0 +1,545 @@
+//! In-memory network connecting several [PerunClient]s with a simulated
+//! watcher, funder and blockchain, for testing complete channel flows in
+//! `cargo test` without the Go side.
+//!
+//! Sent messages are not delivered right away but queued in the [Loopback],
+//! from where the test delivers them one at a time in a deterministic order.
+//! In between, the messages in flight can be inspected, dropped, reordered or
+//! new ones injected, and everything that was ever sent is kept for
+//! inspection in [Loopback::traffic]. [Simulation] wraps this for the common
+//! case of one [ChannelRegistry] per client.
+//!
+//! The simulated services behave like go-perun's watcher and funder:
+//!
+//! - A channel is funded once the funding requests of all participants have
+//!   been sent, see [Loopback::fail_funding] for the other outcome.
+//! - Disputes are registered on a simulated chain and announced to the
+//!   watchers of all participants, which can refute them until the test ends
+//!   the challenge period with [Loopback::end_challenge].
+//! - Final states are concluded right away and the funds of the requesting
+//!   participant withdrawn.
+
+#[cfg(test)]
+mod tests;
+
+use alloc::{collections::BTreeMap, rc::Rc, vec, vec::Vec};
+use core::cell::{Cell, RefCell};
+use rand::{seq::SliceRandom, CryptoRng, Rng};
+
+use crate::{
+    abiencode::types::{Address, Hash},
+    channel::{fixed_size_payment, PartIdx},
+    messages::{
+        FunderReplyMessage, FunderRequestMessage, ParticipantMessage, WatcherReplyMessage,
+        WatcherRequestMessage,
+    },
+    registry::{ChannelRegistry, Event, RegistryError},
+    sig::Signer,
+    time::{Clock, Instant, TimeoutPolicy},
+    wire::{BusError, Identity, MessageBus},
+    PerunClient,
+};
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+/// A message on the simulated network. Clients are identified by the index
+/// returned from [Loopback::add_client].
+#[derive(Debug, Clone)]
+pub enum Message {
+    Participant {
+        from: usize,
+        to: usize,
+        msg: ParticipantMessage,
+    },
+    WatcherRequest {
+        from: usize,
+        msg: WatcherRequestMessage,
+    },
+    FunderRequest {
+        from: usize,
+        msg: FunderRequestMessage,
+    },
+    WatcherReply {
+        to: usize,
+        msg: WatcherReplyMessage,
+    },
+    FunderReply {
+        to: usize,
+        msg: FunderReplyMessage,
+    },
+}
+
+impl Message {
+    /// The client receiving the message, `None` for requests to the
+    /// simulated services.
+    pub fn recipient(&self) -> Option<usize> {
+        match self {
+            Message::Participant { to, .. }
+            | Message::WatcherReply { to, .. }
+            | Message::FunderReply { to, .. } => Some(*to),
+            Message::WatcherRequest { .. } | Message::FunderRequest { .. } => None,
+        }
+    }
+}
+
+/// A [Message] together with its sequence number, which is unique for the
+/// whole simulation and increases in the order the messages were sent.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub seq: u64,
+    pub msg: Message,
+}
+
+/// What the simulated chain knows about a channel.
+#[derive(Debug, Default)]
+struct OnChain {
+    /// Clients watching the channel and their index in it.
+    watchers: Vec<(usize, PartIdx)>,
+    /// Clients that deposited, by their index in the channel.
+    funding: [Option<usize>; PARTICIPANTS],
+    funded: bool,
+    funding_failed: bool,
+    /// State registered in a dispute or concluded.
+    registered: Option<State>,
+    concluded: bool,
+    withdrawn: [bool; PARTICIPANTS],
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    identities: Vec<Identity>,
+    connected: Vec<bool>,
+    in_flight: Vec<Packet>,
+    traffic: Vec<Packet>,
+    next_seq: u64,
+    chain: BTreeMap<Hash, OnChain>,
+}
+
+impl Inner {
+    fn push(&mut self, msg: Message) -> u64 {
+        let packet = Packet {
+            seq: self.next_seq,
+            msg,
+        };
+        self.next_seq += 1;
+        self.traffic.push(packet.clone());
+        self.in_flight.push(packet);
+        self.next_seq - 1
+    }
+
+    fn reply_watcher(&mut self, to: usize, msg: WatcherReplyMessage) {
+        self.push(Message::WatcherReply { to, msg });
+    }
+
+    fn serve_watcher(&mut self, from: usize, msg: WatcherRequestMessage) {
+        let (WatcherRequestMessage::WatchRequest(info)
+        | WatcherRequestMessage::StartDispute(info)
+        | WatcherRequestMessage::Refute(info)
+        | WatcherRequestMessage::Conclude(info)) = msg;
+        let id = info.state.channel_id();
+        let version = info.state.version();
+        let channel = self.chain.entry(id).or_default();
+        match msg {
+            WatcherRequestMessage::WatchRequest(_) => {
+                if !channel.watchers.contains(&(from, info.part_idx)) {
+                    channel.watchers.push((from, info.part_idx));
+                }
+                self.reply_watcher(from, WatcherReplyMessage::Ack { id, version });
+            }
+            WatcherRequestMessage::StartDispute(_) | WatcherRequestMessage::Refute(_) => {
+                let outdated = matches!(channel.registered, Some(s) if s.version() >= version);
+                let register = !channel.concluded && !outdated;
+                if register {
+                    channel.registered = Some(info.state);
+                }
+                let watchers = channel.watchers.clone();
+                self.reply_watcher(from, WatcherReplyMessage::DisputeAck { id });
+                if register {
+                    for (idx, _) in watchers {
+                        self.reply_watcher(
+                            idx,
+                            WatcherReplyMessage::DisputeNotification { id, version },
+                        );
+                    }
+                }
+            }
+            WatcherRequestMessage::Conclude(_) => {
+                // Disputed channels are only concluded after the challenge
+                // period, see [Loopback::end_challenge].
+                if channel.registered.is_none() && info.state.is_final {
+                    channel.registered = Some(info.state);
+                    channel.concluded = true;
+                }
+                if channel.concluded {
+                    self.conclude(id, &[(from, info.part_idx)]);
+                }
+            }
+        }
+    }
+
+    /// Tell `watchers` about the concluded channel and withdraw their funds.
+    fn conclude(&mut self, id: Hash, watchers: &[(usize, PartIdx)]) {
+        let channel = self.chain.get_mut(&id).unwrap();
+        let state = channel.registered.unwrap();
+        let mut replies = vec![];
+        for &(idx, part_idx) in watchers {
+            replies.push((
+                idx,
+                WatcherReplyMessage::Concluded {
+                    id,
+                    version: state.version(),
+                },
+            ));
+            if !core::mem::replace(&mut channel.withdrawn[part_idx], true) {
+                // Without funding, everyone only gets back what they
+                // deposited.
+                let deposited = channel.funded || channel.funding[part_idx].is_some();
+                let amounts = state.outcome.balances.0.map(|bals| {
+                    if deposited {
+                        bals.0[part_idx]
+                    } else {
+                        0.into()
+                    }
+                });
+                replies.push((idx, WatcherReplyMessage::Withdrawn { id, amounts }));
+            }
+        }
+        for (to, msg) in replies {
+            self.reply_watcher(to, msg);
+        }
+    }
+
+    fn serve_funder(&mut self, from: usize, msg: FunderRequestMessage) {
+        let FunderRequestMessage::FundingRequest(req) = msg;
+        let id = req.state.channel_id();
+        let channel = self.chain.entry(id).or_default();
+        channel.funding[req.part_idx] = Some(from);
+        if channel.funding_failed {
+            self.push(Message::FunderReply {
+                to: from,
+                msg: FunderReplyMessage::FundingFailed { id },
+            });
+            return;
+        }
+        let funders = match channel.funding.iter().copied().collect::<Option<Vec<_>>>() {
+            Some(funders) if !channel.funded => funders,
+            _ => return,
+        };
+        channel.funded = true;
+        for to in funders {
+            self.push(Message::FunderReply {
+                to,
+                msg: FunderReplyMessage::Funded { id },
+            });
+        }
+    }
+}
+
+/// The simulated network, shared by the [LoopbackBus] of each client.
+#[derive(Debug, Default)]
+pub struct Loopback {
+    inner: RefCell<Inner>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect a new client with the given identity (its address, if
+    /// [PeerAuth][crate::wire::PeerAuth] is to be used), returns the bus for
+    /// its [PerunClient]. Clients are numbered in the order they are added.
+    pub fn add_client(self: &Rc<Self>, identity: Identity) -> LoopbackBus {
+        let mut inner = self.inner.borrow_mut();
+        inner.identities.push(identity);
+        inner.connected.push(true);
+        LoopbackBus {
+            net: self.clone(),
+            idx: inner.identities.len() - 1,
+        }
+    }
+
+    pub fn identity(&self, idx: usize) -> Identity {
+        self.inner.borrow().identities[idx].clone()
+    }
+
+    /// While disconnected, everything the client sends and everything sent to
+    /// it fails with [BusError::NotConnected]. Messages already in flight are
+    /// still delivered.
+    pub fn set_connected(&self, idx: usize, connected: bool) {
+        self.inner.borrow_mut().connected[idx] = connected;
+    }
+
+    /// Messages sent but not yet delivered, in delivery order.
+    pub fn in_flight(&self) -> Vec<Packet> {
+        self.inner.borrow().in_flight.clone()
+    }
+
+    /// All messages sent so far, including the replies of the simulated
+    /// services and injected messages, in the order they were sent.
+    pub fn traffic(&self) -> Vec<Packet> {
+        self.inner.borrow().traffic.clone()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.inner.borrow().in_flight.is_empty()
+    }
+
+    /// Take the next message for one of the clients out of the network.
+    /// Requests to the watcher and funder on the way are handled by the
+    /// simulated services, whose replies are queued behind all other
+    /// messages in flight.
+    pub fn pop(&self) -> Option<Packet> {
+        let mut inner = self.inner.borrow_mut();
+        while !inner.in_flight.is_empty() {
+            let packet = inner.in_flight.remove(0);
+            match packet.msg {
+                Message::WatcherRequest { from, msg } => inner.serve_watcher(from, msg),
+                Message::FunderRequest { from, msg } => inner.serve_funder(from, msg),
+                _ => return Some(packet),
+            }
+        }
+        None
+    }
+
+    /// Remove the message with sequence number `seq` from the network, to
+    /// drop it or to deliver it out of order.
+    pub fn take(&self, seq: u64) -> Option<Packet> {
+        let mut inner = self.inner.borrow_mut();
+        let pos = inner.in_flight.iter().position(|p| p.seq == seq)?;
+        Some(inner.in_flight.remove(pos))
+    }
+
+    /// Drop all messages in flight for which `f` returns true, returns them.
+    pub fn drop_where(&self, mut f: impl FnMut(&Message) -> bool) -> Vec<Packet> {
+        let mut inner = self.inner.borrow_mut();
+        let (dropped, kept) = inner.in_flight.drain(..).partition(|p| f(&p.msg));
+        inner.in_flight = kept;
+        dropped
+    }
+
+    /// Bring the messages in flight into a random order. Use a seeded `rng`
+    /// to keep the test deterministic.
+    pub fn shuffle<R: Rng>(&self, rng: &mut R) {
+        self.inner.borrow_mut().in_flight.shuffle(rng);
+    }
+
+    /// Send a message as if it came from the network, for example a replayed
+    /// message or a reply the simulated services would not send on their
+    /// own. Returns its sequence number.
+    pub fn inject(&self, msg: Message) -> u64 {
+        self.inner.borrow_mut().push(msg)
+    }
+
+    /// End the challenge period of a disputed channel: It is concluded with
+    /// the registered state and the funds of all clients still watching it
+    /// are withdrawn. Does nothing if there is no dispute.
+    pub fn end_challenge(&self, id: Hash) {
+        let mut inner = self.inner.borrow_mut();
+        let watchers = match inner.chain.get_mut(&id) {
+            Some(channel) if channel.registered.is_some() && !channel.concluded => {
+                channel.concluded = true;
+                channel.watchers.clone()
+            }
+            _ => return,
+        };
+        inner.conclude(id, &watchers);
+    }
+
+    /// Let the funding of a channel fail, for example because another
+    /// participant didn't deposit in time. Everyone who deposited so far is
+    /// told right away, later requests fail, too. Does nothing if the channel
+    /// is already funded.
+    pub fn fail_funding(&self, id: Hash) {
+        let mut inner = self.inner.borrow_mut();
+        let channel = inner.chain.entry(id).or_default();
+        if channel.funded {
+            return;
+        }
+        channel.funding_failed = true;
+        let funders = channel.funding;
+        for to in funders.into_iter().flatten() {
+            inner.push(Message::FunderReply {
+                to,
+                msg: FunderReplyMessage::FundingFailed { id },
+            });
+        }
+    }
+
+    /// Queue `msg` from client `from`, addressed to client `to` if it is a
+    /// participant message.
+    fn send(&self, from: usize, to: Option<usize>, msg: Message) -> Result<(), BusError> {
+        let mut inner = self.inner.borrow_mut();
+        let unreachable = matches!(to, Some(to) if !inner.connected[to]);
+        if !inner.connected[from] || unreachable {
+            return Err(BusError::NotConnected);
+        }
+        inner.push(msg);
+        Ok(())
+    }
+}
+
+/// [MessageBus] of one client on a [Loopback].
+#[derive(Debug, Clone)]
+pub struct LoopbackBus {
+    net: Rc<Loopback>,
+    idx: usize,
+}
+
+impl LoopbackBus {
+    /// Index of the client on the network.
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+}
+
+impl MessageBus for LoopbackBus {
+    fn send_to_watcher(&self, msg: WatcherRequestMessage) -> Result<(), BusError> {
+        let msg = Message::WatcherRequest {
+            from: self.idx,
+            msg,
+        };
+        self.net.send(self.idx, None, msg)
+    }
+
+    fn send_to_funder(&self, msg: FunderRequestMessage) -> Result<(), BusError> {
+        let msg = Message::FunderRequest {
+            from: self.idx,
+            msg,
+        };
+        self.net.send(self.idx, None, msg)
+    }
+
+    fn send_to_participant(
+        &self,
+        _: &Identity,
+        recipient: &Identity,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError> {
+        let to = self
+            .net
+            .inner
+            .borrow()
+            .identities
+            .iter()
+            .position(|i| i == recipient);
+        let to = to.ok_or(BusError::NotConnected)?;
+        let msg = Message::Participant {
+            from: self.idx,
+            to,
+            msg,
+        };
+        self.net.send(self.idx, Some(to), msg)
+    }
+}
+
+/// Clock of a [Simulation], only advanced by the test.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock(Rc<Cell<u64>>);
+
+impl SimClock {
+    pub fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        Instant(self.0.get())
+    }
+}
+
+pub type SimClient = Rc<PerunClient<LoopbackBus>>;
+pub type SimRegistry = ChannelRegistry<SimClient, SimClock>;
+
+/// Several [ChannelRegistry]s on a [Loopback], sharing one [SimClock].
+///
+/// The identity of each client is its address, the registries can be used
+/// directly to propose, update and close channels, [Self::deliver] then runs
+/// the network until all messages have been handled.
+pub struct Simulation {
+    pub net: Rc<Loopback>,
+    pub clock: SimClock,
+    pub registries: Vec<SimRegistry>,
+}
+
+impl Simulation {
+    /// Create `clients` clients with random keys.
+    pub fn new<R: Rng + CryptoRng>(clients: usize, timeouts: TimeoutPolicy, rng: &mut R) -> Self {
+        let net = Rc::new(Loopback::new());
+        let clock = SimClock::default();
+        let registries = (0..clients)
+            .map(|_| {
+                let signer = Signer::new(rng);
+                let bus = net.add_client(signer.address().0.to_vec());
+                let client = Rc::new(PerunClient::new(bus, signer));
+                ChannelRegistry::with_timeouts(client, clock.clone(), timeouts)
+            })
+            .collect();
+        Self {
+            net,
+            clock,
+            registries,
+        }
+    }
+
+    pub fn address(&self, idx: usize) -> Address {
+        self.registries[idx].client().signer.address()
+    }
+
+    pub fn identity(&self, idx: usize) -> Identity {
+        self.net.identity(idx)
+    }
+
+    /// Deliver the next message, returns the recipient and the result of
+    /// handling it.
+    pub fn step(&mut self) -> Option<(usize, Result<Option<Event>, RegistryError>)> {
+        let packet = self.net.pop()?;
+        let (idx, res) = match packet.msg {
+            Message::Participant { to, msg, .. } => {
+                (to, self.registries[to].handle_participant_message(msg))
+            }
+            Message::WatcherReply { to, msg } => {
+                (to, self.registries[to].handle_watcher_message(msg))
+            }
+            Message::FunderReply { to, msg } => {
+                (to, self.registries[to].handle_funder_message(msg))
+            }
+            Message::WatcherRequest { .. } | Message::FunderRequest { .. } => {
+                unreachable!("served by the loopback")
+            }
+        };
+        Some((idx, res))
+    }
+
+    /// Deliver messages until there are none left, returns the events of
+    /// each client.
+    ///
+    /// Stops at the first message that can't be handled, the remaining
+    /// messages stay in flight.
+    pub fn deliver(&mut self) -> Result<Vec<Vec<Event>>, RegistryError> {
+        let mut events: Vec<Vec<Event>> = self.registries.iter().map(|_| vec![]).collect();
+        while let Some((idx, res)) = self.step() {
+            events[idx].extend(res?);
+        }
+        Ok(events)
+    }
+
+    /// Advance the clock to `now` and handle the timeouts of all clients,
+    /// returns their events. Messages sent because of the timeouts stay in
+    /// flight.
+    pub fn advance(&mut self, now: u64) -> Result<Vec<Vec<Event>>, RegistryError> {
+        self.clock.set(now);
+        let mut events = vec![];
+        for registry in &mut self.registries {
+            let mut registry_events = vec![];
+            while let Some(event) = registry.handle_timeouts()? {
+                registry_events.push(event);
+            }
+            events.push(registry_events);
+        }
+        Ok(events)
+    }
+}
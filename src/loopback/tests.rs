@@ -0,0 +1,294 @@
+use super::{Message, Packet, Simulation};
+use crate::{
+    abiencode::types::{Address, Hash, U256},
+    channel::{
+        fixed_size_payment::{Allocation, Balances, ParticipantBalances},
+        Asset, LedgerChannelProposal,
+    },
+    messages::{FunderReplyMessage, ParticipantMessage},
+    registry::{Event, RegistryError},
+    time::TimeoutPolicy,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const ALICE: usize = 0;
+const BOB: usize = 1;
+
+fn setup() -> (Simulation, StdRng) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let sim = Simulation::new(2, TimeoutPolicy::default(), &mut rng);
+    (sim, rng)
+}
+
+/// Alice proposes a channel with 100 for each side, returns the proposal id.
+fn propose(sim: &mut Simulation, rng: &mut StdRng) -> Hash {
+    let prop = LedgerChannelProposal {
+        proposal_id: rng.gen(),
+        challenge_duration: 100,
+        nonce_share: rng.gen(),
+        init_bals: Allocation::new(
+            [Asset {
+                chain_id: U256::from(1337),
+                holder: Address([1; 20]),
+            }],
+            Balances([ParticipantBalances([100.into(), 100.into()])]),
+        ),
+        funding_agreement: Balances([ParticipantBalances([100.into(), 100.into()])]),
+        participant: sim.address(ALICE),
+        peers: vec![sim.identity(ALICE), sim.identity(BOB)],
+    };
+    let withdraw_receiver = sim.address(ALICE);
+    sim.registries[ALICE]
+        .propose_channel(prop, withdraw_receiver)
+        .unwrap()
+}
+
+/// Open a channel between Alice and Bob, returns its id.
+fn open(sim: &mut Simulation, rng: &mut StdRng) -> Hash {
+    let proposal_id = propose(sim, rng);
+    sim.deliver().unwrap();
+    let withdraw_receiver = sim.address(BOB);
+    let id = sim.registries[BOB]
+        .accept_proposal(proposal_id, rng.gen(), withdraw_receiver)
+        .unwrap();
+    let events = sim.deliver().unwrap();
+    for events in events {
+        assert!(matches!(events.last(), Some(Event::ChannelActive { .. })));
+    }
+    id
+}
+
+/// Alice proposes to send `amount` to Bob.
+fn pay(sim: &mut Simulation, id: Hash, amount: u64) {
+    let channel = sim.registries[ALICE].channel(id).unwrap();
+    let mut state = channel.state().make_next_state();
+    state.outcome.balances.0[0].0[ALICE] -= amount.into();
+    state.outcome.balances.0[0].0[BOB] += amount.into();
+    sim.registries[ALICE].update(id, state).unwrap();
+}
+
+fn settled(events: &[Event]) -> U256 {
+    match events.last() {
+        Some(Event::ChannelSettled(s)) if s.is_complete() => s.withdrawn()[0],
+        _ => panic!("not settled: {:?}", events),
+    }
+}
+
+#[test]
+fn open_update_close() {
+    let (mut sim, mut rng) = setup();
+    let id = open(&mut sim, &mut rng);
+
+    pay(&mut sim, id, 10);
+    sim.deliver().unwrap();
+    sim.registries[BOB].accept_update(id).unwrap();
+    sim.deliver().unwrap();
+
+    sim.registries[ALICE].close(id).unwrap();
+    sim.deliver().unwrap();
+    sim.registries[BOB].accept_update(id).unwrap();
+    let events = sim.deliver().unwrap();
+    assert_eq!(settled(&events[ALICE]), 90.into());
+    assert_eq!(settled(&events[BOB]), 110.into());
+    assert!(sim.net.is_idle());
+
+    // Both funding requests went to the funder, before it answered.
+    let traffic = sim.net.traffic();
+    let funding = traffic
+        .iter()
+        .position(|p| matches!(p.msg, Message::FunderRequest { from: BOB, .. }))
+        .unwrap();
+    assert!(traffic[..funding]
+        .iter()
+        .any(|p| matches!(p.msg, Message::FunderRequest { from: ALICE, .. })));
+    assert!(!traffic[..funding]
+        .iter()
+        .any(|p| matches!(p.msg, Message::FunderReply { .. })));
+}
+
+#[test]
+fn force_close_settles() {
+    let (mut sim, mut rng) = setup();
+    let id = open(&mut sim, &mut rng);
+    pay(&mut sim, id, 10);
+    sim.deliver().unwrap();
+    sim.registries[BOB].accept_update(id).unwrap();
+    sim.deliver().unwrap();
+
+    // Alice stays subscribed to her own dispute until it is settled.
+    sim.registries[ALICE].force_close(id).unwrap();
+    let events = sim.deliver().unwrap();
+    assert!(matches!(
+        &events[ALICE][..],
+        [
+            Event::ChannelClosed { .. },
+            Event::ChannelDisputed { version: 1, .. }
+        ]
+    ));
+    assert!(matches!(
+        &events[BOB][..],
+        [Event::ChannelDisputed { version: 1, .. }]
+    ));
+
+    sim.net.end_challenge(id);
+    let events = sim.deliver().unwrap();
+    for events in &events {
+        assert!(matches!(
+            events[0],
+            Event::ChannelConcluded { version: 1, .. }
+        ));
+    }
+    assert_eq!(settled(&events[ALICE]), 90.into());
+    assert_eq!(settled(&events[BOB]), 110.into());
+    assert!(sim.net.is_idle());
+}
+
+#[test]
+fn refute_after_lost_acceptance() {
+    let (mut sim, mut rng) = setup();
+    let id = open(&mut sim, &mut rng);
+
+    // Bob accepts the payment, but his answer never reaches Alice.
+    pay(&mut sim, id, 10);
+    sim.deliver().unwrap();
+    sim.registries[BOB].accept_update(id).unwrap();
+    let dropped = sim.net.drop_where(|msg| {
+        matches!(
+            msg,
+            Message::Participant {
+                msg: ParticipantMessage::ChannelUpdateAccepted(_),
+                ..
+            }
+        )
+    });
+    assert_eq!(dropped.len(), 1);
+    sim.deliver().unwrap();
+    assert_eq!(sim.registries[ALICE].channel(id).unwrap().version(), 0);
+
+    // Alice gives up and registers the old state, Bob refutes with the
+    // payment.
+    sim.registries[ALICE].force_close(id).unwrap();
+    let events = sim.deliver().unwrap();
+    assert!(matches!(
+        &events[ALICE][..],
+        [
+            Event::ChannelClosed { .. },
+            Event::ChannelDisputed { version: 0, .. },
+            Event::ChannelDisputed { version: 1, .. }
+        ]
+    ));
+    assert!(matches!(
+        &events[BOB][..],
+        [
+            Event::ChannelDisputed {
+                version: 0,
+                refuting: true,
+                ..
+            },
+            Event::ChannelDisputed {
+                version: 1,
+                refuting: false,
+                ..
+            }
+        ]
+    ));
+
+    sim.net.end_challenge(id);
+    let events = sim.deliver().unwrap();
+    assert!(matches!(
+        events[BOB][0],
+        Event::ChannelConcluded { version: 1, .. }
+    ));
+    assert_eq!(settled(&events[BOB]), 110.into());
+    // Alice never saw the payment, but gets what it left her.
+    assert!(matches!(
+        events[ALICE].last(),
+        Some(Event::ChannelSettled(s)) if s.balances().is_none() && s.withdrawn() == [90.into()]
+    ));
+}
+
+#[test]
+fn reordered_and_replayed_messages() {
+    let (mut sim, mut rng) = setup();
+    let first = propose(&mut sim, &mut rng);
+    let second = propose(&mut sim, &mut rng);
+
+    // Bob sees the second proposal first.
+    let in_flight = sim.net.in_flight();
+    let packet = sim.net.take(in_flight[0].seq).unwrap();
+    sim.net.inject(packet.msg.clone());
+    let events = sim.deliver().unwrap();
+    assert!(matches!(
+        &events[BOB][..],
+        [Event::ProposalReceived(a), Event::ProposalReceived(b)]
+            if a.proposal_id == second && b.proposal_id == first
+    ));
+
+    // A replayed proposal is refused.
+    sim.net.inject(packet.msg);
+    assert!(matches!(
+        sim.step(),
+        Some((BOB, Err(RegistryError::DuplicateProposal(id)))) if id == first
+    ));
+}
+
+#[test]
+fn disconnected_clients_and_failed_funding() {
+    let (mut sim, mut rng) = setup();
+    let proposal_id = propose(&mut sim, &mut rng);
+    sim.deliver().unwrap();
+
+    sim.net.set_connected(ALICE, false);
+    let withdraw_receiver = sim.address(BOB);
+    assert!(matches!(
+        sim.registries[BOB].accept_proposal(proposal_id, rng.gen(), withdraw_receiver),
+        Err(RegistryError::ProposalAccept(_))
+    ));
+    sim.net.set_connected(ALICE, true);
+    let id = sim.registries[BOB]
+        .accept_proposal(proposal_id, rng.gen(), withdraw_receiver)
+        .unwrap();
+
+    // Alice's deposit never happens.
+    let mut events = [vec![], vec![]];
+    loop {
+        sim.net
+            .drop_where(|msg| matches!(msg, Message::FunderRequest { from: ALICE, .. }));
+        match sim.step() {
+            Some((idx, res)) => events[idx].extend(res.unwrap()),
+            None => break,
+        }
+    }
+    assert!(events[BOB]
+        .iter()
+        .all(|e| !matches!(e, Event::ChannelActive { .. })));
+    sim.net.fail_funding(id);
+    assert!(matches!(
+        &sim.net.in_flight()[..],
+        [Packet {
+            msg: Message::FunderReply {
+                to: BOB,
+                msg: FunderReplyMessage::FundingFailed { .. }
+            },
+            ..
+        }]
+    ));
+
+    // Bob registers the initial state to get his deposit back.
+    let events = sim.deliver().unwrap();
+    assert!(matches!(
+        &events[BOB][..],
+        [
+            Event::FundingFailed { .. },
+            Event::ChannelDisputed { version: 0, .. }
+        ]
+    ));
+    sim.net.end_challenge(id);
+    let events = sim.deliver().unwrap();
+    // Alice didn't deposit, so she gets nothing back.
+    assert!(matches!(
+        events[ALICE].last(),
+        Some(Event::ChannelSettled(s)) if !s.is_complete() && s.withdrawn() == [0.into()]
+    ));
+    assert_eq!(settled(&events[BOB]), 100.into());
+}
@@ -5,11 +5,18 @@ mod watch_request;
 
 pub use funding_request::LedgerChannelFundingRequest;
 pub use proposal::{LedgerChannelProposal, LedgerChannelProposalAcc};
-pub use update::{LedgerChannelUpdate, LedgerChannelUpdateAccepted};
+pub use update::{LedgerChannelSync, LedgerChannelUpdate, LedgerChannelUpdateAccepted};
 pub use watch_request::{SignedWithdrawalAuth, WatchInfo};
 
-use crate::abiencode::types::Hash;
-use alloc::string::String;
+use crate::{
+    abiencode::types::{Hash, Signature, U256},
+    channel::PartIdx,
+    perunwire,
+};
+use alloc::string::{String, ToString};
+use core::fmt;
+
+const ASSETS: usize = 1;
 
 #[derive(Debug)]
 pub enum ConversionError {
@@ -18,10 +25,22 @@ pub enum ConversionError {
     ByteLengthMissmatch,
     ExptectedSome,
     StateChannelsNotSupported,
+    /// A participant index that does not refer to a participant of the
+    /// channel.
+    PartIdxOutOfRange(u32),
+}
+
+/// Participant index received from the wire, which has to be lower than the
+/// number of participants.
+fn part_idx_from_wire(idx: u32, participants: usize) -> Result<PartIdx, ConversionError> {
+    match usize::try_from(idx) {
+        Ok(part_idx) if part_idx < participants => Ok(part_idx),
+        _ => Err(ConversionError::PartIdxOutOfRange(idx)),
+    }
 }
 
 /// Messages sent to the Watcher service.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum WatcherRequestMessage {
     /// Ask the Watcher to start watching the blockchain for disputes.
     /// Acknowledged with [WatcherReplyMessage::Ack] containing `version == 0`.
@@ -31,41 +50,135 @@ pub enum WatcherRequestMessage {
     /// communication needed. Adding it might be useful to make the watcher less
     /// stateful.
     StartDispute(WatchInfo),
+    /// Ask the Watcher to refute an on-chain dispute by registering the given
+    /// (newer) state during the challenge period. Acknowledged with
+    /// [WatcherReplyMessage::DisputeAck].
+    Refute(WatchInfo),
+    /// Ask the Watcher to conclude the channel on-chain with the given final
+    /// state and withdraw our funds. Answered with
+    /// [WatcherReplyMessage::Concluded] and [WatcherReplyMessage::Withdrawn].
+    Conclude(WatchInfo),
 }
 
 /// Messages sent from the Watcher service.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum WatcherReplyMessage {
     /// Reply from the Watcher that a state has been received and will be used
     /// in a dispute case.
     Ack { id: Hash, version: u64 },
+    /// The Watcher could not take the state with the given version, it keeps
+    /// using the last one it acknowledged.
+    WatchFailed { id: Hash, version: u64 },
     /// Ask the Watcher to initialize a dispute on-chain, with the given state.
     /// It currently does not contain the parameters for reducing the amount of
     /// communication needed. Adding it might be useful to make the watcher less
     /// stateful.
     DisputeAck { id: Hash },
+    /// The Watcher could not start the dispute.
+    DisputeFailed { id: Hash },
     /// Used by the Watcher to notify the device of the existence of an on-chain
     /// dispute. This way the device knows that it does not/should not continue
-    /// updating the channel.
-    DisputeNotification { id: Hash },
+    /// updating the channel. `version` is the version of the registered
+    /// state, the notification is repeated for every new registration during
+    /// the challenge period.
+    DisputeNotification { id: Hash, version: u64 },
+    /// The challenge period is over and the channel was concluded on-chain
+    /// with the state of the given version.
+    Concluded { id: Hash, version: u64 },
+    /// Our funds have been withdrawn from the concluded channel, `amounts`
+    /// is what arrived at our withdraw receiver for each asset.
+    Withdrawn { id: Hash, amounts: [U256; ASSETS] },
 }
 
 /// Messages sent to the Funder service.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FunderRequestMessage {
     FundingRequest(LedgerChannelFundingRequest),
 }
 
 /// Messages sent from the Funder service.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FunderReplyMessage {
+    /// The channel is fully funded.
     Funded { id: Hash },
+    /// The channel could not be funded, for example because another
+    /// participant did not deposit in time.
+    FundingFailed { id: Hash },
+}
+
+/// Messages sent from the Watcher or Funder service, which may share a
+/// connection.
+#[derive(Debug, Clone)]
+pub enum ServiceReplyMessage {
+    Watcher(WatcherReplyMessage),
+    Funder(FunderReplyMessage),
+}
+
+impl TryFrom<perunwire::FundingResponseMsg> for FunderReplyMessage {
+    type Error = ConversionError;
+
+    fn try_from(value: perunwire::FundingResponseMsg) -> Result<Self, Self::Error> {
+        let id = Hash(
+            value
+                .channel_id
+                .try_into()
+                .or(Err(ConversionError::ByteLengthMissmatch))?,
+        );
+        if value.success {
+            Ok(Self::Funded { id })
+        } else {
+            Ok(Self::FundingFailed { id })
+        }
+    }
+}
+
+/// Why a proposal or update was rejected.
+///
+/// go-perun's reject messages only carry a free-form reason, so this is sent
+/// as a string, see [Self::as_str]. Reasons this library uses itself get their
+/// own variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The other participant gave up waiting for an answer.
+    Timeout,
+    /// Any other reason, for example from a [Policy][crate::policy::Policy]
+    /// or the application.
+    Other(String),
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RejectReason::Timeout => "timeout",
+            RejectReason::Other(reason) => reason,
+        }
+    }
+}
+
+impl From<&str> for RejectReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "timeout" => RejectReason::Timeout,
+            reason => RejectReason::Other(reason.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 /// Messages sent between participants of a channel.
 #[derive(Debug, Clone)]
 pub enum ParticipantMessage {
-    Auth,
+    /// Handshake at the start of a connection, see
+    /// [PeerAuth][crate::wire::PeerAuth].
+    Auth {
+        /// Signature of the sender's identity.
+        signature: Signature,
+    },
     ChannelProposal(LedgerChannelProposal),
     ProposalAccepted(LedgerChannelProposalAcc),
     ProposalRejected {
@@ -79,4 +192,5 @@ pub enum ParticipantMessage {
         version: u64,
         reason: String,
     },
+    ChannelSync(LedgerChannelSync),
 }
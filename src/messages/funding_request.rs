@@ -1,4 +1,4 @@
-use super::ConversionError;
+use super::{part_idx_from_wire, ConversionError};
 use crate::{
     channel::{fixed_size_payment, PartIdx},
     perunwire,
@@ -23,7 +23,7 @@ impl TryFrom<perunwire::FundingRequestMsg> for LedgerChannelFundingRequest {
 
     fn try_from(value: perunwire::FundingRequestMsg) -> Result<Self, Self::Error> {
         Ok(Self {
-            part_idx: value.participant as usize,
+            part_idx: part_idx_from_wire(value.participant, PARTICIPANTS)?,
             funding_agreement: value
                 .funding_agreement
                 .ok_or(ConversionError::ExptectedSome)?
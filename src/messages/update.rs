@@ -1,4 +1,4 @@
-use super::ConversionError;
+use super::{part_idx_from_wire, ConversionError};
 use crate::{
     abiencode::types::{Hash, Signature},
     channel::{fixed_size_payment, PartIdx},
@@ -27,7 +27,7 @@ impl TryFrom<perunwire::ChannelUpdateMsg> for LedgerChannelUpdate {
                 .state
                 .ok_or(ConversionError::ExptectedSome)?
                 .try_into()?,
-            actor_idx: update.actor_idx as usize,
+            actor_idx: part_idx_from_wire(update.actor_idx, PARTICIPANTS)?,
             sig: Signature(
                 value
                     .sig
@@ -88,3 +88,80 @@ impl From<LedgerChannelUpdateAccepted> for perunwire::ChannelUpdateAccMsg {
         }
     }
 }
+
+/// go-perun's channel phase of a non-final state, see [LedgerChannelSync].
+const PHASE_ACTING: u32 = 3;
+/// go-perun's channel phase of a final state.
+const PHASE_FINAL: u32 = 5;
+
+/// Our latest fully signed state, exchanged with the other participants after
+/// (re)connecting, see
+/// [ActiveChannel::sync][crate::channel::ActiveChannel::sync].
+///
+/// go-perun sends its current state even if an update was still in progress,
+/// so signatures may be missing, see
+/// [ActiveChannel::handle_sync][crate::channel::ActiveChannel::handle_sync].
+#[derive(Debug, Clone, Copy)]
+pub struct LedgerChannelSync {
+    /// go-perun's channel phase, only informational.
+    pub phase: u32,
+    pub state: State,
+    pub signatures: [Option<Signature>; PARTICIPANTS],
+}
+
+impl LedgerChannelSync {
+    /// A fully signed state.
+    pub fn new(state: State, signatures: [Signature; PARTICIPANTS]) -> Self {
+        Self {
+            phase: if state.is_final {
+                PHASE_FINAL
+            } else {
+                PHASE_ACTING
+            },
+            state,
+            signatures: signatures.map(Some),
+        }
+    }
+}
+
+impl TryFrom<perunwire::ChannelSyncMsg> for LedgerChannelSync {
+    type Error = ConversionError;
+
+    fn try_from(value: perunwire::ChannelSyncMsg) -> Result<Self, Self::Error> {
+        let tx = value.current_tx.ok_or(ConversionError::ExptectedSome)?;
+
+        if tx.sigs.len() != PARTICIPANTS {
+            return Err(ConversionError::ParticipantSizeMissmatch);
+        }
+        // Signatures we don't have are sent as empty slots.
+        let mut signatures = [None; PARTICIPANTS];
+        for (a, b) in signatures.iter_mut().zip(tx.sigs) {
+            if !b.is_empty() {
+                *a = Some(Signature(
+                    b.try_into().or(Err(ConversionError::ByteLengthMissmatch))?,
+                ));
+            }
+        }
+
+        Ok(Self {
+            phase: value.phase,
+            state: tx.state.ok_or(ConversionError::ExptectedSome)?.try_into()?,
+            signatures,
+        })
+    }
+}
+
+impl From<LedgerChannelSync> for perunwire::ChannelSyncMsg {
+    fn from(value: LedgerChannelSync) -> Self {
+        Self {
+            phase: value.phase,
+            current_tx: Some(perunwire::Transaction {
+                state: Some(value.state.into()),
+                sigs: value
+                    .signatures
+                    .map(|sig| sig.map(|sig| sig.0.to_vec()).unwrap_or_default())
+                    .to_vec(),
+            }),
+        }
+    }
+}
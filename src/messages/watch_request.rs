@@ -1,4 +1,4 @@
-use super::ConversionError;
+use super::{part_idx_from_wire, ConversionError};
 use crate::{
     abiencode::types::Signature,
     channel::{fixed_size_payment, PartIdx},
@@ -43,7 +43,7 @@ impl TryFrom<perunwire::WatchRequestMsg> for WatchInfo {
         }
 
         Ok(Self {
-            part_idx: value.participant as usize,
+            part_idx: part_idx_from_wire(value.participant, PARTICIPANTS)?,
             params: signed_state
                 .params
                 .ok_or(ConversionError::ExptectedSome)?
@@ -92,6 +92,26 @@ impl From<WatchInfo> for perunwire::ForceCloseRequestMsg {
     }
 }
 
+impl TryFrom<perunwire::ConcludeRequestMsg> for WatchInfo {
+    type Error = ConversionError;
+
+    fn try_from(value: perunwire::ConcludeRequestMsg) -> Result<Self, Self::Error> {
+        value
+            .latest
+            .ok_or(ConversionError::ExptectedSome)?
+            .try_into()
+    }
+}
+
+impl From<WatchInfo> for perunwire::ConcludeRequestMsg {
+    fn from(value: WatchInfo) -> Self {
+        Self {
+            channel_id: value.state.channel_id().0.to_vec(),
+            latest: Some(value.into()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct SignedWithdrawalAuth {
     pub sig: Signature,
@@ -0,0 +1,191 @@
+//! Rules for answering updates and proposals without asking the application.
+//!
+//! Whether an update is structurally valid is not the same as whether it is
+//! good for us. A [Policy] decides that, for example to let an unattended
+//! device accept incoming payments automatically while never signing away
+//! funds. The client's policy (see
+//! [PerunClient::set_policy][crate::PerunClient::set_policy]) is asked by
+//! [ActiveChannel::handle_update][crate::channel::ActiveChannel::handle_update]
+//! and [PerunClient::handle_proposal][crate::PerunClient::handle_proposal], by
+//! default it rejects nothing.
+//! Policies can be combined with tuples: `(NoLoss, AutoAcceptBelow(x))`
+//! rejects everything that decreases our balance and accepts the rest.
+//!
+//! Currently, this can only handle channels with one asset and two
+//! participants, just like the low-level API.
+
+use crate::{
+    abiencode::types::U256,
+    channel::{fixed_size_payment, PartIdx},
+    messages::LedgerChannelProposal,
+};
+use core::fmt::Debug;
+
+#[cfg(test)]
+mod tests;
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+/// What to do with an update proposed by another participant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Sign the update without asking the application.
+    Accept,
+    /// Leave the decision to the application.
+    Ask,
+    /// Reject the update, the reason is sent to the other participant.
+    Reject(&'static str),
+}
+
+/// An update proposed by another participant, as seen by a [Policy].
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateInfo<'a> {
+    /// Our index in the channel.
+    pub part_idx: PartIdx,
+    /// Index of the participant proposing the update.
+    pub actor_idx: PartIdx,
+    pub current: &'a State,
+    pub proposed: &'a State,
+}
+
+impl UpdateInfo<'_> {
+    /// How much participant `part_idx` loses in each asset, `0` if the
+    /// balance does not decrease.
+    pub fn loss(&self, part_idx: PartIdx) -> [U256; ASSETS] {
+        let mut loss = [U256::zero(); ASSETS];
+        for (asset_idx, l) in loss.iter_mut().enumerate() {
+            let current = self.current.outcome.balances.0[asset_idx].0[part_idx];
+            let proposed = self.proposed.outcome.balances.0[asset_idx].0[part_idx];
+            *l = current.saturating_sub(proposed);
+        }
+        loss
+    }
+}
+
+/// Decides which updates and proposals of other participants are accepted.
+///
+/// Both methods default to leaving the decision to the application.
+pub trait Policy: Debug {
+    fn check_update(&self, _update: &UpdateInfo) -> Decision {
+        Decision::Ask
+    }
+
+    /// Return `Err(reason)` to reject a channel proposal in which we would be
+    /// participant `part_idx`. Accepting a proposal needs a nonce share and a
+    /// withdraw receiver, so that is always left to the application.
+    fn check_proposal(
+        &self,
+        _part_idx: PartIdx,
+        _prop: &LedgerChannelProposal,
+    ) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Always ask the application, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AskAlways;
+
+impl Policy for AskAlways {}
+
+/// Reject updates that decrease our balance, and proposals in which we would
+/// deposit more than our initial balance.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoLoss;
+
+impl Policy for NoLoss {
+    fn check_update(&self, update: &UpdateInfo) -> Decision {
+        if update.loss(update.part_idx).iter().any(|l| !l.is_zero()) {
+            Decision::Reject("update decreases our balance")
+        } else {
+            Decision::Ask
+        }
+    }
+
+    fn check_proposal(
+        &self,
+        part_idx: PartIdx,
+        prop: &LedgerChannelProposal,
+    ) -> Result<(), &'static str> {
+        let init_bals = prop.init_bals.balances.0.iter();
+        for (bals, deposit) in init_bals.zip(prop.funding_agreement.0.iter()) {
+            if bals.0[part_idx] < deposit.0[part_idx] {
+                return Err("initial balance lower than our deposit");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reject updates in which the proposing participant gains funds: Whoever
+/// proposes an update has to be the one paying.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ActorPays;
+
+impl Policy for ActorPays {
+    fn check_update(&self, update: &UpdateInfo) -> Decision {
+        let current = update.current.outcome.balances.0.iter();
+        for (bals, new_bals) in current.zip(update.proposed.outcome.balances.0.iter()) {
+            if new_bals.0[update.actor_idx] > bals.0[update.actor_idx] {
+                return Decision::Reject("proposer's balance increases");
+            }
+        }
+        Decision::Ask
+    }
+}
+
+/// Reject updates in which any participant loses more than the given amount
+/// of an asset.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxPayment(pub U256);
+
+impl Policy for MaxPayment {
+    fn check_update(&self, update: &UpdateInfo) -> Decision {
+        for part_idx in 0..PARTICIPANTS {
+            if update.loss(part_idx).iter().any(|l| *l > self.0) {
+                return Decision::Reject("payment too large");
+            }
+        }
+        Decision::Ask
+    }
+}
+
+/// Accept updates in which we lose less than the given amount of each asset
+/// (including all updates paying us), ask for everything else.
+#[derive(Debug, Clone, Copy)]
+pub struct AutoAcceptBelow(pub U256);
+
+impl Policy for AutoAcceptBelow {
+    fn check_update(&self, update: &UpdateInfo) -> Decision {
+        if update.loss(update.part_idx).iter().all(|l| *l < self.0) {
+            Decision::Accept
+        } else {
+            Decision::Ask
+        }
+    }
+}
+
+/// Combines two policies: Reject if either rejects, otherwise accept if either
+/// accepts.
+impl<A: Policy, B: Policy> Policy for (A, B) {
+    fn check_update(&self, update: &UpdateInfo) -> Decision {
+        match (self.0.check_update(update), self.1.check_update(update)) {
+            (Decision::Reject(reason), _) | (_, Decision::Reject(reason)) => {
+                Decision::Reject(reason)
+            }
+            (Decision::Accept, _) | (_, Decision::Accept) => Decision::Accept,
+            (Decision::Ask, Decision::Ask) => Decision::Ask,
+        }
+    }
+
+    fn check_proposal(
+        &self,
+        part_idx: PartIdx,
+        prop: &LedgerChannelProposal,
+    ) -> Result<(), &'static str> {
+        self.0.check_proposal(part_idx, prop)?;
+        self.1.check_proposal(part_idx, prop)
+    }
+}
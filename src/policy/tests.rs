@@ -0,0 +1,70 @@
+use super::{ActorPays, AutoAcceptBelow, Decision, MaxPayment, NoLoss, Policy, State, UpdateInfo};
+use crate::{
+    abiencode::types::{Address, U256},
+    channel::{
+        fixed_size_payment::{Allocation, Balances, Params, ParticipantBalances},
+        Asset,
+    },
+};
+
+fn initial_state() -> State {
+    let params = Params {
+        challenge_duration: 100,
+        nonce: U256::from(1),
+        participants: [Address([1; 20]), Address([2; 20])],
+        app: Address::default(),
+        ledger_channel: true,
+        virtual_channel: false,
+    };
+    let alloc = Allocation::new(
+        [Asset {
+            chain_id: U256::from(1337),
+            holder: Address([3; 20]),
+        }],
+        Balances([ParticipantBalances([100.into(), 100.into()])]),
+    );
+    State::new(params, alloc).unwrap()
+}
+
+/// Participant 1 proposes moving from `[100, 100]` to `balances`, we are 0.
+fn check(policy: impl Policy, balances: [u64; 2]) -> Decision {
+    let current = initial_state();
+    let mut proposed = current.make_next_state();
+    proposed.outcome.balances.0[0].0 = balances.map(U256::from);
+    policy.check_update(&UpdateInfo {
+        part_idx: 0,
+        actor_idx: 1,
+        current: &current,
+        proposed: &proposed,
+    })
+}
+
+#[test]
+fn builtin_policies() {
+    assert_eq!(check(NoLoss, [110, 90]), Decision::Ask);
+    assert!(matches!(check(NoLoss, [90, 110]), Decision::Reject(_)));
+
+    assert_eq!(check(ActorPays, [110, 90]), Decision::Ask);
+    assert!(matches!(check(ActorPays, [90, 110]), Decision::Reject(_)));
+
+    let max = MaxPayment(10.into());
+    assert_eq!(check(max, [110, 90]), Decision::Ask);
+    assert_eq!(check(max, [90, 110]), Decision::Ask);
+    assert!(matches!(check(max, [111, 89]), Decision::Reject(_)));
+
+    let auto = AutoAcceptBelow(10.into());
+    assert_eq!(check(auto, [200, 0]), Decision::Accept);
+    assert_eq!(check(auto, [91, 109]), Decision::Accept);
+    assert_eq!(check(auto, [90, 110]), Decision::Ask);
+}
+
+#[test]
+fn combined_policies() {
+    let policy = (NoLoss, AutoAcceptBelow(10.into()));
+    assert_eq!(check(policy, [110, 90]), Decision::Accept);
+    assert!(matches!(check(policy, [95, 105]), Decision::Reject(_)));
+
+    let policy = (MaxPayment(5.into()), (ActorPays, AutoAcceptBelow(1.into())));
+    assert_eq!(check(policy, [105, 95]), Decision::Accept);
+    assert!(matches!(check(policy, [106, 94]), Decision::Reject(_)));
+}
@@ -0,0 +1,1355 @@
+//! Keeps track of all channels of a client and routes incoming messages to
+//! them.
+//!
+//! The low-level channel API in [crate::channel] is concerned about a single
+//! channel in a single phase, which leaves finding the right channel object for
+//! an incoming message (and moving it into the next phase) to the application.
+//! [ChannelRegistry] does this for all channels of one [PerunClient], keyed by
+//! proposal id while the channel is being proposed and by channel id
+//! afterwards. Only decisions that have to be made by the application (accept
+//! a proposal or update?) and notifications about progress are surfaced as
+//! [Event]s.
+//!
+//! If the registry is created with a [TimeoutPolicy], messages are resent and
+//! channels aborted or force-closed when others don't answer in time, see
+//! [ChannelRegistry::handle_timeouts] and [Phase].
+//!
+//! With a [Policy][crate::policy::Policy] (see [PerunClient::set_policy]),
+//! updates and proposals are accepted or rejected without asking the
+//! application where possible.
+//!
+//! Currently, this can only handle channels with one asset and two
+//! participants, just like the low-level API.
+
+use crate::{
+    abiencode::types::{Address, Hash},
+    channel::{
+        fixed_size_payment, AcceptError, ActiveChannel, AddSignatureError, AgreedUponChannel,
+        ApplyError, BuildError, ChannelUpdate, DisputedChannel, FundingError, HandleAcceptError,
+        HandleUpdateError, InvalidProposalIDError, NonceShare, ProposalAcceptError,
+        ProposalBuildError, ProposeUpdateError, ProposedChannel, RejectError, RejectionError,
+        ResendError, SettleError, SettledChannel, SettlingChannel, SignError, SignedChannel,
+        SyncError, SyncOutcome,
+    },
+    client::InvalidProposal,
+    messages::{
+        FunderReplyMessage, LedgerChannelProposal, LedgerChannelProposalAcc, LedgerChannelSync,
+        LedgerChannelUpdateAccepted, ParticipantMessage, RejectReason, WatcherReplyMessage,
+    },
+    policy::Decision,
+    time::{Clock, Instant, NoClock, Phase, TimeoutPolicy},
+    wire::BusError,
+    ClientRef, PerunClient,
+};
+use alloc::collections::BTreeMap;
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+pub(crate) mod tests;
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownProposal(Hash),
+    UnknownChannel(Hash),
+    DuplicateProposal(Hash),
+    DuplicateChannel(Hash),
+    /// The message or call does not fit the phase the channel is in, for
+    /// example an update for a channel that is not funded yet.
+    InvalidState(Hash),
+    /// There already is an update in progress for this channel.
+    UpdatePending(Hash),
+    /// The watcher could not handle our request, see
+    /// [WatcherReplyMessage::WatchFailed] and
+    /// [WatcherReplyMessage::DisputeFailed]. The channel is unchanged.
+    WatcherFailed(Hash),
+    InvalidProposal(InvalidProposal),
+    ProposalAccept(ProposalAcceptError),
+    HandleAccept(HandleAcceptError),
+    ProposalBuild(ProposalBuildError),
+    Sign(SignError),
+    AddSignature(AddSignatureError),
+    Build(BuildError),
+    ProposeUpdate(ProposeUpdateError),
+    HandleUpdate(HandleUpdateError),
+    Accept(AcceptError),
+    Apply(ApplyError),
+    Resend(ResendError),
+    Settle(SettleError),
+    Funding(FundingError),
+    Rejection(RejectionError),
+    Reject(RejectError),
+    Sync(SyncError),
+    Bus(BusError),
+}
+impl From<InvalidProposal> for RegistryError {
+    fn from(e: InvalidProposal) -> Self {
+        Self::InvalidProposal(e)
+    }
+}
+impl From<InvalidProposalIDError> for RegistryError {
+    fn from(e: InvalidProposalIDError) -> Self {
+        Self::UnknownProposal(e.0)
+    }
+}
+impl From<ProposalAcceptError> for RegistryError {
+    fn from(e: ProposalAcceptError) -> Self {
+        Self::ProposalAccept(e)
+    }
+}
+impl From<HandleAcceptError> for RegistryError {
+    fn from(e: HandleAcceptError) -> Self {
+        Self::HandleAccept(e)
+    }
+}
+impl From<ProposalBuildError> for RegistryError {
+    fn from(e: ProposalBuildError) -> Self {
+        Self::ProposalBuild(e)
+    }
+}
+impl From<SignError> for RegistryError {
+    fn from(e: SignError) -> Self {
+        Self::Sign(e)
+    }
+}
+impl From<AddSignatureError> for RegistryError {
+    fn from(e: AddSignatureError) -> Self {
+        Self::AddSignature(e)
+    }
+}
+impl From<BuildError> for RegistryError {
+    fn from(e: BuildError) -> Self {
+        Self::Build(e)
+    }
+}
+impl From<ProposeUpdateError> for RegistryError {
+    fn from(e: ProposeUpdateError) -> Self {
+        Self::ProposeUpdate(e)
+    }
+}
+impl From<HandleUpdateError> for RegistryError {
+    fn from(e: HandleUpdateError) -> Self {
+        Self::HandleUpdate(e)
+    }
+}
+impl From<AcceptError> for RegistryError {
+    fn from(e: AcceptError) -> Self {
+        Self::Accept(e)
+    }
+}
+impl From<ApplyError> for RegistryError {
+    fn from(e: ApplyError) -> Self {
+        Self::Apply(e)
+    }
+}
+impl From<ResendError> for RegistryError {
+    fn from(e: ResendError) -> Self {
+        Self::Resend(e)
+    }
+}
+impl From<FundingError> for RegistryError {
+    fn from(e: FundingError) -> Self {
+        Self::Funding(e)
+    }
+}
+impl From<RejectionError> for RegistryError {
+    fn from(e: RejectionError) -> Self {
+        Self::Rejection(e)
+    }
+}
+impl From<RejectError> for RegistryError {
+    fn from(e: RejectError) -> Self {
+        Self::Reject(e)
+    }
+}
+impl From<SyncError> for RegistryError {
+    fn from(e: SyncError) -> Self {
+        Self::Sync(e)
+    }
+}
+impl From<BusError> for RegistryError {
+    fn from(e: BusError) -> Self {
+        Self::Bus(e)
+    }
+}
+impl From<SettleError> for RegistryError {
+    fn from(e: SettleError) -> Self {
+        Self::Settle(e)
+    }
+}
+
+/// Things the application has to know about or decide, returned when handling
+/// an incoming message.
+#[derive(Debug)]
+pub enum Event {
+    /// A peer proposed a new channel. Answer with
+    /// [ChannelRegistry::accept_proposal] or
+    /// [ChannelRegistry::reject_proposal].
+    ProposalReceived(LedgerChannelProposal),
+    /// Our proposal was accepted, we're now exchanging signatures on the
+    /// initial state.
+    ProposalAccepted { proposal_id: Hash, channel_id: Hash },
+    ProposalRejected {
+        proposal_id: Hash,
+        reason: RejectReason,
+    },
+    /// A peer proposed a new channel and the client's
+    /// [Policy][crate::policy::Policy] rejected it, the rejection has been
+    /// sent.
+    ProposalRefused {
+        proposal_id: Hash,
+        reason: &'static str,
+    },
+    /// All participants signed the initial state, we're now waiting for the
+    /// funder and watcher.
+    ChannelSigned { channel_id: Hash },
+    /// The funder could not fund the channel. The initial state has been
+    /// registered on-chain to get our deposit back, [Event::ChannelConcluded]
+    /// and [Event::ChannelSettled] follow once the challenge period is over.
+    FundingFailed { channel_id: Hash },
+    /// The channel is funded and watched, it can be updated now.
+    ChannelActive { channel_id: Hash },
+    /// A peer proposed an update. Answer with [ChannelRegistry::accept_update]
+    /// or [ChannelRegistry::reject_update].
+    UpdateReceived { channel_id: Hash, state: State },
+    /// The other participant proposed an update for the same version at the
+    /// same time as we did and theirs has priority, see
+    /// [ActiveChannel::handle_update]. Our update was dropped, answer theirs
+    /// like [Event::UpdateReceived], then propose ours again.
+    UpdateConflict { channel_id: Hash, state: State },
+    /// A peer proposed an update and the client's
+    /// [Policy][crate::policy::Policy] accepted it, it is now the current
+    /// state. If we had proposed an update at the same time, ours was dropped
+    /// like with [Event::UpdateConflict].
+    UpdateAutoAccepted { channel_id: Hash, version: u64 },
+    /// A peer proposed an update and the client's
+    /// [Policy][crate::policy::Policy] rejected it, the rejection has been
+    /// sent. If we had proposed an update at the same time, ours was dropped
+    /// like with [Event::UpdateConflict].
+    UpdateRefused {
+        channel_id: Hash,
+        state: State,
+        reason: &'static str,
+    },
+    /// Our update was accepted and is now the current state. With
+    /// [ChannelRegistry::set_require_watcher_ack], it is only final once
+    /// [Event::StateAcknowledged] follows.
+    UpdateAccepted { channel_id: Hash, version: u64 },
+    /// Only with [ChannelRegistry::set_require_watcher_ack]: The watcher has
+    /// acknowledged the current state, updates that decrease our balance can
+    /// be signed again.
+    StateAcknowledged { channel_id: Hash, version: u64 },
+    /// Our update was rejected, a different one can be proposed now, see
+    /// [ChannelUpdate::handle_rejection].
+    UpdateRejected {
+        channel_id: Hash,
+        version: u64,
+        reason: RejectReason,
+    },
+    /// The other participant had a newer fully signed state after
+    /// reconnecting, see [ChannelRegistry::sync]. It is now the current state,
+    /// our pending update (if any) was dropped.
+    ChannelSynced { channel_id: Hash, version: u64 },
+    /// The channel was closed and can no longer be used. After a normal
+    /// close, the watcher now concludes the final state on-chain and
+    /// [Event::ChannelSettled] follows. After a dispute we started, the
+    /// watcher concludes the channel once the challenge period is over,
+    /// followed by [Event::ChannelConcluded] and [Event::ChannelSettled].
+    ChannelClosed { channel_id: Hash },
+    /// The watcher noticed a dispute on-chain, in which the state with
+    /// `version` was registered. The channel can no longer be updated. If we
+    /// have a newer state, `refuting` is set and the watcher has been asked to
+    /// register it. Emitted again for every new registration during the
+    /// challenge period.
+    ChannelDisputed {
+        channel_id: Hash,
+        version: u64,
+        refuting: bool,
+    },
+    /// The channel was concluded on-chain with the state of the given
+    /// version, after a normal close or once the challenge period of a
+    /// dispute is over. [Event::ChannelSettled] follows once our funds have
+    /// been withdrawn.
+    ChannelConcluded { channel_id: Hash, version: u64 },
+    /// Our funds have arrived at our withdraw receiver, the channel has been
+    /// removed from the registry.
+    ChannelSettled(SettledChannel),
+    /// Nobody answered in time after all retries. The proposal or channel was
+    /// aborted, or is being force-closed, depending on the [Phase]. In the
+    /// latter case [Event::ChannelClosed] follows once the watcher has
+    /// acknowledged the dispute. `id` is the proposal id in
+    /// [Phase::Proposal] and the channel id otherwise.
+    TimedOut { id: Hash, phase: Phase },
+}
+
+/// Update of an active channel that is not yet fully signed.
+#[derive(Debug)]
+enum PendingUpdate {
+    /// Proposed by us, waiting for the other participant.
+    Proposed(ChannelUpdate),
+    /// Proposed by the other participant, waiting for the application.
+    Received(ChannelUpdate),
+}
+
+/// Something we are waiting for, with a deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Proposal(Hash),
+    Channel(Hash),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    phase: Phase,
+    at: Instant,
+    retries: u8,
+}
+
+#[derive(Debug)]
+enum Entry<C: ClientRef> {
+    AgreedUpon(AgreedUponChannel<C>),
+    Signed {
+        channel: SignedChannel<C>,
+        watching: bool,
+    },
+    Active(ActiveChannel<C>),
+    /// We agreed on a final state and are waiting for the watcher to
+    /// acknowledge it.
+    Closing(ActiveChannel<C>),
+    /// We asked the watcher to start a dispute and are waiting for the
+    /// acknowledgement, after which the channel is [Entry::Disputed].
+    ForceClosing(DisputedChannel<C>),
+    /// Someone started a dispute, possibly us with
+    /// [ChannelRegistry::force_close] or to get a refund after the funding
+    /// failed. We are waiting for the channel to be concluded and our funds
+    /// to be withdrawn.
+    Disputed(DisputedChannel<C>),
+    /// The watcher has the final state and we are waiting for the channel to
+    /// be concluded and our funds to be withdrawn.
+    Settling(SettlingChannel<C>),
+}
+
+/// All channels of one client, see the [module documentation][self].
+///
+/// Use an `Rc<PerunClient<B>>` (or `Arc`) as [ClientRef] to be able to store
+/// the registry next to the client. The [Clock] is only needed for timeouts,
+/// see [Self::with_timeouts].
+#[derive(Debug)]
+pub struct ChannelRegistry<C: ClientRef, K: Clock = NoClock> {
+    client: C,
+    clock: K,
+    timeouts: TimeoutPolicy,
+    /// Proposals made by us or accepted by us, waiting for the other
+    /// participant.
+    proposals: BTreeMap<Hash, ProposedChannel<C>>,
+    /// Proposals made by peers, waiting for the application to decide.
+    received_proposals: BTreeMap<Hash, LedgerChannelProposal>,
+    channels: BTreeMap<Hash, Entry<C>>,
+    /// At most one pending update per active channel.
+    updates: BTreeMap<Hash, PendingUpdate>,
+    deadlines: BTreeMap<Key, Deadline>,
+    require_watcher_ack: bool,
+}
+
+impl<C: ClientRef> ChannelRegistry<C> {
+    /// Create a registry without timeouts, it waits forever for answers.
+    pub fn new(client: C) -> Self {
+        Self::with_timeouts(client, NoClock, TimeoutPolicy::default())
+    }
+}
+
+impl<C: ClientRef, K: Clock> ChannelRegistry<C, K> {
+    /// Create a registry that gives up waiting according to `timeouts`. Call
+    /// [Self::handle_timeouts] regularly, at the latest at
+    /// [Self::next_deadline].
+    pub fn with_timeouts(client: C, clock: K, timeouts: TimeoutPolicy) -> Self {
+        ChannelRegistry {
+            client,
+            clock,
+            timeouts,
+            proposals: BTreeMap::new(),
+            received_proposals: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            updates: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            require_watcher_ack: false,
+        }
+    }
+
+    /// Only sign updates that decrease our balance once the watcher has
+    /// acknowledged the current state, see
+    /// [ActiveChannel::set_require_watcher_ack]. Applies to all active
+    /// channels and those activated later. Off by default.
+    pub fn set_require_watcher_ack(&mut self, require: bool) {
+        self.require_watcher_ack = require;
+        for entry in self.channels.values_mut() {
+            if let Entry::Active(channel) = entry {
+                channel.set_require_watcher_ack(require);
+            }
+        }
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// Returns the channel if it is active (funded) or closing.
+    pub fn channel(&self, id: Hash) -> Option<&ActiveChannel<C>> {
+        match self.channels.get(&id) {
+            Some(Entry::Active(channel)) | Some(Entry::Closing(channel)) => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Returns the channel if there is a dispute for it, including one we
+    /// started with [Self::force_close].
+    pub fn disputed_channel(&self, id: Hash) -> Option<&DisputedChannel<C>> {
+        match self.channels.get(&id) {
+            Some(Entry::Disputed(channel)) | Some(Entry::ForceClosing(channel)) => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// Whether there is an update for this channel waiting for the other
+    /// participant or the application.
+    pub fn update_pending(&self, channel_id: Hash) -> bool {
+        self.updates.contains_key(&channel_id)
+    }
+
+    /// Ids of all channels that are currently known, in any phase after the
+    /// proposal was accepted.
+    pub fn channel_ids(&self) -> impl Iterator<Item = Hash> + '_ {
+        self.channels.keys().copied()
+    }
+
+    /// Propose a new channel, see [PerunClient::propose_channel]. Returns the
+    /// proposal id.
+    pub fn propose_channel(
+        &mut self,
+        prop: LedgerChannelProposal,
+        withdraw_receiver: Address,
+    ) -> Result<Hash, RegistryError> {
+        let id = prop.proposal_id;
+        if self.proposals.contains_key(&id) || self.received_proposals.contains_key(&id) {
+            return Err(RegistryError::DuplicateProposal(id));
+        }
+        let channel =
+            PerunClient::propose_channel_with(self.client.clone(), prop, withdraw_receiver)?;
+        self.proposals.insert(id, channel);
+        self.start_timeout(Key::Proposal(id), Phase::Proposal);
+        Ok(id)
+    }
+
+    /// Accept a proposal received with [Event::ProposalReceived]. Returns the
+    /// id of the new channel.
+    pub fn accept_proposal(
+        &mut self,
+        proposal_id: Hash,
+        nonce_share: NonceShare,
+        withdraw_receiver: Address,
+    ) -> Result<Hash, RegistryError> {
+        // Only forget the proposal once our answer is out, so accepting can be
+        // tried again if sending fails.
+        let prop = self
+            .received_proposals
+            .get(&proposal_id)
+            .cloned()
+            .ok_or(RegistryError::UnknownProposal(proposal_id))?;
+        let mut channel =
+            PerunClient::handle_proposal_with(self.client.clone(), prop, withdraw_receiver)?;
+        channel.accept(nonce_share, self.client.client().signer.address())?;
+        self.received_proposals.remove(&proposal_id);
+        self.build_and_sign(proposal_id, channel)
+    }
+
+    /// Reject a proposal received with [Event::ProposalReceived].
+    pub fn reject_proposal(
+        &mut self,
+        proposal_id: Hash,
+        reason: &str,
+    ) -> Result<(), RegistryError> {
+        let prop = self
+            .received_proposals
+            .get(&proposal_id)
+            .cloned()
+            .ok_or(RegistryError::UnknownProposal(proposal_id))?;
+        // We don't care about the withdraw receiver, the channel is dropped
+        // right away.
+        PerunClient::handle_proposal_with(self.client.clone(), prop, Address::default())?
+            .reject(reason)
+            .map_err(|(_, e)| e)?;
+        self.received_proposals.remove(&proposal_id);
+        Ok(())
+    }
+
+    /// Propose an update of an active channel, see [ActiveChannel::update].
+    pub fn update(&mut self, channel_id: Hash, new_state: State) -> Result<(), RegistryError> {
+        if self.updates.contains_key(&channel_id) {
+            return Err(RegistryError::UpdatePending(channel_id));
+        }
+        let update = match self.channels.get_mut(&channel_id) {
+            Some(Entry::Active(channel)) => channel.update(new_state)?,
+            Some(_) => return Err(RegistryError::InvalidState(channel_id)),
+            None => return Err(RegistryError::UnknownChannel(channel_id)),
+        };
+        self.updates
+            .insert(channel_id, PendingUpdate::Proposed(update));
+        self.start_timeout(Key::Channel(channel_id), Phase::Update);
+        Ok(())
+    }
+
+    /// Exchange the latest state with the other participants, for example
+    /// after reconnecting, see [ActiveChannel::sync]. If the connection
+    /// dropped in the middle of an update, this tells whether it went through
+    /// ([Event::UpdateAccepted]) or the other participant had a newer state
+    /// ([Event::ChannelSynced]).
+    pub fn sync(&self, channel_id: Hash) -> Result<(), RegistryError> {
+        match self.channels.get(&channel_id) {
+            Some(Entry::Active(channel)) | Some(Entry::Closing(channel)) => {
+                channel.sync()?;
+                Ok(())
+            }
+            Some(_) => Err(RegistryError::InvalidState(channel_id)),
+            None => Err(RegistryError::UnknownChannel(channel_id)),
+        }
+    }
+
+    /// [Self::sync] all channels that can be updated or are closing. Returns
+    /// the first error after trying all of them.
+    pub fn sync_all(&self) -> Result<(), RegistryError> {
+        let mut res = Ok(());
+        for entry in self.channels.values() {
+            if let Entry::Active(channel) | Entry::Closing(channel) = entry {
+                res = res.and(channel.sync());
+            }
+        }
+        Ok(res?)
+    }
+
+    /// Propose the final update of a channel, see
+    /// [ActiveChannel::close_normal].
+    pub fn close(&mut self, channel_id: Hash) -> Result<(), RegistryError> {
+        let mut new_state = self.active_channel(channel_id)?.state().make_next_state();
+        new_state.is_final = true;
+        self.update(channel_id, new_state)
+    }
+
+    /// Ask the watcher to close the channel on-chain with the latest state.
+    ///
+    /// This is also possible while waiting for the funding, to get our deposit
+    /// back if the other participants never fund the channel.
+    pub fn force_close(&mut self, channel_id: Hash) -> Result<(), RegistryError> {
+        let entry = self
+            .channels
+            .remove(&channel_id)
+            .ok_or(RegistryError::UnknownChannel(channel_id))?;
+        let res = match entry {
+            Entry::Active(channel) => channel
+                .force_close()
+                .map_err(|(channel, e)| (Entry::Active(*channel), e)),
+            Entry::Closing(channel) => channel
+                .force_close()
+                .map_err(|(channel, e)| (Entry::Closing(*channel), e)),
+            Entry::Signed { channel, watching } => channel.force_close().map_err(|(channel, e)| {
+                let entry = Entry::Signed {
+                    channel: *channel,
+                    watching,
+                };
+                (entry, e)
+            }),
+            entry => {
+                self.channels.insert(channel_id, entry);
+                return Err(RegistryError::InvalidState(channel_id));
+            }
+        };
+
+        match res {
+            Ok(channel) => {
+                self.updates.remove(&channel_id);
+                self.deadlines.remove(&Key::Channel(channel_id));
+                self.channels
+                    .insert(channel_id, Entry::ForceClosing(channel));
+                Ok(())
+            }
+            Err((entry, e)) => {
+                self.channels.insert(channel_id, entry);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// When [Self::handle_timeouts] has to be called next, if there is
+    /// anything to wait for.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().map(|d| d.at).min()
+    }
+
+    /// Resend messages or give up on proposals and channels whose deadline
+    /// has passed, see [Phase] for what happens in each phase.
+    ///
+    /// Handles one expired deadline per call that produces an [Event] (or
+    /// fails), call it until it returns `Ok(None)`.
+    pub fn handle_timeouts(&mut self) -> Result<Option<Event>, RegistryError> {
+        let now = self.clock.now();
+        loop {
+            let expired = self
+                .deadlines
+                .iter()
+                .find(|(_, d)| d.at <= now)
+                .map(|(k, d)| (*k, *d));
+            let (key, deadline) = match expired {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            self.deadlines.remove(&key);
+            if let Some(event) = self.handle_timeout(key, deadline)? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Accept an update received with [Event::UpdateReceived]. The update is
+    /// applied immediately.
+    pub fn accept_update(&mut self, channel_id: Hash) -> Result<(), RegistryError> {
+        let channel = match self.channels.get_mut(&channel_id) {
+            Some(Entry::Active(channel)) => channel,
+            Some(_) => return Err(RegistryError::InvalidState(channel_id)),
+            None => return Err(RegistryError::UnknownChannel(channel_id)),
+        };
+        let update = match self.updates.get_mut(&channel_id) {
+            Some(PendingUpdate::Received(update)) => update,
+            _ => return Err(RegistryError::InvalidState(channel_id)),
+        };
+        update.accept(channel)?;
+        update.apply(channel)?;
+
+        let is_final = update.state().is_final;
+        self.finish_update(channel_id, is_final);
+        Ok(())
+    }
+
+    /// Reject an update received with [Event::UpdateReceived].
+    pub fn reject_update(&mut self, channel_id: Hash, reason: &str) -> Result<(), RegistryError> {
+        let channel = match self.channels.get_mut(&channel_id) {
+            Some(Entry::Active(channel)) => channel,
+            Some(_) => return Err(RegistryError::InvalidState(channel_id)),
+            None => return Err(RegistryError::UnknownChannel(channel_id)),
+        };
+        match self.updates.remove(&channel_id) {
+            Some(PendingUpdate::Received(update)) => match update.reject(channel, reason) {
+                Ok(()) => Ok(()),
+                Err((update, RejectError::Bus(e))) => {
+                    self.updates
+                        .insert(channel_id, PendingUpdate::Received(*update));
+                    Err(e.into())
+                }
+                Err(_) => Err(RegistryError::InvalidState(channel_id)),
+            },
+            Some(update) => {
+                self.updates.insert(channel_id, update);
+                Err(RegistryError::InvalidState(channel_id))
+            }
+            None => Err(RegistryError::InvalidState(channel_id)),
+        }
+    }
+
+    /// Route a message from another participant to the corresponding channel.
+    ///
+    /// `Auth` messages are not related to a channel and ignored.
+    pub fn handle_participant_message(
+        &mut self,
+        msg: ParticipantMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        match msg {
+            ParticipantMessage::Auth { .. } => Ok(None),
+            ParticipantMessage::ChannelProposal(prop) => {
+                let id = prop.proposal_id;
+                if self.proposals.contains_key(&id) || self.received_proposals.contains_key(&id) {
+                    return Err(RegistryError::DuplicateProposal(id));
+                }
+                // The withdraw receiver is only needed once the application
+                // accepts the proposal, see Self::accept_proposal.
+                match PerunClient::handle_proposal_with(
+                    self.client.clone(),
+                    prop.clone(),
+                    Address::default(),
+                ) {
+                    Ok(_) => {
+                        self.received_proposals.insert(id, prop.clone());
+                        Ok(Some(Event::ProposalReceived(prop)))
+                    }
+                    Err(InvalidProposal::Rejected(reason)) => Ok(Some(Event::ProposalRefused {
+                        proposal_id: id,
+                        reason,
+                    })),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            ParticipantMessage::ProposalAccepted(acc) => self.handle_proposal_accepted(acc),
+            ParticipantMessage::ProposalRejected { id, reason } => {
+                let proposal = self
+                    .proposals
+                    .remove(&id)
+                    .ok_or(RegistryError::UnknownProposal(id))?;
+                match proposal.handle_rejection(id, &reason) {
+                    Ok(rejected) => {
+                        self.deadlines.remove(&Key::Proposal(id));
+                        Ok(Some(Event::ProposalRejected {
+                            proposal_id: id,
+                            reason: rejected.reason().clone(),
+                        }))
+                    }
+                    Err((proposal, e)) => {
+                        self.proposals.insert(id, *proposal);
+                        Err(e.into())
+                    }
+                }
+            }
+            ParticipantMessage::ChannelUpdate(msg) => {
+                let channel_id = msg.state.channel_id();
+                let channel = match self.channels.get_mut(&channel_id) {
+                    Some(Entry::Active(channel)) => channel,
+                    Some(_) => return Err(RegistryError::InvalidState(channel_id)),
+                    None => return Err(RegistryError::UnknownChannel(channel_id)),
+                };
+                // Both proposed an update for the same version at the same
+                // time, see ActiveChannel::handle_update for who wins.
+                let concurrent = match self.updates.get(&channel_id) {
+                    None => false,
+                    Some(PendingUpdate::Proposed(update))
+                        if update.state().version() == msg.state.version() =>
+                    {
+                        true
+                    }
+                    Some(_) => return Err(RegistryError::UpdatePending(channel_id)),
+                };
+                let state = msg.state;
+                let update = match channel.handle_update(msg) {
+                    Ok(update) => update,
+                    // Ours wins, the other participant drops theirs.
+                    Err(HandleUpdateError::ConflictingUpdate) if concurrent => return Ok(None),
+                    Err(HandleUpdateError::Rejected(reason)) => {
+                        // Our own update was dropped in handle_update.
+                        if concurrent {
+                            self.updates.remove(&channel_id);
+                            self.deadlines.remove(&Key::Channel(channel_id));
+                        }
+                        return Ok(Some(Event::UpdateRefused {
+                            channel_id,
+                            state,
+                            reason,
+                        }));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                // Replaces our own update if we lost (or if both proposed the
+                // same state, theirs already has their signature).
+                if concurrent {
+                    self.deadlines.remove(&Key::Channel(channel_id));
+                }
+                let decision = update.decision();
+                self.updates
+                    .insert(channel_id, PendingUpdate::Received(update));
+                if decision == Decision::Accept {
+                    self.accept_update(channel_id)?;
+                    return Ok(Some(Event::UpdateAutoAccepted {
+                        channel_id,
+                        version: state.version(),
+                    }));
+                }
+                if concurrent {
+                    Ok(Some(Event::UpdateConflict { channel_id, state }))
+                } else {
+                    Ok(Some(Event::UpdateReceived { channel_id, state }))
+                }
+            }
+            ParticipantMessage::ChannelUpdateAccepted(msg) => self.handle_update_accepted(msg),
+            ParticipantMessage::ChannelSync(msg) => self.handle_sync(msg),
+            ParticipantMessage::ChannelUpdateRejected {
+                id,
+                version,
+                reason,
+            } => {
+                let reason = match self.channels.get_mut(&id) {
+                    Some(Entry::Active(channel)) => {
+                        let update = match self.updates.remove(&id) {
+                            Some(PendingUpdate::Proposed(update)) => update,
+                            other => {
+                                self.updates.extend(other.map(|u| (id, u)));
+                                return Err(RegistryError::InvalidState(id));
+                            }
+                        };
+                        match update.handle_rejection(channel, version, &reason) {
+                            Ok(rejected) => rejected.reason().clone(),
+                            Err((update, e)) => {
+                                self.updates.insert(id, PendingUpdate::Proposed(*update));
+                                return Err(e.into());
+                            }
+                        }
+                    }
+                    // The other participant does not want to sign the initial
+                    // state, the channel cannot be opened.
+                    Some(Entry::AgreedUpon(_)) => {
+                        self.channels.remove(&id);
+                        RejectReason::from(reason.as_str())
+                    }
+                    Some(_) => return Err(RegistryError::InvalidState(id)),
+                    None => return Err(RegistryError::UnknownChannel(id)),
+                };
+                self.deadlines.remove(&Key::Channel(id));
+                Ok(Some(Event::UpdateRejected {
+                    channel_id: id,
+                    version,
+                    reason,
+                }))
+            }
+        }
+    }
+
+    /// Route a message from the watcher to the corresponding channel.
+    pub fn handle_watcher_message(
+        &mut self,
+        msg: WatcherReplyMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        let id = match msg {
+            WatcherReplyMessage::Ack { id, .. }
+            | WatcherReplyMessage::WatchFailed { id, .. }
+            | WatcherReplyMessage::DisputeAck { id }
+            | WatcherReplyMessage::DisputeFailed { id }
+            | WatcherReplyMessage::DisputeNotification { id, .. }
+            | WatcherReplyMessage::Concluded { id, .. }
+            | WatcherReplyMessage::Withdrawn { id, .. } => id,
+        };
+        let entry = self
+            .channels
+            .remove(&id)
+            .ok_or(RegistryError::UnknownChannel(id))?;
+
+        match (entry, msg) {
+            (Entry::Signed { channel, .. }, WatcherReplyMessage::Ack { .. }) => {
+                Ok(self.signed_progress(id, channel, true))
+            }
+            (Entry::Closing(channel), WatcherReplyMessage::Ack { version, .. })
+                if version == channel.version() =>
+            {
+                match channel.settle() {
+                    Ok(channel) => {
+                        self.deadlines.remove(&Key::Channel(id));
+                        self.channels.insert(id, Entry::Settling(channel));
+                        Ok(Some(Event::ChannelClosed { channel_id: id }))
+                    }
+                    Err((channel, e)) => {
+                        self.channels.insert(id, Entry::Closing(*channel));
+                        Err(e.into())
+                    }
+                }
+            }
+            (Entry::ForceClosing(channel), WatcherReplyMessage::DisputeAck { .. }) => {
+                self.channels.insert(id, Entry::Disputed(channel));
+                Ok(Some(Event::ChannelClosed { channel_id: id }))
+            }
+            // We already know about it, as we've started it ourselves.
+            (
+                Entry::ForceClosing(mut channel),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => {
+                let res = channel.handle_registered(version);
+                self.channels.insert(id, Entry::ForceClosing(channel));
+                res?;
+                Ok(None)
+            }
+            (
+                Entry::Signed { channel, watching },
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => match channel.handle_dispute(version) {
+                Ok(channel) => Ok(Some(self.disputed(channel))),
+                Err((channel, e)) => {
+                    self.channels.insert(
+                        id,
+                        Entry::Signed {
+                            channel: *channel,
+                            watching,
+                        },
+                    );
+                    Err(e.into())
+                }
+            },
+            (Entry::Active(channel), WatcherReplyMessage::DisputeNotification { version, .. }) => {
+                match channel.handle_dispute(version) {
+                    Ok(channel) => Ok(Some(self.disputed(channel))),
+                    Err((channel, e)) => {
+                        self.channels.insert(id, Entry::Active(*channel));
+                        Err(e.into())
+                    }
+                }
+            }
+            (Entry::Closing(channel), WatcherReplyMessage::DisputeNotification { version, .. }) => {
+                match channel.handle_dispute(version) {
+                    Ok(channel) => Ok(Some(self.disputed(channel))),
+                    Err((channel, e)) => {
+                        self.channels.insert(id, Entry::Closing(*channel));
+                        Err(e.into())
+                    }
+                }
+            }
+            (
+                Entry::Disputed(mut channel),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => {
+                let res = channel.handle_registered(version);
+                let event = Event::ChannelDisputed {
+                    channel_id: id,
+                    version,
+                    refuting: channel.is_refuting(),
+                };
+                self.channels.insert(id, Entry::Disputed(channel));
+                res?;
+                Ok(Some(event))
+            }
+            // Acknowledges our refutation, which is notified separately once
+            // it is registered.
+            (entry @ Entry::Disputed(_), WatcherReplyMessage::DisputeAck { .. }) => {
+                self.channels.insert(id, entry);
+                Ok(None)
+            }
+            // The acknowledgement of our dispute may have been lost.
+            (
+                Entry::Disputed(mut channel) | Entry::ForceClosing(mut channel),
+                WatcherReplyMessage::Concluded { version, .. },
+            ) => {
+                channel.handle_concluded(version);
+                self.channels.insert(id, Entry::Disputed(channel));
+                Ok(Some(Event::ChannelConcluded {
+                    channel_id: id,
+                    version,
+                }))
+            }
+            (
+                Entry::Disputed(channel) | Entry::ForceClosing(channel),
+                WatcherReplyMessage::Withdrawn { amounts, .. },
+            ) => Ok(Some(Event::ChannelSettled(
+                channel.handle_withdrawn(amounts),
+            ))),
+            (
+                Entry::Settling(channel),
+                WatcherReplyMessage::DisputeNotification { version, .. },
+            ) => match channel.handle_dispute(version) {
+                Ok(channel) => Ok(Some(self.disputed(channel))),
+                Err((channel, e)) => {
+                    self.channels.insert(id, Entry::Settling(*channel));
+                    Err(e.into())
+                }
+            },
+            (Entry::Settling(mut channel), WatcherReplyMessage::Concluded { version, .. }) => {
+                channel.handle_concluded(version);
+                self.channels.insert(id, Entry::Settling(channel));
+                Ok(Some(Event::ChannelConcluded {
+                    channel_id: id,
+                    version,
+                }))
+            }
+            (Entry::Settling(channel), WatcherReplyMessage::Withdrawn { amounts, .. }) => Ok(Some(
+                Event::ChannelSettled(channel.handle_withdrawn(amounts)),
+            )),
+            (Entry::Active(mut channel), WatcherReplyMessage::Ack { version, .. }) => {
+                channel.handle_watcher_ack(version);
+                let acked = self.require_watcher_ack && channel.is_acked();
+                self.channels.insert(id, Entry::Active(channel));
+                Ok(acked.then_some(Event::StateAcknowledged {
+                    channel_id: id,
+                    version,
+                }))
+            }
+            // Acknowledgements for intermediate states are not needed.
+            (entry @ (Entry::Closing(_) | Entry::Settling(_)), WatcherReplyMessage::Ack { .. }) => {
+                self.channels.insert(id, entry);
+                Ok(None)
+            }
+            // Leave it to the application to ask again or to give up.
+            (
+                entry,
+                WatcherReplyMessage::WatchFailed { .. } | WatcherReplyMessage::DisputeFailed { .. },
+            ) => {
+                self.channels.insert(id, entry);
+                Err(RegistryError::WatcherFailed(id))
+            }
+            (entry, _) => {
+                self.channels.insert(id, entry);
+                Err(RegistryError::InvalidState(id))
+            }
+        }
+    }
+
+    /// Route a message from the funder to the corresponding channel.
+    pub fn handle_funder_message(
+        &mut self,
+        msg: FunderReplyMessage,
+    ) -> Result<Option<Event>, RegistryError> {
+        let id = match msg {
+            FunderReplyMessage::Funded { id } | FunderReplyMessage::FundingFailed { id } => id,
+        };
+        match self.channels.remove(&id) {
+            Some(Entry::Signed {
+                mut channel,
+                watching,
+            }) => match channel.handle_funder_reply(msg) {
+                Ok(()) => Ok(self.signed_progress(id, channel, watching)),
+                Err(FundingError::Failed) => match channel.refund() {
+                    Ok(channel) => {
+                        self.deadlines.remove(&Key::Channel(id));
+                        self.channels.insert(id, Entry::Disputed(channel));
+                        Ok(Some(Event::FundingFailed { channel_id: id }))
+                    }
+                    Err((channel, e)) => {
+                        self.channels.insert(
+                            id,
+                            Entry::Signed {
+                                channel: *channel,
+                                watching,
+                            },
+                        );
+                        Err(e.into())
+                    }
+                },
+                Err(e) => {
+                    self.channels
+                        .insert(id, Entry::Signed { channel, watching });
+                    Err(e.into())
+                }
+            },
+            Some(entry) => {
+                self.channels.insert(id, entry);
+                Err(RegistryError::InvalidState(id))
+            }
+            None => Err(RegistryError::UnknownChannel(id)),
+        }
+    }
+
+    fn active_channel(&self, id: Hash) -> Result<&ActiveChannel<C>, RegistryError> {
+        match self.channels.get(&id) {
+            Some(Entry::Active(channel)) => Ok(channel),
+            Some(_) => Err(RegistryError::InvalidState(id)),
+            None => Err(RegistryError::UnknownChannel(id)),
+        }
+    }
+
+    fn handle_proposal_accepted(
+        &mut self,
+        acc: LedgerChannelProposalAcc,
+    ) -> Result<Option<Event>, RegistryError> {
+        let id = acc.proposal_id;
+        let mut channel = self
+            .proposals
+            .remove(&id)
+            .ok_or(RegistryError::UnknownProposal(id))?;
+        // Hard-coded part_idx: Only 2-party channels are supported.
+        if let Err(e) = channel.participant_accepted(1, acc) {
+            self.proposals.insert(id, channel);
+            return Err(e.into());
+        }
+        let channel_id = self.build_and_sign(id, channel)?;
+        self.deadlines.remove(&Key::Proposal(id));
+        Ok(Some(Event::ProposalAccepted {
+            proposal_id: id,
+            channel_id,
+        }))
+    }
+
+    fn handle_sync(&mut self, msg: LedgerChannelSync) -> Result<Option<Event>, RegistryError> {
+        let id = msg.state.channel_id();
+        let channel = match self.channels.get_mut(&id) {
+            Some(Entry::Active(channel)) | Some(Entry::Closing(channel)) => channel,
+            Some(_) => return Err(RegistryError::InvalidState(id)),
+            None => return Err(RegistryError::UnknownChannel(id)),
+        };
+        if channel.handle_sync(msg)? != SyncOutcome::Adopted {
+            return Ok(None);
+        }
+        // A closing channel has a final state, which can't be outdated.
+        let version = channel.version();
+        let is_final = channel.state().is_final;
+
+        let (stale, ours) = match self.updates.get(&id) {
+            Some(PendingUpdate::Proposed(update)) => {
+                let pending = update.state().version();
+                // We sign at most one state per version, so if the adopted
+                // state has the version of our proposal, it is our proposal.
+                (pending <= version, pending == version)
+            }
+            Some(PendingUpdate::Received(update)) => (update.state().version() <= version, false),
+            None => (true, false),
+        };
+        if stale {
+            self.finish_update(id, is_final);
+        }
+        if ours {
+            Ok(Some(Event::UpdateAccepted {
+                channel_id: id,
+                version,
+            }))
+        } else {
+            Ok(Some(Event::ChannelSynced {
+                channel_id: id,
+                version,
+            }))
+        }
+    }
+
+    fn handle_update_accepted(
+        &mut self,
+        msg: LedgerChannelUpdateAccepted,
+    ) -> Result<Option<Event>, RegistryError> {
+        let id = msg.channel;
+        match self.channels.get_mut(&id) {
+            Some(Entry::AgreedUpon(channel)) => {
+                channel.add_signature(msg)?;
+                let channel = match self.channels.remove(&id) {
+                    Some(Entry::AgreedUpon(channel)) => channel,
+                    _ => unreachable!(),
+                };
+                match channel.build() {
+                    Ok(channel) => {
+                        self.channels.insert(
+                            id,
+                            Entry::Signed {
+                                channel,
+                                watching: false,
+                            },
+                        );
+                        self.start_timeout(Key::Channel(id), Phase::Funding);
+                        Ok(Some(Event::ChannelSigned { channel_id: id }))
+                    }
+                    Err((channel, e)) => {
+                        self.channels.insert(id, Entry::AgreedUpon(*channel));
+                        Err(e.into())
+                    }
+                }
+            }
+            Some(Entry::Active(channel)) => {
+                let update = match self.updates.get_mut(&id) {
+                    Some(PendingUpdate::Proposed(update)) => update,
+                    _ => return Err(RegistryError::InvalidState(id)),
+                };
+                // Hard-coded: Only 2-party channels are supported.
+                let part_idx = 1 - channel.part_idx();
+                update.participant_accepted(channel, part_idx, msg)?;
+                update.apply(channel)?;
+
+                let is_final = update.state().is_final;
+                self.finish_update(id, is_final);
+                Ok(Some(Event::UpdateAccepted {
+                    channel_id: id,
+                    version: msg.version,
+                }))
+            }
+            Some(_) => Err(RegistryError::InvalidState(id)),
+            None => Err(RegistryError::UnknownChannel(id)),
+        }
+    }
+
+    /// Create the initial state and sign it, after all participants accepted
+    /// the proposal.
+    fn build_and_sign(
+        &mut self,
+        proposal_id: Hash,
+        channel: ProposedChannel<C>,
+    ) -> Result<Hash, RegistryError> {
+        let mut channel = match channel.build() {
+            Ok(v) => v,
+            Err((channel, e)) => {
+                self.proposals.insert(proposal_id, *channel);
+                return Err(e.into());
+            }
+        };
+
+        let channel_id = channel.channel_id();
+        if self.channels.contains_key(&channel_id) {
+            return Err(RegistryError::DuplicateChannel(channel_id));
+        }
+        let res = channel.sign();
+        self.channels.insert(channel_id, Entry::AgreedUpon(channel));
+        self.start_timeout(Key::Channel(channel_id), Phase::Signing);
+        res?;
+        Ok(channel_id)
+    }
+
+    /// Someone else started a dispute, nothing else is pending for the
+    /// channel.
+    fn disputed(&mut self, channel: DisputedChannel<C>) -> Event {
+        let channel_id = channel.channel_id();
+        self.updates.remove(&channel_id);
+        self.deadlines.remove(&Key::Channel(channel_id));
+        let event = Event::ChannelDisputed {
+            channel_id,
+            version: channel.registered_version(),
+            refuting: channel.is_refuting(),
+        };
+        self.channels.insert(channel_id, Entry::Disputed(channel));
+        event
+    }
+
+    /// The channel is active once it is funded and the watcher knows about it.
+    fn signed_progress(
+        &mut self,
+        id: Hash,
+        channel: SignedChannel<C>,
+        watching: bool,
+    ) -> Option<Event> {
+        if !watching {
+            self.channels
+                .insert(id, Entry::Signed { channel, watching });
+            return None;
+        }
+        match channel.mark_funded() {
+            Ok(mut channel) => {
+                // The watcher acknowledged the initial state.
+                channel.handle_watcher_ack(channel.version());
+                channel.set_require_watcher_ack(self.require_watcher_ack);
+                self.deadlines.remove(&Key::Channel(id));
+                self.channels.insert(id, Entry::Active(channel));
+                Some(Event::ChannelActive { channel_id: id })
+            }
+            Err((channel, _)) => {
+                self.channels.insert(
+                    id,
+                    Entry::Signed {
+                        channel: *channel,
+                        watching,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    /// Remove the pending update after applying it, moving to the closing
+    /// phase if it was final.
+    fn finish_update(&mut self, id: Hash, is_final: bool) {
+        self.updates.remove(&id);
+        self.deadlines.remove(&Key::Channel(id));
+        if is_final {
+            if let Some(Entry::Active(channel)) = self.channels.remove(&id) {
+                self.channels.insert(id, Entry::Closing(channel));
+                self.start_timeout(Key::Channel(id), Phase::Closing);
+            }
+        }
+    }
+
+    /// Start waiting for `key` in `phase`, replacing the previous deadline.
+    fn start_timeout(&mut self, key: Key, phase: Phase) {
+        match self.timeouts.get(phase) {
+            Some(timeout) => {
+                let deadline = Deadline {
+                    phase,
+                    at: self.clock.now().saturating_add(timeout.ticks),
+                    retries: 0,
+                };
+                self.deadlines.insert(key, deadline);
+            }
+            None => {
+                self.deadlines.remove(&key);
+            }
+        }
+    }
+
+    /// Resend the last message of the phase or give up, if we're still in it.
+    fn handle_timeout(
+        &mut self,
+        key: Key,
+        mut deadline: Deadline,
+    ) -> Result<Option<Event>, RegistryError> {
+        // Only called for deadlines created from the policy.
+        let timeout = match self.timeouts.get(deadline.phase) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let resend = deadline.retries < timeout.retries;
+        if resend {
+            deadline.retries += 1;
+            deadline.at = self.clock.now().saturating_add(timeout.ticks);
+            self.deadlines.insert(key, deadline);
+        }
+
+        let timed_out = |id| {
+            Ok(Some(Event::TimedOut {
+                id,
+                phase: deadline.phase,
+            }))
+        };
+        match (key, deadline.phase) {
+            (Key::Proposal(id), Phase::Proposal) => match self.proposals.get(&id) {
+                Some(channel) if resend => {
+                    channel.resend()?;
+                    Ok(None)
+                }
+                Some(_) => {
+                    if let Some(channel) = self.proposals.remove(&id) {
+                        // Best effort, the others give up after their timeout
+                        // anyways.
+                        let _ = channel.reject(RejectReason::Timeout.as_str());
+                    }
+                    timed_out(id)
+                }
+                None => Ok(None),
+            },
+            (Key::Channel(id), Phase::Signing) => match self.channels.get_mut(&id) {
+                Some(Entry::AgreedUpon(channel)) if resend => {
+                    match channel.resend_signature() {
+                        // Sending our signature failed before.
+                        Err(ResendError::NothingToResend) => channel.sign()?,
+                        res => res?,
+                    }
+                    Ok(None)
+                }
+                Some(Entry::AgreedUpon(_)) => {
+                    if let Some(Entry::AgreedUpon(channel)) = self.channels.remove(&id) {
+                        // Best effort, see above.
+                        let _ = channel.reject(RejectReason::Timeout.as_str());
+                    }
+                    timed_out(id)
+                }
+                _ => Ok(None),
+            },
+            (Key::Channel(id), Phase::Funding) => match self.channels.get(&id) {
+                Some(Entry::Signed { channel, .. }) if resend => {
+                    channel.send_current_state_to_watcher()?;
+                    Ok(None)
+                }
+                Some(Entry::Signed { .. }) => {
+                    self.force_close(id)?;
+                    timed_out(id)
+                }
+                _ => Ok(None),
+            },
+            (Key::Channel(id), Phase::Update) => {
+                match (self.channels.get(&id), self.updates.get(&id)) {
+                    (Some(Entry::Active(channel)), Some(PendingUpdate::Proposed(update))) => {
+                        if resend {
+                            update.resend(channel)?;
+                            Ok(None)
+                        } else {
+                            self.force_close(id)?;
+                            timed_out(id)
+                        }
+                    }
+                    _ => Ok(None),
+                }
+            }
+            (Key::Channel(id), Phase::Closing) => match self.channels.get(&id) {
+                Some(Entry::Closing(channel)) if resend => {
+                    channel.send_current_state_to_watcher()?;
+                    Ok(None)
+                }
+                Some(Entry::Closing(_)) => {
+                    self.force_close(id)?;
+                    timed_out(id)
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,1272 @@
+use super::{ChannelRegistry, Event, RegistryError};
+use crate::{
+    abiencode::{
+        self,
+        types::{Address, Hash, Signature, U256},
+    },
+    channel::{
+        fixed_size_payment::{Allocation, Balances, ParticipantBalances, TransferError},
+        AcceptError, ActiveChannel, Asset, FundingError, HandleUpdateError, LedgerChannelProposal,
+        ProposeUpdateError, RejectionError, SyncError,
+    },
+    messages::{
+        FunderReplyMessage, FunderRequestMessage, LedgerChannelSync, ParticipantMessage,
+        RejectReason, WatcherReplyMessage, WatcherRequestMessage,
+    },
+    perunwire,
+    policy::{AutoAcceptBelow, NoLoss},
+    sig::Signer,
+    storage::ChannelSnapshot,
+    time::{Clock, Instant, Phase, Timeout, TimeoutPolicy},
+    wire::{BusError, Identity, MessageBus},
+    PerunClient,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+pub(crate) const ALICE: usize = 0;
+pub(crate) const BOB: usize = 1;
+pub(crate) const NAMES: [&[u8]; 2] = [b"Alice", b"Bob"];
+
+pub(crate) enum Delivery {
+    Participant(Box<ParticipantMessage>),
+    Watcher(WatcherReplyMessage),
+    Funder(FunderReplyMessage),
+}
+
+/// Messages in flight, the watcher and funder reply immediately.
+#[derive(Default)]
+pub(crate) struct Network {
+    pub(crate) queue: RefCell<VecDeque<(usize, Delivery)>>,
+    /// While set, every send fails without queueing anything.
+    pub(crate) down: Cell<bool>,
+}
+
+impl Network {
+    fn check(&self) -> Result<(), BusError> {
+        match self.down.get() {
+            true => Err(BusError::NotConnected),
+            false => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct TestBus {
+    pub(crate) net: Rc<Network>,
+    pub(crate) idx: usize,
+}
+
+impl MessageBus for TestBus {
+    fn send_to_watcher(&self, msg: WatcherRequestMessage) -> Result<(), BusError> {
+        self.net.check()?;
+        let replies = match msg {
+            WatcherRequestMessage::WatchRequest(info) => vec![WatcherReplyMessage::Ack {
+                id: info.state.channel_id(),
+                version: info.state.version(),
+            }],
+            WatcherRequestMessage::StartDispute(info) => vec![WatcherReplyMessage::DisputeAck {
+                id: info.state.channel_id(),
+            }],
+            // Skip the acknowledgement, the refutation is registered right
+            // away.
+            WatcherRequestMessage::Refute(info) => {
+                vec![WatcherReplyMessage::DisputeNotification {
+                    id: info.state.channel_id(),
+                    version: info.state.version(),
+                }]
+            }
+            WatcherRequestMessage::Conclude(info) => {
+                let id = info.state.channel_id();
+                let balances = info.state.outcome.balances.0;
+                vec![
+                    WatcherReplyMessage::Concluded {
+                        id,
+                        version: info.state.version(),
+                    },
+                    WatcherReplyMessage::Withdrawn {
+                        id,
+                        amounts: balances.map(|bals| bals.0[info.part_idx]),
+                    },
+                ]
+            }
+        };
+        let mut queue = self.net.queue.borrow_mut();
+        for reply in replies {
+            queue.push_back((self.idx, Delivery::Watcher(reply)));
+        }
+        Ok(())
+    }
+
+    fn send_to_funder(&self, msg: FunderRequestMessage) -> Result<(), BusError> {
+        self.net.check()?;
+        let FunderRequestMessage::FundingRequest(req) = msg;
+        self.net.queue.borrow_mut().push_back((
+            self.idx,
+            Delivery::Funder(FunderReplyMessage::Funded {
+                id: req.state.channel_id(),
+            }),
+        ));
+        Ok(())
+    }
+
+    fn send_to_participant(
+        &self,
+        _: &Identity,
+        recipient: &Identity,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError> {
+        self.net.check()?;
+        let idx = NAMES.iter().position(|n| *n == recipient).unwrap();
+        self.net
+            .queue
+            .borrow_mut()
+            .push_back((idx, Delivery::Participant(Box::new(msg))));
+        Ok(())
+    }
+}
+
+/// Clock advanced by hand, shared by both participants.
+#[derive(Clone, Default)]
+struct TestClock(Rc<Cell<u64>>);
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        Instant(self.0.get())
+    }
+}
+
+type Registry = ChannelRegistry<Rc<PerunClient<TestBus>>, TestClock>;
+
+struct Setup {
+    net: Rc<Network>,
+    registries: [Registry; 2],
+    rng: StdRng,
+    clock: TestClock,
+}
+
+impl Setup {
+    fn new() -> Self {
+        Self::with_timeouts(TimeoutPolicy::default())
+    }
+
+    fn with_timeouts(timeouts: TimeoutPolicy) -> Self {
+        Self::build(timeouts, |_, _| {})
+    }
+
+    /// Lets `configure` set up the client of each participant before it is
+    /// shared with the registry.
+    fn build(
+        timeouts: TimeoutPolicy,
+        configure: impl Fn(usize, &mut PerunClient<TestBus>),
+    ) -> Self {
+        let mut rng = StdRng::seed_from_u64(0);
+        let net = Rc::new(Network::default());
+        let clock = TestClock::default();
+        let registries = [ALICE, BOB].map(|idx| {
+            let bus = TestBus {
+                net: net.clone(),
+                idx,
+            };
+            let mut client = PerunClient::new(bus, Signer::new(&mut rng));
+            configure(idx, &mut client);
+            ChannelRegistry::with_timeouts(Rc::new(client), clock.clone(), timeouts)
+        });
+        Setup {
+            net,
+            registries,
+            rng,
+            clock,
+        }
+    }
+
+    /// Advance the clock to `now` and handle the timeouts of `idx`.
+    fn timeouts_at(&mut self, idx: usize, now: u64) -> Vec<Event> {
+        self.clock.0.set(now);
+        let mut events = vec![];
+        while let Some(event) = self.registries[idx].handle_timeouts().unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    /// Remove all messages in flight without delivering them, returns the
+    /// participant messages among them.
+    fn drop_messages(&mut self) -> Vec<ParticipantMessage> {
+        self.net
+            .queue
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|(_, delivery)| match delivery {
+                Delivery::Participant(msg) => Some(*msg),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn address(&self, idx: usize) -> Address {
+        self.registries[idx].client().signer.address()
+    }
+
+    /// Deliver all messages, returning the events of each participant.
+    fn deliver(&mut self) -> [Vec<Event>; 2] {
+        let mut events = [vec![], vec![]];
+        loop {
+            let next = self.net.queue.borrow_mut().pop_front();
+            let (idx, delivery) = match next {
+                Some(v) => v,
+                None => return events,
+            };
+            let registry = &mut self.registries[idx];
+            let event = match delivery {
+                Delivery::Participant(msg) => registry.handle_participant_message(*msg),
+                Delivery::Watcher(msg) => registry.handle_watcher_message(msg),
+                Delivery::Funder(msg) => registry.handle_funder_message(msg),
+            }
+            .unwrap();
+            events[idx].extend(event);
+        }
+    }
+
+    fn proposal(&mut self) -> LedgerChannelProposal {
+        LedgerChannelProposal {
+            proposal_id: self.rng.gen(),
+            challenge_duration: 100,
+            nonce_share: self.rng.gen(),
+            init_bals: Allocation::new(
+                [Asset {
+                    chain_id: U256::from(1337),
+                    holder: Address([1; 20]),
+                }],
+                Balances([ParticipantBalances([100.into(), 100.into()])]),
+            ),
+            funding_agreement: Balances([ParticipantBalances([100.into(), 100.into()])]),
+            participant: self.address(ALICE),
+            peers: NAMES.iter().map(|n| n.to_vec()).collect(),
+        }
+    }
+
+    /// Open a channel proposed by Alice, returns the channel id.
+    fn open(&mut self) -> Hash {
+        let prop = self.proposal();
+        let withdraw_receiver = self.address(ALICE);
+        let proposal_id = self.registries[ALICE]
+            .propose_channel(prop, withdraw_receiver)
+            .unwrap();
+
+        let [_, bob] = self.deliver();
+        assert!(matches!(&bob[..], [Event::ProposalReceived(p)] if p.proposal_id == proposal_id));
+
+        let nonce_share = self.rng.gen();
+        let withdraw_receiver = self.address(BOB);
+        let channel_id = self.registries[BOB]
+            .accept_proposal(proposal_id, nonce_share, withdraw_receiver)
+            .unwrap();
+
+        let [alice, bob] = self.deliver();
+        assert!(matches!(
+            &alice[..],
+            [
+                Event::ProposalAccepted { channel_id: a, .. },
+                Event::ChannelSigned { .. },
+                Event::ChannelActive { .. },
+            ] if *a == channel_id
+        ));
+        assert!(matches!(
+            &bob[..],
+            [Event::ChannelSigned { .. }, Event::ChannelActive { .. }]
+        ));
+        channel_id
+    }
+
+    /// Alice sends `amount` to Bob.
+    fn pay(&mut self, channel_id: Hash, amount: u64) {
+        self.transfer(ALICE, BOB, channel_id, amount)
+    }
+
+    fn transfer(&mut self, from: usize, to: usize, channel_id: Hash, amount: u64) {
+        let channel = self.registries[from].channel(channel_id).unwrap();
+        let mut state = channel.state().make_next_state();
+        state.outcome.balances.0[0].0[from] -= amount.into();
+        state.outcome.balances.0[0].0[to] += amount.into();
+        self.registries[from].update(channel_id, state).unwrap();
+    }
+}
+
+#[test]
+fn open_update_close() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.pay(id, 10);
+    let [_, bob] = s.deliver();
+    assert!(matches!(&bob[..], [Event::UpdateReceived { state, .. }] if state.version() == 1));
+    s.registries[BOB].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+    for registry in &s.registries {
+        let balances = registry.channel(id).unwrap().state().outcome.balances.0[0].0;
+        assert_eq!(balances, [90.into(), 110.into()]);
+    }
+
+    s.registries[BOB].close(id).unwrap();
+    s.deliver();
+    s.registries[ALICE].accept_update(id).unwrap();
+    let [alice, bob] = s.deliver();
+    let settled = match (&alice[..], &bob[..]) {
+        (
+            [Event::ChannelClosed { .. }, Event::ChannelConcluded { version: 2, .. }, Event::ChannelSettled(a)],
+            [Event::UpdateAccepted { .. }, Event::ChannelClosed { .. }, Event::ChannelConcluded { version: 2, .. }, Event::ChannelSettled(b)],
+        ) => [*a, *b],
+        _ => panic!("unexpected events: {:?}, {:?}", alice, bob),
+    };
+    assert!(settled.iter().all(|s| s.is_complete()));
+    assert_eq!(settled[ALICE].withdrawn(), [90.into()]);
+    assert_eq!(settled[BOB].withdrawn(), [110.into()]);
+    assert_eq!(settled[BOB].withdraw_receiver(), s.address(BOB));
+    assert!(s.registries.iter().all(|r| r.channel_ids().count() == 0));
+}
+
+#[test]
+fn reject_update() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.pay(id, 10);
+    s.deliver();
+    // Only one pending update at a time.
+    assert!(matches!(
+        s.registries[BOB].close(id),
+        Err(RegistryError::UpdatePending(_))
+    ));
+
+    // A rejection for another version doesn't end our update.
+    let wrong = ParticipantMessage::ChannelUpdateRejected {
+        id,
+        version: 2,
+        reason: "no".into(),
+    };
+    assert!(matches!(
+        s.registries[ALICE].handle_participant_message(wrong),
+        Err(RegistryError::Rejection(RejectionError::WrongVersion))
+    ));
+
+    s.registries[BOB].reject_update(id, "no").unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateRejected { version: 1, reason: RejectReason::Other(reason), .. }] if reason == "no"
+    ));
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 0);
+    assert_eq!(s.registries[BOB].channel(id).unwrap().version(), 0);
+
+    // The channel is still usable.
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 1);
+}
+
+#[test]
+fn failed_sends_can_be_retried() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    // Nothing changes if the update can't be sent.
+    s.net.down.set(true);
+    let mut state = s.registries[ALICE]
+        .channel(id)
+        .unwrap()
+        .state()
+        .make_next_state();
+    state.outcome.balances.0[0].0[ALICE] -= 10.into();
+    state.outcome.balances.0[0].0[BOB] += 10.into();
+    assert!(matches!(
+        s.registries[ALICE].update(id, state),
+        Err(RegistryError::ProposeUpdate(ProposeUpdateError::Bus(
+            BusError::NotConnected
+        )))
+    ));
+    s.net.down.set(false);
+
+    // No update is pending, so the same payment can be proposed again.
+    s.pay(id, 10);
+    s.deliver();
+
+    // Neither if the answer can't be sent.
+    s.net.down.set(true);
+    assert!(matches!(
+        s.registries[BOB].reject_update(id, "no"),
+        Err(RegistryError::Bus(BusError::NotConnected))
+    ));
+    assert!(matches!(
+        s.registries[BOB].accept_update(id),
+        Err(RegistryError::Accept(AcceptError::Bus(
+            BusError::NotConnected
+        )))
+    ));
+    assert_eq!(s.registries[BOB].channel(id).unwrap().version(), 0);
+    s.net.down.set(false);
+
+    s.registries[BOB].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+    for registry in &s.registries {
+        assert_eq!(registry.channel(id).unwrap().version(), 1);
+    }
+}
+
+#[test]
+fn policy_decides_updates() {
+    let mut s = Setup::build(TimeoutPolicy::default(), |idx, client| {
+        if idx == BOB {
+            client.set_policy((NoLoss, AutoAcceptBelow(1.into())));
+        }
+    });
+    let id = s.open();
+
+    s.pay(id, 10);
+    let [alice, bob] = s.deliver();
+    assert!(matches!(
+        &bob[..],
+        [Event::UpdateAutoAccepted { version: 1, .. }]
+    ));
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+
+    s.transfer(BOB, ALICE, id, 5);
+    s.deliver();
+    s.registries[ALICE].accept_update(id).unwrap();
+    s.deliver();
+
+    // Alice tries to take money from Bob.
+    let channel = s.registries[ALICE].channel(id).unwrap();
+    let mut state = channel.state().make_next_state();
+    state.outcome.balances.0[0].0[BOB] -= 1.into();
+    state.outcome.balances.0[0].0[ALICE] += 1.into();
+    s.registries[ALICE].update(id, state).unwrap();
+    let [alice, bob] = s.deliver();
+    assert!(matches!(
+        &bob[..],
+        [Event::UpdateRefused { reason, .. }] if *reason == "update decreases our balance"
+    ));
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateRejected { version: 3, .. }]
+    ));
+    for registry in &s.registries {
+        let channel = registry.channel(id).unwrap();
+        assert_eq!(channel.version(), 2);
+        assert_eq!(
+            channel.state().outcome.balances.0[0].0,
+            [95.into(), 105.into()]
+        );
+    }
+}
+
+#[test]
+fn policy_refuses_proposal() {
+    let mut s = Setup::build(TimeoutPolicy::default(), |idx, client| {
+        if idx == BOB {
+            client.set_policy(NoLoss);
+        }
+    });
+
+    let mut prop = s.proposal();
+    prop.init_bals.balances.0[0].0 = [150.into(), 50.into()];
+    let proposal_id = prop.proposal_id;
+    let withdraw_receiver = s.address(ALICE);
+    s.registries[ALICE]
+        .propose_channel(prop, withdraw_receiver)
+        .unwrap();
+    let [alice, bob] = s.deliver();
+    assert!(matches!(
+        &bob[..],
+        [Event::ProposalRefused { proposal_id: id, .. }] if *id == proposal_id
+    ));
+    assert!(matches!(
+        &alice[..],
+        [Event::ProposalRejected { proposal_id: id, .. }] if *id == proposal_id
+    ));
+}
+
+#[test]
+fn invalid_actor_idx() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.pay(id, 10);
+    let msg = match &s.drop_messages()[..] {
+        [ParticipantMessage::ChannelUpdate(msg)] => *msg,
+        msgs => panic!("unexpected messages: {:?}", msgs),
+    };
+    for actor_idx in [BOB, 7] {
+        let mut msg = msg;
+        msg.actor_idx = actor_idx;
+        assert!(matches!(
+            s.registries[BOB].handle_participant_message(ParticipantMessage::ChannelUpdate(msg)),
+            Err(RegistryError::HandleUpdate(HandleUpdateError::InvalidPartIdx(idx))) if idx == actor_idx
+        ));
+    }
+    s.registries[BOB]
+        .handle_participant_message(ParticipantMessage::ChannelUpdate(msg))
+        .unwrap();
+}
+
+#[test]
+fn reject_proposal() {
+    let mut s = Setup::new();
+    let prop = s.proposal();
+    let receiver = s.address(ALICE);
+    let id = s.registries[ALICE].propose_channel(prop, receiver).unwrap();
+    s.deliver();
+
+    s.registries[BOB].reject_proposal(id, "no").unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::ProposalRejected { proposal_id, .. }] if *proposal_id == id
+    ));
+    assert!(matches!(
+        s.registries[BOB].accept_proposal(id, s.rng.gen(), receiver),
+        Err(RegistryError::UnknownProposal(_))
+    ));
+}
+
+#[test]
+fn multiple_channels() {
+    let mut s = Setup::new();
+    let a = s.open();
+    let b = s.open();
+    assert_ne!(a, b);
+
+    s.pay(a, 1);
+    s.pay(b, 2);
+    s.deliver();
+    s.registries[BOB].accept_update(a).unwrap();
+    s.registries[BOB].accept_update(b).unwrap();
+    s.deliver();
+
+    let bob_balance = |id| {
+        s.registries[ALICE]
+            .channel(id)
+            .unwrap()
+            .state()
+            .outcome
+            .balances
+            .0[0]
+            .0[BOB]
+    };
+    assert_eq!(bob_balance(a), 101.into());
+    assert_eq!(bob_balance(b), 102.into());
+}
+
+#[test]
+fn watcher_failure() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    // The channel stays usable, it is up to the application to try again.
+    assert!(matches!(
+        s.registries[ALICE]
+            .handle_watcher_message(WatcherReplyMessage::WatchFailed { id, version: 0 }),
+        Err(RegistryError::WatcherFailed(i)) if i == id
+    ));
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 1);
+}
+
+#[test]
+fn force_close() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.registries[ALICE].force_close(id).unwrap();
+    assert!(matches!(
+        s.registries[ALICE].update(id, s.registries[BOB].channel(id).unwrap().state()),
+        Err(RegistryError::InvalidState(_))
+    ));
+    let [alice, _] = s.deliver();
+    assert!(matches!(&alice[..], [Event::ChannelClosed { .. }]));
+
+    // Bob learns about the dispute from his watcher, Alice registered the
+    // latest state.
+    let event = s.registries[BOB]
+        .handle_watcher_message(WatcherReplyMessage::DisputeNotification { id, version: 0 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelDisputed {
+            version: 0,
+            refuting: false,
+            ..
+        })
+    ));
+    assert!(s.registries[BOB].channel(id).is_none());
+    assert!(s.registries[BOB].disputed_channel(id).is_some());
+}
+
+#[test]
+fn dispute_lifecycle() {
+    let mut s = Setup::new();
+    let id = s.open();
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+
+    // Alice registers the initial state, Bob refutes with the newer one.
+    let event = s.registries[BOB]
+        .handle_watcher_message(WatcherReplyMessage::DisputeNotification { id, version: 0 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelDisputed {
+            version: 0,
+            refuting: true,
+            ..
+        })
+    ));
+    let state = s.registries[BOB].disputed_channel(id).unwrap().state();
+    assert!(matches!(
+        s.registries[BOB].update(id, state.make_next_state()),
+        Err(RegistryError::InvalidState(_))
+    ));
+    let [_, bob] = s.deliver();
+    assert!(matches!(
+        &bob[..],
+        [Event::ChannelDisputed {
+            version: 1,
+            refuting: false,
+            ..
+        }]
+    ));
+
+    let event = s.registries[BOB]
+        .handle_watcher_message(WatcherReplyMessage::Concluded { id, version: 1 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelConcluded { version: 1, .. })
+    ));
+    assert!(s.registries[BOB]
+        .disputed_channel(id)
+        .unwrap()
+        .is_concluded());
+    let event = s.registries[BOB]
+        .handle_watcher_message(WatcherReplyMessage::Withdrawn {
+            id,
+            amounts: [110.into()],
+        })
+        .unwrap();
+    assert!(matches!(event, Some(Event::ChannelSettled(s)) if s.is_complete()));
+    assert!(s.registries[BOB].disputed_channel(id).is_none());
+}
+
+#[test]
+fn concurrent_updates() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.transfer(ALICE, BOB, id, 10);
+    s.transfer(BOB, ALICE, id, 5);
+    // Alice has the lower index, her update wins.
+    let [alice, bob] = s.deliver();
+    assert!(alice.is_empty());
+    assert!(matches!(
+        &bob[..],
+        [Event::UpdateConflict { state, .. }] if state.outcome.balances.0[0].0[BOB] == 110.into()
+    ));
+    s.registries[BOB].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+
+    // Bob tries again.
+    s.transfer(BOB, ALICE, id, 5);
+    s.deliver();
+    s.registries[ALICE].accept_update(id).unwrap();
+    s.deliver();
+    for registry in &s.registries {
+        let channel = registry.channel(id).unwrap();
+        assert_eq!(channel.version(), 2);
+        assert_eq!(
+            channel.state().outcome.balances.0[0].0,
+            [95.into(), 105.into()]
+        );
+    }
+}
+
+#[test]
+fn losing_update_is_never_signed() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    // Bob's update is delayed until Alice's has been accepted.
+    s.transfer(BOB, ALICE, id, 5);
+    let bob_update = match &s.drop_messages()[..] {
+        [ParticipantMessage::ChannelUpdate(msg)] => *msg,
+        msgs => panic!("unexpected messages: {:?}", msgs),
+    };
+    s.transfer(ALICE, BOB, id, 10);
+    let [_, bob] = s.deliver();
+    assert!(matches!(&bob[..], [Event::UpdateConflict { .. }]));
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 1);
+
+    // Alice has signed her own update with that version, Bob's is refused.
+    match s.registries[ALICE].channels.get_mut(&id) {
+        Some(super::Entry::Active(channel)) => assert!(channel.handle_update(bob_update).is_err()),
+        _ => panic!("channel not active"),
+    }
+    assert!(s.drop_messages().is_empty());
+}
+
+#[test]
+fn never_sign_two_states_with_same_version() {
+    let mut s = Setup::new();
+    let id = s.open();
+    s.pay(id, 10);
+
+    let mut other = s.registries[ALICE]
+        .channel(id)
+        .unwrap()
+        .state()
+        .make_next_state();
+    other.outcome.balances.0[0].0[ALICE] -= 20.into();
+    other.outcome.balances.0[0].0[BOB] += 20.into();
+    match s.registries[ALICE].channels.get_mut(&id) {
+        Some(super::Entry::Active(channel)) => assert!(matches!(
+            channel.update(other),
+            Err(ProposeUpdateError::ConflictingSignature)
+        )),
+        _ => panic!("channel not active"),
+    }
+
+    // Once the update is rejected, a different state is fine.
+    s.deliver();
+    s.registries[BOB].reject_update(id, "no").unwrap();
+    s.deliver();
+    s.registries[ALICE].update(id, other).unwrap();
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+    for registry in &s.registries {
+        let channel = registry.channel(id).unwrap();
+        assert_eq!(channel.version(), 1);
+        assert_eq!(
+            channel.state().outcome.balances.0[0].0,
+            [80.into(), 120.into()]
+        );
+    }
+}
+
+#[test]
+fn pay_and_request() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    let channel = s.registries[ALICE].channel(id).unwrap();
+    let asset = channel.state().outcome.assets[0];
+    assert_eq!(channel.my_balance(asset), Some(100.into()));
+    assert!(matches!(
+        channel.payment(asset, 101.into()),
+        Err(TransferError::InsufficientBalance)
+    ));
+    let state = channel.payment(asset, 30.into()).unwrap();
+    s.registries[ALICE].update(id, state).unwrap();
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+
+    let channel = s.registries[BOB].channel(id).unwrap();
+    assert_eq!(channel.my_balance(asset), Some(130.into()));
+    assert_eq!(channel.peer_balance(asset), Some(70.into()));
+
+    // Bob asks Alice to pay more.
+    match s.registries[BOB].channels.get_mut(&id) {
+        Some(super::Entry::Active(channel)) => {
+            assert!(matches!(
+                channel.request(asset, 71.into()),
+                Err(ProposeUpdateError::Transfer(
+                    TransferError::InsufficientBalance
+                ))
+            ));
+            let update = channel.request(asset, 20.into()).unwrap();
+            assert_eq!(update.state().outcome.balance(asset, BOB), Some(150.into()));
+        }
+        _ => panic!("channel not active"),
+    }
+}
+
+#[test]
+fn restored_channel_remembers_signature() {
+    let mut s = Setup::new();
+    let id = s.open();
+    s.pay(id, 10);
+
+    let channel = s.registries[ALICE].channel(id).unwrap();
+    let snapshot = ChannelSnapshot::decode(&channel.snapshot().encode()).unwrap();
+    assert_eq!(snapshot.state.version(), 0);
+    assert_eq!(snapshot.signed.map(|s| s.version), Some(1));
+
+    let client = s.registries[ALICE].client.clone();
+    let mut restored = ActiveChannel::restore(client, snapshot);
+    let mut other = restored.state().make_next_state();
+    other.outcome.balances.0[0].0[ALICE] -= 20.into();
+    other.outcome.balances.0[0].0[BOB] += 20.into();
+    assert!(matches!(
+        restored.update(other),
+        Err(ProposeUpdateError::ConflictingSignature)
+    ));
+
+    // Proposing the state we already signed again is fine.
+    let mut same = restored.state().make_next_state();
+    same.outcome.balances.0[0].0[ALICE] -= 10.into();
+    same.outcome.balances.0[0].0[BOB] += 10.into();
+    restored.update(same).unwrap();
+}
+
+const TIMEOUT: Timeout = Timeout {
+    ticks: 10,
+    retries: 1,
+};
+
+#[test]
+fn proposal_timeout() {
+    let mut s = Setup::with_timeouts(TimeoutPolicy::all(TIMEOUT));
+    let prop = s.proposal();
+    let receiver = s.address(ALICE);
+    let id = s.registries[ALICE].propose_channel(prop, receiver).unwrap();
+    s.drop_messages();
+    assert_eq!(s.registries[ALICE].next_deadline(), Some(Instant(10)));
+
+    assert!(s.timeouts_at(ALICE, 9).is_empty());
+    assert!(s.drop_messages().is_empty());
+
+    // First resend the proposal...
+    assert!(s.timeouts_at(ALICE, 10).is_empty());
+    assert!(matches!(
+        &s.drop_messages()[..],
+        [ParticipantMessage::ChannelProposal(p)] if p.proposal_id == id
+    ));
+
+    // ... then give up and tell the other participant.
+    assert!(matches!(
+        &s.timeouts_at(ALICE, 20)[..],
+        [Event::TimedOut { id: i, phase: Phase::Proposal }] if *i == id
+    ));
+    assert!(matches!(
+        &s.drop_messages()[..],
+        [ParticipantMessage::ProposalRejected { reason, .. }]
+            if RejectReason::from(reason.as_str()) == RejectReason::Timeout
+    ));
+    assert_eq!(s.registries[ALICE].next_deadline(), None);
+}
+
+#[test]
+fn no_timeout_when_answered() {
+    let mut s = Setup::with_timeouts(TimeoutPolicy::all(TIMEOUT));
+    let id = s.open();
+    assert_eq!(s.registries[ALICE].next_deadline(), None);
+    assert_eq!(s.registries[BOB].next_deadline(), None);
+
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+    assert!(s.timeouts_at(ALICE, 100).is_empty());
+    assert!(s.drop_messages().is_empty());
+}
+
+#[test]
+fn update_timeout_force_closes() {
+    let mut s = Setup::with_timeouts(TimeoutPolicy::all(TIMEOUT));
+    let id = s.open();
+
+    s.pay(id, 10);
+    s.drop_messages();
+    assert!(s.timeouts_at(ALICE, 10).is_empty());
+    assert!(matches!(
+        &s.drop_messages()[..],
+        [ParticipantMessage::ChannelUpdate(u)] if u.state.version() == 1
+    ));
+
+    assert!(matches!(
+        &s.timeouts_at(ALICE, 20)[..],
+        [Event::TimedOut {
+            phase: Phase::Update,
+            ..
+        }]
+    ));
+    let [alice, _] = s.deliver();
+    assert!(matches!(&alice[..], [Event::ChannelClosed { .. }]));
+}
+
+#[test]
+fn force_closed_channel_settles() {
+    let mut s = Setup::with_timeouts(TimeoutPolicy::all(TIMEOUT));
+    let id = s.open();
+
+    s.pay(id, 10);
+    s.drop_messages();
+    s.timeouts_at(ALICE, 10);
+    s.drop_messages();
+    s.timeouts_at(ALICE, 20);
+    let [alice, _] = s.deliver();
+    assert!(matches!(&alice[..], [Event::ChannelClosed { .. }]));
+
+    // The channel is kept until the challenge period of our dispute is over
+    // and our funds have been withdrawn.
+    let registry = &mut s.registries[ALICE];
+    let channel = registry.disputed_channel(id).unwrap();
+    assert_eq!(channel.registered_version(), 0);
+    assert!(!channel.is_refuting());
+    let event = registry
+        .handle_watcher_message(WatcherReplyMessage::DisputeNotification { id, version: 0 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelDisputed {
+            version: 0,
+            refuting: false,
+            ..
+        })
+    ));
+
+    let event = registry
+        .handle_watcher_message(WatcherReplyMessage::Concluded { id, version: 0 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelConcluded { version: 0, .. })
+    ));
+    let event = registry
+        .handle_watcher_message(WatcherReplyMessage::Withdrawn {
+            id,
+            amounts: [100.into()],
+        })
+        .unwrap();
+    match event {
+        Some(Event::ChannelSettled(settled)) => {
+            assert_eq!(settled.version(), 0);
+            assert!(settled.is_complete());
+        }
+        e => panic!("unexpected event: {:?}", e),
+    }
+    assert!(registry.channel_ids().all(|c| c != id));
+}
+
+#[test]
+fn funding_timeout_force_closes() {
+    let mut s = Setup::with_timeouts(TimeoutPolicy {
+        funding: Some(Timeout {
+            ticks: 10,
+            retries: 0,
+        }),
+        ..Default::default()
+    });
+    let prop = s.proposal();
+    let receiver = s.address(ALICE);
+    let proposal_id = s.registries[ALICE].propose_channel(prop, receiver).unwrap();
+    s.deliver();
+    let nonce_share = s.rng.gen();
+    let receiver = s.address(BOB);
+    s.registries[BOB]
+        .accept_proposal(proposal_id, nonce_share, receiver)
+        .unwrap();
+
+    // The funder never answers.
+    loop {
+        let next = s.net.queue.borrow_mut().pop_front();
+        let (idx, delivery) = match next {
+            Some(v) => v,
+            None => break,
+        };
+        let registry = &mut s.registries[idx];
+        match delivery {
+            Delivery::Participant(msg) => registry.handle_participant_message(*msg),
+            Delivery::Watcher(msg) => registry.handle_watcher_message(msg),
+            Delivery::Funder(_) => continue,
+        }
+        .unwrap();
+    }
+
+    let events = s.timeouts_at(ALICE, 10);
+    let channel_id = match &events[..] {
+        [Event::TimedOut {
+            id,
+            phase: Phase::Funding,
+        }] => *id,
+        _ => panic!("unexpected events: {:?}", events),
+    };
+    let [alice, _] = s.deliver();
+    assert!(matches!(&alice[..], [Event::ChannelClosed { .. }]));
+    assert!(s.registries[ALICE].disputed_channel(channel_id).is_some());
+}
+
+#[test]
+fn funding_failed_refunds() {
+    let mut s = Setup::new();
+    let prop = s.proposal();
+    let receiver = s.address(ALICE);
+    let proposal_id = s.registries[ALICE].propose_channel(prop, receiver).unwrap();
+    s.deliver();
+    let nonce_share = s.rng.gen();
+    let receiver = s.address(BOB);
+    let id = s.registries[BOB]
+        .accept_proposal(proposal_id, nonce_share, receiver)
+        .unwrap();
+
+    // Alice's funder fails, Bob's never answers.
+    let mut events = vec![];
+    loop {
+        let next = s.net.queue.borrow_mut().pop_front();
+        let (idx, delivery) = match next {
+            Some(v) => v,
+            None => break,
+        };
+        let registry = &mut s.registries[idx];
+        let event = match delivery {
+            Delivery::Participant(msg) => registry.handle_participant_message(*msg),
+            Delivery::Watcher(msg) => registry.handle_watcher_message(msg),
+            Delivery::Funder(_) if idx == ALICE => {
+                registry.handle_funder_message(FunderReplyMessage::FundingFailed { id })
+            }
+            Delivery::Funder(_) => continue,
+        }
+        .unwrap();
+        if idx == ALICE {
+            events.extend(event);
+        }
+    }
+    assert!(matches!(events.last(), Some(Event::FundingFailed { .. })));
+    assert!(s.registries[ALICE].channel(id).is_none());
+
+    // The initial state is registered, Alice gets her deposit back.
+    let registry = &mut s.registries[ALICE];
+    let event = registry
+        .handle_watcher_message(WatcherReplyMessage::DisputeNotification { id, version: 0 })
+        .unwrap();
+    assert!(matches!(
+        event,
+        Some(Event::ChannelDisputed {
+            refuting: false,
+            ..
+        })
+    ));
+    registry
+        .handle_watcher_message(WatcherReplyMessage::Concluded { id, version: 0 })
+        .unwrap();
+    let event = registry
+        .handle_watcher_message(WatcherReplyMessage::Withdrawn {
+            id,
+            amounts: [100.into()],
+        })
+        .unwrap();
+    assert!(matches!(event, Some(Event::ChannelSettled(s)) if s.is_complete()));
+
+    // Bob's channel can't be used without a matching confirmation.
+    let mut channel = match s.registries[BOB].channels.remove(&id) {
+        Some(super::Entry::Signed { channel, .. }) => channel,
+        _ => panic!("channel not signed"),
+    };
+    assert!(matches!(
+        channel.handle_funder_reply(FunderReplyMessage::Funded { id: Hash([1; 32]) }),
+        Err(FundingError::WrongChannel(_))
+    ));
+    assert!(!channel.is_funded());
+    assert!(matches!(
+        channel.mark_funded(),
+        Err((_, FundingError::NotFunded))
+    ));
+}
+
+#[test]
+fn watcher_ack_gates_payments() {
+    let mut s = Setup::new();
+    let id = s.open();
+    s.registries[ALICE].set_require_watcher_ack(true);
+    // The initial state was acknowledged while opening.
+    assert!(s.registries[ALICE].channel(id).unwrap().is_acked());
+
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    // Drop the acknowledgement from Alice's watcher.
+    let mut alice = vec![];
+    loop {
+        let next = s.net.queue.borrow_mut().pop_front();
+        match next {
+            Some((ALICE, Delivery::Participant(msg))) => alice.extend(
+                s.registries[ALICE]
+                    .handle_participant_message(*msg)
+                    .unwrap(),
+            ),
+            Some((ALICE, Delivery::Watcher(_))) | Some((BOB, _)) => {}
+            Some(_) => panic!("unexpected delivery"),
+            None => break,
+        }
+    }
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+    let channel = s.registries[ALICE].channel(id).unwrap();
+    assert_eq!(channel.acked_version(), Some(0));
+    assert!(!channel.is_acked());
+
+    // Paying Bob has to wait, getting paid doesn't.
+    let channel = s.registries[ALICE].channel(id).unwrap();
+    let mut state = channel.state().make_next_state();
+    state.outcome.balances.0[0].0[ALICE] -= 10.into();
+    state.outcome.balances.0[0].0[BOB] += 10.into();
+    assert!(matches!(
+        s.registries[ALICE].update(id, state),
+        Err(RegistryError::ProposeUpdate(ProposeUpdateError::NotAcked))
+    ));
+
+    s.transfer(BOB, ALICE, id, 5);
+    let [alice, _] = s.deliver();
+    assert!(matches!(&alice[..], [Event::UpdateReceived { .. }]));
+    s.registries[ALICE].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::StateAcknowledged { version: 2, .. }]
+    ));
+
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [
+            Event::UpdateAccepted { version: 3, .. },
+            Event::StateAcknowledged { version: 3, .. }
+        ]
+    ));
+}
+
+#[test]
+fn sync_after_lost_acceptance() {
+    let mut s = Setup::new();
+    let id = s.open();
+
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    // The connection drops before Alice receives Bob's signature.
+    s.drop_messages();
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 0);
+    assert_eq!(s.registries[BOB].channel(id).unwrap().version(), 1);
+
+    s.registries[ALICE].sync(id).unwrap();
+    let [alice, bob] = s.deliver();
+    assert!(bob.is_empty());
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 1, .. }]
+    ));
+    let balances = s.registries[ALICE]
+        .channel(id)
+        .unwrap()
+        .state()
+        .outcome
+        .balances
+        .0[0]
+        .0;
+    assert_eq!(balances, [90.into(), 110.into()]);
+
+    // The pending update is gone, the channel is usable again.
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    let [alice, _] = s.deliver();
+    assert!(matches!(
+        &alice[..],
+        [Event::UpdateAccepted { version: 2, .. }]
+    ));
+}
+
+#[test]
+fn sync_adopts_newer_state() {
+    let mut s = Setup::new();
+    let id = s.open();
+    let snapshot = s.registries[ALICE].channel(id).unwrap().snapshot();
+
+    s.pay(id, 10);
+    s.deliver();
+    s.registries[BOB].accept_update(id).unwrap();
+    s.deliver();
+
+    // Alice reboots and only has the initial state in storage.
+    let client = s.registries[ALICE].client.clone();
+    let restored = ActiveChannel::restore(client, snapshot);
+    s.registries[ALICE]
+        .channels
+        .insert(id, super::Entry::Active(restored));
+
+    s.registries[BOB].sync_all().unwrap();
+    let [alice, bob] = s.deliver();
+    assert!(bob.is_empty());
+    assert!(matches!(
+        &alice[..],
+        [Event::ChannelSynced { version: 1, .. }]
+    ));
+
+    // Nothing happens if both are up to date.
+    s.registries[ALICE].sync(id).unwrap();
+    assert!(matches!(s.deliver(), [a, b] if a.is_empty() && b.is_empty()));
+
+    // Only fully signed states are adopted.
+    let channel = s.registries[BOB].channel(id).unwrap();
+    let mut state = channel.state().make_next_state();
+    state.outcome.balances.0[0].0[ALICE] -= 10.into();
+    state.outcome.balances.0[0].0[BOB] += 10.into();
+    let mut msg = LedgerChannelSync::new(state, [Signature::default(); 2]);
+    msg.signatures[BOB] = Some(
+        s.registries[BOB]
+            .client()
+            .signer
+            .sign_eth(abiencode::to_hash(&state).unwrap()),
+    );
+    msg.signatures[ALICE] = msg.signatures[BOB];
+    assert!(matches!(
+        s.registries[ALICE].handle_participant_message(ParticipantMessage::ChannelSync(msg)),
+        Err(RegistryError::Sync(SyncError::InvalidSignature(_)))
+    ));
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 1);
+
+    // go-perun sends an empty slot for Alice's missing signature.
+    msg.signatures[ALICE] = None;
+    let wire = perunwire::ChannelSyncMsg::from(msg);
+    assert!(wire.current_tx.as_ref().unwrap().sigs[ALICE].is_empty());
+    let msg = LedgerChannelSync::try_from(wire).unwrap();
+    assert!(msg.signatures[ALICE].is_none());
+    assert!(msg.signatures[BOB].is_some());
+    assert!(matches!(
+        s.registries[ALICE].handle_participant_message(ParticipantMessage::ChannelSync(msg)),
+        Ok(None)
+    ));
+    assert_eq!(s.registries[ALICE].channel(id).unwrap().version(), 1);
+}
@@ -0,0 +1,190 @@
+//! Persistent storage for channel states.
+//!
+//! A device has to survive a reboot (or power loss) without forgetting the
+//! latest fully signed state of each channel, otherwise it cannot defend
+//! itself in a dispute. This module contains the [ChannelStorage] trait used by
+//! the channel objects to persist states, the [ChannelSnapshot] stored for
+//! each channel and (with the `embedded-storage` feature flag) an
+//! implementation on top of NOR flash.
+
+#[cfg(feature = "embedded-storage")]
+#[cfg_attr(docsrs, doc(cfg(feature = "embedded-storage")))]
+pub mod flash;
+#[cfg(all(feature = "embedded-storage", feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(all(feature = "embedded-storage", feature = "std"))))]
+pub mod sim;
+
+#[cfg(test)]
+#[cfg(all(feature = "embedded-storage", feature = "std"))]
+mod tests;
+
+use crate::{
+    abiencode::types::{Address, Hash, Signature},
+    channel::{fixed_size_payment, PartIdx, Peers},
+    messages::ConversionError,
+    perunwire,
+};
+use alloc::vec::Vec;
+use prost::Message;
+
+const ASSETS: usize = 1;
+const PARTICIPANTS: usize = 2;
+type State = fixed_size_payment::State<ASSETS, PARTICIPANTS>;
+type Params = fixed_size_payment::Params<PARTICIPANTS>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The underlying medium reported an error, for example a failed flash
+    /// write or erase.
+    Medium,
+    /// There is not enough free space left to store the snapshot.
+    OutOfSpace,
+    /// The encoded snapshot is larger than what the storage can hold in a
+    /// single record.
+    RecordTooLarge(usize),
+    /// The stored data could not be decoded.
+    Corrupted,
+    Conversion(ConversionError),
+}
+impl From<ConversionError> for StorageError {
+    fn from(e: ConversionError) -> Self {
+        Self::Conversion(e)
+    }
+}
+
+/// The highest version we have signed and the hash of the state we signed
+/// for it. We must never sign a different state with the same version, so
+/// this has to survive a reboot just like the channel state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedVersion {
+    pub version: u64,
+    pub hash: Hash,
+}
+
+/// Everything needed to restore an
+/// [ActiveChannel][crate::channel::ActiveChannel] after a reboot.
+///
+/// The state is always fully signed, as persisting a partially signed state
+/// does not help in a dispute.
+#[derive(Debug, Clone)]
+pub struct ChannelSnapshot {
+    pub part_idx: PartIdx,
+    pub withdraw_receiver: Address,
+    pub params: Params,
+    pub state: State,
+    pub signatures: [Signature; PARTICIPANTS],
+    pub peers: Peers,
+    /// Set if we have signed a state newer than `state` that is not fully
+    /// signed (yet), for example a pending update proposed by us.
+    pub signed: Option<SignedVersion>,
+}
+
+/// Protobuf representation of [ChannelSnapshot]. We re-use the wire types for
+/// params/state, as we already have conversions for them and protobuf allows
+/// adding fields later without breaking already stored snapshots.
+#[derive(Clone, PartialEq, Message)]
+struct StoredChannel {
+    #[prost(uint32, tag = "1")]
+    part_idx: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    withdraw_receiver: Vec<u8>,
+    #[prost(message, optional, tag = "3")]
+    state: Option<perunwire::SignedState>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    peers: Vec<Vec<u8>>,
+    #[prost(uint64, tag = "5")]
+    signed_version: u64,
+    /// Empty if there is no [SignedVersion].
+    #[prost(bytes = "vec", tag = "6")]
+    signed_hash: Vec<u8>,
+}
+
+impl ChannelSnapshot {
+    pub fn channel_id(&self) -> Hash {
+        self.state.channel_id()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        StoredChannel {
+            part_idx: self.part_idx as u32,
+            withdraw_receiver: self.withdraw_receiver.0.to_vec(),
+            state: Some(perunwire::SignedState {
+                params: Some(self.params.into()),
+                state: Some(self.state.into()),
+                sigs: self.signatures.map(|sig| sig.0.to_vec()).to_vec(),
+            }),
+            peers: self.peers.clone(),
+            signed_version: self.signed.map_or(0, |s| s.version),
+            signed_hash: self.signed.map_or(Vec::new(), |s| s.hash.0.to_vec()),
+        }
+        .encode_to_vec()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, StorageError> {
+        let stored = StoredChannel::decode(buf).or(Err(StorageError::Corrupted))?;
+        let signed_state = stored.state.ok_or(ConversionError::ExptectedSome)?;
+
+        if signed_state.sigs.len() != PARTICIPANTS {
+            return Err(ConversionError::ParticipantSizeMissmatch.into());
+        }
+        let mut signatures = [Signature::default(); PARTICIPANTS];
+        for (a, b) in signatures.iter_mut().zip(signed_state.sigs) {
+            *a = Signature(b.try_into().or(Err(ConversionError::ByteLengthMissmatch))?);
+        }
+
+        let part_idx = stored.part_idx as usize;
+        if part_idx >= PARTICIPANTS {
+            return Err(ConversionError::ParticipantSizeMissmatch.into());
+        }
+
+        let signed = match stored.signed_hash.len() {
+            0 => None,
+            _ => Some(SignedVersion {
+                version: stored.signed_version,
+                hash: Hash(
+                    stored
+                        .signed_hash
+                        .try_into()
+                        .or(Err(ConversionError::ByteLengthMissmatch))?,
+                ),
+            }),
+        };
+
+        Ok(Self {
+            part_idx,
+            withdraw_receiver: Address(
+                stored
+                    .withdraw_receiver
+                    .try_into()
+                    .or(Err(ConversionError::ByteLengthMissmatch))?,
+            ),
+            params: signed_state
+                .params
+                .ok_or(ConversionError::ExptectedSome)?
+                .try_into()?,
+            state: signed_state
+                .state
+                .ok_or(ConversionError::ExptectedSome)?
+                .try_into()?,
+            signatures,
+            peers: stored.peers,
+            signed,
+        })
+    }
+}
+
+/// Storage for the latest fully signed state of each channel.
+///
+/// Implementations must make `store` atomic: If the device loses power while
+/// storing, a later `load` has to return either the previous or the new
+/// snapshot, never a mix of both. The channel objects call `store` before
+/// releasing our signature to other participants, so returning `Ok` means the
+/// snapshot survives a reboot.
+pub trait ChannelStorage {
+    /// Replace the stored snapshot of `snapshot.channel_id()`.
+    fn store(&mut self, snapshot: &ChannelSnapshot) -> Result<(), StorageError>;
+    /// Load the latest snapshot of the channel, if there is one.
+    fn load(&mut self, id: Hash) -> Result<Option<ChannelSnapshot>, StorageError>;
+    /// Forget a channel, for example after it has been settled.
+    fn remove(&mut self, id: Hash) -> Result<(), StorageError>;
+}
@@ -0,0 +1,470 @@
+//! [ChannelStorage] on top of NOR flash, using the `embedded-storage` traits.
+//!
+//! The flash is used as a log: Every snapshot is appended as a new record and
+//! the record with the highest sequence number wins (compared with wrapping,
+//! see [newer]). Records are never
+//! modified in place, which gives us atomic commits and wear leveling:
+//!
+//! - A record consists of a header (containing its own checksum), the payload
+//!   and a commit marker. The commit marker is written in a separate write
+//!   after the header and payload. A record without a complete commit marker
+//!   is ignored, so losing power during a write leaves the previous record of
+//!   that channel as the latest one.
+//! - Sectors are used round-robin. When the current sector is full we move to
+//!   the next (already erased) one, then make sure the sector after that is
+//!   erased, too, by first copying all records still in use into the current
+//!   sector. Because the copies are committed before the erase starts, a power
+//!   loss during garbage collection cannot lose a channel, either.
+//! - Every sector is erased once per round, so the erase cycles are spread
+//!   evenly over the whole partition.
+//!
+//! The partition must consist of at least two sectors and each sector must be
+//! large enough to hold the records of all channels plus one.
+
+use super::{ChannelSnapshot, ChannelStorage, StorageError};
+use crate::abiencode::types::Hash;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use embedded_storage::nor_flash::NorFlash;
+
+const MAGIC: [u8; 4] = *b"PRN1";
+/// magic + sequence number + payload length + channel id + payload checksum +
+/// header checksum.
+const HEADER_SIZE: usize = 4 + 4 + 4 + 32 + 4 + 4;
+const ERASED: u8 = 0xff;
+/// Chunk size used when checking if a region is erased.
+const CHUNK_SIZE: usize = 64;
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+/// CRC-32 (IEEE 802.3). Computed bit by bit to not waste flash on a lookup
+/// table, records are small and written rarely.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Location and metadata of a committed record.
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    sector: usize,
+    offset: usize,
+    seq: u32,
+    key: [u8; 32],
+    len: usize,
+}
+
+enum Header {
+    /// All bytes are still erased, there is no record at this position.
+    Erased,
+    /// Header checksum matches.
+    Valid {
+        seq: u32,
+        len: usize,
+        key: [u8; 32],
+        payload_crc: u32,
+    },
+    /// Neither erased nor a valid header (e.g. a torn write).
+    Invalid,
+}
+
+impl Header {
+    fn encode(seq: u32, key: &[u8; 32], payload: &[u8]) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..8].copy_from_slice(&seq.to_le_bytes());
+        buf[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf[12..44].copy_from_slice(key);
+        buf[44..48].copy_from_slice(&crc32(payload).to_le_bytes());
+        let header_crc = crc32(&buf[..48]);
+        buf[48..52].copy_from_slice(&header_crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; HEADER_SIZE]) -> Self {
+        if buf.iter().all(|&b| b == ERASED) {
+            return Header::Erased;
+        }
+        let header_crc = u32::from_le_bytes(buf[48..52].try_into().unwrap());
+        if buf[0..4] != MAGIC || crc32(&buf[..48]) != header_crc {
+            return Header::Invalid;
+        }
+        Header::Valid {
+            seq: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize,
+            key: buf[12..44].try_into().unwrap(),
+            payload_crc: u32::from_le_bytes(buf[44..48].try_into().unwrap()),
+        }
+    }
+}
+
+/// Whether sequence number `a` was written after `b`. Sequence numbers wrap
+/// around, so this only holds as long as all records on flash are less than
+/// `2^31` writes apart, which is far more than a partition can hold.
+fn newer(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) as i32 > 0
+}
+
+/// Result of scanning a single sector.
+struct SectorScan {
+    records: Vec<Record>,
+    /// Offset after the last record (valid or torn), where the next record
+    /// can be written.
+    end: usize,
+    /// Highest sequence number of any record with a valid header, including
+    /// uncommitted ones.
+    max_seq: Option<u32>,
+}
+
+/// Log-structured, power-fail safe [ChannelStorage] on NOR flash.
+///
+/// Use a dedicated partition of the flash, everything in it belongs to this
+/// storage.
+#[derive(Debug)]
+pub struct FlashStorage<F: NorFlash> {
+    flash: F,
+    sectors: usize,
+    /// Sector we're currently appending to.
+    head: usize,
+    /// Write offset inside of the head sector.
+    offset: usize,
+    next_seq: u32,
+}
+
+impl<F: NorFlash> FlashStorage<F> {
+    /// Mount the storage, restoring the write position from the records on
+    /// flash and finishing garbage collection if it was interrupted.
+    ///
+    /// A new (fully erased) flash partition can be used directly, use
+    /// [Self::format] if it may contain other data.
+    pub fn new(flash: F) -> Result<Self, StorageError> {
+        let sectors = flash.capacity() / F::ERASE_SIZE;
+        if sectors < 2 {
+            return Err(StorageError::OutOfSpace);
+        }
+
+        let mut storage = FlashStorage {
+            flash,
+            sectors,
+            head: 0,
+            offset: 0,
+            next_seq: 0,
+        };
+
+        // The head is the sector containing the most recent record.
+        let mut max_seq: Option<u32> = None;
+        let mut head_end = 0;
+        for sector in 0..sectors {
+            let scan = storage.scan_sector(sector)?;
+            if let Some(seq) = scan.max_seq {
+                if !matches!(max_seq, Some(m) if !newer(seq, m)) {
+                    max_seq = Some(seq);
+                    storage.head = sector;
+                    head_end = scan.end;
+                }
+            }
+        }
+        if max_seq.is_none() {
+            head_end = storage.scan_sector(0)?.end;
+        }
+        storage.next_seq = max_seq.map_or(0, |s| s.wrapping_add(1));
+
+        // Never write behind data we don't understand, treat the sector as
+        // full instead.
+        storage.offset = if storage.is_erased(storage.head, head_end)? {
+            head_end
+        } else {
+            F::ERASE_SIZE
+        };
+
+        storage.ensure_next_free()?;
+        Ok(storage)
+    }
+
+    /// Erase the whole partition and mount it. All stored channels are lost.
+    pub fn format(mut flash: F) -> Result<Self, StorageError> {
+        let capacity = flash.capacity() / F::ERASE_SIZE * F::ERASE_SIZE;
+        flash
+            .erase(0, capacity as u32)
+            .or(Err(StorageError::Medium))?;
+        Self::new(flash)
+    }
+
+    /// Give back the underlying flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Continue with sequence number `seq`, to test the wrap around.
+    #[cfg(test)]
+    pub(super) fn set_next_seq(&mut self, seq: u32) {
+        self.next_seq = seq;
+    }
+
+    /// Start and end of the region written by a record with the given payload
+    /// length, relative to the start of the record. The commit marker is
+    /// located at `commit_offset(len)`.
+    fn commit_offset(len: usize) -> usize {
+        align_up(HEADER_SIZE + len, F::WRITE_SIZE)
+    }
+    fn commit_len() -> usize {
+        align_up(4, F::WRITE_SIZE)
+    }
+    fn record_alignment() -> usize {
+        F::WRITE_SIZE.max(4)
+    }
+    fn record_len(len: usize) -> usize {
+        align_up(
+            Self::commit_offset(len) + Self::commit_len(),
+            Self::record_alignment(),
+        )
+    }
+
+    fn sector_addr(sector: usize) -> usize {
+        sector * F::ERASE_SIZE
+    }
+
+    fn next_sector(&self, sector: usize) -> usize {
+        (sector + 1) % self.sectors
+    }
+
+    /// Read at an arbitrary (not necessarily READ_SIZE aligned) position.
+    fn read(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        let start = addr - addr % F::READ_SIZE;
+        let end = align_up(addr + buf.len(), F::READ_SIZE);
+        if start == addr && end == addr + buf.len() {
+            return self
+                .flash
+                .read(addr as u32, buf)
+                .or(Err(StorageError::Medium));
+        }
+
+        let mut tmp = vec![0u8; end - start];
+        self.flash
+            .read(start as u32, &mut tmp)
+            .or(Err(StorageError::Medium))?;
+        buf.copy_from_slice(&tmp[addr - start..addr - start + buf.len()]);
+        Ok(())
+    }
+
+    /// Check if everything in `sector` starting at `from` is erased.
+    fn is_erased(&mut self, sector: usize, from: usize) -> Result<bool, StorageError> {
+        let mut buf = [0u8; CHUNK_SIZE];
+        let mut offset = from;
+        while offset < F::ERASE_SIZE {
+            let len = CHUNK_SIZE.min(F::ERASE_SIZE - offset);
+            self.read(Self::sector_addr(sector) + offset, &mut buf[..len])?;
+            if buf[..len].iter().any(|&b| b != ERASED) {
+                return Ok(false);
+            }
+            offset += len;
+        }
+        Ok(true)
+    }
+
+    fn scan_sector(&mut self, sector: usize) -> Result<SectorScan, StorageError> {
+        let base = Self::sector_addr(sector);
+        let align = Self::record_alignment();
+        let mut scan = SectorScan {
+            records: Vec::new(),
+            end: 0,
+            max_seq: None,
+        };
+
+        let mut offset = 0;
+        // When finding an invalid header (torn write) we search for the next
+        // record in the following bytes. Writes are sequential, so a torn
+        // header cannot be longer than HEADER_SIZE and the next record (if we
+        // wrote one after rebooting) starts right after it.
+        let mut resync_limit: Option<usize> = None;
+        while offset + HEADER_SIZE <= F::ERASE_SIZE {
+            let mut buf = [0u8; HEADER_SIZE];
+            self.read(base + offset, &mut buf)?;
+
+            match Header::decode(&buf) {
+                Header::Erased => break,
+                Header::Invalid => {
+                    let limit = *resync_limit.get_or_insert(offset + HEADER_SIZE);
+                    offset += align;
+                    scan.end = offset;
+                    if offset > limit {
+                        // Not a torn write but garbage, don't trust anything
+                        // in the rest of this sector.
+                        scan.end = F::ERASE_SIZE;
+                        break;
+                    }
+                }
+                Header::Valid {
+                    seq,
+                    len,
+                    key,
+                    payload_crc,
+                } => {
+                    resync_limit = None;
+                    let total = Self::record_len(len);
+                    if offset + total > F::ERASE_SIZE {
+                        scan.end = F::ERASE_SIZE;
+                        break;
+                    }
+                    scan.max_seq = match scan.max_seq {
+                        Some(m) if !newer(seq, m) => Some(m),
+                        _ => Some(seq),
+                    };
+
+                    let mut commit = vec![0u8; Self::commit_len()];
+                    self.read(base + offset + Self::commit_offset(len), &mut commit)?;
+                    if commit.iter().all(|&b| b == 0) {
+                        let mut payload = vec![0u8; len];
+                        self.read(base + offset + HEADER_SIZE, &mut payload)?;
+                        if crc32(&payload) == payload_crc {
+                            scan.records.push(Record {
+                                sector,
+                                offset,
+                                seq,
+                                key,
+                                len,
+                            });
+                        }
+                    }
+
+                    offset += total;
+                    scan.end = offset;
+                }
+            }
+        }
+
+        Ok(scan)
+    }
+
+    /// Latest record of each channel (including removal records).
+    fn index(&mut self) -> Result<BTreeMap<[u8; 32], Record>, StorageError> {
+        let mut index: BTreeMap<[u8; 32], Record> = BTreeMap::new();
+        for sector in 0..self.sectors {
+            for record in self.scan_sector(sector)?.records {
+                match index.get(&record.key) {
+                    Some(r) if newer(r.seq, record.seq) => {}
+                    _ => {
+                        index.insert(record.key, record);
+                    }
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn read_payload(&mut self, record: &Record) -> Result<Vec<u8>, StorageError> {
+        let mut payload = vec![0u8; record.len];
+        self.read(
+            Self::sector_addr(record.sector) + record.offset + HEADER_SIZE,
+            &mut payload,
+        )?;
+        Ok(payload)
+    }
+
+    /// Write a record at the current position in the head sector. The caller
+    /// has to make sure it fits.
+    fn write_record(&mut self, key: &[u8; 32], payload: &[u8]) -> Result<(), StorageError> {
+        let total = Self::record_len(payload.len());
+        debug_assert!(self.offset + total <= F::ERASE_SIZE);
+
+        let addr = Self::sector_addr(self.head) + self.offset;
+        let commit_offset = Self::commit_offset(payload.len());
+
+        // Header and payload in a single write...
+        let mut buf = vec![ERASED; commit_offset];
+        buf[..HEADER_SIZE].copy_from_slice(&Header::encode(self.next_seq, key, payload));
+        buf[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        // Reserve the space before writing, so a failed write does not result
+        // in a second write to the same location.
+        self.offset += total;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.flash
+            .write(addr as u32, &buf)
+            .or(Err(StorageError::Medium))?;
+
+        // ...then commit it.
+        let commit = vec![0u8; Self::commit_len()];
+        self.flash
+            .write((addr + commit_offset) as u32, &commit)
+            .or(Err(StorageError::Medium))
+    }
+
+    /// Make sure the sector after the head is erased, copying records that are
+    /// still in use to the head sector first.
+    fn ensure_next_free(&mut self) -> Result<(), StorageError> {
+        let next = self.next_sector(self.head);
+        if self.is_erased(next, 0)? {
+            return Ok(());
+        }
+
+        let index = self.index()?;
+        let scan = self.scan_sector(next)?;
+        for record in scan.records {
+            // Only copy the latest record of each channel. Removal records can
+            // be dropped: All older records of that channel are in this
+            // sector, too, as it is the oldest one.
+            let is_latest = matches!(index.get(&record.key), Some(r) if r.seq == record.seq);
+            if !is_latest || record.len == 0 {
+                continue;
+            }
+            if self.offset + Self::record_len(record.len) > F::ERASE_SIZE {
+                return Err(StorageError::OutOfSpace);
+            }
+            let payload = self.read_payload(&record)?;
+            self.write_record(&record.key, &payload)?;
+        }
+
+        let from = Self::sector_addr(next) as u32;
+        self.flash
+            .erase(from, from + F::ERASE_SIZE as u32)
+            .or(Err(StorageError::Medium))
+    }
+
+    fn append(&mut self, key: &[u8; 32], payload: &[u8]) -> Result<(), StorageError> {
+        let total = Self::record_len(payload.len());
+        if total > F::ERASE_SIZE {
+            return Err(StorageError::RecordTooLarge(payload.len()));
+        }
+
+        if self.offset + total > F::ERASE_SIZE {
+            // Move to the next sector, which is always erased at this point.
+            self.head = self.next_sector(self.head);
+            self.offset = 0;
+            self.ensure_next_free()?;
+            if self.offset + total > F::ERASE_SIZE {
+                return Err(StorageError::OutOfSpace);
+            }
+        }
+
+        self.write_record(key, payload)
+    }
+}
+
+impl<F: NorFlash> ChannelStorage for FlashStorage<F> {
+    fn store(&mut self, snapshot: &ChannelSnapshot) -> Result<(), StorageError> {
+        self.append(&snapshot.channel_id().0, &snapshot.encode())
+    }
+
+    fn load(&mut self, id: Hash) -> Result<Option<ChannelSnapshot>, StorageError> {
+        let record = match self.index()?.get(&id.0) {
+            Some(r) if r.len > 0 => *r,
+            _ => return Ok(None),
+        };
+        let payload = self.read_payload(&record)?;
+        ChannelSnapshot::decode(&payload).map(Some)
+    }
+
+    fn remove(&mut self, id: Hash) -> Result<(), StorageError> {
+        // An empty payload marks the channel as removed. Snapshots are never
+        // empty, as they always contain the state.
+        self.append(&id.0, &[])
+    }
+}
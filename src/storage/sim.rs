@@ -0,0 +1,177 @@
+//! In-memory NOR flash for testing storage backends on the host.
+//!
+//! Behaves like real NOR flash (writes can only clear bits, erasing sets a
+//! whole sector to `0xff`) and allows simulating a power loss in the middle of
+//! a write or erase.
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulatedFlashError {
+    NotAligned,
+    OutOfBounds,
+    /// The (simulated) device lost power, call
+    /// [SimulatedFlash::power_cycle] to continue.
+    PowerLoss,
+}
+
+impl NorFlashError for SimulatedFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            SimulatedFlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            SimulatedFlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            SimulatedFlashError::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for SimulatedFlashError {
+    fn from(e: NorFlashErrorKind) -> Self {
+        match e {
+            NorFlashErrorKind::NotAligned => SimulatedFlashError::NotAligned,
+            _ => SimulatedFlashError::OutOfBounds,
+        }
+    }
+}
+
+/// Simulated NOR flash with `WRITE_SIZE` bytes write granularity and sectors of
+/// `ERASE_SIZE` bytes.
+///
+/// Each programmed write unit and each erased sector counts as one operation.
+/// With [Self::fail_after] the power is cut while performing the given
+/// operation: A write unit is then only partially programmed and an erase only
+/// erases half of the sector. All further accesses fail until
+/// [Self::power_cycle] is called.
+#[derive(Debug, Clone)]
+pub struct SimulatedFlash<const WRITE_SIZE: usize, const ERASE_SIZE: usize> {
+    data: Vec<u8>,
+    erase_counts: Vec<usize>,
+    operations: usize,
+    fail_at: Option<usize>,
+    powered: bool,
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> SimulatedFlash<WRITE_SIZE, ERASE_SIZE> {
+    /// Create a fully erased flash with `sectors` sectors.
+    pub fn new(sectors: usize) -> Self {
+        SimulatedFlash {
+            data: vec![0xff; sectors * ERASE_SIZE],
+            erase_counts: vec![0; sectors],
+            operations: 0,
+            fail_at: None,
+            powered: true,
+        }
+    }
+
+    /// Lose power while performing the operation with index `operations`,
+    /// counted from now. `fail_after(0)` fails the next operation.
+    pub fn fail_after(&mut self, operations: usize) {
+        self.fail_at = Some(self.operations + operations);
+    }
+
+    /// Restore power after a simulated power loss.
+    pub fn power_cycle(&mut self) {
+        self.fail_at = None;
+        self.powered = true;
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Number of write units programmed and sectors erased so far.
+    pub fn operations(&self) -> usize {
+        self.operations
+    }
+
+    /// How often each sector has been erased.
+    pub fn erase_counts(&self) -> &[usize] {
+        &self.erase_counts
+    }
+
+    /// Count an operation, returns `true` if the power is lost during it.
+    fn next_operation_fails(&mut self) -> bool {
+        let fails = self.fail_at == Some(self.operations);
+        self.operations += 1;
+        if fails {
+            self.powered = false;
+        }
+        fails
+    }
+
+    fn ensure_powered(&self) -> Result<(), SimulatedFlashError> {
+        if self.powered {
+            Ok(())
+        } else {
+            Err(SimulatedFlashError::PowerLoss)
+        }
+    }
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> ErrorType
+    for SimulatedFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    type Error = SimulatedFlashError;
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> ReadNorFlash
+    for SimulatedFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.ensure_powered()?;
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> NorFlash
+    for SimulatedFlash<WRITE_SIZE, ERASE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.ensure_powered()?;
+        check_erase(self, from, to)?;
+        for start in (from as usize..to as usize).step_by(ERASE_SIZE) {
+            if self.next_operation_fails() {
+                self.data[start..start + ERASE_SIZE / 2].fill(0xff);
+                return Err(SimulatedFlashError::PowerLoss);
+            }
+            self.data[start..start + ERASE_SIZE].fill(0xff);
+            self.erase_counts[start / ERASE_SIZE] += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.ensure_powered()?;
+        check_write(self, offset, bytes.len())?;
+        for (i, unit) in bytes.chunks(WRITE_SIZE).enumerate() {
+            let start = offset as usize + i * WRITE_SIZE;
+            let (unit, fails) = match self.next_operation_fails() {
+                true => (&unit[..unit.len() / 2], true),
+                false => (unit, false),
+            };
+            // NOR flash can only clear bits.
+            for (dst, src) in self.data[start..].iter_mut().zip(unit) {
+                *dst &= src;
+            }
+            if fails {
+                return Err(SimulatedFlashError::PowerLoss);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use super::{
+    flash::FlashStorage, sim::SimulatedFlash, ChannelSnapshot, ChannelStorage, SignedVersion,
+    StorageError,
+};
+use crate::{
+    abiencode::types::{Address, Hash, Signature, U256},
+    channel::{
+        fixed_size_payment::{Allocation, Balances, Params, ParticipantBalances, State},
+        Asset,
+    },
+};
+use embedded_storage::nor_flash::NorFlash;
+
+// Small sectors, so the tests frequently move to the next sector and collect
+// garbage.
+type Flash = SimulatedFlash<4, 2048>;
+const SECTORS: usize = 4;
+
+fn snapshot(nonce: u64, version: u64) -> ChannelSnapshot {
+    let params = Params {
+        challenge_duration: 100,
+        nonce: U256::from(nonce),
+        participants: [Address([1; 20]), Address([2; 20])],
+        app: Address::default(),
+        ledger_channel: true,
+        virtual_channel: false,
+    };
+    let alloc = Allocation::new(
+        [Asset {
+            chain_id: U256::from(1337),
+            holder: Address([3; 20]),
+        }],
+        Balances([ParticipantBalances([100.into(), 100.into()])]),
+    );
+    let mut state = State::new(params, alloc).unwrap();
+    for _ in 0..version {
+        state = state.make_next_state();
+    }
+
+    ChannelSnapshot {
+        part_idx: 1,
+        withdraw_receiver: Address([4; 20]),
+        params,
+        state,
+        signatures: [Signature([5; 65]), Signature([6; 65])],
+        peers: vec![b"Alice".to_vec(), b"Bob".to_vec()],
+        signed: None,
+    }
+}
+
+fn load_version(storage: &mut FlashStorage<impl NorFlash>, nonce: u64) -> Option<u64> {
+    let id = snapshot(nonce, 0).channel_id();
+    storage.load(id).unwrap().map(|s| s.state.version())
+}
+
+#[test]
+fn store_and_load() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    let s = snapshot(1, 3);
+    storage.store(&s).unwrap();
+
+    let loaded = storage.load(s.channel_id()).unwrap().unwrap();
+    assert_eq!(loaded.encode(), s.encode());
+    assert_eq!(load_version(&mut storage, 2), None);
+}
+
+#[test]
+fn signed_version_is_stored() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    let mut s = snapshot(1, 3);
+    let signed = SignedVersion {
+        version: 4,
+        hash: Hash([7; 32]),
+    };
+    s.signed = Some(signed);
+    storage.store(&s).unwrap();
+
+    let loaded = storage.load(s.channel_id()).unwrap().unwrap();
+    assert_eq!(loaded.signed, Some(signed));
+}
+
+#[test]
+fn latest_snapshot_survives_remount() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    for version in 0..20 {
+        storage.store(&snapshot(1, version)).unwrap();
+        storage.store(&snapshot(2, 100 + version)).unwrap();
+    }
+
+    let mut storage = FlashStorage::new(storage.release()).unwrap();
+    assert_eq!(load_version(&mut storage, 1), Some(19));
+    assert_eq!(load_version(&mut storage, 2), Some(119));
+}
+
+#[test]
+fn sequence_number_wraps_around() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    storage.set_next_seq(u32::MAX - 5);
+    for version in 0..20 {
+        storage.store(&snapshot(1, version)).unwrap();
+        assert_eq!(load_version(&mut storage, 1), Some(version));
+    }
+
+    let mut storage = FlashStorage::new(storage.release()).unwrap();
+    assert_eq!(load_version(&mut storage, 1), Some(19));
+    storage.store(&snapshot(1, 20)).unwrap();
+    assert_eq!(load_version(&mut storage, 1), Some(20));
+}
+
+#[test]
+fn remove() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    storage.store(&snapshot(1, 1)).unwrap();
+    storage.store(&snapshot(2, 1)).unwrap();
+    storage.remove(snapshot(1, 0).channel_id()).unwrap();
+    assert_eq!(load_version(&mut storage, 1), None);
+
+    // Removed channels must stay removed after garbage collection.
+    for version in 2..30 {
+        storage.store(&snapshot(2, version)).unwrap();
+    }
+    let mut storage = FlashStorage::new(storage.release()).unwrap();
+    assert_eq!(load_version(&mut storage, 1), None);
+    assert_eq!(load_version(&mut storage, 2), Some(29));
+}
+
+#[test]
+fn wear_leveling() {
+    let mut storage = FlashStorage::new(Flash::new(SECTORS)).unwrap();
+    for version in 0..200 {
+        storage.store(&snapshot(1, version)).unwrap();
+    }
+
+    let flash = storage.release();
+    let counts = flash.erase_counts();
+    let min = counts.iter().min().unwrap();
+    let max = counts.iter().max().unwrap();
+    assert!(*min > 0);
+    assert!(max - min <= 1, "uneven wear: {:?}", counts);
+}
+
+#[test]
+fn record_too_large() {
+    let mut storage = FlashStorage::new(SimulatedFlash::<4, 256>::new(SECTORS)).unwrap();
+    assert!(matches!(
+        storage.store(&snapshot(1, 0)),
+        Err(StorageError::RecordTooLarge(_))
+    ));
+}
+
+/// Lose power at every possible point while storing a snapshot and check that
+/// after rebooting we get either the old or the new snapshot, and that other
+/// channels (which may have to be moved during garbage collection) are not
+/// affected.
+#[test]
+fn power_loss_at_every_operation() {
+    // Enough updates to cross several sector boundaries.
+    for updates in 1..12 {
+        let mut flash = Flash::new(SECTORS);
+        {
+            let mut storage = FlashStorage::new(&mut flash).unwrap();
+            storage.store(&snapshot(2, 7)).unwrap();
+            for version in 0..updates {
+                storage.store(&snapshot(1, version)).unwrap();
+            }
+        }
+
+        // Count the operations needed for the next store.
+        let mut reference = flash.clone();
+        let start = reference.operations();
+        let mut storage = FlashStorage::new(&mut reference).unwrap();
+        storage.store(&snapshot(1, updates)).unwrap();
+        let operations = reference.operations() - start;
+
+        for fail_at in 0..operations {
+            let mut flash = flash.clone();
+            flash.fail_after(fail_at);
+            let mut storage = FlashStorage::new(&mut flash).unwrap();
+            assert!(storage.store(&snapshot(1, updates)).is_err());
+            assert!(!flash.is_powered());
+            flash.power_cycle();
+
+            let mut storage = FlashStorage::new(&mut flash).unwrap();
+            let version = load_version(&mut storage, 1).unwrap();
+            assert!(
+                version == updates - 1 || version == updates,
+                "unexpected version {} after failing at {}/{}",
+                version,
+                fail_at,
+                operations
+            );
+            assert_eq!(load_version(&mut storage, 2), Some(7));
+
+            // The storage must still be usable after the power loss.
+            for version in updates + 1..updates + 8 {
+                storage.store(&snapshot(1, version)).unwrap();
+            }
+            let mut storage = FlashStorage::new(&mut flash).unwrap();
+            assert_eq!(load_version(&mut storage, 1), Some(updates + 7));
+            assert_eq!(load_version(&mut storage, 2), Some(7));
+        }
+    }
+}
+
+/// Lose power while mounting, which may finish an interrupted garbage
+/// collection.
+#[test]
+fn power_loss_during_mount() {
+    let mut flash = Flash::new(SECTORS);
+    {
+        let mut storage = FlashStorage::new(&mut flash).unwrap();
+        storage.store(&snapshot(2, 7)).unwrap();
+        for version in 0..9 {
+            storage.store(&snapshot(1, version)).unwrap();
+        }
+    }
+
+    // Interrupt garbage collection while storing, then again while mounting.
+    for first in 0..40 {
+        for second in 0..10 {
+            let mut flash = flash.clone();
+            flash.fail_after(first);
+            if let Ok(mut storage) = FlashStorage::new(&mut flash) {
+                let _ = storage.store(&snapshot(1, 9));
+            }
+            flash.power_cycle();
+            flash.fail_after(second);
+            let _ = FlashStorage::new(&mut flash);
+            flash.power_cycle();
+
+            let mut storage = FlashStorage::new(&mut flash).unwrap();
+            let version = load_version(&mut storage, 1).unwrap();
+            assert!(version == 8 || version == 9);
+            assert_eq!(load_version(&mut storage, 2), Some(7));
+        }
+    }
+}
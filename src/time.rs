@@ -0,0 +1,126 @@
+//! Monotonic time and timeouts for the phases in which we wait for someone
+//! else.
+//!
+//! Time is measured in ticks of a [Clock] provided by the application, the
+//! length of a tick (milliseconds, timer ticks, ...) is up to the application,
+//! as long as the [TimeoutPolicy] uses the same unit. This works in `no_std`,
+//! where there is no common notion of time.
+
+/// Point in time, in ticks of the [Clock] it was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Instant(pub u64);
+
+impl Instant {
+    pub fn saturating_add(self, ticks: u64) -> Self {
+        Instant(self.0.saturating_add(ticks))
+    }
+}
+
+/// Source of monotonic time, it must never go backwards.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Clock that never advances, used when no timeouts are configured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now(&self) -> Instant {
+        Instant(0)
+    }
+}
+
+/// Clock based on [std::time::Instant] with a resolution of milliseconds.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        StdClock {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant(self.start.elapsed().as_millis() as u64)
+    }
+}
+
+/// The phases in which we wait for someone else and what happens if they
+/// don't answer in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the other participant to accept our proposal. Resends the
+    /// proposal, then rejects it.
+    Proposal,
+    /// Waiting for the signature on the initial state. Resends our signature,
+    /// then aborts opening the channel. Nothing has been funded at this point.
+    Signing,
+    /// Waiting for the funder (and the watcher). Resends the state to the
+    /// watcher, then force-closes the channel to get our deposit back. The
+    /// funder is never asked twice, as that could lead to depositing twice.
+    Funding,
+    /// Waiting for the other participant to sign our update. Resends the
+    /// update, then force-closes the channel with the last fully signed state.
+    Update,
+    /// Waiting for the watcher to acknowledge the final state. Resends it,
+    /// then force-closes the channel.
+    Closing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    /// How long to wait for an answer, in ticks.
+    pub ticks: u64,
+    /// How often to resend the last message before giving up, waiting `ticks`
+    /// after each one.
+    pub retries: u8,
+}
+
+/// Timeouts for each [Phase], `None` means waiting forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeoutPolicy {
+    pub proposal: Option<Timeout>,
+    pub signing: Option<Timeout>,
+    pub funding: Option<Timeout>,
+    pub update: Option<Timeout>,
+    pub closing: Option<Timeout>,
+}
+
+impl TimeoutPolicy {
+    /// Use the same timeout for all phases.
+    pub fn all(timeout: Timeout) -> Self {
+        TimeoutPolicy {
+            proposal: Some(timeout),
+            signing: Some(timeout),
+            funding: Some(timeout),
+            update: Some(timeout),
+            closing: Some(timeout),
+        }
+    }
+
+    pub fn get(&self, phase: Phase) -> Option<Timeout> {
+        match phase {
+            Phase::Proposal => self.proposal,
+            Phase::Signing => self.signing,
+            Phase::Funding => self.funding,
+            Phase::Update => self.update,
+            Phase::Closing => self.closing,
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! TCP transport on top of tokio, for applications running on a desktop or
+//! server.
+//!
+//! [TcpTransport] implements [BytesBus], so it can be put into a
+//! [ProtoBufEncodingLayer][crate::wire::ProtoBufEncodingLayer] and used with
+//! [PerunClient][crate::PerunClient] like any other bus. It keeps one TCP
+//! connection per peer, keyed by its [Identity], and one to the remote
+//! watcher/funder service. All messages use the 2 byte big-endian length
+//! prefix.
+//!
+//! Peers can be dialed explicitly ([TcpTransport::dial]) or put into the
+//! [AddressBook], in which case the first message sent to them opens the
+//! connection in the background. Either way, an open connection is reused for
+//! all messages to that peer, no matter which side opened it.
+//!
+//! Both sides have to authenticate a connection with the go-perun handshake
+//! (see [PeerAuth][crate::wire::PeerAuth]) before it is used for anything
+//! else: The first message from the peer has to be a valid `Auth` message,
+//! otherwise the connection is closed, and until then we only send our own
+//! `Auth` message over it.
+//!
+//! Sending never blocks: Each connection has a queue of [SEND_QUEUE_LEN]
+//! frames, which a background task writes to the socket. If it is full,
+//! sending fails with [BusError::Busy]. Received messages are decoded in the
+//! background, too, and handed to the application through the channel
+//! returned by [TcpTransport::new], in the order they arrived on each
+//! connection.
+//!
+//! The background tasks are started with [tokio::spawn], so all methods that
+//! connect have to be called from within a tokio runtime.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::HashMap,
+    future::{self, Future},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream, ToSocketAddrs},
+    runtime::Handle,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    messages::{ParticipantMessage, ServiceReplyMessage},
+    sig::Signer,
+    wire::{
+        decode_participant_message, decode_service_message, verify_identity, AddressBook, BusError,
+        BytesBus, DecodeError, Identity, ParticipantEnvelope,
+    },
+};
+
+/// Number of frames that can wait to be written to each connection.
+pub const SEND_QUEUE_LEN: usize = 32;
+/// Time an incoming connection has to send its `Auth` message before it is
+/// closed.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened on one of the connections of a [TcpTransport].
+#[derive(Debug)]
+pub enum Received {
+    /// A message from the peer with the given identity. The sender in the
+    /// envelope is whatever the peer claims, use
+    /// [PeerAuth][crate::wire::PeerAuth] to check it.
+    Participant(Identity, Box<ParticipantEnvelope>),
+    /// A reply from the watcher or funder.
+    Service(ServiceReplyMessage),
+    /// A frame that could not be decoded, from the peer with the given
+    /// identity or from the service (`None`). The connection stays open.
+    Invalid(Option<Identity>, DecodeError),
+    /// The connection to the peer (or the service, `None`) was closed, or
+    /// dialing the peer failed.
+    Disconnected(Option<Identity>),
+}
+
+/// Sending half of a connection, the receiving half runs in its own task.
+#[derive(Debug)]
+struct Connection {
+    /// Distinguishes connections to the same peer, so a closing connection
+    /// does not remove the one that replaced it.
+    id: u64,
+    frames: mpsc::Sender<Vec<u8>>,
+    reader: JoinHandle<()>,
+    /// The peer proved its identity with an `Auth` message on this
+    /// connection.
+    authenticated: bool,
+    /// The identity we sent our `Auth` message for, the peer only accepts
+    /// messages from it on this connection.
+    local: Option<Identity>,
+}
+
+impl Connection {
+    fn send(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.frames.try_send(msg.to_vec()).map_err(|e| match e {
+            TrySendError::Full(_) => BusError::Busy,
+            TrySendError::Closed(_) => BusError::NotConnected,
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The writer stops once the queue is dropped.
+        self.reader.abort();
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    peers: Mutex<HashMap<Identity, Connection>>,
+    service: Mutex<Option<Connection>>,
+    addresses: Mutex<AddressBook<SocketAddr>>,
+    received: mpsc::UnboundedSender<Received>,
+    next_id: AtomicU64,
+    /// Only used to check the signature of incoming `Auth` messages, which
+    /// does not need our own key.
+    verifier: Signer,
+}
+
+/// Connections to other participants and the watcher/funder service, see the
+/// [module documentation][self].
+///
+/// Cloning it gives another handle to the same connections.
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    shared: Arc<Shared>,
+}
+
+impl TcpTransport {
+    /// Create a transport without any connections, returns it together with
+    /// the channel on which everything received is reported.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Received>) {
+        Self::with_addresses(AddressBook::new())
+    }
+
+    /// Like [TcpTransport::new], but peers in `addresses` are dialed when
+    /// something is sent to them.
+    pub fn with_addresses(
+        addresses: AddressBook<SocketAddr>,
+    ) -> (Self, mpsc::UnboundedReceiver<Received>) {
+        let (received, rx) = mpsc::unbounded_channel();
+        let shared = Shared {
+            peers: Mutex::new(HashMap::new()),
+            service: Mutex::new(None),
+            addresses: Mutex::new(addresses),
+            received,
+            next_id: AtomicU64::new(0),
+            verifier: Signer::new(&mut rand::thread_rng()),
+        };
+        let transport = Self {
+            shared: Arc::new(shared),
+        };
+        (transport, rx)
+    }
+
+    /// Connect to the remote watcher/funder service, replacing the previous
+    /// connection, if any.
+    pub async fn connect_service(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        let (read, write) = stream.into_split();
+        let id = self.next_id();
+        let shared = self.shared.clone();
+        let (frames, queued) = mpsc::channel(SEND_QUEUE_LEN);
+        spawn_writer(write, queued);
+        let reader = tokio::spawn(async move {
+            let mut read = read;
+            while let Ok(frame) = read_frame(&mut read).await {
+                let msg = match decode_service_message(&frame) {
+                    Ok(msg) => Received::Service(msg),
+                    Err(e) => Received::Invalid(None, e),
+                };
+                let _ = shared.received.send(msg);
+            }
+            let mut service = shared.service.lock().unwrap();
+            if service.as_ref().is_some_and(|c| c.id == id) {
+                *service = None;
+                let _ = shared.received.send(Received::Disconnected(None));
+            }
+        });
+        let conn = Connection {
+            id,
+            frames,
+            reader,
+            authenticated: true,
+            local: None,
+        };
+        *self.shared.service.lock().unwrap() = Some(conn);
+        Ok(())
+    }
+
+    /// Open a connection to the peer with the given identity, replacing the
+    /// previous connection to it, if any.
+    ///
+    /// The peer only knows who we are once we've sent our `Auth` message
+    /// ([PerunClient::send_handshake_msg][crate::PerunClient::send_handshake_msg]),
+    /// which should be the first thing to do after connecting. Nothing else
+    /// can be sent until the peer answered with its own `Auth` message.
+    pub async fn dial(&self, identity: Identity, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut peers = self.shared.peers.lock().unwrap();
+        self.add_peer(&mut peers, identity, future::ready(Ok(stream)), None);
+        Ok(())
+    }
+
+    /// Set the address of a peer, returns the previous one. Takes effect the
+    /// next time we're not connected to the peer and send something to it.
+    pub fn set_address(&self, identity: Identity, addr: SocketAddr) -> Option<SocketAddr> {
+        self.shared.addresses.lock().unwrap().insert(identity, addr)
+    }
+
+    /// Forget the address of a peer, without closing the connection to it.
+    pub fn remove_address(&self, identity: &Identity) -> Option<SocketAddr> {
+        self.shared.addresses.lock().unwrap().remove(identity)
+    }
+
+    /// Accept connections from other participants in the background, returns
+    /// the address we're listening on.
+    ///
+    /// The first message on an incoming connection has to be an `Auth` message
+    /// with a valid signature, sent within [AUTH_TIMEOUT]. Its sender is the
+    /// identity of the connection. Connections starting with anything else
+    /// are closed, as are connections from peers we're already connected to:
+    /// The go-perun handshake can be replayed by anyone who saw it (see
+    /// [PeerAuth][crate::wire::PeerAuth]), so it must not take over an
+    /// existing connection.
+    pub async fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        let transport = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(transport.clone().accept(stream));
+            }
+        });
+        Ok(local)
+    }
+
+    async fn accept(self, mut stream: TcpStream) {
+        let env = match tokio::time::timeout(AUTH_TIMEOUT, read_frame(&mut stream))
+            .await
+            .map(|f| f.map(|f| decode_participant_message(&f)))
+        {
+            Ok(Ok(Ok(env))) => env,
+            _ => return,
+        };
+        let signature = match env.msg {
+            ParticipantMessage::Auth { signature } => signature,
+            _ => return,
+        };
+        // Plain TCP has nothing to bind the handshake to.
+        if verify_identity(&self.shared.verifier, &env.sender, signature, None).is_err() {
+            return;
+        }
+        let mut peers = self.shared.peers.lock().unwrap();
+        if peers.contains_key(&env.sender) {
+            return;
+        }
+        self.add_peer(
+            &mut peers,
+            env.sender.clone(),
+            future::ready(Ok(stream)),
+            Some(env),
+        );
+    }
+
+    /// Register the connection to `identity` and start reading from it once
+    /// `connect` is done, reporting `first` before anything else. Frames sent
+    /// in the meantime are queued.
+    ///
+    /// `first` is the already verified `Auth` message of an accepted
+    /// connection. Without it, the first frame we read has to be a valid
+    /// `Auth` message from `identity`.
+    fn add_peer<F>(
+        &self,
+        peers: &mut HashMap<Identity, Connection>,
+        identity: Identity,
+        connect: F,
+        first: Option<ParticipantEnvelope>,
+    ) where
+        F: Future<Output = io::Result<TcpStream>> + Send + 'static,
+    {
+        let id = self.next_id();
+        let shared = self.shared.clone();
+        let peer = identity.clone();
+        let (frames, queued) = mpsc::channel(SEND_QUEUE_LEN);
+        let (registered, wait) = oneshot::channel();
+        let authenticated = first.is_some();
+        let reader = tokio::spawn(async move {
+            // Only report messages once the connection can be used to answer
+            // them.
+            if wait.await.is_err() {
+                return;
+            }
+            if let Ok(stream) = connect.await {
+                let (mut read, write) = stream.into_split();
+                spawn_writer(write, queued);
+                let first = match first {
+                    Some(env) => Some(env),
+                    None => read_auth(&shared, &mut read, &peer, id).await,
+                };
+                if let Some(env) = first {
+                    let _ = shared
+                        .received
+                        .send(Received::Participant(peer.clone(), Box::new(env)));
+                    while let Ok(frame) = read_frame(&mut read).await {
+                        let msg = match decode_participant_message(&frame) {
+                            Ok(env) => Received::Participant(peer.clone(), Box::new(env)),
+                            Err(e) => Received::Invalid(Some(peer.clone()), e),
+                        };
+                        let _ = shared.received.send(msg);
+                    }
+                }
+            }
+            let mut peers = shared.peers.lock().unwrap();
+            if peers.get(&peer).is_some_and(|c| c.id == id) {
+                peers.remove(&peer);
+                let _ = shared.received.send(Received::Disconnected(Some(peer)));
+            }
+        });
+        let conn = Connection {
+            id,
+            frames,
+            reader,
+            authenticated,
+            local: None,
+        };
+        peers.insert(identity, conn);
+        let _ = registered.send(());
+    }
+
+    /// Close the connection to a peer. Frames still waiting to be sent are
+    /// dropped.
+    pub fn disconnect(&self, identity: &Identity) {
+        self.shared.peers.lock().unwrap().remove(identity);
+    }
+
+    pub fn is_connected(&self, identity: &Identity) -> bool {
+        self.shared.peers.lock().unwrap().contains_key(identity)
+    }
+
+    /// Identities of all connected peers.
+    pub fn peers(&self) -> Vec<Identity> {
+        self.shared.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.shared.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn send_to_service(&self, msg: &[u8]) -> Result<(), BusError> {
+        match &*self.shared.service.lock().unwrap() {
+            Some(conn) => conn.send(msg),
+            None => Err(BusError::NotConnected),
+        }
+    }
+}
+
+impl BytesBus for TcpTransport {
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.send_to_service(msg)
+    }
+
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.send_to_service(msg)
+    }
+
+    /// Send to the connection of `recipient`. Without one, the peer is dialed
+    /// in the background if its address is known and we're inside a tokio
+    /// runtime, and `msg` is queued until the connection is open. If dialing
+    /// fails, [Received::Disconnected] is reported.
+    ///
+    /// Until the peer has authenticated itself and we have sent our `Auth`
+    /// message for `sender`, nothing else is sent and this fails with
+    /// [BusError::NotConnected]. Messages from any other sender than the one
+    /// we authenticated as are refused the same way.
+    fn send_to_participant(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        msg: &[u8],
+    ) -> Result<(), BusError> {
+        let mut peers = self.shared.peers.lock().unwrap();
+        if !peers.contains_key(recipient) {
+            let addr = match self.shared.addresses.lock().unwrap().get(recipient) {
+                Some(addr) => *addr,
+                None => return Err(BusError::NotConnected),
+            };
+            if Handle::try_current().is_err() {
+                return Err(BusError::NotConnected);
+            }
+            self.add_peer(
+                &mut peers,
+                recipient.clone(),
+                TcpStream::connect(addr),
+                None,
+            );
+        }
+        let conn = peers.get_mut(recipient).unwrap();
+        if conn.authenticated && conn.local.as_ref() == Some(sender) {
+            return conn.send(msg);
+        }
+        // The frame still has its length prefix.
+        let is_auth = matches!(
+            msg.get(2..).map(decode_participant_message),
+            Some(Ok(ParticipantEnvelope {
+                sender: s,
+                msg: ParticipantMessage::Auth { .. },
+                ..
+            })) if s == *sender
+        );
+        if !is_auth || conn.local.as_ref().is_some_and(|l| l != sender) {
+            return Err(BusError::NotConnected);
+        }
+        conn.send(msg)?;
+        conn.local = Some(sender.clone());
+        Ok(())
+    }
+}
+
+/// Write the queued frames (which already have their length prefix) until
+/// the queue is dropped or writing fails.
+fn spawn_writer(mut write: OwnedWriteHalf, mut queued: mpsc::Receiver<Vec<u8>>) {
+    tokio::spawn(async move {
+        while let Some(frame) = queued.recv().await {
+            if write.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Read the `Auth` message the peer of a connection we opened has to start
+/// with and mark the connection as authenticated. Returns `None` if the
+/// connection has to be closed instead.
+async fn read_auth<R: AsyncReadExt + Unpin>(
+    shared: &Shared,
+    read: &mut R,
+    peer: &Identity,
+    id: u64,
+) -> Option<ParticipantEnvelope> {
+    let env = decode_participant_message(&read_frame(read).await.ok()?).ok()?;
+    let signature = match env.msg {
+        ParticipantMessage::Auth { signature } => signature,
+        _ => return None,
+    };
+    if env.sender != *peer || verify_identity(&shared.verifier, peer, signature, None).is_err() {
+        return None;
+    }
+    let mut peers = shared.peers.lock().unwrap();
+    match peers.get_mut(peer) {
+        Some(conn) if conn.id == id => conn.authenticated = true,
+        _ => return None,
+    }
+    Some(env)
+}
+
+/// Read one length-prefixed frame, returns it without the prefix.
+async fn read_frame<R: AsyncReadExt + Unpin>(read: &mut R) -> io::Result<Vec<u8>> {
+    let len = read.read_u16().await?;
+    let mut frame = vec![0; len.into()];
+    read.read_exact(&mut frame).await?;
+    Ok(frame)
+}
@@ -0,0 +1,387 @@
+use super::{Received, TcpTransport};
+use crate::{
+    abiencode::types::Hash,
+    messages::{FunderReplyMessage, ParticipantMessage, ServiceReplyMessage, WatcherReplyMessage},
+    perunwire::{self, message},
+    sig::Signer,
+    wire::{
+        sign_identity, AddressBook, BusError, BytesBus, Identity, MessageBus, PeerAuth,
+        ProtoBufEncodingLayer,
+    },
+    PerunClient,
+};
+use prost::Message;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{cell::RefCell, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::UnboundedReceiver,
+};
+
+type Client = PerunClient<ProtoBufEncodingLayer<TcpTransport>>;
+
+fn client(rng: &mut StdRng) -> (Client, UnboundedReceiver<Received>) {
+    let (transport, rx) = TcpTransport::new();
+    let client = PerunClient::new(ProtoBufEncodingLayer { bus: transport }, Signer::new(rng));
+    (client, rx)
+}
+
+/// Remembers the last frame sent to a participant.
+struct Capture<'a>(&'a RefCell<Vec<u8>>);
+
+impl BytesBus for Capture<'_> {
+    fn send_to_watcher(&self, _: &[u8]) -> Result<(), BusError> {
+        Ok(())
+    }
+    fn send_to_funder(&self, _: &[u8]) -> Result<(), BusError> {
+        Ok(())
+    }
+    fn send_to_participant(&self, _: &Identity, _: &Identity, msg: &[u8]) -> Result<(), BusError> {
+        *self.0.borrow_mut() = msg.to_vec();
+        Ok(())
+    }
+}
+
+async fn recv(rx: &mut UnboundedReceiver<Received>) -> Received {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("nothing received")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn peers_exchange_messages() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (alice, mut alice_rx) = client(&mut rng);
+    let (bob, mut bob_rx) = client(&mut rng);
+    let (alice_id, bob_id) = (alice.identity(), bob.identity());
+    let addr = bob.bus.bus.listen("127.0.0.1:0").await.unwrap();
+
+    // Alice dials Bob and authenticates, Bob can answer her right away.
+    alice.bus.bus.dial(bob_id.clone(), addr).await.unwrap();
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    let mut bob_auth = PeerAuth::new(bob_id.clone(), None);
+    match recv(&mut bob_rx).await {
+        Received::Participant(peer, env) => {
+            assert_eq!(peer, alice_id);
+            assert!(bob_auth.receive(&bob, *env).unwrap().is_none());
+        }
+        r => panic!("unexpected: {:?}", r),
+    }
+    assert!(bob.bus.bus.is_connected(&alice_id));
+    bob.send_handshake_msg(&bob_id, &alice_id, None).unwrap();
+    let mut alice_auth = PeerAuth::new(alice_id.clone(), Some(bob_id.clone()));
+    match recv(&mut alice_rx).await {
+        Received::Participant(peer, env) => {
+            assert_eq!(peer, bob_id);
+            assert!(alice_auth.receive(&alice, *env).unwrap().is_none());
+        }
+        r => panic!("unexpected: {:?}", r),
+    }
+
+    // Only peers we're connected to can be reached.
+    assert_eq!(
+        alice
+            .bus
+            .bus
+            .send_to_participant(&alice_id, &alice_id, &[0, 0]),
+        Err(BusError::NotConnected)
+    );
+
+    alice.bus.bus.disconnect(&bob_id);
+    assert!(matches!(
+        recv(&mut bob_rx).await,
+        Received::Disconnected(Some(peer)) if peer == alice_id
+    ));
+    assert!(bob.bus.bus.peers().is_empty());
+    assert_eq!(
+        bob.send_handshake_msg(&bob_id, &alice_id, None),
+        Err(BusError::NotConnected)
+    );
+}
+
+#[tokio::test]
+async fn connections_must_start_with_auth() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let (bob, mut bob_rx) = client(&mut rng);
+    let addr = bob.bus.bus.listen("127.0.0.1:0").await.unwrap();
+
+    let frame = RefCell::new(vec![]);
+    ProtoBufEncodingLayer {
+        bus: &Capture(&frame),
+    }
+    .send_to_participant(
+        &vec![1],
+        &bob.identity(),
+        ParticipantMessage::ProposalRejected {
+            id: Hash([1; 32]),
+            reason: "no".into(),
+        },
+    )
+    .unwrap();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&frame.into_inner()).await.unwrap();
+    // Bob closes the connection without reporting anything.
+    assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+    assert!(bob_rx.try_recv().is_err());
+    assert!(bob.bus.bus.peers().is_empty());
+}
+
+/// Frame of an `Auth` message from `sender`, signed by `signer`.
+fn auth_frame(sender: &Identity, signer: &Signer) -> Vec<u8> {
+    let frame = RefCell::new(vec![]);
+    ProtoBufEncodingLayer {
+        bus: &Capture(&frame),
+    }
+    .send_to_participant(
+        sender,
+        &vec![2],
+        ParticipantMessage::Auth {
+            signature: sign_identity(signer, sender, None),
+        },
+    )
+    .unwrap();
+    frame.into_inner()
+}
+
+/// Open a connection to `addr` and send an `Auth` message from `sender`,
+/// signed by `signer`.
+async fn connect_as(addr: SocketAddr, sender: &Identity, signer: &Signer) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&auth_frame(sender, signer)).await.unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn connections_must_prove_their_identity() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let (alice, _alice_rx) = client(&mut rng);
+    let (bob, mut bob_rx) = client(&mut rng);
+    let mallory = Signer::new(&mut rng);
+    let (alice_id, bob_id) = (alice.identity(), bob.identity());
+    let addr = bob.bus.bus.listen("127.0.0.1:0").await.unwrap();
+
+    // Mallory can't sign for Alice.
+    let mut stream = connect_as(addr, &alice_id, &mallory).await;
+    assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+    assert!(bob_rx.try_recv().is_err());
+    assert!(bob.bus.bus.peers().is_empty());
+
+    alice.bus.bus.dial(bob_id.clone(), addr).await.unwrap();
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    assert!(matches!(
+        recv(&mut bob_rx).await,
+        Received::Participant(peer, _) if peer == alice_id
+    ));
+
+    // A replayed handshake does not replace Alice's connection.
+    let mut stream = connect_as(addr, &alice_id, &alice.signer).await;
+    assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+    bob.send_handshake_msg(&bob_id, &alice_id, None).unwrap();
+    assert!(bob.bus.bus.is_connected(&alice_id));
+    assert!(bob_rx.try_recv().is_err());
+}
+
+fn rejection() -> ParticipantMessage {
+    ParticipantMessage::ProposalRejected {
+        id: Hash([1; 32]),
+        reason: "no".into(),
+    }
+}
+
+#[tokio::test]
+async fn only_authenticated_connections_are_used() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let (alice, mut alice_rx) = client(&mut rng);
+    let (bob, mut bob_rx) = client(&mut rng);
+    let (alice_id, bob_id) = (alice.identity(), bob.identity());
+    let addr = bob.bus.bus.listen("127.0.0.1:0").await.unwrap();
+
+    // Nothing but our own `Auth` message goes out before both sides are
+    // authenticated.
+    alice.bus.bus.dial(bob_id.clone(), addr).await.unwrap();
+    assert_eq!(
+        alice
+            .bus
+            .send_to_participant(&alice_id, &bob_id, rejection()),
+        Err(BusError::NotConnected)
+    );
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    assert_eq!(
+        alice
+            .bus
+            .send_to_participant(&alice_id, &bob_id, rejection()),
+        Err(BusError::NotConnected)
+    );
+    assert!(matches!(
+        recv(&mut bob_rx).await,
+        Received::Participant(peer, _) if peer == alice_id
+    ));
+    assert_eq!(
+        bob.bus.send_to_participant(&bob_id, &alice_id, rejection()),
+        Err(BusError::NotConnected)
+    );
+    bob.send_handshake_msg(&bob_id, &alice_id, None).unwrap();
+    assert!(matches!(
+        recv(&mut alice_rx).await,
+        Received::Participant(peer, _) if peer == bob_id
+    ));
+
+    alice
+        .bus
+        .send_to_participant(&alice_id, &bob_id, rejection())
+        .unwrap();
+    assert!(matches!(
+        recv(&mut bob_rx).await,
+        Received::Participant(peer, env) if peer == alice_id
+            && matches!(env.msg, ParticipantMessage::ProposalRejected { .. })
+    ));
+    // Bob only knows Alice under the identity she authenticated as.
+    assert_eq!(
+        alice
+            .bus
+            .send_to_participant(&vec![9], &bob_id, rejection()),
+        Err(BusError::NotConnected)
+    );
+}
+
+#[tokio::test]
+async fn dialed_peer_must_prove_its_identity() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let (alice, mut alice_rx) = client(&mut rng);
+    let (bob, _bob_rx) = client(&mut rng);
+    let mallory = Signer::new(&mut rng);
+    let (alice_id, bob_id) = (alice.identity(), bob.identity());
+
+    // Whoever listens at Bob's address can't sign for him.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    alice.bus.bus.dial(bob_id.clone(), addr).await.unwrap();
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    stream
+        .write_all(&auth_frame(&bob_id, &mallory))
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        recv(&mut alice_rx).await,
+        Received::Disconnected(Some(peer)) if peer == bob_id
+    ));
+    assert!(!alice.bus.bus.is_connected(&bob_id));
+}
+
+#[tokio::test]
+async fn dial_on_demand() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let (bob, mut bob_rx) = client(&mut rng);
+    let bob_id = bob.identity();
+    let addr = bob.bus.bus.listen("127.0.0.1:0").await.unwrap();
+    let (transport, mut alice_rx) =
+        TcpTransport::with_addresses(AddressBook::from_iter([(bob_id.clone(), addr)]));
+    let alice = PerunClient::new(
+        ProtoBufEncodingLayer { bus: transport },
+        Signer::new(&mut rng),
+    );
+    let alice_id = alice.identity();
+
+    // Sending to Bob connects to him, Bob answers on the same connection
+    // without knowing Alice's address.
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    assert!(matches!(
+        recv(&mut bob_rx).await,
+        Received::Participant(peer, _) if peer == alice_id
+    ));
+    bob.send_handshake_msg(&bob_id, &alice_id, None).unwrap();
+    assert!(matches!(
+        recv(&mut alice_rx).await,
+        Received::Participant(peer, _) if peer == bob_id
+    ));
+    assert_eq!(alice.bus.bus.peers(), std::slice::from_ref(&bob_id));
+    assert_eq!(bob.bus.bus.peers(), std::slice::from_ref(&alice_id));
+
+    // Peers without an address can't be reached.
+    let carol: Identity = vec![3];
+    assert_eq!(
+        alice.send_handshake_msg(&alice_id, &carol, None),
+        Err(BusError::NotConnected)
+    );
+
+    // A failed dial is reported like a closed connection.
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    alice
+        .bus
+        .bus
+        .set_address(carol.clone(), closed.local_addr().unwrap());
+    drop(closed);
+    alice.send_handshake_msg(&alice_id, &carol, None).unwrap();
+    assert!(matches!(
+        recv(&mut alice_rx).await,
+        Received::Disconnected(Some(peer)) if peer == carol
+    ));
+    assert!(!alice.bus.bus.is_connected(&carol));
+    assert!(alice.bus.bus.is_connected(&bob_id));
+}
+
+/// Encode a reply of the remote service including the length prefix.
+fn service_frame(msg: message::Msg) -> Vec<u8> {
+    let msg = perunwire::Message { msg: Some(msg) }.encode_to_vec();
+    let mut frame = (msg.len() as u16).to_be_bytes().to_vec();
+    frame.extend(msg);
+    frame
+}
+
+#[tokio::test]
+async fn service_connection() {
+    let (transport, mut rx) = TcpTransport::new();
+    assert_eq!(
+        transport.send_to_funder(&[0, 0]),
+        Err(BusError::NotConnected)
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (accepted, connected) = tokio::join!(listener.accept(), transport.connect_service(addr));
+    connected.unwrap();
+    let (mut service, _) = accepted.unwrap();
+
+    // Watcher and funder requests share the connection.
+    transport.send_to_watcher(&[0, 1, 7]).unwrap();
+    transport.send_to_funder(&[0, 1, 8]).unwrap();
+    let mut buf = [0; 6];
+    service.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [0, 1, 7, 0, 1, 8]);
+
+    let id = Hash([1; 32]);
+    let funded = service_frame(message::Msg::FundingResponse(
+        perunwire::FundingResponseMsg {
+            channel_id: id.0.to_vec(),
+            success: true,
+        },
+    ));
+    let failed = service_frame(message::Msg::ForceCloseResponse(
+        perunwire::ForceCloseResponseMsg {
+            channel_id: id.0.to_vec(),
+            success: false,
+        },
+    ));
+    service.write_all(&funded).await.unwrap();
+    service.write_all(&failed).await.unwrap();
+    assert!(matches!(
+        recv(&mut rx).await,
+        Received::Service(ServiceReplyMessage::Funder(FunderReplyMessage::Funded { id: i })) if i == id
+    ));
+    assert!(matches!(
+        recv(&mut rx).await,
+        Received::Service(ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeFailed { id: i })) if i == id
+    ));
+
+    drop(service);
+    assert!(matches!(recv(&mut rx).await, Received::Disconnected(None)));
+    assert_eq!(
+        transport.send_to_watcher(&[0, 0]),
+        Err(BusError::NotConnected)
+    );
+}
@@ -1,7 +1,30 @@
+mod address_book;
+mod auth;
+mod decoding;
 mod encoding;
+mod framing;
+#[cfg(feature = "noise")]
+mod noise;
+mod queue;
+#[cfg(test)]
+mod tests;
 
+pub use address_book::AddressBook;
 use alloc::vec::Vec;
+pub use auth::{sign_identity, verify_identity, AuthError, PeerAuth};
+pub use decoding::{
+    decode_participant_message, decode_service_message, DecodeError, ParticipantEnvelope,
+};
 pub use encoding::ProtoBufEncodingLayer;
+pub use framing::{FrameDecoder, FrameError};
+#[cfg(feature = "noise")]
+#[cfg_attr(docsrs, doc(cfg(feature = "noise")))]
+pub use noise::{
+    NoiseBus, NoiseError, NoiseHandshake, NoiseKey, NoiseTransport, MAX_HANDSHAKE_FRAME,
+    NOISE_OVERHEAD,
+};
+use prost::EncodeError;
+pub use queue::{OutboundQueue, QueuedBus};
 
 use crate::{
     channel::{PartIdx, Peers},
@@ -10,10 +33,53 @@ use crate::{
 
 pub type Identity = Vec<u8>;
 
+/// Error returned when a message could not be sent. Nothing has been sent in
+/// this case, so the channel objects stay unchanged and the operation can be
+/// tried again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// The encoded message is too large for the 2 byte length prefix or the
+    /// buffer it has to fit into.
+    MessageTooLarge(usize),
+    Encode(EncodeError),
+    /// The transport can't take the message right now, for example because
+    /// its send buffer is full.
+    Busy,
+    /// There is no connection to the recipient.
+    NotConnected,
+}
+impl From<EncodeError> for BusError {
+    fn from(e: EncodeError) -> Self {
+        Self::Encode(e)
+    }
+}
+
 pub trait BytesBus {
-    fn send_to_watcher(&self, msg: &[u8]);
-    fn send_to_funder(&self, msg: &[u8]);
-    fn send_to_participant(&self, sender: &Identity, recipient: &Identity, msg: &[u8]);
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError>;
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError>;
+    fn send_to_participant(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        msg: &[u8],
+    ) -> Result<(), BusError>;
+}
+
+impl<B: BytesBus + ?Sized> BytesBus for &B {
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+        (**self).send_to_watcher(msg)
+    }
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+        (**self).send_to_funder(msg)
+    }
+    fn send_to_participant(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        msg: &[u8],
+    ) -> Result<(), BusError> {
+        (**self).send_to_participant(sender, recipient, msg)
+    }
 }
 
 /// Low-Level abstraction over the network configuration.
@@ -21,24 +87,42 @@ pub trait BytesBus {
 /// Might be moved into a byte based MessageBus or behind a `unstable` feature
 /// flag.
 pub trait MessageBus {
-    fn send_to_watcher(&self, msg: WatcherRequestMessage);
-    fn send_to_funder(&self, msg: FunderRequestMessage);
-    fn send_to_participant(&self, sender: &Identity, recipient: &Identity, msg: ParticipantMessage);
+    fn send_to_watcher(&self, msg: WatcherRequestMessage) -> Result<(), BusError>;
+    fn send_to_funder(&self, msg: FunderRequestMessage) -> Result<(), BusError>;
+    fn send_to_participant(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError>;
 }
 
 pub trait BroadcastMessageBus: MessageBus {
-    fn broadcast_to_participants(&self, part_idx: PartIdx, peers: &Peers, msg: ParticipantMessage);
+    /// Send `msg` to all participants except ourselves, stopping at the first
+    /// error.
+    fn broadcast_to_participants(
+        &self,
+        part_idx: PartIdx,
+        peers: &Peers,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError>;
 }
 
 impl<B: MessageBus> BroadcastMessageBus for B {
-    fn broadcast_to_participants(&self, part_idx: PartIdx, peers: &Peers, msg: ParticipantMessage) {
+    fn broadcast_to_participants(
+        &self,
+        part_idx: PartIdx,
+        peers: &Peers,
+        msg: ParticipantMessage,
+    ) -> Result<(), BusError> {
         let sender = &peers[part_idx];
         for (i, peer) in peers.iter().enumerate() {
             if i == part_idx {
                 continue;
             }
 
-            self.send_to_participant(sender, peer, msg.clone());
+            self.send_to_participant(sender, peer, msg.clone())?;
         }
+        Ok(())
     }
 }
@@ -0,0 +1,58 @@
+use alloc::collections::BTreeMap;
+
+use super::Identity;
+
+/// Where to reach other participants, for transports that connect to them on
+/// demand. `A` is whatever the transport needs to open a connection, for
+/// example a socket address.
+#[derive(Debug, Clone)]
+pub struct AddressBook<A> {
+    entries: BTreeMap<Identity, A>,
+}
+
+impl<A> Default for AddressBook<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> AddressBook<A> {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Add or replace the address of `identity`, returns the previous one.
+    pub fn insert(&mut self, identity: Identity, addr: A) -> Option<A> {
+        self.entries.insert(identity, addr)
+    }
+
+    pub fn get(&self, identity: &Identity) -> Option<&A> {
+        self.entries.get(identity)
+    }
+
+    pub fn remove(&mut self, identity: &Identity) -> Option<A> {
+        self.entries.remove(identity)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Identity, &A)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<A> FromIterator<(Identity, A)> for AddressBook<A> {
+    fn from_iter<T: IntoIterator<Item = (Identity, A)>>(iter: T) -> Self {
+        Self {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
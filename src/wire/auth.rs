@@ -0,0 +1,193 @@
+use super::{Identity, MessageBus, ParticipantEnvelope};
+use crate::{
+    abiencode::types::{Hash, Signature},
+    messages::ParticipantMessage,
+    sig::Signer,
+    PerunClient,
+};
+use sha3::{Digest, Keccak256};
+
+/// Error returned by [PeerAuth::receive]. The message is dropped in all cases.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The peer sent something other than an `Auth` message before
+    /// authenticating.
+    NotAuthenticated,
+    /// The signature in the `Auth` message was not made with the key of the
+    /// claimed sender, or the sender is not a valid wire address.
+    InvalidSignature,
+    /// The peer proved its identity, but is not the one we expected on this
+    /// connection.
+    UnexpectedPeer,
+    /// The envelope claims to be from someone other than the authenticated
+    /// peer.
+    WrongSender,
+    /// The envelope is not addressed to us.
+    WrongRecipient,
+    /// A channel proposal that doesn't list the authenticated peer as one of
+    /// its peers.
+    NotAPeer,
+}
+
+/// Hash signed in the `Auth` message, go-perun signs the binary encoding of
+/// its wire address, which for Ethereum are the 20 address bytes.
+///
+/// With a `binding` (see [PeerAuth::bind]) it is appended to the address, so
+/// the signature is only valid for that connection.
+fn identity_hash(identity: &Identity, binding: Option<&Hash>) -> Hash {
+    let mut hasher = Keccak256::new();
+    hasher.update(identity);
+    if let Some(binding) = binding {
+        hasher.update(binding.0);
+    }
+    Hash(hasher.finalize().into())
+}
+
+/// Signature proving that we own the key belonging to `identity`, sent in
+/// [ParticipantMessage::Auth].
+///
+/// For the other participant to accept it, `identity` has to be the address
+/// of `signer` and `binding` the one its [PeerAuth] is bound to. Without a
+/// binding the signature is what go-perun sends, it is the same for every
+/// connection and can be replayed by anyone who saw it.
+pub fn sign_identity(signer: &Signer, identity: &Identity, binding: Option<&Hash>) -> Signature {
+    signer.sign_eth(identity_hash(identity, binding))
+}
+
+/// Check that `signature` was made by the owner of `identity`, which has to
+/// be an address, for the connection identified by `binding`.
+pub fn verify_identity(
+    signer: &Signer,
+    identity: &Identity,
+    signature: Signature,
+    binding: Option<&Hash>,
+) -> Result<(), AuthError> {
+    if identity.len() != 20 || !matches!(signature.0[64], 27 | 28) {
+        return Err(AuthError::InvalidSignature);
+    }
+    match signer.recover_signer(identity_hash(identity, binding), signature) {
+        Ok(addr) if addr.0[..] == identity[..] => Ok(()),
+        _ => Err(AuthError::InvalidSignature),
+    }
+}
+
+/// Authentication of a connection to another participant, using the
+/// go-perun handshake: Each side sends an `Auth` message
+/// ([PerunClient::send_handshake_msg]) signing its own identity, which must be
+/// its address. The side opening the connection sends first, the other side
+/// answers after verifying it.
+///
+/// All envelopes received on the connection have to go through
+/// [Self::receive], which only lets messages through once the peer is
+/// authenticated and only if they are sent by it and addressed to us.
+///
+/// The go-perun handshake has no challenge, the signed message only depends on
+/// the identity. Whoever observes it (there is no encryption on plain
+/// connections) can replay it on a new connection and pass as the peer. This
+/// can't be fixed without breaking compatibility with go-perun, so between
+/// peers using this crate the handshake should be bound to the connection
+/// with [Self::bind], for example to the
+/// [NoiseTransport::handshake_hash][super::NoiseTransport::handshake_hash].
+#[derive(Debug, Clone)]
+pub struct PeerAuth {
+    local: Identity,
+    expected: Option<Identity>,
+    peer: Option<Identity>,
+    binding: Option<Hash>,
+}
+
+impl PeerAuth {
+    /// Start authenticating a new connection. `local` is our own identity,
+    /// `expected` the peer we dialed or are waiting for, if known. Otherwise
+    /// any peer proving its identity is accepted and can be queried with
+    /// [Self::peer].
+    pub fn new(local: Identity, expected: Option<Identity>) -> Self {
+        Self {
+            local,
+            expected,
+            peer: None,
+            binding: None,
+        }
+    }
+
+    /// Only accept `Auth` messages signed for `binding`, a value both sides
+    /// know and that is unique for the connection. The peer has to send its
+    /// handshake with the same binding, see
+    /// [PerunClient::send_handshake_msg]. go-perun can't do that.
+    pub fn bind(&mut self, binding: Hash) {
+        self.binding = Some(binding);
+    }
+
+    /// Value the handshake is bound to, if any.
+    pub fn binding(&self) -> Option<&Hash> {
+        self.binding.as_ref()
+    }
+
+    /// Our own identity.
+    pub fn local(&self) -> &Identity {
+        &self.local
+    }
+
+    /// Identity of the peer, once it is authenticated.
+    pub fn peer(&self) -> Option<&Identity> {
+        self.peer.as_ref()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    /// Check an envelope received on this connection.
+    ///
+    /// Returns `Ok(None)` for the peer's `Auth` message, which authenticates
+    /// the connection. After that, all other messages from the peer are
+    /// returned, channel proposals only if they list the peer in their
+    /// `peers`.
+    pub fn receive<B: MessageBus>(
+        &mut self,
+        client: &PerunClient<B>,
+        env: ParticipantEnvelope,
+    ) -> Result<Option<ParticipantMessage>, AuthError> {
+        if env.recipient != self.local {
+            return Err(AuthError::WrongRecipient);
+        }
+        let peer = match &self.peer {
+            Some(peer) => peer,
+            None => {
+                let signature = match env.msg {
+                    ParticipantMessage::Auth { signature } => signature,
+                    _ => return Err(AuthError::NotAuthenticated),
+                };
+                verify_identity(
+                    &client.signer,
+                    &env.sender,
+                    signature,
+                    self.binding.as_ref(),
+                )?;
+                if self.expected.as_ref().is_some_and(|e| *e != env.sender) {
+                    return Err(AuthError::UnexpectedPeer);
+                }
+                self.peer = Some(env.sender);
+                return Ok(None);
+            }
+        };
+        if env.sender != *peer {
+            return Err(AuthError::WrongSender);
+        }
+        match env.msg {
+            // Already authenticated, a repeated handshake has nothing to tell.
+            ParticipantMessage::Auth { .. } => Ok(None),
+            ParticipantMessage::ChannelProposal(prop) if !prop.peers.contains(peer) => {
+                Err(AuthError::NotAPeer)
+            }
+            msg => Ok(Some(msg)),
+        }
+    }
+
+    /// Forget the peer and the binding, for example after the connection was
+    /// closed. A new connection has to be bound again.
+    pub fn reset(&mut self) {
+        self.peer = None;
+        self.binding = None;
+    }
+}
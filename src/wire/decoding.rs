@@ -0,0 +1,173 @@
+use super::Identity;
+use crate::{
+    abiencode::types::{Hash, Signature, U256},
+    messages::{ConversionError, ParticipantMessage, ServiceReplyMessage, WatcherReplyMessage},
+    perunwire::{envelope, message, Envelope, Message, SignedAuthEnvelope},
+};
+use alloc::vec::Vec;
+use prost::{DecodeError as ProstDecodeError, Message as _};
+
+const ASSETS: usize = 1;
+
+/// Error returned when a received message could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    Protobuf(ProstDecodeError),
+    Conversion(ConversionError),
+    /// The envelope does not contain a message.
+    Empty,
+    /// A valid message this library does not handle, for example one for
+    /// virtual channels or a request only the watcher/funder should receive.
+    Unsupported,
+}
+impl From<ProstDecodeError> for DecodeError {
+    fn from(e: ProstDecodeError) -> Self {
+        Self::Protobuf(e)
+    }
+}
+impl From<ConversionError> for DecodeError {
+    fn from(e: ConversionError) -> Self {
+        Self::Conversion(e)
+    }
+}
+
+/// A message from another participant, see [decode_participant_message].
+#[derive(Debug, Clone)]
+pub struct ParticipantEnvelope {
+    pub sender: Identity,
+    pub recipient: Identity,
+    pub msg: ParticipantMessage,
+}
+
+/// Decode a message sent by another participant's
+/// [ProtoBufEncodingLayer][super::ProtoBufEncodingLayer] or go-perun.
+///
+/// `frame` is the content of one frame, without the 2 byte length prefix.
+pub fn decode_participant_message(frame: &[u8]) -> Result<ParticipantEnvelope, DecodeError> {
+    let env = Envelope::decode(frame)?;
+    let msg = match env.msg.ok_or(DecodeError::Empty)? {
+        envelope::Msg::AuthResponseMsg(_) => {
+            // `Envelope` doesn't know about the signature, see
+            // `SignedAuthEnvelope`.
+            let signature = SignedAuthEnvelope::decode(frame)?
+                .auth_response_msg
+                .ok_or(ConversionError::ExptectedSome)?
+                .signature;
+            ParticipantMessage::Auth {
+                signature: Signature(
+                    signature
+                        .try_into()
+                        .or(Err(ConversionError::ByteLengthMissmatch))?,
+                ),
+            }
+        }
+        envelope::Msg::LedgerChannelProposalMsg(m) => {
+            ParticipantMessage::ChannelProposal(m.try_into()?)
+        }
+        envelope::Msg::LedgerChannelProposalAccMsg(m) => {
+            ParticipantMessage::ProposalAccepted(m.try_into()?)
+        }
+        envelope::Msg::ChannelProposalRejMsg(m) => ParticipantMessage::ProposalRejected {
+            id: hash_from_wire(m.proposal_id)?,
+            reason: m.reason,
+        },
+        envelope::Msg::ChannelUpdateMsg(m) => ParticipantMessage::ChannelUpdate(m.try_into()?),
+        envelope::Msg::ChannelUpdateAccMsg(m) => {
+            ParticipantMessage::ChannelUpdateAccepted(m.try_into()?)
+        }
+        envelope::Msg::ChannelUpdateRejMsg(m) => ParticipantMessage::ChannelUpdateRejected {
+            id: hash_from_wire(m.channel_id)?,
+            version: m.version,
+            reason: m.reason,
+        },
+        envelope::Msg::ChannelSyncMsg(m) => ParticipantMessage::ChannelSync(m.try_into()?),
+        envelope::Msg::PingMsg(_)
+        | envelope::Msg::PongMsg(_)
+        | envelope::Msg::ShutdownMsg(_)
+        | envelope::Msg::SubChannelProposalMsg(_)
+        | envelope::Msg::SubChannelProposalAccMsg(_)
+        | envelope::Msg::VirtualChannelProposalMsg(_)
+        | envelope::Msg::VirtualChannelProposalAccMsg(_)
+        | envelope::Msg::VirtualChannelFundingProposalMsg(_)
+        | envelope::Msg::VirtualChannelSettlementProposalMsg(_) => {
+            return Err(DecodeError::Unsupported)
+        }
+    };
+    Ok(ParticipantEnvelope {
+        sender: env.sender,
+        recipient: env.recipient,
+        msg,
+    })
+}
+
+/// Decode a reply from the watcher or funder.
+///
+/// `frame` is the content of one frame, without the 2 byte length prefix.
+pub fn decode_service_message(frame: &[u8]) -> Result<ServiceReplyMessage, DecodeError> {
+    let msg = match Message::decode(frame)?.msg.ok_or(DecodeError::Empty)? {
+        message::Msg::FundingResponse(m) => ServiceReplyMessage::Funder(m.try_into()?),
+        message::Msg::WatchResponse(m) => {
+            let id = hash_from_wire(m.channel_id)?;
+            ServiceReplyMessage::Watcher(if m.success {
+                WatcherReplyMessage::Ack {
+                    id,
+                    version: m.version,
+                }
+            } else {
+                WatcherReplyMessage::WatchFailed {
+                    id,
+                    version: m.version,
+                }
+            })
+        }
+        message::Msg::ForceCloseResponse(m) => {
+            let id = hash_from_wire(m.channel_id)?;
+            ServiceReplyMessage::Watcher(if m.success {
+                WatcherReplyMessage::DisputeAck { id }
+            } else {
+                WatcherReplyMessage::DisputeFailed { id }
+            })
+        }
+        message::Msg::DisputeNotification(m) => {
+            ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeNotification {
+                id: hash_from_wire(m.channel_id)?,
+                version: m.version,
+            })
+        }
+        message::Msg::ConcludedNotification(m) => {
+            ServiceReplyMessage::Watcher(WatcherReplyMessage::Concluded {
+                id: hash_from_wire(m.channel_id)?,
+                version: m.version,
+            })
+        }
+        message::Msg::WithdrawnNotification(m) => {
+            if m.amounts.len() != ASSETS {
+                return Err(ConversionError::AssetSizeMissmatch.into());
+            }
+            let mut amounts = [U256::zero(); ASSETS];
+            for (a, b) in amounts.iter_mut().zip(m.amounts) {
+                if b.len() > 32 {
+                    return Err(ConversionError::ByteLengthMissmatch.into());
+                }
+                *a = U256::from_big_endian(&b);
+            }
+            ServiceReplyMessage::Watcher(WatcherReplyMessage::Withdrawn {
+                id: hash_from_wire(m.channel_id)?,
+                amounts,
+            })
+        }
+        message::Msg::FundingRequest(_)
+        | message::Msg::WatchRequest(_)
+        | message::Msg::ForceCloseRequest(_)
+        | message::Msg::ConcludeRequest(_) => return Err(DecodeError::Unsupported),
+    };
+    Ok(msg)
+}
+
+fn hash_from_wire(bytes: Vec<u8>) -> Result<Hash, ConversionError> {
+    Ok(Hash(
+        bytes
+            .try_into()
+            .or(Err(ConversionError::ByteLengthMissmatch))?,
+    ))
+}
@@ -1,11 +1,11 @@
-use prost::{bytes::BufMut, EncodeError};
+use prost::bytes::BufMut;
 
-use super::{BytesBus, Identity, MessageBus, ParticipantMessage};
+use super::{BusError, BytesBus, Identity, MessageBus, ParticipantMessage};
 use crate::{
     messages::{FunderRequestMessage, WatcherRequestMessage},
     perunwire::{
-        envelope, message, AuthResponseMsg, ChannelProposalRejMsg, ChannelUpdateRejMsg, Envelope,
-        Message,
+        envelope, message, ChannelProposalRejMsg, ChannelUpdateRejMsg, Envelope, Message,
+        SignedAuthEnvelope, SignedAuthResponseMsg,
     },
 };
 use alloc::vec::Vec;
@@ -16,16 +16,16 @@ pub struct ProtoBufEncodingLayer<B: BytesBus> {
 }
 
 impl<B: BytesBus> ProtoBufEncodingLayer<B> {
-    fn encode<T: prost::Message>(msg: T) -> Result<Vec<u8>, EncodeError> {
+    fn encode<T: prost::Message>(msg: T) -> Result<Vec<u8>, BusError> {
         // Go-perun writes a u16 for the length (2 bytes), this means we cannot
         // use `encode_length_delimited`, which would write a variable length
         // integer using LEB128.
         let len = msg.encoded_len();
-        // TODO: How should we handle this case? Go-perun seems to just cast to
-        // uint16 and throws away the rest (no panic, no error). It is probably
-        // best to return an error here, for now we're just panicking in this
-        // case (as we're using unwrap below, too).
-        assert!(len < (1 << 16));
+        // Go-perun seems to just cast to uint16 and throws away the rest (no
+        // panic, no error), which would garble the stream.
+        if len >= (1 << 16) {
+            return Err(BusError::MessageTooLarge(len));
+        }
 
         let mut buf = Vec::with_capacity(2 + len);
         buf.put_slice(&(len as u16).to_be_bytes());
@@ -35,25 +35,29 @@ impl<B: BytesBus> ProtoBufEncodingLayer<B> {
 }
 
 impl<B: BytesBus> MessageBus for ProtoBufEncodingLayer<B> {
-    fn send_to_watcher(&self, msg: WatcherRequestMessage) {
+    fn send_to_watcher(&self, msg: WatcherRequestMessage) -> Result<(), BusError> {
         let wiremsg: message::Msg = match msg {
             WatcherRequestMessage::WatchRequest(msg) => message::Msg::WatchRequest(msg.into()),
-            WatcherRequestMessage::StartDispute(msg) => message::Msg::ForceCloseRequest(msg.into()),
+            // Refuting registers a newer state, just like starting a dispute.
+            WatcherRequestMessage::StartDispute(msg) | WatcherRequestMessage::Refute(msg) => {
+                message::Msg::ForceCloseRequest(msg.into())
+            }
+            WatcherRequestMessage::Conclude(msg) => message::Msg::ConcludeRequest(msg.into()),
         };
         let envelope = Message { msg: Some(wiremsg) };
 
-        let buf = Self::encode(envelope).unwrap();
-        self.bus.send_to_watcher(&buf);
+        let buf = Self::encode(envelope)?;
+        self.bus.send_to_watcher(&buf)
     }
 
-    fn send_to_funder(&self, msg: FunderRequestMessage) {
+    fn send_to_funder(&self, msg: FunderRequestMessage) -> Result<(), BusError> {
         let wiremsg: message::Msg = match msg {
             FunderRequestMessage::FundingRequest(msg) => message::Msg::FundingRequest(msg.into()),
         };
         let envelope = Message { msg: Some(wiremsg) };
 
-        let buf = Self::encode(envelope).unwrap();
-        self.bus.send_to_funder(&buf);
+        let buf = Self::encode(envelope)?;
+        self.bus.send_to_funder(&buf)
     }
 
     fn send_to_participant(
@@ -61,9 +65,20 @@ impl<B: BytesBus> MessageBus for ProtoBufEncodingLayer<B> {
         sender: &Identity,
         recipient: &Identity,
         msg: ParticipantMessage,
-    ) {
+    ) -> Result<(), BusError> {
         let wiremsg: envelope::Msg = match msg {
-            ParticipantMessage::Auth => envelope::Msg::AuthResponseMsg(AuthResponseMsg {}),
+            ParticipantMessage::Auth { signature } => {
+                // Not representable with `Envelope`, see `SignedAuthEnvelope`.
+                let envelope = SignedAuthEnvelope {
+                    sender: sender.clone(),
+                    recipient: recipient.clone(),
+                    auth_response_msg: Some(SignedAuthResponseMsg {
+                        signature: signature.0.to_vec(),
+                    }),
+                };
+                let buf = Self::encode(envelope)?;
+                return self.bus.send_to_participant(sender, recipient, &buf);
+            }
             ParticipantMessage::ChannelProposal(msg) => {
                 envelope::Msg::LedgerChannelProposalMsg(msg.into())
             }
@@ -89,6 +104,7 @@ impl<B: BytesBus> MessageBus for ProtoBufEncodingLayer<B> {
                 version,
                 reason,
             }),
+            ParticipantMessage::ChannelSync(msg) => envelope::Msg::ChannelSyncMsg(msg.into()),
         };
 
         let envelope = Envelope {
@@ -97,7 +113,7 @@ impl<B: BytesBus> MessageBus for ProtoBufEncodingLayer<B> {
             msg: Some(wiremsg),
         };
 
-        let buf = Self::encode(envelope).unwrap();
-        self.bus.send_to_participant(sender, recipient, &buf);
+        let buf = Self::encode(envelope)?;
+        self.bus.send_to_participant(sender, recipient, &buf)
     }
 }
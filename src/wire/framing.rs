@@ -0,0 +1,112 @@
+/// Error returned by [FrameDecoder::next_frame].
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The length prefix announces a frame with more payload bytes than the
+    /// decoder can buffer. The frame is skipped as its bytes arrive, so the
+    /// decoder stays in sync with the stream and can be used further.
+    TooLarge(usize),
+}
+
+/// Incremental reader-side counterpart to the framing done by
+/// [ProtoBufEncodingLayer][super::ProtoBufEncodingLayer]: Each frame is a 2
+/// byte big-endian length prefix followed by that many bytes of payload.
+///
+/// Bytes are fed in arbitrary chunks with [Self::push], for example whatever
+/// the transport currently has available or the two halves of a ring buffer.
+/// Complete payloads are then taken out with [Self::next_frame] and can be
+/// passed to [decode_participant_message][super::decode_participant_message]
+/// or [decode_service_message][super::decode_service_message].
+///
+/// The decoder does not allocate, it buffers at most `N` bytes (including the
+/// length prefix), which limits the payload of a frame to `N - 2` bytes.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder<const N: usize> {
+    buf: [u8; N],
+    /// Number of bytes in `buf`.
+    len: usize,
+    /// Number of bytes at the start of `buf` belonging to the frame last
+    /// returned by [Self::next_frame]. They are dropped on the next call to
+    /// [Self::push] or [Self::next_frame].
+    consumed: usize,
+    /// Number of bytes of an oversized frame that still have to be skipped.
+    discard: usize,
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            consumed: 0,
+            discard: 0,
+        }
+    }
+
+    /// Buffer as many bytes of `data` as possible and return how many were
+    /// taken.
+    ///
+    /// If this is less than `data.len()` the buffer is full and the remaining
+    /// bytes have to be pushed again after taking out the next frame with
+    /// [Self::next_frame].
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let skipped = self.discard.min(data.len());
+        self.discard -= skipped;
+        let data = &data[skipped..];
+        if self.discard > 0 {
+            return skipped;
+        }
+
+        self.compact();
+        let n = data.len().min(N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        skipped + n
+    }
+
+    /// Return the payload of the next complete frame, if there is one.
+    ///
+    /// The payload is only borrowed until the next call to [Self::push] or
+    /// [Self::next_frame], which drop it from the buffer.
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, FrameError> {
+        self.compact();
+        if self.len < 2 {
+            return Ok(None);
+        }
+        let length: usize = u16::from_be_bytes([self.buf[0], self.buf[1]]).into();
+        if 2 + length > N {
+            // Everything we have buffered belongs to this frame, otherwise it
+            // would fit.
+            self.discard = 2 + length - self.len;
+            self.len = 0;
+            return Err(FrameError::TooLarge(length));
+        }
+        if self.len < 2 + length {
+            return Ok(None);
+        }
+        self.consumed = 2 + length;
+        Ok(Some(&self.buf[2..2 + length]))
+    }
+
+    /// Drop all buffered data, for example after reconnecting.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+        self.discard = 0;
+    }
+
+    /// Move the bytes after the last returned frame to the start of the
+    /// buffer.
+    fn compact(&mut self) {
+        if self.consumed > 0 {
+            self.buf.copy_within(self.consumed..self.len, 0);
+            self.len -= self.consumed;
+            self.consumed = 0;
+        }
+    }
+}
@@ -0,0 +1,694 @@
+//! Encrypted participant connections using the Noise protocol framework
+//! (`Noise_XX_25519_ChaChaPoly_SHA256`).
+//!
+//! Both participants have a long-term X25519 key ([NoiseKey]), which is
+//! signed with their wire identity. The signature is sent encrypted during the
+//! handshake, so after [NoiseHandshake] finished each side knows the identity
+//! of the other one, and that it owns the key the traffic is encrypted for.
+//!
+//! Everything works on caller provided buffers, nothing is allocated. Noise
+//! messages are framed like all other messages, with a 2 byte big-endian
+//! length prefix, so they can be read with a [FrameDecoder][super::FrameDecoder].
+//!
+//! Note that go-perun does not speak Noise, this only works between peers
+//! using this crate.
+
+use core::cell::RefCell;
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{AuthError, BusError, BytesBus, Identity, MessageBus};
+use crate::{
+    abiencode::types::{Hash, Signature},
+    PerunClient,
+};
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+/// Prefix of the signed static key, so the signature can't be mistaken for
+/// anything else.
+const STATIC_KEY_DOMAIN: &[u8] = b"perun-noise-static-key:";
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const SIG_LEN: usize = 65;
+
+/// Size of the largest (framed) handshake message, which is the second one:
+/// Ephemeral key, encrypted static key and encrypted signature.
+pub const MAX_HANDSHAKE_FRAME: usize = 2 + KEY_LEN + (KEY_LEN + TAG_LEN) + (SIG_LEN + TAG_LEN);
+/// Number of bytes encryption adds to a frame.
+pub const NOISE_OVERHEAD: usize = 2 + TAG_LEN;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NoiseError {
+    /// The output buffer can't hold the message.
+    BufferTooSmall,
+    /// A handshake message was written or read out of order, or the
+    /// connection has no transport yet.
+    InvalidState,
+    /// The message is too short or its length prefix is wrong.
+    Malformed,
+    /// The message could not be decrypted, it was either tampered with or not
+    /// encrypted for us.
+    Decrypt,
+    /// The static key of the peer is not signed by the expected identity.
+    Auth(AuthError),
+}
+impl From<AuthError> for NoiseError {
+    fn from(e: AuthError) -> Self {
+        Self::Auth(e)
+    }
+}
+
+/// Hash signed to tie a Noise static key to a wire identity.
+fn static_key_hash(public: &PublicKey) -> Hash {
+    let mut hasher = Keccak256::new();
+    hasher.update(STATIC_KEY_DOMAIN);
+    hasher.update(public.as_bytes());
+    Hash(hasher.finalize().into())
+}
+
+/// Long-term Noise key, signed by our wire identity (the address of the
+/// [PerunClient]'s signer).
+#[derive(Clone)]
+pub struct NoiseKey {
+    secret: StaticSecret,
+    public: PublicKey,
+    signature: Signature,
+}
+
+impl NoiseKey {
+    /// Generate a new random key.
+    pub fn new<B: MessageBus, R: RngCore + CryptoRng>(
+        client: &PerunClient<B>,
+        rng: &mut R,
+    ) -> Self {
+        Self::from_secret(client, StaticSecret::random_from_rng(rng).to_bytes())
+    }
+
+    /// Use a stored key, so the peer sees the same static key across
+    /// reconnects.
+    pub fn from_secret<B: MessageBus>(client: &PerunClient<B>, secret: [u8; KEY_LEN]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        let signature = client.signer.sign_eth(static_key_hash(&public));
+        Self {
+            secret,
+            public,
+            signature,
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+}
+
+impl core::fmt::Debug for NoiseKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Don't print the secret.
+        f.debug_struct("NoiseKey")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One direction of an encrypted connection.
+#[derive(Clone)]
+struct CipherState {
+    key: Option<[u8; KEY_LEN]>,
+    nonce: u64,
+}
+
+impl CipherState {
+    const fn empty() -> Self {
+        Self {
+            key: None,
+            nonce: 0,
+        }
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypt `buf[..len]` in place and append the tag, returns the new
+    /// length. Without a key (early in the handshake) the plaintext is kept.
+    fn encrypt(&mut self, ad: &[u8], buf: &mut [u8], len: usize) -> Result<usize, NoiseError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(len),
+        };
+        if buf.len() < len + TAG_LEN {
+            return Err(NoiseError::BufferTooSmall);
+        }
+        let tag = ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt_in_place_detached(&self.nonce(), ad, &mut buf[..len])
+            .or(Err(NoiseError::BufferTooSmall))?;
+        buf[len..len + TAG_LEN].copy_from_slice(&tag);
+        self.nonce += 1;
+        Ok(len + TAG_LEN)
+    }
+
+    /// Decrypt `buf` in place, returns the length of the plaintext at its
+    /// start.
+    fn decrypt(&mut self, ad: &[u8], buf: &mut [u8]) -> Result<usize, NoiseError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(buf.len()),
+        };
+        let len = buf
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(NoiseError::Malformed)?;
+        let (msg, tag) = buf.split_at_mut(len);
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt_in_place_detached(&self.nonce(), ad, msg, Tag::from_slice(tag))
+            .or(Err(NoiseError::Decrypt))?;
+        self.nonce += 1;
+        Ok(len)
+    }
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for d in data {
+        mac.update(d);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// HKDF as defined by Noise, returning two outputs.
+fn hkdf(chaining_key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp = hmac(chaining_key, &[input]);
+    let out1 = hmac(&temp, &[&[1]]);
+    let out2 = hmac(&temp, &[&out1, &[2]]);
+    (out1, out2)
+}
+
+/// Handshake hash, chaining key and the cipher derived so far.
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    cipher: CipherState,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut state = Self {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            cipher: CipherState::empty(),
+        };
+        // Empty prologue.
+        state.mix_hash(&[]);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (ck, key) = hkdf(&self.ck, input);
+        self.ck = ck;
+        self.cipher = CipherState {
+            key: Some(key),
+            nonce: 0,
+        };
+    }
+
+    fn encrypt_and_hash(&mut self, buf: &mut [u8], len: usize) -> Result<usize, NoiseError> {
+        let h = self.h;
+        let len = self.cipher.encrypt(&h, buf, len)?;
+        self.mix_hash(&buf[..len]);
+        Ok(len)
+    }
+
+    fn decrypt_and_hash(&mut self, buf: &mut [u8]) -> Result<usize, NoiseError> {
+        let h = self.h;
+        let mut ciphertext = [0; KEY_LEN + SIG_LEN + TAG_LEN];
+        let ciphertext = &mut ciphertext[..buf.len()];
+        ciphertext.copy_from_slice(buf);
+        let len = self.cipher.decrypt(&h, buf)?;
+        self.mix_hash(ciphertext);
+        Ok(len)
+    }
+
+    /// Cipher states for the messages sent by the initiator and the
+    /// responder.
+    fn split(&self) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        let cipher = |key| CipherState {
+            key: Some(key),
+            nonce: 0,
+        };
+        (cipher(k1), cipher(k2))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Noise XX handshake:
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es, signature
+/// -> s, se, signature
+/// ```
+///
+/// The initiator (the side opening the connection) starts with
+/// [Self::write_message], the responder with [Self::read_message], then they
+/// alternate until [Self::is_finished]. Any error, including a failure to send
+/// one of the messages, means the handshake has to be started again.
+pub struct NoiseHandshake {
+    role: Role,
+    /// Number of messages written or read so far.
+    step: u8,
+    sym: SymmetricState,
+    key: NoiseKey,
+    e: StaticSecret,
+    re: Option<PublicKey>,
+    expected: Option<Identity>,
+    remote: Option<Identity>,
+}
+
+impl NoiseHandshake {
+    /// Start a handshake as the side opening the connection. `expected` is
+    /// the identity of the peer we dial, if we know it.
+    pub fn initiator<R: RngCore + CryptoRng>(
+        key: &NoiseKey,
+        expected: Option<Identity>,
+        rng: &mut R,
+    ) -> Self {
+        Self::new(Role::Initiator, key, expected, rng)
+    }
+
+    /// Start a handshake as the side accepting the connection.
+    pub fn responder<R: RngCore + CryptoRng>(
+        key: &NoiseKey,
+        expected: Option<Identity>,
+        rng: &mut R,
+    ) -> Self {
+        Self::new(Role::Responder, key, expected, rng)
+    }
+
+    fn new<R: RngCore + CryptoRng>(
+        role: Role,
+        key: &NoiseKey,
+        expected: Option<Identity>,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            role,
+            step: 0,
+            sym: SymmetricState::new(),
+            key: key.clone(),
+            e: StaticSecret::random_from_rng(rng),
+            re: None,
+            expected,
+            remote: None,
+        }
+    }
+
+    /// Whether the next step is [Self::write_message] (as opposed to
+    /// [Self::read_message]).
+    pub fn is_writing(&self) -> bool {
+        matches!(
+            (self.role, self.step),
+            (Role::Initiator, 0 | 2) | (Role::Responder, 1)
+        )
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.step == 3
+    }
+
+    /// Identity of the peer, known after reading its static key.
+    pub fn remote_identity(&self) -> Option<&Identity> {
+        self.remote.as_ref()
+    }
+
+    /// Write the next handshake message including its length prefix into
+    /// `out`, returns the number of bytes written.
+    pub fn write_message(&mut self, out: &mut [u8]) -> Result<usize, NoiseError> {
+        if !self.is_writing() {
+            return Err(NoiseError::InvalidState);
+        }
+        if out.len() < MAX_HANDSHAKE_FRAME {
+            return Err(NoiseError::BufferTooSmall);
+        }
+        let buf = &mut out[2..];
+        let mut len = 0;
+        if self.step == 0 || self.step == 1 {
+            // e
+            let e = PublicKey::from(&self.e);
+            buf[..KEY_LEN].copy_from_slice(e.as_bytes());
+            self.sym.mix_hash(e.as_bytes());
+            len += KEY_LEN;
+        }
+        if self.step == 1 {
+            // ee
+            self.mix_dh(&self.e.clone(), self.re);
+        }
+        if self.step >= 1 {
+            // s
+            buf[len..len + KEY_LEN].copy_from_slice(self.key.public.as_bytes());
+            len += self.sym.encrypt_and_hash(&mut buf[len..], KEY_LEN)?;
+            // es for the responder, se for the initiator: Our static key with
+            // their ephemeral key.
+            self.mix_dh(&self.key.secret.clone(), self.re);
+            buf[len..len + SIG_LEN].copy_from_slice(&self.key.signature.0);
+            len += self.sym.encrypt_and_hash(&mut buf[len..], SIG_LEN)?;
+        } else {
+            // Empty payload
+            len += self.sym.encrypt_and_hash(&mut buf[len..], 0)?;
+        }
+        out[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        self.step += 1;
+        Ok(2 + len)
+    }
+
+    /// Read the next handshake message of the peer, without the length
+    /// prefix (as returned by [FrameDecoder][super::FrameDecoder]).
+    pub fn read_message<B: MessageBus>(
+        &mut self,
+        client: &PerunClient<B>,
+        frame: &[u8],
+    ) -> Result<(), NoiseError> {
+        if self.is_writing() || self.is_finished() {
+            return Err(NoiseError::InvalidState);
+        }
+        let mut buf = [0; MAX_HANDSHAKE_FRAME - 2];
+        let expected_len = match self.step {
+            0 => KEY_LEN,
+            1 => MAX_HANDSHAKE_FRAME - 2,
+            _ => MAX_HANDSHAKE_FRAME - 2 - KEY_LEN,
+        };
+        if frame.len() != expected_len {
+            return Err(NoiseError::Malformed);
+        }
+        let buf = &mut buf[..frame.len()];
+        buf.copy_from_slice(frame);
+
+        let mut rest = &mut buf[..];
+        if self.step <= 1 {
+            // e
+            let (re, tail) = rest.split_at_mut(KEY_LEN);
+            let re: [u8; KEY_LEN] = (&*re).try_into().unwrap();
+            self.sym.mix_hash(&re);
+            self.re = Some(PublicKey::from(re));
+            rest = tail;
+        }
+        if self.step == 1 {
+            // ee
+            self.mix_dh(&self.e.clone(), self.re);
+        }
+        if self.step == 0 {
+            // Empty payload
+            self.sym.decrypt_and_hash(rest)?;
+        } else {
+            // s
+            let (rs, sig) = rest.split_at_mut(KEY_LEN + TAG_LEN);
+            self.sym.decrypt_and_hash(rs)?;
+            let rs = PublicKey::from(<[u8; KEY_LEN]>::try_from(&rs[..KEY_LEN]).unwrap());
+            // es for the initiator, se for the responder: Our ephemeral key
+            // with their static key.
+            self.mix_dh(&self.e.clone(), Some(rs));
+            self.sym.decrypt_and_hash(sig)?;
+            let sig = Signature(sig[..SIG_LEN].try_into().unwrap());
+            self.remote = Some(Self::verify(client, &rs, sig, self.expected.as_ref())?);
+        }
+        self.step += 1;
+        Ok(())
+    }
+
+    /// Check that the static key is signed by a valid identity (and the
+    /// expected one, if any), returns the identity.
+    fn verify<B: MessageBus>(
+        client: &PerunClient<B>,
+        rs: &PublicKey,
+        sig: Signature,
+        expected: Option<&Identity>,
+    ) -> Result<Identity, AuthError> {
+        if !matches!(sig.0[64], 27 | 28) {
+            return Err(AuthError::InvalidSignature);
+        }
+        let identity = client
+            .signer
+            .recover_signer(static_key_hash(rs), sig)
+            .or(Err(AuthError::InvalidSignature))?
+            .0
+            .to_vec();
+        if expected.is_some_and(|e| *e != identity) {
+            return Err(AuthError::UnexpectedPeer);
+        }
+        Ok(identity)
+    }
+
+    fn mix_dh(&mut self, secret: &StaticSecret, public: Option<PublicKey>) {
+        // The public key is always read before it is used in the pattern.
+        let public = public.expect("Handshake steps out of order");
+        self.sym.mix_key(secret.diffie_hellman(&public).as_bytes());
+    }
+
+    /// Finish the handshake, returns the keys for the encrypted connection.
+    pub fn into_transport(self) -> Result<NoiseTransport, NoiseError> {
+        let remote = match (self.is_finished(), self.remote) {
+            (true, Some(remote)) => remote,
+            _ => return Err(NoiseError::InvalidState),
+        };
+        let handshake_hash = Hash(self.sym.h);
+        let (initiator, responder) = self.sym.split();
+        let (send, recv) = match self.role {
+            Role::Initiator => (initiator, responder),
+            Role::Responder => (responder, initiator),
+        };
+        Ok(NoiseTransport {
+            send,
+            recv,
+            remote,
+            handshake_hash,
+        })
+    }
+}
+
+/// Keys of an established encrypted connection, see [NoiseBus].
+#[derive(Clone)]
+pub struct NoiseTransport {
+    send: CipherState,
+    recv: CipherState,
+    remote: Identity,
+    handshake_hash: Hash,
+}
+
+impl NoiseTransport {
+    /// Identity of the peer, proven during the handshake.
+    pub fn remote_identity(&self) -> &Identity {
+        &self.remote
+    }
+
+    /// Hash of the whole handshake, which is the same on both sides and
+    /// different for every connection. Binding the go-perun handshake to it
+    /// with [PeerAuth::bind][super::PeerAuth::bind] stops it from being
+    /// replayed on another connection.
+    pub fn handshake_hash(&self) -> &Hash {
+        &self.handshake_hash
+    }
+
+    /// Encrypt `msg` and write it including the length prefix into `out`,
+    /// returns the number of bytes written, which is
+    /// `msg.len() + NOISE_OVERHEAD`.
+    pub fn encrypt(&mut self, msg: &[u8], out: &mut [u8]) -> Result<usize, NoiseError> {
+        let len = msg.len() + TAG_LEN;
+        if len > u16::MAX.into() || out.len() < 2 + len {
+            return Err(NoiseError::BufferTooSmall);
+        }
+        out[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        out[2..2 + msg.len()].copy_from_slice(msg);
+        self.send.encrypt(&[], &mut out[2..], msg.len())?;
+        Ok(2 + len)
+    }
+
+    /// Decrypt a frame received from the peer (without its length prefix) into
+    /// `out` and return the plaintext.
+    pub fn decrypt<'a>(&mut self, frame: &[u8], out: &'a mut [u8]) -> Result<&'a [u8], NoiseError> {
+        let out = out
+            .get_mut(..frame.len())
+            .ok_or(NoiseError::BufferTooSmall)?;
+        out.copy_from_slice(frame);
+        let len = self.recv.decrypt(&[], out)?;
+        Ok(&out[..len])
+    }
+}
+
+impl core::fmt::Debug for NoiseTransport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Don't print the keys.
+        f.debug_struct("NoiseTransport")
+            .field("remote", &self.remote)
+            .finish_non_exhaustive()
+    }
+}
+
+/// [BytesBus] encrypting the messages to the other participant, for
+/// connections with a single peer like [QueuedBus][super::QueuedBus].
+/// Messages to the watcher and funder are passed through unchanged.
+///
+/// Until a transport is set with [Self::connect], sending to the participant
+/// fails with [BusError::NotConnected], nothing is ever sent in plaintext.
+/// `N` is the size of the buffer for the encrypted frames, so frames from
+/// [ProtoBufEncodingLayer][super::ProtoBufEncodingLayer] can be up to
+/// `N - NOISE_OVERHEAD` bytes.
+///
+/// Received frames have to be decrypted with [Self::decrypt] before decoding
+/// them.
+///
+/// Every frame uses up a nonce, even if the inner bus fails to send it (it
+/// may have written part of it). The frame is kept and sent again unchanged
+/// when the same message is retried. If something else is sent instead, the
+/// peer would never get the frame it waits for, so the session is closed
+/// ([BusError::NotConnected]) and a new handshake is needed.
+pub struct NoiseBus<B: BytesBus, const N: usize> {
+    pub bus: B,
+    transport: RefCell<Option<NoiseTransport>>,
+    buf: RefCell<[u8; N]>,
+    unsent: RefCell<Option<Unsent>>,
+}
+
+/// Frame in the buffer of a [NoiseBus] that could not be sent.
+#[derive(Debug, Clone, Copy)]
+struct Unsent {
+    len: usize,
+    /// Hash of the recipient and the plaintext, to recognize a retry.
+    digest: [u8; 32],
+}
+
+fn message_digest(recipient: &Identity, msg: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((recipient.len() as u64).to_be_bytes());
+    hasher.update(recipient);
+    hasher.update(msg);
+    hasher.finalize().into()
+}
+
+impl<B: BytesBus, const N: usize> NoiseBus<B, N> {
+    pub const fn new(bus: B) -> Self {
+        Self {
+            bus,
+            transport: RefCell::new(None),
+            buf: RefCell::new([0; N]),
+            unsent: RefCell::new(None),
+        }
+    }
+
+    /// Send the next handshake message to the peer, in plaintext.
+    pub fn send_handshake(
+        &self,
+        handshake: &mut NoiseHandshake,
+        sender: &Identity,
+        recipient: &Identity,
+    ) -> Result<(), BusError> {
+        let mut buf = [0; MAX_HANDSHAKE_FRAME];
+        let len = handshake
+            .write_message(&mut buf)
+            .or(Err(BusError::NotConnected))?;
+        self.bus.send_to_participant(sender, recipient, &buf[..len])
+    }
+
+    /// Start encrypting with the keys from a finished handshake.
+    pub fn connect(&self, transport: NoiseTransport) {
+        *self.transport.borrow_mut() = Some(transport);
+        *self.unsent.borrow_mut() = None;
+    }
+
+    /// Forget the keys, for example after the connection was closed.
+    pub fn disconnect(&self) {
+        *self.transport.borrow_mut() = None;
+        *self.unsent.borrow_mut() = None;
+    }
+
+    /// Identity of the peer, if connected.
+    pub fn remote_identity(&self) -> Option<Identity> {
+        self.transport
+            .borrow()
+            .as_ref()
+            .map(|t| t.remote_identity().clone())
+    }
+
+    /// Handshake hash of the connection, if connected, see
+    /// [NoiseTransport::handshake_hash].
+    pub fn handshake_hash(&self) -> Option<Hash> {
+        self.transport.borrow().as_ref().map(|t| t.handshake_hash)
+    }
+
+    /// Decrypt a frame received from the peer (without its length prefix) into
+    /// `out`. The plaintext is a frame from the peer's
+    /// [ProtoBufEncodingLayer][super::ProtoBufEncodingLayer], which is
+    /// returned without its length prefix, ready for
+    /// [decode_participant_message][super::decode_participant_message].
+    pub fn decrypt<'a>(&self, frame: &[u8], out: &'a mut [u8]) -> Result<&'a [u8], NoiseError> {
+        let mut transport = self.transport.borrow_mut();
+        let transport = transport.as_mut().ok_or(NoiseError::InvalidState)?;
+        let msg = transport.decrypt(frame, out)?;
+        match msg {
+            [a, b, payload @ ..] if usize::from(u16::from_be_bytes([*a, *b])) == payload.len() => {
+                Ok(payload)
+            }
+            _ => Err(NoiseError::Malformed),
+        }
+    }
+}
+
+impl<B: BytesBus, const N: usize> BytesBus for NoiseBus<B, N> {
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.bus.send_to_watcher(msg)
+    }
+
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.bus.send_to_funder(msg)
+    }
+
+    fn send_to_participant(
+        &self,
+        sender: &Identity,
+        recipient: &Identity,
+        msg: &[u8],
+    ) -> Result<(), BusError> {
+        let mut transport = self.transport.borrow_mut();
+        let mut buf = self.buf.borrow_mut();
+        let mut unsent = self.unsent.borrow_mut();
+        let digest = message_digest(recipient, msg);
+        let len = match (transport.as_mut(), *unsent) {
+            (None, _) => return Err(BusError::NotConnected),
+            // Never encrypt anything else with the nonce of the unsent frame.
+            (Some(_), Some(u)) if u.digest == digest => u.len,
+            (Some(_), Some(_)) => {
+                *transport = None;
+                *unsent = None;
+                return Err(BusError::NotConnected);
+            }
+            (Some(transport), None) => transport
+                .encrypt(msg, &mut buf[..])
+                .or(Err(BusError::MessageTooLarge(msg.len())))?,
+        };
+        let res = self.bus.send_to_participant(sender, recipient, &buf[..len]);
+        *unsent = res.is_err().then_some(Unsent { len, digest });
+        res
+    }
+}
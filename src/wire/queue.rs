@@ -0,0 +1,170 @@
+use core::cell::RefCell;
+
+use super::{BusError, BytesBus, Identity};
+
+/// Bounded FIFO of outgoing frames, stored back to back in a ring buffer of
+/// `N` bytes.
+///
+/// Frames are only ever taken as a whole, so a frame is either sent
+/// completely or not at all. Draining on the other hand may stop anywhere,
+/// for example when the send buffer of a socket is full, and continues at the
+/// same byte on the next call to [Self::drain].
+///
+/// The queue does not allocate, which makes it usable on targets without a
+/// heap.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue<const N: usize> {
+    buf: [u8; N],
+    /// Index of the first queued byte.
+    start: usize,
+    /// Number of queued bytes.
+    len: usize,
+}
+
+impl<const N: usize> Default for OutboundQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> OutboundQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Append `frame` to the queue.
+    ///
+    /// Returns [BusError::Busy] if there currently is not enough space left,
+    /// which goes away as the queue is drained, and
+    /// [BusError::MessageTooLarge] if the frame would not even fit into the
+    /// empty queue. Nothing is queued in both cases.
+    pub fn push(&mut self, frame: &[u8]) -> Result<(), BusError> {
+        if frame.len() > N {
+            return Err(BusError::MessageTooLarge(frame.len()));
+        }
+        // Also keeps a queue with `N == 0` from dividing by zero below.
+        if frame.is_empty() {
+            return Ok(());
+        }
+        if frame.len() > N - self.len {
+            return Err(BusError::Busy);
+        }
+        let end = (self.start + self.len) % N;
+        let first = frame.len().min(N - end);
+        self.buf[end..end + first].copy_from_slice(&frame[..first]);
+        self.buf[..frame.len() - first].copy_from_slice(&frame[first..]);
+        self.len += frame.len();
+        Ok(())
+    }
+
+    /// Hand the queued bytes to `write`, which returns how many of them it
+    /// took. Stops once `write` takes less than it was given or the queue is
+    /// empty and returns the total number of bytes taken.
+    ///
+    /// `write` is called with at most two slices per call, as the queued
+    /// bytes may wrap around the end of the ring buffer.
+    pub fn drain(&mut self, mut write: impl FnMut(&[u8]) -> usize) -> usize {
+        let mut total = 0;
+        while self.len > 0 {
+            let end = (self.start + self.len).min(N);
+            let chunk = &self.buf[self.start..end];
+            let n = write(chunk).min(chunk.len());
+            self.start = (self.start + n) % N;
+            self.len -= n;
+            total += n;
+            if n < chunk.len() {
+                break;
+            }
+        }
+        if self.len == 0 {
+            // Keep the free space in one piece.
+            self.start = 0;
+        }
+        total
+    }
+
+    /// Number of queued bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drop all queued bytes, for example when the connection was lost.
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+/// [BytesBus] that only queues the frames from
+/// [ProtoBufEncodingLayer][super::ProtoBufEncodingLayer], which the transport
+/// then sends with [Self::drain_participant] and [Self::drain_service]
+/// whenever its sockets have space.
+///
+/// This decouples the size of the messages from the size of the send buffers
+/// of the transport. If a queue is full, sending fails with [BusError::Busy]
+/// and the channel is left unchanged, so the operation can be tried again
+/// after draining.
+///
+/// Meant for transports with a single connection to the other participant
+/// (`P` bytes of queue) and one to the watcher and funder (`S` bytes of
+/// queue), so the identities passed to
+/// [BytesBus::send_to_participant] are not used.
+#[derive(Debug, Default)]
+pub struct QueuedBus<const P: usize, const S: usize> {
+    participant: RefCell<OutboundQueue<P>>,
+    service: RefCell<OutboundQueue<S>>,
+}
+
+impl<const P: usize, const S: usize> QueuedBus<P, S> {
+    pub const fn new() -> Self {
+        Self {
+            participant: RefCell::new(OutboundQueue::new()),
+            service: RefCell::new(OutboundQueue::new()),
+        }
+    }
+
+    /// Send queued frames for the other participant, see
+    /// [OutboundQueue::drain].
+    pub fn drain_participant(&self, write: impl FnMut(&[u8]) -> usize) -> usize {
+        self.participant.borrow_mut().drain(write)
+    }
+
+    /// Send queued frames for the watcher and funder, see
+    /// [OutboundQueue::drain].
+    pub fn drain_service(&self, write: impl FnMut(&[u8]) -> usize) -> usize {
+        self.service.borrow_mut().drain(write)
+    }
+
+    /// Whether everything has been drained.
+    pub fn is_empty(&self) -> bool {
+        self.participant.borrow().is_empty() && self.service.borrow().is_empty()
+    }
+
+    /// Drop all queued frames, for example after reconnecting.
+    pub fn clear(&self) {
+        self.participant.borrow_mut().clear();
+        self.service.borrow_mut().clear();
+    }
+}
+
+impl<const P: usize, const S: usize> BytesBus for QueuedBus<P, S> {
+    fn send_to_watcher(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.service.borrow_mut().push(msg)
+    }
+
+    fn send_to_funder(&self, msg: &[u8]) -> Result<(), BusError> {
+        self.service.borrow_mut().push(msg)
+    }
+
+    fn send_to_participant(&self, _: &Identity, _: &Identity, msg: &[u8]) -> Result<(), BusError> {
+        self.participant.borrow_mut().push(msg)
+    }
+}
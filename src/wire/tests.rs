@@ -0,0 +1,725 @@
+use super::{
+    decode_participant_message, decode_service_message, AuthError, BusError, BytesBus, DecodeError,
+    FrameDecoder, FrameError, Identity, MessageBus, OutboundQueue, ParticipantEnvelope, PeerAuth,
+    ProtoBufEncodingLayer, QueuedBus,
+};
+use crate::{
+    abiencode::types::{Hash, Signature},
+    messages::{
+        ConversionError, FunderReplyMessage, ParticipantMessage, ServiceReplyMessage,
+        WatcherReplyMessage,
+    },
+    perunwire::{self, message},
+    sig::Signer,
+    PerunClient,
+};
+use prost::Message;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{cell::RefCell, vec, vec::Vec};
+
+/// Remembers the frames sent to participants.
+#[derive(Default)]
+struct Capture(RefCell<Vec<Vec<u8>>>);
+
+impl BytesBus for Capture {
+    fn send_to_watcher(&self, _: &[u8]) -> Result<(), BusError> {
+        Ok(())
+    }
+    fn send_to_funder(&self, _: &[u8]) -> Result<(), BusError> {
+        Ok(())
+    }
+    fn send_to_participant(&self, _: &Identity, _: &Identity, msg: &[u8]) -> Result<(), BusError> {
+        self.0.borrow_mut().push(msg.to_vec());
+        Ok(())
+    }
+}
+
+/// Handshake message, with a signature that is not checked.
+fn auth() -> ParticipantMessage {
+    ParticipantMessage::Auth {
+        signature: Signature([27; 65]),
+    }
+}
+
+/// Encode `msg` like go-perun's remote services do, without length prefix.
+fn service_frame(msg: message::Msg) -> Vec<u8> {
+    perunwire::Message { msg: Some(msg) }.encode_to_vec()
+}
+
+#[test]
+fn participant_message_roundtrip() {
+    let capture = Capture::default();
+    let layer = ProtoBufEncodingLayer { bus: &capture };
+    let (alice, bob) = (b"Alice".to_vec(), b"Bob".to_vec());
+    let id = Hash([7; 32]);
+    layer
+        .send_to_participant(
+            &alice,
+            &bob,
+            ParticipantMessage::ChannelUpdateRejected {
+                id,
+                version: 3,
+                reason: "no".into(),
+            },
+        )
+        .unwrap();
+
+    let frame = capture.0.borrow_mut().pop().unwrap();
+    let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+    assert_eq!(len, frame.len() - 2);
+    let env = decode_participant_message(&frame[2..]).unwrap();
+    assert_eq!(env.sender, alice);
+    assert_eq!(env.recipient, bob);
+    assert!(matches!(
+        env.msg,
+        ParticipantMessage::ChannelUpdateRejected { id: i, version: 3, reason } if i == id && reason == "no"
+    ));
+}
+
+#[test]
+fn oversized_messages_are_not_sent() {
+    let capture = Capture::default();
+    let layer = ProtoBufEncodingLayer { bus: &capture };
+    let (alice, bob) = (b"Alice".to_vec(), b"Bob".to_vec());
+    let res = layer.send_to_participant(
+        &alice,
+        &bob,
+        ParticipantMessage::ChannelUpdateRejected {
+            id: Hash([7; 32]),
+            version: 3,
+            reason: "x".repeat(1 << 16),
+        },
+    );
+    assert!(matches!(res, Err(BusError::MessageTooLarge(len)) if len > 1 << 16));
+    assert!(capture.0.borrow().is_empty());
+}
+
+#[test]
+fn unsupported_participant_messages() {
+    let env = perunwire::Envelope {
+        sender: vec![],
+        recipient: vec![],
+        msg: Some(perunwire::envelope::Msg::PingMsg(perunwire::PingMsg {
+            created: 0,
+        })),
+    };
+    assert!(matches!(
+        decode_participant_message(&env.encode_to_vec()),
+        Err(DecodeError::Unsupported)
+    ));
+
+    let env = perunwire::Envelope::default();
+    assert!(matches!(
+        decode_participant_message(&env.encode_to_vec()),
+        Err(DecodeError::Empty)
+    ));
+    assert!(matches!(
+        decode_participant_message(&[0xff, 0xff]),
+        Err(DecodeError::Protobuf(_))
+    ));
+}
+
+#[test]
+fn service_messages() {
+    let id = Hash([1; 32]);
+    let frame = service_frame(message::Msg::WatchResponse(perunwire::WatchResponseMsg {
+        channel_id: id.0.to_vec(),
+        version: 4,
+        success: true,
+    }));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Watcher(WatcherReplyMessage::Ack { id: i, version: 4 })) if i == id
+    ));
+
+    let frame = service_frame(message::Msg::FundingResponse(
+        perunwire::FundingResponseMsg {
+            channel_id: id.0.to_vec(),
+            success: false,
+        },
+    ));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Funder(FunderReplyMessage::FundingFailed { id: i })) if i == id
+    ));
+
+    let frame = service_frame(message::Msg::ForceCloseResponse(
+        perunwire::ForceCloseResponseMsg {
+            channel_id: id.0.to_vec(),
+            success: false,
+        },
+    ));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Watcher(WatcherReplyMessage::DisputeFailed { id: i })) if i == id
+    ));
+
+    let frame = service_frame(message::Msg::WatchResponse(perunwire::WatchResponseMsg {
+        channel_id: id.0.to_vec(),
+        version: 5,
+        success: false,
+    }));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Watcher(WatcherReplyMessage::WatchFailed { id: i, version: 5 })) if i == id
+    ));
+
+    let frame = service_frame(message::Msg::DisputeNotification(
+        perunwire::DisputeNotification {
+            channel_id: id.0.to_vec(),
+            version: 2,
+        },
+    ));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Watcher(
+            WatcherReplyMessage::DisputeNotification { version: 2, .. }
+        ))
+    ));
+
+    let frame = service_frame(message::Msg::WithdrawnNotification(
+        perunwire::WithdrawnNotification {
+            channel_id: id.0.to_vec(),
+            amounts: vec![vec![1, 0]],
+        },
+    ));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Ok(ServiceReplyMessage::Watcher(WatcherReplyMessage::Withdrawn { amounts, .. })) if amounts == [256.into()]
+    ));
+
+    let frame = service_frame(message::Msg::DisputeNotification(
+        perunwire::DisputeNotification {
+            channel_id: vec![1; 20],
+            version: 2,
+        },
+    ));
+    assert!(matches!(
+        decode_service_message(&frame),
+        Err(DecodeError::Conversion(
+            ConversionError::ByteLengthMissmatch
+        ))
+    ));
+}
+
+/// Prefix `payload` with its length like [ProtoBufEncodingLayer] does.
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = (payload.len() as u16).to_be_bytes().to_vec();
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Push all of `data` into `decoder`, collecting the frames that complete.
+fn push_all<const N: usize>(
+    decoder: &mut FrameDecoder<N>,
+    mut data: &[u8],
+) -> Vec<Result<Vec<u8>, FrameError>> {
+    let mut frames = Vec::new();
+    loop {
+        let n = decoder.push(data);
+        data = &data[n..];
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => frames.push(Ok(frame.to_vec())),
+                Ok(None) => break,
+                Err(e) => frames.push(Err(e)),
+            }
+        }
+        if data.is_empty() {
+            return frames;
+        }
+    }
+}
+
+#[test]
+fn frames_in_arbitrary_chunks() {
+    let payloads: [&[u8]; 4] = [b"hello", b"", b"perun", &[42; 100]];
+    let stream: Vec<u8> = payloads.iter().flat_map(|p| frame(p)).collect();
+
+    // Everything at once, one byte at a time and split in odd places (like
+    // both halves of a ring buffer).
+    for chunk_size in [stream.len(), 1, 3, 7, 50] {
+        let mut decoder = FrameDecoder::<128>::new();
+        let frames: Vec<_> = stream
+            .chunks(chunk_size)
+            .flat_map(|chunk| push_all(&mut decoder, chunk))
+            .collect();
+        let expected: Vec<_> = payloads.iter().map(|p| Ok(p.to_vec())).collect();
+        assert_eq!(frames, expected, "chunk size {chunk_size}");
+    }
+}
+
+#[test]
+fn frames_fill_the_whole_buffer() {
+    // A buffer of 8 bytes fits frames with up to 6 bytes payload, which have
+    // to be taken out before the next frame can be pushed.
+    let mut decoder = FrameDecoder::<8>::new();
+    let stream = [frame(b"abcdef"), frame(b"ghijkl")].concat();
+
+    assert_eq!(decoder.push(&stream), 8);
+    assert_eq!(decoder.next_frame(), Ok(Some(&b"abcdef"[..])));
+    assert_eq!(decoder.next_frame(), Ok(None));
+    assert_eq!(decoder.push(&stream[8..]), 8);
+    assert_eq!(decoder.next_frame(), Ok(Some(&b"ghijkl"[..])));
+    assert_eq!(decoder.next_frame(), Ok(None));
+}
+
+#[test]
+fn oversized_frames_are_skipped() {
+    let mut decoder = FrameDecoder::<16>::new();
+    let stream = [frame(b"before"), frame(&[1; 40]), frame(b"after")].concat();
+
+    for chunk_size in [stream.len(), 5] {
+        decoder.reset();
+        let frames: Vec<_> = stream
+            .chunks(chunk_size)
+            .flat_map(|chunk| push_all(&mut decoder, chunk))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                Ok(b"before".to_vec()),
+                Err(FrameError::TooLarge(40)),
+                Ok(b"after".to_vec()),
+            ]
+        );
+    }
+}
+
+#[test]
+fn decode_framed_participant_message() {
+    let capture = Capture::default();
+    let layer = ProtoBufEncodingLayer { bus: &capture };
+    let (alice, bob) = (b"Alice".to_vec(), b"Bob".to_vec());
+    layer.send_to_participant(&alice, &bob, auth()).unwrap();
+    layer.send_to_participant(&bob, &alice, auth()).unwrap();
+    let stream = capture.0.borrow().concat();
+
+    let mut decoder = FrameDecoder::<256>::new();
+    let (head, tail) = stream.split_at(stream.len() / 2 + 1);
+    assert_eq!(decoder.push(head), head.len());
+    let env = decode_participant_message(decoder.next_frame().unwrap().unwrap()).unwrap();
+    assert_eq!(env.sender, alice);
+    assert_eq!(decoder.next_frame(), Ok(None));
+    assert_eq!(decoder.push(tail), tail.len());
+    let env = decode_participant_message(decoder.next_frame().unwrap().unwrap()).unwrap();
+    assert_eq!(env.sender, bob);
+    assert!(matches!(env.msg, ParticipantMessage::Auth { signature } if signature.0 == [27; 65]));
+}
+
+#[test]
+fn queue_wraps_around() {
+    let mut queue = OutboundQueue::<8>::new();
+    let mut sent = vec![];
+    queue.push(b"abcde").unwrap();
+    assert_eq!(queue.push(b"fghi"), Err(BusError::Busy));
+    assert_eq!(queue.push(b"too large"), Err(BusError::MessageTooLarge(9)));
+
+    // A socket with space for 3 bytes.
+    assert_eq!(queue.drain(|data| take(&mut sent, data, 3)), 3);
+    assert_eq!(queue.len(), 2);
+    queue.push(b"fghi").unwrap();
+    queue.push(b"jk").unwrap();
+    assert_eq!(queue.push(b"l"), Err(BusError::Busy));
+
+    // The rest is handed out in two pieces, wrapping around the end.
+    assert_eq!(queue.drain(|data| take(&mut sent, data, usize::MAX)), 8);
+    assert!(queue.is_empty());
+    assert_eq!(sent, b"abcdefghijk");
+}
+
+#[test]
+fn empty_queue_size() {
+    let mut queue = OutboundQueue::<0>::new();
+    queue.push(b"").unwrap();
+    assert_eq!(queue.push(b"a"), Err(BusError::MessageTooLarge(1)));
+    assert_eq!(queue.drain(|_| unreachable!()), 0);
+}
+
+#[test]
+fn queued_bus_applies_back_pressure() {
+    let queue = QueuedBus::<256, 16>::new();
+    let layer = ProtoBufEncodingLayer { bus: &queue };
+    let (alice, bob) = (b"Alice".to_vec(), b"Bob".to_vec());
+    let mut sent = vec![];
+    let mut count = 0;
+    while layer.send_to_participant(&alice, &bob, auth()).is_ok() {
+        count += 1;
+    }
+    assert!(count > 0);
+    assert!(matches!(
+        layer.send_to_participant(&alice, &bob, auth()),
+        Err(BusError::Busy)
+    ));
+    assert_eq!(
+        queue.drain_service(|data| take(&mut sent, data, usize::MAX)),
+        0
+    );
+
+    // Draining a little at a time, like a socket with a small send buffer.
+    while !queue.is_empty() {
+        queue.drain_participant(|data| take(&mut sent, data, 5));
+    }
+    layer.send_to_participant(&alice, &bob, auth()).unwrap();
+    queue.drain_participant(|data| take(&mut sent, data, usize::MAX));
+
+    let mut decoder = FrameDecoder::<512>::new();
+    assert_eq!(decoder.push(&sent), sent.len());
+    for _ in 0..count + 1 {
+        let env = decode_participant_message(decoder.next_frame().unwrap().unwrap()).unwrap();
+        assert_eq!(env.recipient, bob);
+    }
+    assert_eq!(decoder.next_frame(), Ok(None));
+}
+
+/// Append up to `max` bytes of `data` to `sent`.
+fn take(sent: &mut Vec<u8>, data: &[u8], max: usize) -> usize {
+    let n = data.len().min(max);
+    sent.extend_from_slice(&data[..n]);
+    n
+}
+
+type Client = PerunClient<ProtoBufEncodingLayer<Capture>>;
+
+fn client(rng: &mut StdRng) -> Client {
+    PerunClient::new(
+        ProtoBufEncodingLayer {
+            bus: Capture::default(),
+        },
+        Signer::new(rng),
+    )
+}
+
+/// Decode the last message `client` sent.
+fn last_sent(client: &Client) -> ParticipantEnvelope {
+    let frame = client.bus.bus.0.borrow_mut().pop().unwrap();
+    decode_participant_message(&frame[2..]).unwrap()
+}
+
+#[test]
+fn authenticated_handshake() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (alice, bob) = (client(&mut rng), client(&mut rng));
+    let (alice_id, bob_id) = (alice.identity(), bob.identity());
+
+    // Alice dials Bob, Bob doesn't know who to expect.
+    let mut alice_auth = PeerAuth::new(alice_id.clone(), Some(bob_id.clone()));
+    let mut bob_auth = PeerAuth::new(bob_id.clone(), None);
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    assert!(matches!(
+        bob_auth.receive(&bob, last_sent(&alice)),
+        Ok(None)
+    ));
+    assert_eq!(bob_auth.peer(), Some(&alice_id));
+    bob.send_handshake_msg(&bob_id, &alice_id, None).unwrap();
+    assert!(matches!(
+        alice_auth.receive(&alice, last_sent(&bob)),
+        Ok(None)
+    ));
+    assert!(alice_auth.is_authenticated());
+
+    let reject = ParticipantMessage::ProposalRejected {
+        id: Hash([1; 32]),
+        reason: "no".into(),
+    };
+    alice
+        .bus
+        .send_to_participant(&alice_id, &bob_id, reject.clone())
+        .unwrap();
+    assert!(matches!(
+        bob_auth.receive(&bob, last_sent(&alice)),
+        Ok(Some(ParticipantMessage::ProposalRejected { .. }))
+    ));
+
+    // Envelopes claiming another sender or recipient are dropped.
+    let mut env = ParticipantEnvelope {
+        sender: b"Mallory".to_vec(),
+        recipient: bob_id.clone(),
+        msg: reject,
+    };
+    assert_eq!(
+        bob_auth.receive(&bob, env.clone()).unwrap_err(),
+        AuthError::WrongSender
+    );
+    env.sender = alice_id;
+    env.recipient = b"Mallory".to_vec();
+    assert_eq!(
+        bob_auth.receive(&bob, env).unwrap_err(),
+        AuthError::WrongRecipient
+    );
+}
+
+#[test]
+fn impersonation_is_rejected() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let (alice, bob, mallory) = (client(&mut rng), client(&mut rng), client(&mut rng));
+    let (alice_id, bob_id, mallory_id) = (alice.identity(), bob.identity(), mallory.identity());
+    let mut bob_auth = PeerAuth::new(bob_id.clone(), Some(alice_id.clone()));
+
+    // Nothing is accepted before the handshake.
+    let env = ParticipantEnvelope {
+        sender: alice_id.clone(),
+        recipient: bob_id.clone(),
+        msg: ParticipantMessage::ProposalRejected {
+            id: Hash([1; 32]),
+            reason: "no".into(),
+        },
+    };
+    assert_eq!(
+        bob_auth.receive(&bob, env).unwrap_err(),
+        AuthError::NotAuthenticated
+    );
+
+    // Mallory can't sign for Alice.
+    mallory
+        .send_handshake_msg(&alice_id, &bob_id, None)
+        .unwrap();
+    assert_eq!(
+        bob_auth.receive(&bob, last_sent(&mallory)).unwrap_err(),
+        AuthError::InvalidSignature
+    );
+    // Or replay Alice's signature with a garbled recovery id.
+    alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+    let mut env = last_sent(&alice);
+    if let ParticipantMessage::Auth { signature } = &mut env.msg {
+        signature.0[64] = 0;
+    }
+    assert_eq!(
+        bob_auth.receive(&bob, env).unwrap_err(),
+        AuthError::InvalidSignature
+    );
+
+    // A valid handshake by someone other than Alice.
+    mallory
+        .send_handshake_msg(&mallory_id, &bob_id, None)
+        .unwrap();
+    assert_eq!(
+        bob_auth.receive(&bob, last_sent(&mallory)).unwrap_err(),
+        AuthError::UnexpectedPeer
+    );
+    assert!(!bob_auth.is_authenticated());
+}
+
+#[cfg(feature = "noise")]
+mod noise {
+    use super::*;
+    use crate::wire::{NoiseBus, NoiseError, NoiseHandshake, NoiseKey, MAX_HANDSHAKE_FRAME};
+
+    /// Run the handshake until one side fails or both are finished.
+    fn handshake(
+        (a, a_hs): (&Client, &mut NoiseHandshake),
+        (b, b_hs): (&Client, &mut NoiseHandshake),
+    ) -> Result<(), NoiseError> {
+        let mut buf = [0; MAX_HANDSHAKE_FRAME];
+        while !(a_hs.is_finished() && b_hs.is_finished()) {
+            let (writer, reader, client) = if a_hs.is_writing() {
+                (&mut *a_hs, &mut *b_hs, b)
+            } else {
+                (&mut *b_hs, &mut *a_hs, a)
+            };
+            let len = writer.write_message(&mut buf)?;
+            reader.read_message(client, &buf[2..len])?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_participant_messages() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let (alice, bob) = (client(&mut rng), client(&mut rng));
+        let (alice_id, bob_id) = (alice.identity(), bob.identity());
+        let mut alice_hs = NoiseHandshake::initiator(
+            &NoiseKey::new(&alice, &mut rng),
+            Some(bob_id.clone()),
+            &mut rng,
+        );
+        let mut bob_hs = NoiseHandshake::responder(&NoiseKey::new(&bob, &mut rng), None, &mut rng);
+        assert_eq!(
+            bob_hs.write_message(&mut [0; MAX_HANDSHAKE_FRAME]),
+            Err(NoiseError::InvalidState)
+        );
+        handshake((&alice, &mut alice_hs), (&bob, &mut bob_hs)).unwrap();
+        assert_eq!(alice_hs.remote_identity(), Some(&bob_id));
+        assert_eq!(bob_hs.remote_identity(), Some(&alice_id));
+
+        // Alice's side is a queue that is full after the first message.
+        let alice_bus = NoiseBus::<_, 256>::new(QueuedBus::<200, 0>::new());
+        let bob_bus = NoiseBus::<_, 256>::new(Capture::default());
+        let layer = ProtoBufEncodingLayer { bus: &alice_bus };
+        let msg = |version| ParticipantMessage::ChannelUpdateRejected {
+            id: Hash([7; 32]),
+            version,
+            reason: "no".into(),
+        };
+        assert_eq!(
+            layer.send_to_participant(&alice_id, &bob_id, msg(1)),
+            Err(BusError::NotConnected)
+        );
+        alice_bus.connect(alice_hs.into_transport().unwrap());
+        bob_bus.connect(bob_hs.into_transport().unwrap());
+
+        layer
+            .send_to_participant(&alice_id, &bob_id, msg(1))
+            .unwrap();
+        assert_eq!(
+            layer.send_to_participant(&alice_id, &bob_id, msg(2)),
+            Err(BusError::Busy)
+        );
+        let mut sent = Vec::new();
+        alice_bus
+            .bus
+            .drain_participant(|data| take(&mut sent, data, usize::MAX));
+        // The failed message is sent again unchanged, so it can be decrypted
+        // after the first one.
+        layer
+            .send_to_participant(&alice_id, &bob_id, msg(2))
+            .unwrap();
+        alice_bus
+            .bus
+            .drain_participant(|data| take(&mut sent, data, usize::MAX));
+
+        let mut decoder = FrameDecoder::<256>::new();
+        assert_eq!(decoder.push(&sent), sent.len());
+        let mut out = [0; 256];
+        for version in [1, 2] {
+            let frame = decoder.next_frame().unwrap().unwrap();
+            let env =
+                decode_participant_message(bob_bus.decrypt(frame, &mut out).unwrap()).unwrap();
+            assert_eq!(env.sender, alice_id);
+            assert!(matches!(
+                env.msg,
+                ParticipantMessage::ChannelUpdateRejected { version: v, .. } if v == version
+            ));
+        }
+
+        // Bob answers, a tampered frame is rejected.
+        ProtoBufEncodingLayer { bus: &bob_bus }
+            .send_to_participant(&bob_id, &alice_id, msg(3))
+            .unwrap();
+        let mut frame = bob_bus.bus.0.borrow_mut().pop().unwrap();
+        frame[10] ^= 1;
+        assert_eq!(
+            alice_bus.decrypt(&frame[2..], &mut out),
+            Err(NoiseError::Decrypt)
+        );
+    }
+
+    #[test]
+    fn noise_rejects_unexpected_peer() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let (alice, bob, mallory) = (client(&mut rng), client(&mut rng), client(&mut rng));
+
+        // Alice dials Bob, but Mallory answers.
+        let mut alice_hs = NoiseHandshake::initiator(
+            &NoiseKey::new(&alice, &mut rng),
+            Some(bob.identity()),
+            &mut rng,
+        );
+        let mut mallory_hs =
+            NoiseHandshake::responder(&NoiseKey::new(&mallory, &mut rng), None, &mut rng);
+        assert_eq!(
+            handshake((&alice, &mut alice_hs), (&mallory, &mut mallory_hs)),
+            Err(NoiseError::Auth(AuthError::UnexpectedPeer))
+        );
+        assert!(alice_hs.into_transport().is_err());
+
+        // Mallory replaces Bob's static key in transit.
+        let mut alice_hs = NoiseHandshake::initiator(
+            &NoiseKey::new(&alice, &mut rng),
+            Some(bob.identity()),
+            &mut rng,
+        );
+        let mut bob_hs = NoiseHandshake::responder(&NoiseKey::new(&bob, &mut rng), None, &mut rng);
+        let mut buf = [0; MAX_HANDSHAKE_FRAME];
+        let len = alice_hs.write_message(&mut buf).unwrap();
+        bob_hs.read_message(&bob, &buf[2..len]).unwrap();
+        let len = bob_hs.write_message(&mut buf).unwrap();
+        buf[40] ^= 1;
+        assert_eq!(
+            alice_hs.read_message(&alice, &buf[2..len]),
+            Err(NoiseError::Decrypt)
+        );
+    }
+
+    #[test]
+    fn bound_handshake_is_not_replayable() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let (alice, bob) = (client(&mut rng), client(&mut rng));
+        let (alice_id, bob_id) = (alice.identity(), bob.identity());
+        let connect = |rng: &mut StdRng| {
+            let mut alice_hs =
+                NoiseHandshake::initiator(&NoiseKey::new(&alice, rng), Some(bob_id.clone()), rng);
+            let mut bob_hs = NoiseHandshake::responder(&NoiseKey::new(&bob, rng), None, rng);
+            handshake((&alice, &mut alice_hs), (&bob, &mut bob_hs)).unwrap();
+            let (alice_tr, bob_tr) = (
+                alice_hs.into_transport().unwrap(),
+                bob_hs.into_transport().unwrap(),
+            );
+            assert_eq!(alice_tr.handshake_hash(), bob_tr.handshake_hash());
+            *bob_tr.handshake_hash()
+        };
+        let (first, second) = (connect(&mut rng), connect(&mut rng));
+        assert_ne!(first, second);
+
+        // Alice's handshake on the first connection is captured and replayed
+        // on the second one.
+        alice
+            .send_handshake_msg(&alice_id, &bob_id, Some(&first))
+            .unwrap();
+        let captured = last_sent(&alice);
+        let mut bob_auth = PeerAuth::new(bob_id.clone(), Some(alice_id.clone()));
+        bob_auth.bind(second);
+        assert_eq!(
+            bob_auth.receive(&bob, captured.clone()).unwrap_err(),
+            AuthError::InvalidSignature
+        );
+        // An unbound (go-perun) handshake doesn't do either.
+        alice.send_handshake_msg(&alice_id, &bob_id, None).unwrap();
+        assert_eq!(
+            bob_auth.receive(&bob, last_sent(&alice)).unwrap_err(),
+            AuthError::InvalidSignature
+        );
+        assert!(!bob_auth.is_authenticated());
+
+        // On the connection it was made for, it is accepted.
+        bob_auth.reset();
+        bob_auth.bind(first);
+        assert!(matches!(bob_auth.receive(&bob, captured), Ok(None)));
+        assert_eq!(bob_auth.peer(), Some(&alice_id));
+    }
+
+    #[test]
+    fn noise_session_ends_when_a_frame_is_skipped() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let (alice, bob) = (client(&mut rng), client(&mut rng));
+        let (alice_id, bob_id) = (alice.identity(), bob.identity());
+        let mut alice_hs = NoiseHandshake::initiator(
+            &NoiseKey::new(&alice, &mut rng),
+            Some(bob_id.clone()),
+            &mut rng,
+        );
+        let mut bob_hs = NoiseHandshake::responder(&NoiseKey::new(&bob, &mut rng), None, &mut rng);
+        handshake((&alice, &mut alice_hs), (&bob, &mut bob_hs)).unwrap();
+
+        // The queue is full after the first frame.
+        let alice_bus = NoiseBus::<_, 64>::new(QueuedBus::<40, 0>::new());
+        alice_bus.connect(alice_hs.into_transport().unwrap());
+        alice_bus
+            .send_to_participant(&alice_id, &bob_id, &[1; 16])
+            .unwrap();
+        assert_eq!(
+            alice_bus.send_to_participant(&alice_id, &bob_id, &[2; 16]),
+            Err(BusError::Busy)
+        );
+        // Sending something else instead would skip the failed frame.
+        let mut sent = Vec::new();
+        alice_bus
+            .bus
+            .drain_participant(|data| take(&mut sent, data, usize::MAX));
+        assert_eq!(
+            alice_bus.send_to_participant(&alice_id, &bob_id, &[3; 16]),
+            Err(BusError::NotConnected)
+        );
+        assert_eq!(alice_bus.remote_identity(), None);
+    }
+}